@@ -0,0 +1,58 @@
+use crate::*;
+
+/// admin log, the latest MAX_ADMIN_LOG_ENTRIES configuration changes kept for audits
+pub const MAX_ADMIN_LOG_ENTRIES: u64 = 1_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AdminLogEntry {
+    pub index: U64,
+    pub account_id: AccountId,
+    pub method: String,
+    pub args: String, // JSON
+    pub timestamp: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    // entries ever recorded, the oldest are overwritten past MAX_ADMIN_LOG_ENTRIES
+    pub fn get_admin_log_len(&self) -> U64 {
+        U64(self.admin_log_len)
+    }
+
+    // from_index counts every entry ever recorded, overwritten ones are skipped
+    pub fn get_admin_log(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<AdminLogEntry> {
+        let first_index = self.admin_log_len.saturating_sub(MAX_ADMIN_LOG_ENTRIES);
+        let from_index = from_index.map_or(first_index, |from_index| {
+            std::cmp::max(from_index.0, first_index)
+        });
+        let limit = limit.unwrap_or(MAX_ADMIN_LOG_ENTRIES);
+
+        (from_index..self.admin_log_len)
+            .take(limit as usize)
+            .filter_map(|index| self.admin_log.get(index % MAX_ADMIN_LOG_ENTRIES))
+            .collect()
+    }
+
+    pub(crate) fn internal_record_admin_action(
+        &mut self,
+        method: &str,
+        args: near_sdk::serde_json::Value,
+    ) {
+        let index = self.admin_log_len;
+        let entry = AdminLogEntry {
+            index: U64(index),
+            account_id: env::predecessor_account_id(),
+            method: method.to_string(),
+            args: args.to_string(),
+            timestamp: U64(env::block_timestamp()),
+        };
+        if index < MAX_ADMIN_LOG_ENTRIES {
+            self.admin_log.push(&entry);
+        } else {
+            self.admin_log
+                .replace(index % MAX_ADMIN_LOG_ENTRIES, &entry);
+        }
+        self.admin_log_len += 1;
+    }
+}
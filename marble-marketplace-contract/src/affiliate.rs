@@ -0,0 +1,216 @@
+use crate::*;
+
+/// affiliate front-ends, registered referral ids accrue a share of the treasury fee
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Affiliate {
+    pub fee_bps: Option<u16>, // share of the treasury fee, affiliate_fee when unset
+    pub registered_at: U64,
+}
+
+impl Config {
+    fn gas_for_callback_claim_affiliate(&self) -> Gas {
+        Gas(self.base_gas.0 * 2)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // a front-end passes its account as referral_id in buy, ft_on_transfer or MarketArgs
+    #[payable]
+    pub fn register_affiliate(&mut self) {
+        let affiliate_id = env::predecessor_account_id();
+        assert!(
+            env::attached_deposit() >= self.config.storage_add_market_data.0,
+            "Marble: Attach at least {} yoctoNEAR for storage",
+            self.config.storage_add_market_data.0
+        );
+        assert!(
+            self.affiliates.get(&affiliate_id).is_none(),
+            "Marble: Affiliate is already registered"
+        );
+        self.affiliates.insert(
+            &affiliate_id,
+            &Affiliate {
+                fee_bps: None,
+                registered_at: U64(env::block_timestamp()),
+            },
+        );
+
+        env::log_str(
+            &json!({
+                "type": "register_affiliate",
+                "params": {
+                    "affiliate_id": affiliate_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn set_affiliate_fee(&mut self, affiliate_fee: u16) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_affiliate_fee",
+            json!({ "affiliate_fee": affiliate_fee }),
+        );
+        assert!(
+            affiliate_fee <= 10_000,
+            "Marble: affiliate_fee cannot be higher than 10000"
+        );
+        self.affiliate_fee = affiliate_fee;
+    }
+
+    pub fn get_affiliate_fee(&self) -> u16 {
+        self.affiliate_fee
+    }
+
+    // negotiated share for a single front-end, None falls back to affiliate_fee
+    #[payable]
+    pub fn set_affiliate_fee_bps(&mut self, affiliate_id: AccountId, fee_bps: Option<u16>) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_affiliate_fee_bps",
+            json!({ "affiliate_id": affiliate_id, "fee_bps": fee_bps }),
+        );
+        assert!(
+            fee_bps.map_or(true, |fee_bps| fee_bps <= 10_000),
+            "Marble: fee_bps cannot be higher than 10000"
+        );
+        let mut affiliate = self
+            .affiliates
+            .get(&affiliate_id)
+            .expect("Marble: Affiliate is not registered");
+        affiliate.fee_bps = fee_bps;
+        self.affiliates.insert(&affiliate_id, &affiliate);
+    }
+
+    // earnings already accrued stay claimable
+    #[payable]
+    pub fn remove_affiliate(&mut self, affiliate_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "remove_affiliate",
+            json!({ "affiliate_id": affiliate_id }),
+        );
+        self.affiliates
+            .remove(&affiliate_id)
+            .expect("Marble: Affiliate is not registered");
+    }
+
+    pub fn get_affiliate(&self, affiliate_id: AccountId) -> Option<Affiliate> {
+        self.affiliates.get(&affiliate_id)
+    }
+
+    pub fn get_affiliates(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<AccountId> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.affiliates.len());
+
+        self.affiliates
+            .keys()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_affiliate_earnings(
+        &self,
+        affiliate_id: AccountId,
+        ft_token_id: Option<AccountId>,
+    ) -> U128 {
+        let affiliate_and_ft_token_id = format!(
+            "{}{}{}",
+            affiliate_id,
+            DELIMETER,
+            ft_token_id.unwrap_or(near_account())
+        );
+        self.affiliate_earnings
+            .get(&affiliate_and_ft_token_id)
+            .unwrap_or(0)
+            .into()
+    }
+
+    #[payable]
+    pub fn claim_affiliate_earnings(&mut self, ft_token_id: Option<AccountId>) -> U128 {
+        assert_one_yocto();
+        let affiliate_id = env::predecessor_account_id();
+        let ft_token_id = ft_token_id.unwrap_or(near_account());
+        let affiliate_and_ft_token_id = format!("{}{}{}", affiliate_id, DELIMETER, ft_token_id);
+        let amount = self
+            .affiliate_earnings
+            .remove(&affiliate_and_ft_token_id)
+            .unwrap_or(0);
+        assert!(amount > 0, "Marble: Nothing to claim");
+
+        if ft_token_id == near_account() {
+            Promise::new(affiliate_id.clone()).transfer(amount);
+        } else {
+            self.internal_ft_transfer(&ft_token_id, affiliate_id.clone(), amount.into())
+                .then(ext_self::callback_claim_affiliate_earnings(
+                    affiliate_id.clone(),
+                    ft_token_id.clone(),
+                    amount.into(),
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    self.config.gas_for_callback_claim_affiliate(),
+                ));
+        }
+
+        env::log_str(
+            &json!({
+                "type": "claim_affiliate_earnings",
+                "params": {
+                    "affiliate_id": affiliate_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                }
+            })
+            .to_string(),
+        );
+        amount.into()
+    }
+
+    // a failed ft_transfer leaves the earnings claimable
+    #[private]
+    pub fn callback_claim_affiliate_earnings(
+        &mut self,
+        affiliate_id: AccountId,
+        ft_token_id: AccountId,
+        amount: U128,
+    ) {
+        if is_promise_success() {
+            return;
+        }
+        self.internal_accrue_affiliate_earnings(&affiliate_id, &ft_token_id, amount.0);
+    }
+
+    // the affiliate's part of the treasury fee, None when referral_id is not an affiliate
+    pub(crate) fn internal_affiliate_fee(
+        &self,
+        referral_id: &AccountId,
+        treasury_fee: u128,
+    ) -> Option<u128> {
+        self.affiliates.get(referral_id).map(|affiliate| {
+            treasury_fee * affiliate.fee_bps.unwrap_or(self.affiliate_fee) as u128 / 10_000u128
+        })
+    }
+
+    pub(crate) fn internal_accrue_affiliate_earnings(
+        &mut self,
+        affiliate_id: &AccountId,
+        ft_token_id: &AccountId,
+        amount: u128,
+    ) {
+        let affiliate_and_ft_token_id = format!("{}{}{}", affiliate_id, DELIMETER, ft_token_id);
+        let earnings = self
+            .affiliate_earnings
+            .get(&affiliate_and_ft_token_id)
+            .unwrap_or(0);
+        self.affiliate_earnings
+            .insert(&affiliate_and_ft_token_id, &(earnings + amount));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::*;
+
+/// blocklists for accounts under legal hold, they can no longer buy, bid, offer or list
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn add_blacklisted_buyer_ids(&mut self, account_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "add_blacklisted_buyer_ids",
+            json!({ "account_ids": account_ids }),
+        );
+        add_accounts(Some(account_ids), &mut self.blacklisted_buyer_ids);
+    }
+
+    #[payable]
+    pub fn remove_blacklisted_buyer_ids(&mut self, account_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "remove_blacklisted_buyer_ids",
+            json!({ "account_ids": account_ids }),
+        );
+        remove_accounts(Some(account_ids), &mut self.blacklisted_buyer_ids);
+    }
+
+    // listings already on the market stay until delete_blacklisted_market_data
+    #[payable]
+    pub fn add_blacklisted_seller_ids(&mut self, account_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "add_blacklisted_seller_ids",
+            json!({ "account_ids": account_ids }),
+        );
+        add_accounts(Some(account_ids), &mut self.blacklisted_seller_ids);
+    }
+
+    #[payable]
+    pub fn remove_blacklisted_seller_ids(&mut self, account_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "remove_blacklisted_seller_ids",
+            json!({ "account_ids": account_ids }),
+        );
+        remove_accounts(Some(account_ids), &mut self.blacklisted_seller_ids);
+    }
+
+    pub fn get_blacklisted_buyer_ids(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.blacklisted_buyer_ids.len());
+
+        self.blacklisted_buyer_ids
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_blacklisted_seller_ids(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.blacklisted_seller_ids.len());
+
+        self.blacklisted_seller_ids
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn is_blacklisted(&self, account_id: AccountId) -> bool {
+        self.blacklisted_buyer_ids.contains(&account_id)
+            || self.blacklisted_seller_ids.contains(&account_id)
+    }
+
+    // force-delists a blacklisted seller in pages of limit listings, bids and listing fees are refunded
+    #[payable]
+    pub fn delete_blacklisted_market_data(
+        &mut self,
+        owner_id: AccountId,
+        limit: Option<u64>,
+    ) -> u64 {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        assert!(
+            self.blacklisted_seller_ids.contains(&owner_id),
+            "Marble: Seller is not blacklisted"
+        );
+        let contract_and_token_ids: Vec<ContractAndTokenId> = match self.by_owner_id.get(&owner_id)
+        {
+            Some(by_owner_id) => {
+                let limit = limit.unwrap_or(by_owner_id.len());
+                by_owner_id.iter().take(limit as usize).collect()
+            }
+            None => vec![],
+        };
+
+        for contract_and_token_id in &contract_and_token_ids {
+            let mut split = contract_and_token_id.split(DELIMETER);
+            let nft_contract_id: AccountId = split.next().unwrap().parse().unwrap();
+            let token_id: TokenId = split.next().unwrap().to_string();
+
+            self.internal_refund_listing_fee(&owner_id, contract_and_token_id);
+            self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+            env::log_str(
+                &json!({
+                    "type": "delete_market_data",
+                    "params": {
+                        "owner_id": owner_id,
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                        "reason": "blacklisted",
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        contract_and_token_ids.len() as u64
+    }
+
+    pub(crate) fn assert_not_blacklisted_buyer(&self, account_id: &AccountId) {
+        assert!(
+            !self.blacklisted_buyer_ids.contains(account_id),
+            "Marble: Buyer is blacklisted"
+        );
+    }
+
+    pub(crate) fn assert_not_blacklisted_seller(&self, account_id: &AccountId) {
+        assert!(
+            !self.blacklisted_seller_ids.contains(account_id),
+            "Marble: Seller is blacklisted"
+        );
+    }
+}
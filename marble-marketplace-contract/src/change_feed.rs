@@ -0,0 +1,77 @@
+use crate::*;
+
+/// change feed, the latest MAX_CHANGES keys written to market, offers and trades.
+/// A poller re-reads the keys changed since its last block height, a missing key was removed.
+pub const MAX_CHANGES: u64 = 1_000;
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeType {
+    Market, // nft_contract_id||token_id
+    Offer,  // nft_contract_id||buyer_id||token
+    Trade,  // buyer_nft_contract_id||buyer_id||buyer_token_id
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Change {
+    pub index: U64,
+    pub change_type: ChangeType,
+    pub key: String,
+    pub block_height: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    // changes ever recorded, the oldest are overwritten past MAX_CHANGES
+    pub fn get_changes_len(&self) -> U64 {
+        U64(self.changes_len)
+    }
+
+    // the oldest retained change is returned if height is older, the poller then resyncs from the
+    // views when its last height is below that change's block_height
+    pub fn get_changes_since(&self, height: U64, limit: Option<u64>) -> Vec<Change> {
+        let limit = limit.unwrap_or(MAX_CHANGES);
+        assert!(limit > 0, "Marble: limit must be higher than 0");
+
+        // changes are recorded in block order, the first one at or after height is searched
+        let mut from_index = self.changes_len.saturating_sub(MAX_CHANGES);
+        let mut to_index = self.changes_len;
+        while from_index < to_index {
+            let index = from_index + (to_index - from_index) / 2;
+            let block_height = self
+                .changes
+                .get(index % MAX_CHANGES)
+                .map_or(0, |change| change.block_height.0);
+            if block_height < height.0 {
+                from_index = index + 1;
+            } else {
+                to_index = index;
+            }
+        }
+
+        (from_index..self.changes_len)
+            .take(limit as usize)
+            .filter_map(|index| self.changes.get(index % MAX_CHANGES))
+            .collect()
+    }
+
+    pub(crate) fn internal_record_change(&mut self, change_type: ChangeType, key: &str) {
+        let index = self.changes_len;
+        let change = Change {
+            index: U64(index),
+            change_type,
+            key: key.to_string(),
+            block_height: U64(env::block_height()),
+        };
+        if index < MAX_CHANGES {
+            self.changes.push(&change);
+        } else {
+            self.changes.replace(index % MAX_CHANGES, &change);
+        }
+        self.changes_len += 1;
+    }
+}
@@ -0,0 +1,121 @@
+use crate::*;
+
+/// claimable balances, FT payouts and refunds that could not be delivered
+impl Config {
+    fn gas_for_callback_transfer_payout(&self) -> Gas {
+        Gas(self.gas_for_ft_transfer.0 * 2 + self.base_gas.0)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_claimable_balance(&self, account_id: AccountId, ft_token_id: AccountId) -> U128 {
+        let account_and_ft_token_id = format!("{}{}{}", account_id, DELIMETER, ft_token_id);
+        self.claimable_balances
+            .get(&account_and_ft_token_id)
+            .unwrap_or(0)
+            .into()
+    }
+
+    // register with the token contract first, a failed transfer is credited back
+    #[payable]
+    pub fn claim_balance(&mut self, ft_token_id: AccountId) -> U128 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let account_and_ft_token_id = format!("{}{}{}", account_id, DELIMETER, ft_token_id);
+        let amount = self
+            .claimable_balances
+            .remove(&account_and_ft_token_id)
+            .unwrap_or(0);
+        assert!(amount > 0, "Marble: Nothing to claim");
+
+        self.internal_transfer(&ft_token_id, account_id.clone(), amount);
+
+        env::log_str(
+            &json!({
+                "type": "claim_balance",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                }
+            })
+            .to_string(),
+        );
+        amount.into()
+    }
+
+    // sale proceeds, royalties and referral fees, unregistered receivers are credited instead
+    pub(crate) fn internal_transfer_payout(
+        &self,
+        ft_token_id: &AccountId,
+        receiver_id: AccountId,
+        amount: Balance,
+    ) {
+        if *ft_token_id == near_account() || self.mt_currencies.get(ft_token_id).is_some() {
+            return self.internal_transfer(ft_token_id, receiver_id, amount);
+        }
+        ext_storage_management::storage_balance_of(
+            receiver_id.clone(),
+            ft_token_id.clone(),
+            NO_DEPOSIT,
+            self.config.base_gas,
+        )
+        .then(ext_self::callback_transfer_payout(
+            ft_token_id.clone(),
+            receiver_id,
+            U128(amount),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_transfer_payout(),
+        ));
+    }
+
+    // a token without storage_balance_of is tried anyway, a failed transfer is credited too
+    #[private]
+    pub fn callback_transfer_payout(
+        &mut self,
+        ft_token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) {
+        let is_registered = promise_result_as_success().map_or(true, |value| {
+            near_sdk::serde_json::from_slice::<Option<near_sdk::serde_json::Value>>(&value)
+                .map_or(true, |storage_balance| storage_balance.is_some())
+        });
+        if is_registered {
+            self.internal_transfer(&ft_token_id, receiver_id, amount.0);
+        } else {
+            self.internal_credit_claimable_balance(&receiver_id, &ft_token_id, amount.0);
+        }
+    }
+
+    pub(crate) fn internal_credit_claimable_balance(
+        &mut self,
+        account_id: &AccountId,
+        ft_token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let account_and_ft_token_id = format!("{}{}{}", account_id, DELIMETER, ft_token_id);
+        let balance = self
+            .claimable_balances
+            .get(&account_and_ft_token_id)
+            .unwrap_or(0)
+            + amount;
+        self.claimable_balances
+            .insert(&account_and_ft_token_id, &balance);
+
+        env::log_str(
+            &json!({
+                "type": "credit_claimable_balance",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                    "balance": U128(balance),
+                }
+            })
+            .to_string(),
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use crate::*;
+
+/// runtime configuration, tuned by the owner without a redeploy
+pub const MAX_GAS_PER_CALL: Gas = Gas(300_000_000_000_000);
+pub const MAX_BIDS: u64 = 100; // bounded by the listing storage deposit
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    pub max_active_per_account: u64, // listings and offers, 0 for no limit
+    pub max_price: U128,
+    pub storage_add_market_data: U128,
+    pub anti_snipe_window: U64, // bids this close to the end extend the auction by as much
+    pub max_bids: u64,          // the oldest bid is cancelled past this
+    pub base_gas: Gas,
+    pub gas_for_nft_transfer: Gas,
+    pub gas_for_nft_token: Gas,
+    pub gas_for_royalties: Gas,
+    pub gas_for_ft_transfer: Gas,
+    pub gas_for_ft_payout: Gas,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_active_per_account: 0,
+            max_price: U128(MAX_PRICE),
+            storage_add_market_data: U128(STORAGE_ADD_MARKET_DATA),
+            anti_snipe_window: U64(FIVE_MINUTES),
+            max_bids: MAX_BIDS,
+            base_gas: BASE_GAS,
+            gas_for_nft_transfer: GAS_FOR_NFT_TRANSFER,
+            gas_for_nft_token: GAS_FOR_NFT_TOKEN,
+            gas_for_royalties: GAS_FOR_ROYALTIES,
+            gas_for_ft_transfer: GAS_FOR_FT_TRANSFER,
+            gas_for_ft_payout: GAS_FOR_FT_PAYOUT,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn gas_for_nft_mint(&self) -> Gas {
+        Gas(self.gas_for_nft_transfer.0 * 2)
+    }
+
+    pub(crate) fn gas_for_callback_buy_usd(&self) -> Gas {
+        Gas(self.gas_for_nft_token.0
+            + self.gas_for_nft_transfer.0
+            + self.gas_for_royalties.0
+            + self.base_gas.0 * 2)
+    }
+
+    pub(crate) fn gas_for_callback_relist(&self) -> Gas {
+        Gas(self.base_gas.0 * 4)
+    }
+
+    pub(crate) fn gas_for_callback_mint_sale(&self) -> Gas {
+        Gas(self.gas_for_nft_token.0 + self.gas_for_royalties.0 + self.base_gas.0)
+    }
+
+    pub(crate) fn gas_for_callback_decline_offer(&self) -> Gas {
+        Gas(self.base_gas.0 * 2)
+    }
+
+    pub(crate) fn gas_for_callback_reject_trade(&self) -> Gas {
+        Gas(self.gas_for_ft_transfer.0 * 4 + self.base_gas.0)
+    }
+
+    // the callback itself reads and deletes the offer before the transfer
+    pub(crate) fn gas_for_callback_accept_offer(&self) -> Gas {
+        Gas(self.gas_for_nft_transfer.0 + self.gas_for_royalties.0 + self.base_gas.0 * 2)
+    }
+
+    // FT royalties are paid with an ft_transfer each
+    pub(crate) fn gas_for_resolve_offer(&self, ft_token_id: &AccountId) -> Gas {
+        if *ft_token_id == near_account() {
+            self.gas_for_royalties
+        } else {
+            self.gas_for_ft_payout
+        }
+    }
+
+    pub(crate) fn gas_for_callback_resolve_trade(&self, buyer_tokens_count: usize) -> Gas {
+        // the escrowed buyer tokens are forwarded with their callbacks, releasing cash in an FT
+        // takes two ft_transfer plus the treasury one, with their callbacks
+        Gas(
+            (self.gas_for_nft_transfer.0 + self.base_gas.0) * buyer_tokens_count as u64
+                + self.gas_for_ft_transfer.0 * 6
+                + self.base_gas.0,
+        )
+    }
+
+    pub(crate) fn gas_for_callback_escrow_trade(
+        &self,
+        buyer_tokens_count: usize,
+        seller_tokens_count: usize,
+    ) -> Gas {
+        Gas(self.gas_for_nft_transfer.0 * seller_tokens_count as u64
+            + self.gas_for_callback_resolve_trade(buyer_tokens_count).0
+            + self.base_gas.0)
+    }
+
+    pub(crate) fn gas_for_callback_verify_trade(
+        &self,
+        buyer_tokens_count: usize,
+        seller_tokens_count: usize,
+    ) -> Gas {
+        Gas(self.gas_for_nft_transfer.0 * buyer_tokens_count as u64
+            + self
+                .gas_for_callback_escrow_trade(buyer_tokens_count, seller_tokens_count)
+                .0
+            + self.base_gas.0)
+    }
+
+    fn assert_valid(&self) {
+        assert!(
+            self.max_price.0 > 0,
+            "Marble: max_price must be higher than 0"
+        );
+        assert!(
+            self.storage_add_market_data.0 > 0,
+            "Marble: storage_add_market_data must be higher than 0"
+        );
+        assert!(
+            self.anti_snipe_window.0 <= ONE_DAY,
+            "Marble: anti_snipe_window cannot be longer than a day"
+        );
+        assert!(
+            self.max_bids > 0 && self.max_bids <= MAX_BIDS,
+            "Marble: max_bids must be between 1 and {}",
+            MAX_BIDS
+        );
+        for gas in [
+            self.base_gas,
+            self.gas_for_nft_transfer,
+            self.gas_for_nft_token,
+            self.gas_for_royalties,
+            self.gas_for_ft_transfer,
+            self.gas_for_ft_payout,
+        ] {
+            assert!(
+                gas.0 > 0 && gas.0 <= MAX_GAS_PER_CALL.0,
+                "Marble: Gas must be between 1 and {}",
+                MAX_GAS_PER_CALL.0
+            );
+        }
+        // the heaviest fixed callback still has to fit in one call
+        assert!(
+            self.gas_for_callback_buy_usd().0 <= MAX_GAS_PER_CALL.0,
+            "Marble: Gas for a purchase cannot be higher than {}",
+            MAX_GAS_PER_CALL.0
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn set_config(&mut self, config: Config) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action("set_config", json!({ "config": config }));
+        config.assert_valid();
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    // replacing an account's existing listing or offer never counts against the limit
+    pub(crate) fn assert_below_active_limit(&self, account_id: &AccountId, key: &String) {
+        let max_active_per_account = self.config.max_active_per_account;
+        if max_active_per_account == 0 {
+            return;
+        }
+        let is_new = self
+            .by_owner_id
+            .get(account_id)
+            .map_or(true, |by_owner_id| !by_owner_id.contains(key));
+        assert!(
+            !is_new || self.get_supply_by_owner_id(account_id.clone()).0 < max_active_per_account,
+            "Marble: Up to {} active listings and offers per account",
+            max_active_per_account
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use crate::*;
+
+/// checkout through ref.finance, the paid token is swapped into the listing's currency first.
+/// NEAR listings are swapped into wNEAR, the purchase then unwraps it as a wNEAR payment would.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapPurchase {
+    pub dex_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub buyer_id: AccountId,
+    pub receiver_id: Option<AccountId>,
+    pub referral_id: Option<AccountId>,
+    pub swap_pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+impl Config {
+    // ft_transfer_call into the DEX, its ft_on_transfer and ft_resolve_transfer
+    fn gas_for_dex_deposit(&self) -> Gas {
+        Gas(self.gas_for_ft_transfer.0 * 3)
+    }
+
+    fn gas_for_dex_swap(&self) -> Gas {
+        Gas(self.base_gas.0 * 4)
+    }
+
+    fn gas_for_dex_withdraw(&self) -> Gas {
+        Gas(self.gas_for_ft_transfer.0 * 3)
+    }
+
+    // what is left for royalties once the swap has run
+    fn gas_for_swap_resolve_purchase(&self) -> Gas {
+        Gas(self.gas_for_ft_payout.0 / 2)
+    }
+
+    fn gas_for_callback_swap_withdraw(&self) -> Gas {
+        Gas(self.gas_for_nft_transfer.0
+            + self.gas_for_swap_resolve_purchase().0
+            + self.base_gas.0 * 2)
+    }
+
+    fn gas_for_callback_swap(&self) -> Gas {
+        Gas(self.gas_for_dex_withdraw().0
+            + self.gas_for_callback_swap_withdraw().0
+            + self.base_gas.0)
+    }
+
+    fn gas_for_callback_swap_deposit(&self) -> Gas {
+        Gas(self.gas_for_dex_swap().0 + self.gas_for_callback_swap().0 + self.base_gas.0)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // the marketplace must be registered with the DEX and every token it swaps
+    #[payable]
+    pub fn set_dex_id(&mut self, dex_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action("set_dex_id", json!({ "dex_id": dex_id }));
+        self.dex_id = dex_id;
+    }
+
+    pub fn get_dex_id(&self) -> Option<AccountId> {
+        self.dex_id.clone()
+    }
+
+    // checked up front so that ft_resolve_transfer refunds a purchase that cannot happen
+    pub(crate) fn internal_buy_with_swap(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        token_in: AccountId,
+        buyer_id: AccountId,
+        amount_in: Balance,
+        swap_pool_id: u64,
+        min_amount_out: U128,
+        referral_id: Option<AccountId>,
+        receiver_id: Option<AccountId>,
+    ) {
+        let dex_id = self.dex_id.clone().expect("Marble: DEX is not set");
+        assert!(
+            self.mt_currencies.get(&token_in).is_none(),
+            "Marble: Multi-token currencies cannot be swapped"
+        );
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .internal_get_market_data(&contract_and_token_id)
+            .expect("Marble: Market data does not exist");
+        assert_ne!(
+            buyer_id, market_data.owner_id,
+            "Marble: Cannot buy your own sale"
+        );
+        assert!(
+            self.layaways.get(&contract_and_token_id).is_none(),
+            "Marble: Listing is locked by a layaway"
+        );
+        assert_ne!(
+            referral_id.as_ref(),
+            Some(&buyer_id),
+            "Marble: Cannot refer yourself"
+        );
+
+        let token_out = self.internal_swap_token_out(&market_data);
+        assert_ne!(
+            token_in, token_out,
+            "Marble: Transferred token is already the listing's currency"
+        );
+        let price = self
+            .internal_listing_price(
+                &contract_and_token_id,
+                &market_data,
+                &market_data.ft_token_id,
+            )
+            .expect("Marble: Listing has no price");
+        assert!(
+            min_amount_out.0 >= price,
+            "Marble: min_amount_out is less than price {}",
+            price
+        );
+
+        let swap_purchase = SwapPurchase {
+            dex_id: dex_id.clone(),
+            nft_contract_id,
+            token_id,
+            buyer_id,
+            receiver_id,
+            referral_id,
+            swap_pool_id,
+            token_in: token_in.clone(),
+            amount_in: U128(amount_in),
+            token_out,
+            min_amount_out,
+        };
+        assert!(
+            self.internal_is_swap_purchasable(&swap_purchase, min_amount_out.0),
+            "Marble: Listing cannot be bought with a swap"
+        );
+        ext_fungible_token::ft_transfer_call(
+            dex_id,
+            U128(amount_in),
+            None,
+            "".to_string(),
+            token_in,
+            1,
+            self.config.gas_for_dex_deposit(),
+        )
+        .then(ext_self::callback_swap_deposit(
+            swap_purchase,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_swap_deposit(),
+        ));
+    }
+
+    fn internal_swap_token_out(&self, market_data: &MarketData) -> AccountId {
+        if market_data.ft_token_id == near_account() {
+            self.wrap_near_id
+                .clone()
+                .expect("Marble: wNEAR contract is not set")
+        } else {
+            market_data.ft_token_id.clone()
+        }
+    }
+
+    #[private]
+    pub fn callback_swap_deposit(&mut self, swap_purchase: SwapPurchase) {
+        // ft_transfer_call returns what the DEX kept
+        let deposited = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<U128>(&value).ok())
+            .map_or(0, |deposited| deposited.0);
+        let refund = swap_purchase.amount_in.0 - deposited;
+        if refund > 0 {
+            self.internal_transfer(
+                &swap_purchase.token_in,
+                swap_purchase.buyer_id.clone(),
+                refund,
+            );
+        }
+        if deposited == 0 {
+            return log_swap_fail(&swap_purchase, "deposit failed");
+        }
+        let swap_purchase = SwapPurchase {
+            amount_in: U128(deposited),
+            ..swap_purchase
+        };
+
+        ext_ref_exchange::swap(
+            vec![SwapAction {
+                pool_id: swap_purchase.swap_pool_id,
+                token_in: swap_purchase.token_in.clone(),
+                amount_in: Some(swap_purchase.amount_in),
+                token_out: swap_purchase.token_out.clone(),
+                min_amount_out: swap_purchase.min_amount_out,
+            }],
+            None,
+            swap_purchase.dex_id.clone(),
+            1,
+            self.config.gas_for_dex_swap(),
+        )
+        .then(ext_self::callback_swap(
+            swap_purchase,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_swap(),
+        ));
+    }
+
+    // a swap below min_amount_out fails, the paid token is withdrawn for the refund instead
+    #[private]
+    pub fn callback_swap(&mut self, swap_purchase: SwapPurchase) {
+        let amount_out = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<U128>(&value).ok());
+        let (ft_token_id, amount) = match amount_out {
+            Some(amount_out) => (swap_purchase.token_out.clone(), amount_out),
+            None => (swap_purchase.token_in.clone(), swap_purchase.amount_in),
+        };
+
+        ext_ref_exchange::withdraw(
+            ft_token_id.clone(),
+            amount,
+            None,
+            swap_purchase.dex_id.clone(),
+            1,
+            self.config.gas_for_dex_withdraw(),
+        )
+        .then(ext_self::callback_swap_withdraw(
+            swap_purchase,
+            ft_token_id,
+            amount,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_swap_withdraw(),
+        ));
+    }
+
+    #[private]
+    pub fn callback_swap_withdraw(
+        &mut self,
+        swap_purchase: SwapPurchase,
+        ft_token_id: AccountId,
+        amount: U128,
+    ) {
+        // the tokens stay in the marketplace's DEX deposit for the owner to return
+        if !is_promise_success() {
+            return log_swap_fail(&swap_purchase, "withdraw failed");
+        }
+        let buyer_id = swap_purchase.buyer_id.clone();
+        if ft_token_id != swap_purchase.token_out {
+            self.internal_transfer(&ft_token_id, buyer_id, amount.0);
+            return log_swap_fail(&swap_purchase, "swap failed");
+        }
+        // paused while swapping, the swapped tokens are refunded instead
+        if self.is_paused(PauseFeature::Buys) {
+            self.internal_transfer(&ft_token_id, buyer_id, amount.0);
+            return log_swap_fail(&swap_purchase, "buys are paused");
+        }
+        if !self.internal_is_swap_purchasable(&swap_purchase, amount.0) {
+            self.internal_transfer(&ft_token_id, buyer_id, amount.0);
+            return log_swap_fail(&swap_purchase, "sale has changed");
+        }
+
+        env::log_str(
+            &json!({
+                "type": "buy_with_swap",
+                "params": {
+                    "nft_contract_id": swap_purchase.nft_contract_id,
+                    "token_id": swap_purchase.token_id,
+                    "buyer_id": buyer_id,
+                    "token_in": swap_purchase.token_in,
+                    "amount_in": swap_purchase.amount_in,
+                    "token_out": swap_purchase.token_out,
+                    "amount_out": amount,
+                }
+            })
+            .to_string(),
+        );
+        let unused_amount = self.internal_buy(
+            swap_purchase.nft_contract_id,
+            swap_purchase.token_id,
+            ft_token_id.clone(),
+            buyer_id.clone(),
+            amount,
+            swap_purchase.referral_id,
+            swap_purchase.receiver_id,
+            self.config.gas_for_swap_resolve_purchase(),
+        );
+        if unused_amount > 0 {
+            self.internal_transfer(&ft_token_id, buyer_id, unused_amount);
+        }
+    }
+
+    // checked again after the swap, the listing may have changed in the meantime
+    fn internal_is_swap_purchasable(&self, swap_purchase: &SwapPurchase, amount_out: u128) -> bool {
+        let contract_and_token_id = format!(
+            "{}{}{}",
+            swap_purchase.nft_contract_id, DELIMETER, swap_purchase.token_id
+        );
+        let market_data = match self.internal_get_market_data(&contract_and_token_id) {
+            Some(market_data) => market_data,
+            None => return false,
+        };
+        let token_out = if market_data.ft_token_id == near_account() {
+            self.wrap_near_id.clone()
+        } else {
+            Some(market_data.ft_token_id.clone())
+        };
+        if token_out.as_ref() != Some(&swap_purchase.token_out)
+            || !self
+                .approved_ft_token_ids
+                .contains(&swap_purchase.token_out)
+        {
+            return false;
+        }
+        self.internal_purchasable_price(
+            &contract_and_token_id,
+            &market_data,
+            &swap_purchase.buyer_id,
+        )
+        .map_or(false, |price| price <= amount_out)
+    }
+}
+
+fn log_swap_fail(swap_purchase: &SwapPurchase, reason: &str) {
+    env::log_str(
+        &json!({
+            "type": "buy_with_swap_fail",
+            "params": {
+                "nft_contract_id": swap_purchase.nft_contract_id,
+                "token_id": swap_purchase.token_id,
+                "buyer_id": swap_purchase.buyer_id,
+                "token_in": swap_purchase.token_in,
+                "amount_in": swap_purchase.amount_in,
+                "reason": reason,
+            }
+        })
+        .to_string(),
+    );
+}
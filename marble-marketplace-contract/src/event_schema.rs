@@ -0,0 +1,127 @@
+use crate::*;
+
+/// event schema, every log is {"type", "params"} JSON and indexers read the types below.
+/// Bump EVENT_VERSION when an event is renamed or loses a param, new params and types are minor.
+pub const EVENT_STANDARD: &str = "marble_marketplace";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+// sorted, one entry per "type" logged anywhere in the contract
+const EVENT_TYPES: &[&str] = &[
+    "accept_counter_offer",
+    "accept_offer_batch_skip",
+    "accept_ownership",
+    "accept_trade",
+    "accept_treasury",
+    "add_bid",
+    "add_bundle_listing",
+    "add_layaway",
+    "add_listing_edition",
+    "add_listing_operator",
+    "add_market_data",
+    "add_offer",
+    "add_presale",
+    "add_rental",
+    "add_ring_trade",
+    "add_stuck_nft_claim",
+    "add_trade",
+    "add_trade_deposit",
+    "affiliate_fee",
+    "apply_transaction_fee",
+    "approve_ring_trade",
+    "bid_refunded",
+    "burn_fee",
+    "burn_fee_fail",
+    "buy_batch_skip",
+    "buy_presale_fail",
+    "buy_with_swap",
+    "buy_with_swap_fail",
+    "cancel_admin_action",
+    "cancel_bid",
+    "cancel_layaway",
+    "cancel_signed_orders",
+    "cancel_transaction_fee",
+    "claim_affiliate_earnings",
+    "claim_balance",
+    "claim_treasury_fees",
+    "counter_offer",
+    "counter_trade",
+    "credit_claimable_balance",
+    "decline_offer",
+    "delete_bundle_listing",
+    "delete_market_data",
+    "delete_offer",
+    "delete_rental",
+    "delete_ring_trade",
+    "delete_trade",
+    "end_rental",
+    "execute_admin_action",
+    "extend_auction",
+    "fill_signed_order",
+    "freeze_collection",
+    "gift_purchase",
+    "grant_role",
+    "migrate_chunk",
+    "moderate_delist",
+    "outbid",
+    "pause",
+    "pay_layaway",
+    "pay_trade_fee",
+    "payout_fail",
+    "propose_ownership",
+    "propose_treasury",
+    "queue_admin_action",
+    "referral_fee",
+    "register_affiliate",
+    "reject_trade",
+    "remove_listing_operator",
+    "rent",
+    "rescue_stuck_nft",
+    "resolve_bundle_purchase",
+    "resolve_mint_sale",
+    "resolve_mint_sale_fail",
+    "resolve_purchase",
+    "resolve_purchase_fail",
+    "resolve_trade",
+    "revoke_role",
+    "schedule_transaction_fee",
+    "sell_to_highest_offer",
+    "set_alternative_prices",
+    "set_payout_preference",
+    "set_stable_priced",
+    "sponsor_storage",
+    "sync_fee_tier",
+    "sync_ft_token_decimals",
+    "token_deposit_refund",
+    "trade_failed",
+    "unfreeze_collection",
+    "unpause",
+    "unwrapped_proceeds_fail",
+    "update_market_data",
+    "update_offer",
+    "upgrade",
+    "withdraw_storage_sponsorship",
+    "withdraw_trade_fee_payment",
+    "wrapped_proceeds_fail",
+];
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EventSchema {
+    pub standard: String,
+    pub version: String,
+    pub event_types: Vec<String>,
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_event_schema(&self) -> EventSchema {
+        EventSchema {
+            standard: EVENT_STANDARD.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event_types: EVENT_TYPES
+                .iter()
+                .map(|event_type| event_type.to_string())
+                .collect(),
+        }
+    }
+}
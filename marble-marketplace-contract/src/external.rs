@@ -13,10 +13,87 @@ trait ExtContract {
         max_len_payout: Option<u32>,
     );
     fn nft_transfer(&mut self, receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>);
+    fn nft_token(&self, token_id: TokenId);
+    fn nft_mint(&mut self, token_series_id: TokenSeriesId, receiver_id: AccountId);
+    fn nft_payout(&self, token_id: TokenId, balance: U128, max_len_payout: Option<u32>);
+    fn nft_supply_for_owner(&self, account_id: AccountId);
 }
 
 /// TODO: this should be in the near_standard_contracts
 #[ext_contract(ext_fungible_token)]
 pub trait FungibleToken {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    );
+    fn ft_burn(&mut self, amount: U128, memo: Option<String>); // not part of NEP-141
+    fn ft_metadata(&self);
+}
+
+// NEP-245
+#[ext_contract(ext_multi_token)]
+pub trait MultiToken {
+    fn mt_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+        approval: Option<(AccountId, u64)>,
+        memo: Option<String>,
+    );
+}
+
+// ref.finance
+#[ext_contract(ext_ref_exchange)]
+pub trait RefExchange {
+    fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>);
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>);
+}
+
+// NEP-145
+#[ext_contract(ext_storage_management)]
+pub trait StorageManagement {
+    fn storage_balance_of(&self, account_id: AccountId);
+}
+
+#[ext_contract(ext_staking_pool)]
+pub trait StakingPool {
+    fn get_account_staked_balance(&self, account_id: AccountId);
+}
+
+#[ext_contract(ext_wrap_near)]
+pub trait WrapNear {
+    fn near_deposit(&mut self);
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+#[ext_contract(ext_price_oracle)]
+pub trait PriceOracle {
+    fn get_price_data(&self, asset_ids: Option<Vec<String>>);
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Price {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetOptionalPrice {
+    pub asset_id: String,
+    pub price: Option<Price>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceData {
+    pub timestamp: U64,
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
 }
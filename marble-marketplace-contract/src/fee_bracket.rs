@@ -0,0 +1,81 @@
+use crate::*;
+
+/// price-tiered fees, a sale inside a bracket pays its fee instead of the listing's
+pub const MAX_FEE_BRACKETS: usize = 10;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeBracket {
+    pub min_price: U128,
+    pub max_price: Option<U128>, // exclusive, None for no upper bound
+    pub fee: u16,
+}
+
+#[near_bindgen]
+impl Contract {
+    // brackets ascend and may leave gaps, e.g. 2% under 10 NEAR and 1% from 1000 NEAR
+    #[payable]
+    pub fn set_fee_brackets(&mut self, fee_brackets: Vec<FeeBracket>) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_fee_brackets",
+            json!({ "fee_brackets": fee_brackets }),
+        );
+        assert!(
+            fee_brackets.len() <= MAX_FEE_BRACKETS,
+            "Marble: Up to {} fee brackets",
+            MAX_FEE_BRACKETS
+        );
+        for (index, fee_bracket) in fee_brackets.iter().enumerate() {
+            assert!(
+                fee_bracket.fee < 10_000,
+                "Marble: fee is higher than 10_000"
+            );
+            assert!(
+                fee_bracket
+                    .max_price
+                    .map_or(true, |max_price| max_price.0 > fee_bracket.min_price.0),
+                "Marble: max_price must be higher than min_price"
+            );
+            if index > 0 {
+                let prev_max_price = fee_brackets[index - 1]
+                    .max_price
+                    .expect("Marble: Only the last fee bracket can be unbounded");
+                assert!(
+                    fee_bracket.min_price.0 >= prev_max_price.0,
+                    "Marble: Fee brackets must ascend without overlapping"
+                );
+            }
+        }
+        if let Some(result) = self.internal_queue_admin_action(AdminAction::SetFeeBrackets {
+            fee_brackets: fee_brackets.clone(),
+        }) {
+            return result;
+        }
+        self.fee_brackets = fee_brackets;
+        AdminActionResult::Applied
+    }
+
+    pub fn get_fee_brackets(&self) -> Vec<FeeBracket> {
+        self.fee_brackets.clone()
+    }
+
+    // the fee a new listing sold at price would pay, before exemptions and staker discounts
+    pub fn get_transaction_fee_for_price(&self, price: U128) -> u16 {
+        self.internal_bracket_fee(price.0)
+            .unwrap_or(self.transaction_fee.current_fee)
+    }
+
+    pub(crate) fn internal_bracket_fee(&self, price: u128) -> Option<u16> {
+        self.fee_brackets
+            .iter()
+            .find(|fee_bracket| {
+                price >= fee_bracket.min_price.0
+                    && fee_bracket
+                        .max_price
+                        .map_or(true, |max_price| price < max_price.0)
+            })
+            .map(|fee_bracket| fee_bracket.fee)
+    }
+}
@@ -0,0 +1,140 @@
+use crate::*;
+
+/// fee discounts for stakers, a synced snapshot of each account's stake picks its tier
+pub const MAX_FEE_TIERS: usize = 10;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeTier {
+    pub min_staked_balance: U128,
+    pub discount_bps: u16, // taken off the treasury fee
+}
+
+impl Config {
+    fn gas_for_callback_sync_fee_tier(&self) -> Gas {
+        Gas(self.base_gas.0 * 2)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // tiers ascend by stake, e.g. 100 staked for 10% off and 1000 staked for 25% off
+    #[payable]
+    pub fn set_fee_tiers(
+        &mut self,
+        fee_tier_contract_id: Option<AccountId>,
+        fee_tiers: Vec<FeeTier>,
+    ) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_fee_tiers",
+            json!({ "fee_tier_contract_id": fee_tier_contract_id, "fee_tiers": fee_tiers }),
+        );
+        assert!(
+            fee_tiers.len() <= MAX_FEE_TIERS,
+            "Marble: Up to {} fee tiers",
+            MAX_FEE_TIERS
+        );
+        for (index, fee_tier) in fee_tiers.iter().enumerate() {
+            assert!(
+                fee_tier.discount_bps <= 10_000,
+                "Marble: discount_bps cannot be higher than 10000"
+            );
+            if index > 0 {
+                assert!(
+                    fee_tier.min_staked_balance.0 > fee_tiers[index - 1].min_staked_balance.0,
+                    "Marble: Fee tiers must ascend by min_staked_balance"
+                );
+            }
+        }
+        self.fee_tier_contract_id = fee_tier_contract_id;
+        self.fee_tiers = fee_tiers;
+    }
+
+    pub fn get_fee_tiers(&self) -> Vec<FeeTier> {
+        self.fee_tiers.clone()
+    }
+
+    pub fn get_fee_tier_contract_id(&self) -> Option<AccountId> {
+        self.fee_tier_contract_id.clone()
+    }
+
+    pub fn get_account_fee_discount(&self, account_id: AccountId) -> u16 {
+        self.account_fee_discounts.get(&account_id).unwrap_or(0)
+    }
+
+    // anyone can refresh a snapshot, stakers after staking and keepers after unstaking
+    pub fn sync_fee_tier(&mut self, account_id: AccountId) -> Promise {
+        let fee_tier_contract_id = self
+            .fee_tier_contract_id
+            .clone()
+            .expect("Marble: Fee tier contract is not set");
+
+        ext_staking_pool::get_account_staked_balance(
+            account_id.clone(),
+            fee_tier_contract_id,
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::callback_sync_fee_tier(
+            account_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_sync_fee_tier(),
+        ))
+    }
+
+    #[private]
+    pub fn callback_sync_fee_tier(&mut self, account_id: AccountId) -> u16 {
+        let staked_balance = match promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<U128>(&value).ok())
+        {
+            Some(staked_balance) => staked_balance,
+            None => {
+                env::log_str("Marble: Staked balance is unavailable");
+                return self.get_account_fee_discount(account_id);
+            }
+        };
+
+        let discount_bps = self
+            .fee_tiers
+            .iter()
+            .rev()
+            .find(|fee_tier| staked_balance.0 >= fee_tier.min_staked_balance.0)
+            .map_or(0, |fee_tier| fee_tier.discount_bps);
+        if discount_bps > 0 {
+            self.account_fee_discounts
+                .insert(&account_id, &discount_bps);
+        } else {
+            self.account_fee_discounts.remove(&account_id);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "sync_fee_tier",
+                "params": {
+                    "account_id": account_id,
+                    "staked_balance": staked_balance,
+                    "discount_bps": discount_bps,
+                }
+            })
+            .to_string(),
+        );
+        discount_bps
+    }
+
+    // the better discount of the two sides applies
+    pub(crate) fn internal_discount_treasury_fee(
+        &self,
+        treasury_fee: u128,
+        buyer_id: &AccountId,
+        seller_id: &AccountId,
+    ) -> u128 {
+        let discount_bps = std::cmp::max(
+            self.account_fee_discounts.get(buyer_id).unwrap_or(0),
+            self.account_fee_discounts.get(seller_id).unwrap_or(0),
+        );
+        treasury_fee - treasury_fee * discount_bps as u128 / 10_000u128
+    }
+}
@@ -0,0 +1,85 @@
+use crate::*;
+
+/// decimals of approved FTs, price bounds are set in NEAR's 24 decimals and scaled per token
+pub const NEAR_DECIMALS: u8 = 24;
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct FtDecimals {
+    decimals: u8,
+}
+
+#[near_bindgen]
+impl Contract {
+    // tokens approved before the registry, or whose metadata changed, are refreshed by anyone
+    pub fn sync_ft_token_decimals(&mut self, ft_token_id: AccountId) {
+        assert!(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            "Marble: ft_token_id not approved"
+        );
+        assert_ne!(
+            ft_token_id,
+            near_account(),
+            "Marble: NEAR has {} decimals",
+            NEAR_DECIMALS
+        );
+        self.internal_sync_ft_token_decimals(ft_token_id);
+    }
+
+    pub fn get_ft_token_decimals(&self, ft_token_id: AccountId) -> Option<u8> {
+        if ft_token_id == near_account() {
+            return Some(NEAR_DECIMALS);
+        }
+        self.ft_token_decimals.get(&ft_token_id)
+    }
+
+    pub fn get_max_price(&self, ft_token_id: AccountId) -> U128 {
+        U128(self.internal_max_price(&ft_token_id))
+    }
+
+    #[private]
+    pub fn callback_ft_metadata(&mut self, ft_token_id: AccountId) {
+        let decimals = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<FtDecimals>(&value).ok())
+            .map(|metadata| metadata.decimals);
+        if let Some(decimals) = decimals {
+            self.ft_token_decimals.insert(&ft_token_id, &decimals);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "sync_ft_token_decimals",
+                "params": {
+                    "ft_token_id": ft_token_id,
+                    "decimals": decimals,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub(crate) fn internal_sync_ft_token_decimals(&self, ft_token_id: AccountId) {
+        ext_fungible_token::ft_metadata(ft_token_id.clone(), NO_DEPOSIT, self.config.base_gas)
+            .then(ext_self::callback_ft_metadata(
+                ft_token_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.config.base_gas,
+            ));
+    }
+
+    // tokens without known decimals keep the NEAR bound
+    pub(crate) fn internal_max_price(&self, ft_token_id: &AccountId) -> Balance {
+        let max_price = self.config.max_price.0;
+        match self.ft_token_decimals.get(ft_token_id) {
+            Some(decimals) if decimals < NEAR_DECIMALS => {
+                max_price / 10u128.pow((NEAR_DECIMALS - decimals) as u32)
+            }
+            Some(decimals) => 10u128
+                .checked_pow((decimals - NEAR_DECIMALS) as u32)
+                .and_then(|scale| max_price.checked_mul(scale))
+                .unwrap_or(u128::MAX),
+            None => max_price,
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use crate::*;
+
+/// installment purchases, a deposit locks a fixed price NEAR listing until it is paid off
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LayawayConfig {
+    pub deposit_bps: u16, // upfront share of the price
+    pub duration: u64,    // nanoseconds to pay the rest
+    pub penalty_bps: u16, // kept from the paid amount on default, half goes to the seller
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Layaway {
+    pub buyer_id: AccountId,
+    pub price: U128,
+    pub paid: U128,
+    pub created_at: u64,
+    pub deadline: u64,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn set_layaway_config(&mut self, layaway_config: LayawayConfig) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "set_layaway_config",
+            json!({ "layaway_config": layaway_config }),
+        );
+        assert!(
+            layaway_config.deposit_bps > 0 && layaway_config.deposit_bps <= 10_000,
+            "Marble: deposit_bps must be between 1 and 10000"
+        );
+        assert!(
+            layaway_config.penalty_bps <= 10_000,
+            "Marble: penalty_bps cannot be higher than 10000"
+        );
+        self.layaway_config = layaway_config;
+    }
+
+    pub fn get_layaway_config(&self) -> LayawayConfig {
+        self.layaway_config.clone()
+    }
+
+    #[payable]
+    pub fn add_layaway(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        self.assert_not_paused(PauseFeature::Buys);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        self.assert_collection_not_frozen(&nft_contract_id);
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .internal_get_market_data(&contract_and_token_id)
+            .expect("Marble: Market data does not exist");
+        let buyer_id = env::predecessor_account_id();
+
+        assert!(
+            self.layaways.get(&contract_and_token_id).is_none(),
+            "Marble: Listing is locked by a layaway"
+        );
+        assert_ne!(
+            buyer_id, market_data.owner_id,
+            "Marble: Cannot buy your own sale"
+        );
+        self.assert_not_blacklisted_seller(&market_data.owner_id);
+        if let Some(allowed_buyer_id) = self.allowed_buyer_ids.get(&contract_and_token_id) {
+            assert_eq!(
+                buyer_id, allowed_buyer_id,
+                "Marble: Sale is reserved for another buyer"
+            );
+        }
+        assert_eq!(
+            market_data.ft_token_id.to_string(),
+            NEAR,
+            "Marble: NEAR support only"
+        );
+        assert!(
+            market_data.is_auction != Some(true) && market_data.end_price.is_none(),
+            "Marble: Layaway is for fixed price sales only"
+        );
+        assert!(
+            !self.mint_sales.contains(&contract_and_token_id)
+                && self.listing_editions.get(&contract_and_token_id).is_none()
+                && self.usd_prices.get(&contract_and_token_id).is_none(),
+            "Marble: Layaway is for single token sales only"
+        );
+        assert!(!is_listing_expired(&market_data), "Marble: Sale has ended");
+        assert!(
+            self.presales
+                .get(&contract_and_token_id)
+                .map_or(true, |presale| env::block_timestamp()
+                    >= presale.public_at.0),
+            "Marble: Sale is not public yet"
+        );
+
+        let price = market_data.price;
+        let deposit = price * self.layaway_config.deposit_bps as u128 / 10_000u128;
+        assert!(
+            env::attached_deposit() >= deposit,
+            "Marble: Attached deposit is less than the layaway deposit {}",
+            deposit
+        );
+
+        let created_at = env::block_timestamp();
+        let layaway = Layaway {
+            buyer_id: buyer_id.clone(),
+            price: U128(price),
+            paid: U128(env::attached_deposit()),
+            created_at,
+            deadline: created_at + self.layaway_config.duration,
+        };
+        self.layaways.insert(&contract_and_token_id, &layaway);
+
+        env::log_str(
+            &json!({
+                "type": "add_layaway",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "buyer_id": buyer_id,
+                    "price": layaway.price,
+                    "paid": layaway.paid,
+                    "deadline": U64(layaway.deadline),
+                }
+            })
+            .to_string(),
+        );
+
+        if layaway.paid.0 >= price {
+            self.internal_complete_layaway(nft_contract_id, token_id, layaway);
+        }
+    }
+
+    #[payable]
+    pub fn pay_layaway(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut layaway = self
+            .layaways
+            .get(&contract_and_token_id)
+            .expect("Marble: Layaway does not exist");
+        assert_eq!(
+            env::predecessor_account_id(),
+            layaway.buyer_id,
+            "Marble: Layaway buyer only"
+        );
+        assert!(
+            env::block_timestamp() <= layaway.deadline,
+            "Marble: Layaway has expired"
+        );
+
+        layaway.paid = U128(layaway.paid.0 + env::attached_deposit());
+        self.layaways.insert(&contract_and_token_id, &layaway);
+
+        env::log_str(
+            &json!({
+                "type": "pay_layaway",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "buyer_id": layaway.buyer_id,
+                    "amount": U128(env::attached_deposit()),
+                    "paid": layaway.paid,
+                }
+            })
+            .to_string(),
+        );
+
+        if layaway.paid.0 >= layaway.price.0 {
+            self.internal_complete_layaway(nft_contract_id, token_id, layaway);
+        }
+    }
+
+    // anyone can release a defaulted layaway, the listing reopens
+    pub fn cancel_expired_layaway(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let layaway = self
+            .layaways
+            .get(&contract_and_token_id)
+            .expect("Marble: Layaway does not exist");
+        assert!(
+            env::block_timestamp() > layaway.deadline,
+            "Marble: Layaway has not expired yet"
+        );
+        let market_data = self
+            .internal_get_market_data(&contract_and_token_id)
+            .expect("Marble: Market data does not exist");
+
+        self.layaways.remove(&contract_and_token_id);
+
+        let penalty = layaway.paid.0 * self.layaway_config.penalty_bps as u128 / 10_000u128;
+        let seller_penalty = penalty / 2;
+        if seller_penalty > 0 {
+            Promise::new(market_data.owner_id.clone()).transfer(seller_penalty);
+        }
+        self.internal_transfer_treasury_fee(&near_account(), penalty - seller_penalty, None);
+        if layaway.paid.0 > penalty {
+            Promise::new(layaway.buyer_id.clone()).transfer(layaway.paid.0 - penalty);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "cancel_layaway",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "buyer_id": layaway.buyer_id,
+                    "paid": layaway.paid,
+                    "penalty": U128(penalty),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_layaway(&self, nft_contract_id: AccountId, token_id: TokenId) -> Option<Layaway> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.layaways.get(&contract_and_token_id)
+    }
+
+    fn internal_complete_layaway(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        layaway: Layaway,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.layaways.remove(&contract_and_token_id);
+
+        let excess_deposit = layaway.paid.0 - layaway.price.0;
+        if excess_deposit > 0 {
+            Promise::new(layaway.buyer_id.clone()).transfer(excess_deposit);
+        }
+
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            near_account(),
+            layaway.buyer_id.clone(),
+            layaway.buyer_id,
+            layaway.price.0,
+            None,
+            self.config.gas_for_ft_payout,
+        );
+    }
+
+    // a delisted or sold token releases the whole layaway back to the buyer
+    pub(crate) fn internal_refund_layaway(&mut self, contract_and_token_id: &ContractAndTokenId) {
+        if let Some(layaway) = self.layaways.remove(contract_and_token_id) {
+            Promise::new(layaway.buyer_id.clone()).transfer(layaway.paid.0);
+
+            env::log_str(
+                &json!({
+                    "type": "cancel_layaway",
+                    "params": {
+                        "contract_and_token_id": contract_and_token_id,
+                        "buyer_id": layaway.buyer_id,
+                        "paid": layaway.paid,
+                        "penalty": U128(0),
+                    }
+                })
+                .to_string(),
+            );
+        }
+    }
+}
@@ -1,32 +1,96 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::collections::{LookupMap, LookupSet, UnorderedMap, UnorderedSet, Vector};
 use near_sdk::json_types::{ValidAccountId, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, env, ext_contract, near_bindgen, serde_json::json, AccountId, Balance,
-    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, Timestamp,
+    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, PublicKey, Timestamp,
 };
-use near_sdk::{is_promise_success, promise_result_as_success};
-use std::collections::HashMap;
-
+use near_sdk::{is_promise_success, promise_result_as_success, PromiseResult};
+use std::collections::{HashMap, HashSet};
+
+use crate::admin_log::*;
+use crate::affiliate::*;
+use crate::change_feed::*;
+use crate::config::*;
+use crate::dex_swap::*;
 use crate::external::*;
-
+use crate::fee_bracket::*;
+use crate::fee_tier::*;
+use crate::layaway::*;
+use crate::migration::*;
+use crate::pause::*;
+use crate::presale::*;
+use crate::rental::*;
+use crate::roles::*;
+use crate::timelock::*;
+use crate::token_receiver::*;
+use crate::trade_fee_payment::*;
+use crate::verification::*;
+
+mod admin_log;
+mod affiliate;
+mod blacklist;
+mod change_feed;
+mod claimable;
+mod config;
+mod dex_swap;
+mod event_schema;
 mod external;
+mod fee_bracket;
+mod fee_tier;
+mod ft_decimals;
+mod layaway;
+mod listing_operator;
+mod migration;
+mod moderation;
+mod multi_token;
 mod nft_callbacks;
+mod pause;
+mod presale;
+mod relayer;
+mod rental;
+mod roles;
+mod signed_order;
+mod sponsored_storage;
+mod stablecoin;
+mod timelock;
 mod token_receiver;
+mod trade_fee_payment;
+mod upgrade;
 mod utils;
+mod verification;
+mod wrap_near;
 
 const GAS_FOR_NFT_TRANSFER: Gas = Gas(20_000_000_000_000);
 const BASE_GAS: Gas = Gas(5_000_000_000_000);
 const GAS_FOR_ROYALTIES: Gas = Gas(BASE_GAS.0 * 10u64);
-const GAS_FOR_CALLBACK_FIRST_TRADE: Gas = Gas(30_000_000_000_000);
-const GAS_FOR_CALLBACK_SECOND_TRADE: Gas = Gas(80_000_000_000_000);
 const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
 const GAS_FOR_FT_PAYOUT: Gas = Gas(200_000_000_000_000);
+const GAS_FOR_NFT_TOKEN: Gas = Gas(10_000_000_000_000);
+// FT payouts need more gas than a batch callback gets, they are accepted one by one
+const FT_OFFER_BATCH_SKIP_REASON: &str = "offer is in a fungible token";
 const NO_DEPOSIT: Balance = 0;
 const MAX_PRICE: Balance = 1_000_000_000 * 10u128.pow(24);
+const MAX_TRADE_BUNDLE_SIZE: usize = 2; // tokens per side, bounded by swap gas
+const MAX_ACCEPT_OFFERS_BATCH: usize = 3; // bounded by the nft_token check and payout of each offer
+const MAX_ADD_OFFERS_BATCH: usize = 10; // bounded by the refund of each replaced offer
+const MAX_TRADE_HISTORY: usize = 5;
+const MAX_CLEANUP_TRADES: u64 = 50; // trade lists scanned per call, bounded by the refunds
+const MAX_OFFERS_PER_TOKEN: u64 = 50; // scanned by sell_to_highest_offer
+const RING_TRADE_SIZE: usize = 3; // A gives to B, B to C and C to A
+const MAX_BUNDLE_LISTING_SIZE: usize = 5; // bounded by the payout gas of a purchase
+const MAX_LISTING_EDITIONS: usize = 100;
+const MAX_ALTERNATIVE_PRICES: usize = 4; // other currencies a fixed price sale accepts
+const MAX_TREASURY_RECIPIENTS: usize = 5;
+const MAX_BATCH_BIDS: usize = 10; // bounded by the refund of each outbid bidder
+const DEFAULT_MAX_LEN_PAYOUT: u32 = 10;
+const MAX_LEN_PAYOUT: u32 = 20; // bounded by the gas of one ft_transfer per receiver
+const MAX_TRANSACTION_FEE_CHANGES: usize = 10;
 
 pub const STORAGE_ADD_MARKET_DATA: u128 = 8590000000000000000000;
+pub const USD_DECIMALS: u8 = 6; // usd prices are in millionths of a dollar
+pub const STORAGE_MINT_NFT: u128 = 10_000_000_000_000_000_000_000; // paid out of the mint sale price
 pub const FIVE_MINUTES: u64 = 300000000000;
 
 pub type PayoutHashMap = HashMap<AccountId, U128>;
@@ -42,6 +106,38 @@ pub struct Payout {
     pub payout: PayoutHashMap,
 }
 
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct JsonToken {
+    pub owner_id: AccountId,
+    pub approved_account_ids: Option<HashMap<AccountId, u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptOfferArgs {
+    pub buyer_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BuyArgs {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeleteTradeArgs {
+    pub nft_contract_id: AccountId,
+    pub token_id: Option<TokenId>,
+    pub token_series_id: Option<TokenSeriesId>,
+    pub buyer_nft_contract_id: AccountId,
+    pub buyer_token_id: TokenId,
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct TransactionFee {
@@ -50,6 +146,36 @@ pub struct TransactionFee {
     pub current_fee: u16,
 }
 
+// cumulative treasury fees, before referral shares and burns
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeStats {
+    pub ft_token_ids: HashMap<AccountId, U128>,
+    pub nft_contract_ids: HashMap<AccountId, HashMap<AccountId, U128>>, // sales only
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EffectiveFee {
+    pub fee: u16,     // basis points
+    pub amount: U128, // taken from the price, before staker discounts
+}
+
+// changes scheduled after next_fee, ascending by start_time
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransactionFeeChange {
+    pub fee: u16,
+    pub start_time: TimestampSec,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryRecipient {
+    pub account_id: AccountId,
+    pub share_bps: u16,
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Bid {
@@ -96,6 +222,14 @@ pub struct MarketData {
     pub reserve_price: Option<u128>,
 }
 
+// an approved edition waiting behind a series listing
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ListingEdition {
+    pub token_id: TokenId,
+    pub approval_id: u64,
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct MarketDataTransactionFee {
@@ -111,6 +245,19 @@ pub struct OfferData {
     pub token_series_id: Option<TokenId>,
     pub ft_token_id: AccountId, // "near" for NEAR token
     pub price: u128,
+    pub counter: Option<CounterOfferData>,
+    pub seller_id: Option<AccountId>, // offer only acceptable by this seller
+    pub quantity: Option<u64>,        // remaining editions for series offers
+    pub referral_id: Option<AccountId>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CounterOfferData {
+    pub seller_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: u64,
+    pub price: u128,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -122,6 +269,13 @@ pub struct OfferDataJson {
     token_series_id: Option<TokenId>,
     ft_token_id: AccountId, // "near" for NEAR token
     price: U128,
+    counter_seller_id: Option<AccountId>,
+    counter_token_id: Option<TokenId>,
+    counter_price: Option<U128>,
+    seller_id: Option<AccountId>,
+    quantity: Option<u64>,
+    referral_id: Option<AccountId>,
+    transaction_fee: u16, // locked when the offer was made
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
@@ -134,6 +288,47 @@ pub struct TradeData {
     pub nft_contract_id: AccountId,
     pub token_id: Option<TokenId>,
     pub token_series_id: Option<TokenSeriesId>,
+    pub buyer_tokens: Option<Vec<TradeToken>>, // bundled with the buyer's approved token
+    pub seller_tokens: Option<Vec<TradeToken>>, // wanted alongside the seller's token
+    pub seller_id: Option<AccountId>,          // seller who escrowed seller_amount
+    pub expires_at: Option<u64>,
+    pub owner_id: Option<AccountId>, // owner of the wanted token when proposed
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeCash {
+    pub ft_token_id: AccountId, // "near" for NEAR token
+    pub buyer_amount: U128,     // paid to the seller
+    pub seller_amount: U128,    // paid to the buyer
+    pub trade_fee: U128,        // NEAR taken from the seller's storage deposit
+    pub fee_bps: u16,           // treasury share of the released cash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trade_fee_ft_token_id: Option<AccountId>, // trade_fee was prepaid in this FT
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceOracleConfig {
+    pub oracle_id: AccountId,
+    pub near_asset_id: String, // the asset the oracle prices NEAR as, e.g. wrap.near
+    pub max_staleness_sec: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeFee {
+    pub flat_fee: U128,      // NEAR charged to the accepting seller
+    pub fee_bps: u16,        // charged on the escrowed cash of a trade
+    pub marble_exempt: bool, // no fee when the wanted token is a Marble NFT
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -143,7 +338,8 @@ pub struct MarketDataJson {
     approval_id: U64,
     nft_contract_id: AccountId,
     token_id: TokenId,
-    ft_token_id: AccountId, // "near" for NEAR token
+    listing_id: Option<U64>, // listings made before ids were assigned have none
+    ft_token_id: AccountId,  // "near" for NEAR token
     price: U128,
     bids: Option<Bids>,
     started_at: Option<U64>,
@@ -153,6 +349,13 @@ pub struct MarketDataJson {
     transaction_fee: U128,
     reserve_price: Option<U128>,
     current_time: TimestampSec,
+    allowed_buyer_id: Option<AccountId>, // private sale
+    quantity: Option<U64>,               // editions left on a series listing
+    usd_price: Option<U128>,             // converted to NEAR when bought
+    alternative_prices: Option<HashMap<AccountId, U128>>, // price per other accepted ft_token_id
+    is_stable_priced: bool,              // other curated stablecoins are accepted 1:1
+    presale: Option<Presale>,
+    verification: Option<CollectionVerification>, // set for verified collections
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
@@ -165,16 +368,73 @@ pub struct ContractV2 {
     pub approved_nft_contract_ids: UnorderedSet<AccountId>,
     pub storage_deposits: LookupMap<AccountId, Balance>,
     pub by_owner_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
-    pub offers: UnorderedMap<ContractAccountIdTokenId, OfferData>,
+    pub offers: UnorderedMap<ContractAccountIdTokenId, OfferDataV1>,
     pub marble_nft_contracts: UnorderedSet<AccountId>,
     pub transaction_fee: TransactionFee,
-    pub trades: UnorderedMap<ContractAccountIdTokenId, TradeList>,
+    pub trades: UnorderedMap<ContractAccountIdTokenId, TradeListV1>,
+    pub market_data_transaction_fee: MarketDataTransactionFee,
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct TradeList {
     pub approval_id: u64,
     pub trade_data: HashMap<ContractAccountIdTokenId, TradeData>,
+    pub history: Vec<TradeCounterData>, // latest counters, capped at MAX_TRADE_HISTORY
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeCounterData {
+    pub contract_account_id_token_id: ContractAccountIdTokenId,
+    pub seller_id: AccountId,
+    pub token_id: TokenId, // seller token, approved with the counter
+    pub approval_id: u64,
+    pub buyer_nft_contract_id: AccountId, // buyer token asked for in return
+    pub buyer_token_id: TokenId,
+    pub buyer_amount: Balance, // cash asked from the buyer in total
+    pub created_at: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RingTradeParticipant {
+    pub account_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId, // given to the next participant
+    pub approval_id: Option<u64>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RingTrade {
+    pub creator_id: AccountId,
+    pub participants: Vec<RingTradeParticipant>,
+    pub storage_deposit: Balance, // refunded to the creator when the ring trade is removed
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleListing {
+    pub owner_id: AccountId,
+    pub tokens: Vec<TradeToken>, // approval_id is set through nft_on_approve
+    pub price: U128,             // NEAR for the whole bundle
+    pub storage_deposit: Balance, // refunded to the owner when the bundle is removed
+    pub created_at: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeRecord {
+    pub buyer_id: AccountId,
+    pub seller_id: AccountId,
+    pub buyer_tokens: Vec<TradeToken>,  // now owned by the seller
+    pub seller_tokens: Vec<TradeToken>, // now owned by the buyer
+    pub ft_token_id: AccountId,
+    pub buyer_amount: U128,
+    pub seller_amount: U128,
+    pub traded_at: u64,
 }
 
 #[near_bindgen]
@@ -193,6 +453,94 @@ pub struct Contract {
     pub transaction_fee: TransactionFee,
     pub trades: UnorderedMap<ContractAccountIdTokenId, TradeList>,
     pub market_data_transaction_fee: MarketDataTransactionFee,
+    pub offers_by_token: LookupMap<ContractAndTokenId, UnorderedSet<AccountId>>,
+    pub min_offer_price: Balance,
+    pub collection_min_offer_prices: LookupMap<AccountId, Balance>,
+    pub collection_admins: LookupMap<AccountId, AccountId>,
+    pub bids_by_bidder_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    pub referral_fee: u16,
+    pub stuck_nft_claims: LookupMap<ContractAndTokenId, AccountId>,
+    pub trade_fee: TradeFee,
+    pub trade_history: Vector<TradeRecord>,
+    pub ring_trades: UnorderedMap<u64, RingTrade>,
+    pub next_ring_trade_id: u64,
+    pub allowed_buyer_ids: LookupMap<ContractAndTokenId, AccountId>,
+    pub bundle_listings: UnorderedMap<u64, BundleListing>,
+    pub next_bundle_listing_id: u64,
+    pub listing_editions: LookupMap<ContractAndTokenId, Vec<ListingEdition>>,
+    pub mint_sales: LookupSet<ContractAndTokenId>,
+    pub rentals: UnorderedMap<ContractAndTokenId, Rental>,
+    pub layaway_config: LayawayConfig,
+    pub layaways: LookupMap<ContractAndTokenId, Layaway>,
+    pub price_oracle: Option<PriceOracleConfig>,
+    pub usd_prices: LookupMap<ContractAndTokenId, Balance>,
+    pub collection_min_listing_prices: LookupMap<String, Balance>,
+    pub listing_tombstones: LookupMap<ContractAndTokenId, MarketData>,
+    pub alternative_prices: LookupMap<ContractAndTokenId, HashMap<AccountId, U128>>,
+    pub presales: LookupMap<ContractAndTokenId, Presale>,
+    pub wrap_near_id: Option<AccountId>,
+    pub wrapped_near_sellers: LookupSet<AccountId>,
+    pub affiliate_fee: u16,
+    pub affiliates: UnorderedMap<AccountId, Affiliate>,
+    pub affiliate_earnings: LookupMap<String, Balance>,
+    pub fee_tier_contract_id: Option<AccountId>,
+    pub fee_tiers: Vec<FeeTier>,
+    pub account_fee_discounts: LookupMap<AccountId, u16>,
+    pub treasury_recipients: Vec<TreasuryRecipient>,
+    pub max_len_payout: u32,
+    pub treasury_fees: LookupMap<AccountId, Balance>,
+    pub listing_fee: Balance,
+    pub listing_fees: LookupMap<ContractAndTokenId, Balance>,
+    pub fee_exempt_account_ids: UnorderedSet<AccountId>,
+    pub fee_exempt_nft_contract_ids: UnorderedSet<AccountId>,
+    pub transaction_fee_changes: Vec<TransactionFeeChange>,
+    pub fee_brackets: Vec<FeeBracket>,
+    pub burn_bps: u16,
+    pub burn_account_id: Option<AccountId>,
+    pub offer_transaction_fees: LookupMap<ContractAccountIdTokenId, u16>,
+    pub ft_fee_stats: UnorderedMap<AccountId, Balance>,
+    pub collection_fee_stats: UnorderedMap<String, Balance>,
+    pub paused_features: Vec<PauseFeature>,
+    pub roles: UnorderedMap<AccountId, Vec<Role>>,
+    pub pending_owner_id: Option<AccountId>,
+    pub pending_treasury_id: Option<AccountId>,
+    pub pending_treasury_recipients: Vec<TreasuryRecipient>, // split taken on accept_treasury
+    pub blacklisted_buyer_ids: UnorderedSet<AccountId>,
+    pub blacklisted_seller_ids: UnorderedSet<AccountId>,
+    pub frozen_nft_contract_ids: UnorderedSet<AccountId>,
+    pub timelock_delay: u64,
+    pub queued_admin_actions: UnorderedMap<u64, QueuedAdminAction>,
+    pub next_admin_action_id: u64,
+    pub settling_listings: LookupSet<ContractAndTokenId>,
+    pub order_public_keys: LookupMap<AccountId, PublicKey>,
+    pub order_min_nonces: LookupMap<AccountId, u64>,
+    pub filled_order_nonces: LookupSet<String>, // owner_id and nonce of every filled order
+    pub listing_operators: LookupMap<AccountId, Vec<AccountId>>,
+    pub admin_log: Vector<AdminLogEntry>,
+    pub admin_log_len: u64,
+    pub config: Config,
+    pub verified_collections: UnorderedMap<AccountId, CollectionVerification>,
+    pub storage_sponsor_pools: LookupMap<AccountId, Balance>,
+    pub sponsored_listings: LookupMap<ContractAndTokenId, Balance>,
+    pub trusted_relayer_ids: UnorderedSet<AccountId>,
+    pub state_version: StateVersion,
+    pub migration: Option<Migration>,
+    pub mt_currencies: UnorderedMap<AccountId, TokenId>,
+    pub dex_id: Option<AccountId>,
+    pub claimable_balances: LookupMap<String, Balance>,
+    pub ft_token_decimals: LookupMap<AccountId, u8>,
+    pub ft_trade_fees: LookupMap<AccountId, Balance>,
+    pub trade_fee_payments: LookupMap<AccountId, TradeFeePayment>,
+    pub native_near_sellers: LookupSet<AccountId>,
+    pub stablecoin_ids: UnorderedSet<AccountId>,
+    pub stable_priced_listings: LookupSet<ContractAndTokenId>,
+    pub listing_ids: LookupMap<ContractAndTokenId, u64>,
+    pub next_listing_id: u64,
+    pub next_sale_id: u64,
+    pub changes: Vector<Change>,
+    pub changes_len: u64,
+    pub old_offers: UnorderedMap<ContractAccountIdTokenId, OfferDataV1>, // drained by migrate_chunk
+    pub old_trades: UnorderedMap<ContractAccountIdTokenId, TradeListV1>, // drained by migrate_chunk
 }
 
 #[derive(BorshStorageKey, BorshSerialize)]
@@ -202,7 +550,9 @@ pub enum StorageKey {
     NFTContractIds,
     StorageDeposits,
     ByOwnerId,
-    ByOwnerIdInner { account_id_hash: CryptoHash },
+    ByOwnerIdInner {
+        account_id_hash: CryptoHash,
+    },
     Offers,
     MarbleNFTContractIds,
     MarketV2,
@@ -211,6 +561,68 @@ pub enum StorageKey {
     MarbleNFTContractIdsV2,
     Trade,
     MarketDataTransactionFee,
+    OffersByToken,
+    OffersByTokenInner {
+        contract_and_token_id_hash: CryptoHash,
+    },
+    CollectionMinOfferPrices,
+    CollectionAdmins,
+    BidsByBidderId,
+    BidsByBidderIdInner {
+        account_id_hash: CryptoHash,
+    },
+    StuckNftClaims,
+    TradeHistory,
+    RingTrades,
+    AllowedBuyerIds,
+    BundleListings,
+    ListingEditions,
+    MintSales,
+    Rentals,
+    Layaways,
+    UsdPrices,
+    CollectionMinListingPrices,
+    ListingTombstones,
+    AlternativePrices,
+    Presales,
+    WrappedNearSellers,
+    Affiliates,
+    AffiliateEarnings,
+    AccountFeeDiscounts,
+    TreasuryFees,
+    ListingFees,
+    FeeExemptAccountIds,
+    FeeExemptNFTContractIds,
+    OfferTransactionFees,
+    FtFeeStats,
+    CollectionFeeStats,
+    Roles,
+    BlacklistedBuyerIds,
+    BlacklistedSellerIds,
+    FrozenNftContractIds,
+    QueuedAdminActions,
+    SettlingListings,
+    OrderPublicKeys,
+    OrderMinNonces,
+    ListingOperators,
+    AdminLog,
+    VerifiedCollections,
+    StorageSponsorPools,
+    SponsoredListings,
+    TrustedRelayerIds,
+    MtCurrencies,
+    ClaimableBalances,
+    FtTokenDecimals,
+    FtTradeFees,
+    TradeFeePayments,
+    NativeNearSellers,
+    StablecoinIds,
+    StablePricedListings,
+    ListingIds,
+    Changes,
+    OffersV3,
+    TradesV2,
+    FilledOrderNonces,
 }
 
 #[near_bindgen]
@@ -233,17 +645,113 @@ impl Contract {
             approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
             storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
             by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
-            offers: UnorderedMap::new(StorageKey::Offers),
+            offers: UnorderedMap::new(StorageKey::OffersV3),
             marble_nft_contracts: UnorderedSet::new(StorageKey::MarbleNFTContractIds),
             transaction_fee: TransactionFee {
                 next_fee: None,
                 start_time: None,
                 current_fee,
             },
-            trades: UnorderedMap::new(StorageKey::Trade),
+            trades: UnorderedMap::new(StorageKey::TradesV2),
             market_data_transaction_fee: MarketDataTransactionFee {
                 transaction_fee: UnorderedMap::new(StorageKey::MarketDataTransactionFee),
             },
+            offers_by_token: LookupMap::new(StorageKey::OffersByToken),
+            min_offer_price: 0,
+            collection_min_offer_prices: LookupMap::new(StorageKey::CollectionMinOfferPrices),
+            collection_admins: LookupMap::new(StorageKey::CollectionAdmins),
+            bids_by_bidder_id: LookupMap::new(StorageKey::BidsByBidderId),
+            referral_fee: 0,
+            stuck_nft_claims: LookupMap::new(StorageKey::StuckNftClaims),
+            trade_fee: TradeFee {
+                flat_fee: U128(0),
+                fee_bps: 0,
+                marble_exempt: false,
+            },
+            trade_history: Vector::new(StorageKey::TradeHistory),
+            ring_trades: UnorderedMap::new(StorageKey::RingTrades),
+            next_ring_trade_id: 0,
+            allowed_buyer_ids: LookupMap::new(StorageKey::AllowedBuyerIds),
+            bundle_listings: UnorderedMap::new(StorageKey::BundleListings),
+            next_bundle_listing_id: 0,
+            listing_editions: LookupMap::new(StorageKey::ListingEditions),
+            mint_sales: LookupSet::new(StorageKey::MintSales),
+            rentals: UnorderedMap::new(StorageKey::Rentals),
+            layaway_config: LayawayConfig {
+                deposit_bps: 2_000,
+                duration: 7 * ONE_DAY,
+                penalty_bps: 1_000,
+            },
+            layaways: LookupMap::new(StorageKey::Layaways),
+            price_oracle: None,
+            usd_prices: LookupMap::new(StorageKey::UsdPrices),
+            collection_min_listing_prices: LookupMap::new(StorageKey::CollectionMinListingPrices),
+            listing_tombstones: LookupMap::new(StorageKey::ListingTombstones),
+            alternative_prices: LookupMap::new(StorageKey::AlternativePrices),
+            presales: LookupMap::new(StorageKey::Presales),
+            wrap_near_id: None,
+            wrapped_near_sellers: LookupSet::new(StorageKey::WrappedNearSellers),
+            affiliate_fee: 0,
+            affiliates: UnorderedMap::new(StorageKey::Affiliates),
+            affiliate_earnings: LookupMap::new(StorageKey::AffiliateEarnings),
+            fee_tier_contract_id: None,
+            fee_tiers: vec![],
+            account_fee_discounts: LookupMap::new(StorageKey::AccountFeeDiscounts),
+            treasury_recipients: vec![],
+            max_len_payout: DEFAULT_MAX_LEN_PAYOUT,
+            treasury_fees: LookupMap::new(StorageKey::TreasuryFees),
+            listing_fee: 0,
+            listing_fees: LookupMap::new(StorageKey::ListingFees),
+            fee_exempt_account_ids: UnorderedSet::new(StorageKey::FeeExemptAccountIds),
+            fee_exempt_nft_contract_ids: UnorderedSet::new(StorageKey::FeeExemptNFTContractIds),
+            transaction_fee_changes: Vec::new(),
+            fee_brackets: Vec::new(),
+            burn_bps: 0,
+            burn_account_id: None,
+            offer_transaction_fees: LookupMap::new(StorageKey::OfferTransactionFees),
+            ft_fee_stats: UnorderedMap::new(StorageKey::FtFeeStats),
+            collection_fee_stats: UnorderedMap::new(StorageKey::CollectionFeeStats),
+            paused_features: Vec::new(),
+            roles: UnorderedMap::new(StorageKey::Roles),
+            pending_owner_id: None,
+            pending_treasury_id: None,
+            pending_treasury_recipients: vec![],
+            blacklisted_buyer_ids: UnorderedSet::new(StorageKey::BlacklistedBuyerIds),
+            blacklisted_seller_ids: UnorderedSet::new(StorageKey::BlacklistedSellerIds),
+            frozen_nft_contract_ids: UnorderedSet::new(StorageKey::FrozenNftContractIds),
+            timelock_delay: 0,
+            queued_admin_actions: UnorderedMap::new(StorageKey::QueuedAdminActions),
+            next_admin_action_id: 0,
+            settling_listings: LookupSet::new(StorageKey::SettlingListings),
+            order_public_keys: LookupMap::new(StorageKey::OrderPublicKeys),
+            order_min_nonces: LookupMap::new(StorageKey::OrderMinNonces),
+            filled_order_nonces: LookupSet::new(StorageKey::FilledOrderNonces),
+            listing_operators: LookupMap::new(StorageKey::ListingOperators),
+            admin_log: Vector::new(StorageKey::AdminLog),
+            admin_log_len: 0,
+            config: Config::default(),
+            verified_collections: UnorderedMap::new(StorageKey::VerifiedCollections),
+            storage_sponsor_pools: LookupMap::new(StorageKey::StorageSponsorPools),
+            sponsored_listings: LookupMap::new(StorageKey::SponsoredListings),
+            trusted_relayer_ids: UnorderedSet::new(StorageKey::TrustedRelayerIds),
+            state_version: CURRENT_STATE_VERSION,
+            migration: None,
+            mt_currencies: UnorderedMap::new(StorageKey::MtCurrencies),
+            dex_id: None,
+            claimable_balances: LookupMap::new(StorageKey::ClaimableBalances),
+            ft_token_decimals: LookupMap::new(StorageKey::FtTokenDecimals),
+            ft_trade_fees: LookupMap::new(StorageKey::FtTradeFees),
+            trade_fee_payments: LookupMap::new(StorageKey::TradeFeePayments),
+            native_near_sellers: LookupSet::new(StorageKey::NativeNearSellers),
+            stablecoin_ids: UnorderedSet::new(StorageKey::StablecoinIds),
+            stable_priced_listings: LookupSet::new(StorageKey::StablePricedListings),
+            listing_ids: LookupMap::new(StorageKey::ListingIds),
+            next_listing_id: 0,
+            next_sale_id: 0,
+            changes: Vector::new(StorageKey::Changes),
+            changes_len: 0,
+            old_offers: UnorderedMap::new(StorageKey::Offers),
+            old_trades: UnorderedMap::new(StorageKey::Trade),
         };
 
         this.approved_ft_token_ids.insert(&near_account());
@@ -261,13 +769,14 @@ impl Contract {
     #[init(ignore_state)]
     pub fn migrate() -> Self {
         let prev: ContractV2 = env::state_read().expect("ERR_NOT_INITIALIZED");
-        assert_eq!(
-            env::predecessor_account_id(),
-            prev.owner_id,
+        // the contract itself calls migrate at the end of an upgrade
+        assert!(
+            [prev.owner_id.clone(), env::current_account_id()]
+                .contains(&env::predecessor_account_id()),
             "Marble: Only owner"
         );
 
-        let this = Contract {
+        Contract {
             owner_id: prev.owner_id,
             treasury_id: prev.treasury_id,
             old_market: prev.old_market,
@@ -276,37 +785,264 @@ impl Contract {
             approved_nft_contract_ids: prev.approved_nft_contract_ids,
             storage_deposits: prev.storage_deposits,
             by_owner_id: prev.by_owner_id,
-            offers: prev.offers,
+            offers: UnorderedMap::new(StorageKey::OffersV3),
             marble_nft_contracts: prev.marble_nft_contracts,
             transaction_fee: prev.transaction_fee,
-            trades: prev.trades,
-            market_data_transaction_fee: MarketDataTransactionFee {
-                transaction_fee: UnorderedMap::new(StorageKey::MarketDataTransactionFee),
+            trades: UnorderedMap::new(StorageKey::TradesV2),
+            market_data_transaction_fee: prev.market_data_transaction_fee,
+            offers_by_token: LookupMap::new(StorageKey::OffersByToken),
+            min_offer_price: 0,
+            collection_min_offer_prices: LookupMap::new(StorageKey::CollectionMinOfferPrices),
+            collection_admins: LookupMap::new(StorageKey::CollectionAdmins),
+            bids_by_bidder_id: LookupMap::new(StorageKey::BidsByBidderId),
+            referral_fee: 0,
+            stuck_nft_claims: LookupMap::new(StorageKey::StuckNftClaims),
+            trade_fee: TradeFee {
+                flat_fee: U128(0),
+                fee_bps: 0,
+                marble_exempt: false,
             },
-        };
-
-        this
+            trade_history: Vector::new(StorageKey::TradeHistory),
+            ring_trades: UnorderedMap::new(StorageKey::RingTrades),
+            next_ring_trade_id: 0,
+            allowed_buyer_ids: LookupMap::new(StorageKey::AllowedBuyerIds),
+            bundle_listings: UnorderedMap::new(StorageKey::BundleListings),
+            next_bundle_listing_id: 0,
+            listing_editions: LookupMap::new(StorageKey::ListingEditions),
+            mint_sales: LookupSet::new(StorageKey::MintSales),
+            rentals: UnorderedMap::new(StorageKey::Rentals),
+            layaway_config: LayawayConfig {
+                deposit_bps: 2_000,
+                duration: 7 * ONE_DAY,
+                penalty_bps: 1_000,
+            },
+            layaways: LookupMap::new(StorageKey::Layaways),
+            price_oracle: None,
+            usd_prices: LookupMap::new(StorageKey::UsdPrices),
+            collection_min_listing_prices: LookupMap::new(StorageKey::CollectionMinListingPrices),
+            listing_tombstones: LookupMap::new(StorageKey::ListingTombstones),
+            alternative_prices: LookupMap::new(StorageKey::AlternativePrices),
+            presales: LookupMap::new(StorageKey::Presales),
+            wrap_near_id: None,
+            wrapped_near_sellers: LookupSet::new(StorageKey::WrappedNearSellers),
+            affiliate_fee: 0,
+            affiliates: UnorderedMap::new(StorageKey::Affiliates),
+            affiliate_earnings: LookupMap::new(StorageKey::AffiliateEarnings),
+            fee_tier_contract_id: None,
+            fee_tiers: vec![],
+            account_fee_discounts: LookupMap::new(StorageKey::AccountFeeDiscounts),
+            treasury_recipients: vec![],
+            max_len_payout: DEFAULT_MAX_LEN_PAYOUT,
+            treasury_fees: LookupMap::new(StorageKey::TreasuryFees),
+            listing_fee: 0,
+            listing_fees: LookupMap::new(StorageKey::ListingFees),
+            fee_exempt_account_ids: UnorderedSet::new(StorageKey::FeeExemptAccountIds),
+            fee_exempt_nft_contract_ids: UnorderedSet::new(StorageKey::FeeExemptNFTContractIds),
+            transaction_fee_changes: Vec::new(),
+            fee_brackets: Vec::new(),
+            burn_bps: 0,
+            burn_account_id: None,
+            offer_transaction_fees: LookupMap::new(StorageKey::OfferTransactionFees),
+            ft_fee_stats: UnorderedMap::new(StorageKey::FtFeeStats),
+            collection_fee_stats: UnorderedMap::new(StorageKey::CollectionFeeStats),
+            paused_features: Vec::new(),
+            roles: UnorderedMap::new(StorageKey::Roles),
+            pending_owner_id: None,
+            pending_treasury_id: None,
+            pending_treasury_recipients: vec![],
+            blacklisted_buyer_ids: UnorderedSet::new(StorageKey::BlacklistedBuyerIds),
+            blacklisted_seller_ids: UnorderedSet::new(StorageKey::BlacklistedSellerIds),
+            frozen_nft_contract_ids: UnorderedSet::new(StorageKey::FrozenNftContractIds),
+            timelock_delay: 0,
+            queued_admin_actions: UnorderedMap::new(StorageKey::QueuedAdminActions),
+            next_admin_action_id: 0,
+            settling_listings: LookupSet::new(StorageKey::SettlingListings),
+            order_public_keys: LookupMap::new(StorageKey::OrderPublicKeys),
+            order_min_nonces: LookupMap::new(StorageKey::OrderMinNonces),
+            filled_order_nonces: LookupSet::new(StorageKey::FilledOrderNonces),
+            listing_operators: LookupMap::new(StorageKey::ListingOperators),
+            admin_log: Vector::new(StorageKey::AdminLog),
+            admin_log_len: 0,
+            config: Config::default(),
+            verified_collections: UnorderedMap::new(StorageKey::VerifiedCollections),
+            storage_sponsor_pools: LookupMap::new(StorageKey::StorageSponsorPools),
+            sponsored_listings: LookupMap::new(StorageKey::SponsoredListings),
+            trusted_relayer_ids: UnorderedSet::new(StorageKey::TrustedRelayerIds),
+            state_version: CURRENT_STATE_VERSION,
+            // offers and trades are rewritten in the new layouts first, then indexed
+            migration: Some(Migration {
+                from_version: StateVersion::V2,
+                to_version: CURRENT_STATE_VERSION,
+                steps: vec![
+                    MigrationStep::RewriteOffers,
+                    MigrationStep::RewriteTrades,
+                    MigrationStep::IndexBids,
+                    MigrationStep::IndexOffers,
+                ],
+                cursor: U64(0),
+            }),
+            mt_currencies: UnorderedMap::new(StorageKey::MtCurrencies),
+            dex_id: None,
+            claimable_balances: LookupMap::new(StorageKey::ClaimableBalances),
+            ft_token_decimals: LookupMap::new(StorageKey::FtTokenDecimals),
+            ft_trade_fees: LookupMap::new(StorageKey::FtTradeFees),
+            trade_fee_payments: LookupMap::new(StorageKey::TradeFeePayments),
+            native_near_sellers: LookupSet::new(StorageKey::NativeNearSellers),
+            stablecoin_ids: UnorderedSet::new(StorageKey::StablecoinIds),
+            stable_priced_listings: LookupSet::new(StorageKey::StablePricedListings),
+            listing_ids: LookupMap::new(StorageKey::ListingIds),
+            next_listing_id: 0,
+            next_sale_id: 0,
+            changes: Vector::new(StorageKey::Changes),
+            changes_len: 0,
+            old_offers: prev.offers,
+            old_trades: prev.trades,
+        }
     }
     // Changing treasury & ownership
 
+    // a single treasury replaces any split set with set_treasury_recipients,
+    // it takes effect once the new treasury calls accept_treasury
     #[payable]
-    pub fn set_treasury(&mut self, treasury_id: AccountId) {
+    pub fn set_treasury(&mut self, treasury_id: AccountId) -> AdminActionResult {
         assert_one_yocto();
         self.assert_owner();
-        self.treasury_id = treasury_id;
+        self.internal_record_admin_action("set_treasury", json!({ "treasury_id": treasury_id }));
+        if let Some(result) = self.internal_queue_admin_action(AdminAction::SetTreasury {
+            treasury_id: treasury_id.clone(),
+        }) {
+            return result;
+        }
+        self.internal_propose_treasury(treasury_id, vec![]);
+        AdminActionResult::Applied
+    }
+
+    // the first of split treasury_recipients accepts for the split
+    pub(crate) fn internal_propose_treasury(
+        &mut self,
+        treasury_id: AccountId,
+        treasury_recipients: Vec<TreasuryRecipient>,
+    ) {
+        self.pending_treasury_id = Some(treasury_id.clone());
+        self.pending_treasury_recipients = treasury_recipients.clone();
+
+        env::log_str(
+            &json!({
+                "type": "propose_treasury",
+                "params": {
+                    "treasury_id": treasury_id,
+                    "treasury_recipients": treasury_recipients,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn accept_treasury(&mut self) {
+        assert_one_yocto();
+        let treasury_id = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_treasury_id.as_ref(),
+            Some(&treasury_id),
+            "Marble: Pending treasury only"
+        );
+        self.internal_record_admin_action("accept_treasury", json!({}));
+        self.pending_treasury_id = None;
+        self.treasury_id = treasury_id.clone();
+        self.treasury_recipients = std::mem::take(&mut self.pending_treasury_recipients);
+
+        env::log_str(
+            &json!({
+                "type": "accept_treasury",
+                "params": {
+                    "treasury_id": treasury_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_pending_treasury(&self) -> Option<AccountId> {
+        self.pending_treasury_id.clone()
     }
 
+    // e.g. a DAO, a dev fund and a burn account, shares must sum to 10000
     #[payable]
-    pub fn set_transaction_fee(&mut self, next_fee: u16, start_time: Option<TimestampSec>) {
+    pub fn set_treasury_recipients(
+        &mut self,
+        treasury_recipients: Vec<TreasuryRecipient>,
+    ) -> AdminActionResult {
         assert_one_yocto();
         self.assert_owner();
+        self.internal_record_admin_action(
+            "set_treasury_recipients",
+            json!({ "treasury_recipients": treasury_recipients }),
+        );
+        assert!(
+            !treasury_recipients.is_empty() && treasury_recipients.len() <= MAX_TREASURY_RECIPIENTS,
+            "Marble: Between 1 and {} treasury recipients",
+            MAX_TREASURY_RECIPIENTS
+        );
+        let mut account_ids = HashSet::new();
+        let mut total_share_bps = 0u32;
+        for treasury_recipient in treasury_recipients.iter() {
+            assert!(
+                account_ids.insert(treasury_recipient.account_id.clone()),
+                "Marble: Duplicate treasury recipient"
+            );
+            total_share_bps += treasury_recipient.share_bps as u32;
+        }
+        assert_eq!(
+            total_share_bps, 10_000,
+            "Marble: Treasury shares must sum to 10000"
+        );
+
+        if let Some(result) = self.internal_queue_admin_action(AdminAction::SetTreasuryRecipients {
+            treasury_recipients: treasury_recipients.clone(),
+        }) {
+            return result;
+        }
+        // the first recipient answers get_treasury once it accepts the split
+        self.internal_propose_treasury(
+            treasury_recipients[0].account_id.clone(),
+            treasury_recipients,
+        );
+        AdminActionResult::Applied
+    }
+
+    #[payable]
+    pub fn set_transaction_fee(
+        &mut self,
+        next_fee: u16,
+        start_time: Option<TimestampSec>,
+    ) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_transaction_fee",
+            json!({ "next_fee": next_fee, "start_time": start_time }),
+        );
 
         assert!(next_fee < 10_000, "Marble: fee is higher than 10_000");
+        if let Some(result) = self.internal_queue_admin_action(AdminAction::SetTransactionFee {
+            next_fee,
+            start_time,
+        }) {
+            return result;
+        }
+        self.internal_set_transaction_fee(next_fee, start_time);
+        AdminActionResult::Applied
+    }
 
+    pub(crate) fn internal_set_transaction_fee(
+        &mut self,
+        next_fee: u16,
+        start_time: Option<TimestampSec>,
+    ) {
+        // an immediate change leaves the scheduled ones in place
         if start_time.is_none() {
             self.transaction_fee.current_fee = next_fee;
-            self.transaction_fee.next_fee = None;
-            self.transaction_fee.start_time = None;
             return;
         } else {
             let start_time: TimestampSec = start_time.unwrap();
@@ -314,16 +1050,105 @@ impl Contract {
                 start_time > to_sec(env::block_timestamp()),
                 "start_time is less than current block_timestamp"
             );
-            self.transaction_fee.next_fee = Some(next_fee);
-            self.transaction_fee.start_time = Some(start_time);
+            let mut fee_changes = self.get_transaction_fee_changes();
+            assert!(
+                fee_changes.len() < MAX_TRANSACTION_FEE_CHANGES,
+                "Marble: Up to {} fee changes can be scheduled",
+                MAX_TRANSACTION_FEE_CHANGES
+            );
+            assert!(
+                fee_changes
+                    .iter()
+                    .all(|fee_change| fee_change.start_time != start_time),
+                "Marble: A fee change is already scheduled at start_time"
+            );
+            fee_changes.push(TransactionFeeChange {
+                fee: next_fee,
+                start_time,
+            });
+            self.internal_set_transaction_fee_changes(fee_changes);
+
+            env::log_str(
+                &json!({
+                    "type": "schedule_transaction_fee",
+                    "params": {
+                        "fee": next_fee,
+                        "start_time": start_time,
+                    }
+                })
+                .to_string(),
+            );
+        }
+    }
+
+    #[payable]
+    pub fn cancel_transaction_fee_change(&mut self, start_time: TimestampSec) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "cancel_transaction_fee_change",
+            json!({ "start_time": start_time }),
+        );
+
+        let mut fee_changes = self.get_transaction_fee_changes();
+        let index = fee_changes
+            .iter()
+            .position(|fee_change| fee_change.start_time == start_time)
+            .expect("Marble: No fee change is scheduled at start_time");
+        let fee_change = fee_changes.remove(index);
+        self.internal_set_transaction_fee_changes(fee_changes);
+
+        env::log_str(
+            &json!({
+                "type": "cancel_transaction_fee",
+                "params": {
+                    "fee": fee_change.fee,
+                    "start_time": fee_change.start_time,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // every pending change, next_fee first
+    pub fn get_transaction_fee_changes(&self) -> Vec<TransactionFeeChange> {
+        let mut fee_changes = vec![];
+        if let (Some(fee), Some(start_time)) = (
+            self.transaction_fee.next_fee,
+            self.transaction_fee.start_time,
+        ) {
+            fee_changes.push(TransactionFeeChange { fee, start_time });
         }
+        fee_changes.extend(self.transaction_fee_changes.iter().cloned());
+        fee_changes
+    }
+
+    // the earliest change is kept in transaction_fee, the rest queue behind it
+    fn internal_set_transaction_fee_changes(&mut self, mut fee_changes: Vec<TransactionFeeChange>) {
+        fee_changes.sort_by_key(|fee_change| fee_change.start_time);
+        let next_fee_change = if fee_changes.is_empty() {
+            None
+        } else {
+            Some(fee_changes.remove(0))
+        };
+        self.transaction_fee.next_fee = next_fee_change.as_ref().map(|fee_change| fee_change.fee);
+        self.transaction_fee.start_time = next_fee_change.map(|fee_change| fee_change.start_time);
+        self.transaction_fee_changes = fee_changes;
     }
 
     pub fn calculate_market_data_transaction_fee(
         &mut self,
         nft_contract_id: &AccountId,
         token_id: &TokenId,
+        owner_id: &AccountId,
+        price: u128,
     ) -> u128 {
+        if self.is_fee_exempt(nft_contract_id.clone(), owner_id.clone()) {
+            return 0;
+        }
+        if let Some(bracket_fee) = self.internal_bracket_fee(price) {
+            return bracket_fee as u128;
+        }
         let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
         if let Some(transaction_fee) = self
             .market_data_transaction_fee
@@ -337,77 +1162,594 @@ impl Contract {
         self.calculate_current_transaction_fee()
     }
 
-    pub fn calculate_current_transaction_fee(&mut self) -> u128 {
-        let transaction_fee: &TransactionFee = &self.transaction_fee;
-        if transaction_fee.next_fee.is_some() {
-            if to_sec(env::block_timestamp()) >= transaction_fee.start_time.unwrap() {
-                self.transaction_fee.current_fee = transaction_fee.next_fee.unwrap();
-                self.transaction_fee.next_fee = None;
-                self.transaction_fee.start_time = None;
-            }
+    // offers settle at the fee locked when they were made, unless the seller or collection is exempt
+    pub fn calculate_offer_transaction_fee(
+        &mut self,
+        nft_contract_id: &AccountId,
+        seller_id: &AccountId,
+        price: u128,
+        transaction_fee: u16,
+    ) -> u128 {
+        if self.is_fee_exempt(nft_contract_id.clone(), seller_id.clone()) {
+            return 0;
         }
-        self.transaction_fee.current_fee as u128
+        if let Some(bracket_fee) = self.internal_bracket_fee(price) {
+            return bracket_fee as u128;
+        }
+        transaction_fee as u128
     }
 
-    pub fn get_transaction_fee(&self) -> &TransactionFee {
-        &self.transaction_fee
+    // offers made before fees were locked settle at the current fee
+    fn internal_offer_transaction_fee(
+        &mut self,
+        contract_account_id_token_id: &ContractAccountIdTokenId,
+    ) -> u16 {
+        match self
+            .offer_transaction_fees
+            .get(contract_account_id_token_id)
+        {
+            Some(transaction_fee) => transaction_fee,
+            None => self.calculate_current_transaction_fee() as u16,
+        }
     }
 
-    pub fn get_market_data_transaction_fee(
-        self,
-        nft_contract_id: &AccountId,
-        token_id: &TokenId,
-    ) -> u128 {
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        if let Some(transaction_fee) = self
-            .market_data_transaction_fee
+    pub fn calculate_current_transaction_fee(&mut self) -> u128 {
+        let current_time = to_sec(env::block_timestamp());
+        if self
             .transaction_fee
-            .get(&contract_and_token_id)
+            .start_time
+            .map_or(false, |start_time| current_time >= start_time)
         {
-            return transaction_fee;
+            let mut fee_changes = self.get_transaction_fee_changes();
+            let due = fee_changes
+                .iter()
+                .take_while(|fee_change| current_time >= fee_change.start_time)
+                .count();
+            for fee_change in fee_changes.drain(..due) {
+                self.transaction_fee.current_fee = fee_change.fee;
+                env::log_str(
+                    &json!({
+                        "type": "apply_transaction_fee",
+                        "params": {
+                            "fee": fee_change.fee,
+                            "start_time": fee_change.start_time,
+                        }
+                    })
+                    .to_string(),
+                );
+            }
+            self.internal_set_transaction_fee_changes(fee_changes);
         }
-
-        // fallback to default transaction fee
         self.transaction_fee.current_fee as u128
     }
 
     #[payable]
-    pub fn transfer_ownership(&mut self, owner_id: AccountId) {
-        assert_one_yocto();
-        self.assert_owner();
-        self.owner_id = owner_id;
-    }
-
-    // Approved contracts
-    #[payable]
-    pub fn add_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+    pub fn set_max_len_payout(&mut self, max_len_payout: u32) {
         assert_one_yocto();
         self.assert_owner();
-        add_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+        self.internal_record_admin_action(
+            "set_max_len_payout",
+            json!({ "max_len_payout": max_len_payout }),
+        );
+        assert!(
+            max_len_payout > 0 && max_len_payout <= MAX_LEN_PAYOUT,
+            "Marble: max_len_payout must be between 1 and {}",
+            MAX_LEN_PAYOUT
+        );
+        self.max_len_payout = max_len_payout;
     }
 
-    #[payable]
-    pub fn remove_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
-        assert_one_yocto();
-        self.assert_owner();
-        remove_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+    pub fn get_max_len_payout(&self) -> u32 {
+        self.max_len_payout
     }
 
-    // Approved marble contracts
+    // payouts beyond max_len_payout are rejected like malformed ones, the seller is paid in full
+    fn internal_filter_payout(
+        &self,
+        payout: Option<PayoutHashMap>,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> Option<PayoutHashMap> {
+        payout.filter(|payout| {
+            let is_too_complex = payout.len() > self.max_len_payout as usize;
+            if is_too_complex {
+                env::log_str(
+                    &json!({
+                        "type": "payout_fail",
+                        "params": {
+                            "nft_contract_id": nft_contract_id,
+                            "token_id": token_id,
+                            "payout_len": payout.len(),
+                            "max_len_payout": self.max_len_payout,
+                            "reason": "royalty too complex",
+                        }
+                    })
+                    .to_string(),
+                );
+            }
+            !is_too_complex
+        })
+    }
+
+    pub fn get_transaction_fee(&self) -> &TransactionFee {
+        &self.transaction_fee
+    }
+
+    // the fee a sale of the token at price would pay right now, for proceeds previews
+    pub fn get_effective_fee(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        price: U128,
+    ) -> EffectiveFee {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let is_exempt = self.fee_exempt_nft_contract_ids.contains(&nft_contract_id)
+            || self
+                .internal_get_market_data(&contract_and_token_id)
+                .map_or(false, |market_data| {
+                    self.fee_exempt_account_ids.contains(&market_data.owner_id)
+                });
+
+        let fee = if is_exempt {
+            0
+        } else if let Some(bracket_fee) = self.internal_bracket_fee(price.0) {
+            bracket_fee
+        } else if let Some(transaction_fee) = self
+            .market_data_transaction_fee
+            .transaction_fee
+            .get(&contract_and_token_id)
+        {
+            transaction_fee as u16
+        } else {
+            // scheduled changes that are due but not applied yet
+            let current_time = to_sec(env::block_timestamp());
+            self.get_transaction_fee_changes()
+                .iter()
+                .take_while(|fee_change| current_time >= fee_change.start_time)
+                .last()
+                .map_or(self.transaction_fee.current_fee, |fee_change| {
+                    fee_change.fee
+                })
+        };
+
+        EffectiveFee {
+            fee,
+            amount: U128(price.0 * fee as u128 / 10_000u128),
+        }
+    }
+
+    pub fn get_market_data_transaction_fee(
+        self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> u128 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if let Some(transaction_fee) = self
+            .market_data_transaction_fee
+            .transaction_fee
+            .get(&contract_and_token_id)
+        {
+            return transaction_fee;
+        }
+
+        // fallback to default transaction fee
+        self.transaction_fee.current_fee as u128
+    }
+
+    // takes effect once the new owner calls accept_ownership
     #[payable]
-    pub fn add_approved_marble_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+    pub fn transfer_ownership(&mut self, owner_id: AccountId) {
         assert_one_yocto();
         self.assert_owner();
+        self.internal_record_admin_action("transfer_ownership", json!({ "owner_id": owner_id }));
+        self.pending_owner_id = Some(owner_id.clone());
+
+        env::log_str(
+            &json!({
+                "type": "propose_ownership",
+                "params": {
+                    "owner_id": owner_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn accept_ownership(&mut self) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_owner_id.as_ref(),
+            Some(&owner_id),
+            "Marble: Pending owner only"
+        );
+        self.internal_record_admin_action("accept_ownership", json!({}));
+        self.pending_owner_id = None;
+        self.owner_id = owner_id.clone();
+
+        env::log_str(
+            &json!({
+                "type": "accept_ownership",
+                "params": {
+                    "owner_id": owner_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner_id.clone()
+    }
+
+    // Approved contracts
+    #[payable]
+    pub fn add_approved_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_record_admin_action(
+            "add_approved_nft_contract_ids",
+            json!({ "nft_contract_ids": nft_contract_ids }),
+        );
+        add_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+    }
+
+    #[payable]
+    pub fn remove_approved_nft_contract_ids(
+        &mut self,
+        nft_contract_ids: Vec<AccountId>,
+    ) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_record_admin_action(
+            "remove_approved_nft_contract_ids",
+            json!({ "nft_contract_ids": nft_contract_ids }),
+        );
+        if let Some(result) =
+            self.internal_queue_admin_action(AdminAction::RemoveApprovedNftContractIds {
+                nft_contract_ids: nft_contract_ids.clone(),
+            })
+        {
+            return result;
+        }
+        remove_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
+        AdminActionResult::Applied
+    }
+
+    // Approved marble contracts
+    #[payable]
+    pub fn add_approved_marble_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_record_admin_action(
+            "add_approved_marble_nft_contract_ids",
+            json!({ "nft_contract_ids": nft_contract_ids }),
+        );
         add_accounts(Some(nft_contract_ids), &mut self.marble_nft_contracts);
     }
 
+    // Fee exemptions, sales by these sellers or of these collections skip the treasury fee
+    #[payable]
+    pub fn add_fee_exempt_account_ids(&mut self, account_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "add_fee_exempt_account_ids",
+            json!({ "account_ids": account_ids }),
+        );
+        add_accounts(Some(account_ids), &mut self.fee_exempt_account_ids);
+    }
+
+    #[payable]
+    pub fn remove_fee_exempt_account_ids(&mut self, account_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "remove_fee_exempt_account_ids",
+            json!({ "account_ids": account_ids }),
+        );
+        remove_accounts(Some(account_ids), &mut self.fee_exempt_account_ids);
+    }
+
+    #[payable]
+    pub fn add_fee_exempt_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "add_fee_exempt_nft_contract_ids",
+            json!({ "nft_contract_ids": nft_contract_ids }),
+        );
+        add_accounts(
+            Some(nft_contract_ids),
+            &mut self.fee_exempt_nft_contract_ids,
+        );
+    }
+
+    #[payable]
+    pub fn remove_fee_exempt_nft_contract_ids(&mut self, nft_contract_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "remove_fee_exempt_nft_contract_ids",
+            json!({ "nft_contract_ids": nft_contract_ids }),
+        );
+        remove_accounts(
+            Some(nft_contract_ids),
+            &mut self.fee_exempt_nft_contract_ids,
+        );
+    }
+
+    pub fn is_fee_exempt(&self, nft_contract_id: AccountId, seller_id: AccountId) -> bool {
+        self.fee_exempt_nft_contract_ids.contains(&nft_contract_id)
+            || self.fee_exempt_account_ids.contains(&seller_id)
+    }
+
     #[payable]
     pub fn add_approved_ft_token_ids(&mut self, ft_token_ids: Vec<AccountId>) {
         assert_one_yocto();
         self.assert_owner();
+        self.internal_record_admin_action(
+            "add_approved_ft_token_ids",
+            json!({ "ft_token_ids": ft_token_ids }),
+        );
+        for ft_token_id in ft_token_ids.iter() {
+            if *ft_token_id != near_account() {
+                self.internal_sync_ft_token_decimals(ft_token_id.clone());
+            }
+        }
         add_accounts(Some(ft_token_ids), &mut self.approved_ft_token_ids);
     }
 
+    // Collection admins
+    #[payable]
+    pub fn set_collection_admin(
+        &mut self,
+        nft_contract_id: AccountId,
+        admin_id: Option<AccountId>,
+    ) {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_record_admin_action(
+            "set_collection_admin",
+            json!({ "nft_contract_id": nft_contract_id, "admin_id": admin_id }),
+        );
+        if let Some(admin_id) = admin_id {
+            self.collection_admins.insert(&nft_contract_id, &admin_id);
+        } else {
+            self.collection_admins.remove(&nft_contract_id);
+        }
+    }
+
+    pub fn get_collection_admin(&self, nft_contract_id: AccountId) -> Option<AccountId> {
+        self.collection_admins.get(&nft_contract_id)
+    }
+
+    // Referral share of the treasury fee, in basis points
+    #[payable]
+    pub fn set_referral_fee(&mut self, referral_fee: u16) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_referral_fee",
+            json!({ "referral_fee": referral_fee }),
+        );
+        assert!(
+            referral_fee <= 10_000,
+            "Marble: referral_fee cannot be higher than 10000"
+        );
+        self.referral_fee = referral_fee;
+    }
+
+    pub fn get_referral_fee(&self) -> u16 {
+        self.referral_fee
+    }
+
+    // Burnt share of the treasury fee, NEAR goes to burn_account_id and FTs are ft_burn'ed
+    #[payable]
+    pub fn set_burn_bps(&mut self, burn_bps: u16, burn_account_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_burn_bps",
+            json!({ "burn_bps": burn_bps, "burn_account_id": burn_account_id }),
+        );
+        assert!(
+            burn_bps <= 10_000,
+            "Marble: burn_bps cannot be higher than 10000"
+        );
+        assert!(
+            burn_bps == 0 || burn_account_id.is_some(),
+            "Marble: burn_account_id is required to burn NEAR"
+        );
+        self.burn_bps = burn_bps;
+        self.burn_account_id = burn_account_id;
+    }
+
+    pub fn get_burn_bps(&self) -> u16 {
+        self.burn_bps
+    }
+
+    pub fn get_burn_account_id(&self) -> Option<AccountId> {
+        self.burn_account_id.clone()
+    }
+
+    // Trade fee, the flat part is paid in NEAR from the seller's storage deposit or prepaid in an FT
+    #[payable]
+    pub fn set_trade_fee(&mut self, trade_fee: TradeFee) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action("set_trade_fee", json!({ "trade_fee": trade_fee }));
+        assert!(
+            trade_fee.fee_bps <= 10_000,
+            "Marble: fee_bps cannot be higher than 10000"
+        );
+        if let Some(result) = self.internal_queue_admin_action(AdminAction::SetTradeFee {
+            trade_fee: trade_fee.clone(),
+        }) {
+            return result;
+        }
+        self.trade_fee = trade_fee;
+        AdminActionResult::Applied
+    }
+
+    pub fn get_trade_fee(&self) -> TradeFee {
+        self.trade_fee.clone()
+    }
+
+    // Listing fee, NEAR taken from the seller's storage deposit and refunded when they delist
+    #[payable]
+    pub fn set_listing_fee(&mut self, listing_fee: U128) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action("set_listing_fee", json!({ "listing_fee": listing_fee }));
+        if let Some(result) =
+            self.internal_queue_admin_action(AdminAction::SetListingFee { listing_fee })
+        {
+            return result;
+        }
+        self.listing_fee = listing_fee.0;
+        AdminActionResult::Applied
+    }
+
+    pub fn get_listing_fee(&self) -> U128 {
+        self.listing_fee.into()
+    }
+
+    // Oracle for USD priced listings
+    #[payable]
+    pub fn set_price_oracle(&mut self, price_oracle: Option<PriceOracleConfig>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "set_price_oracle",
+            json!({ "price_oracle": price_oracle }),
+        );
+        self.price_oracle = price_oracle;
+    }
+
+    pub fn get_price_oracle(&self) -> Option<PriceOracleConfig> {
+        self.price_oracle.clone()
+    }
+
+    // wNEAR contract, e.g. wrap.near, its token must be approved to pay with it
+    #[payable]
+    pub fn set_wrap_near_id(&mut self, wrap_near_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "set_wrap_near_id",
+            json!({ "wrap_near_id": wrap_near_id }),
+        );
+        self.wrap_near_id = wrap_near_id;
+    }
+
+    pub fn get_wrap_near_id(&self) -> Option<AccountId> {
+        self.wrap_near_id.clone()
+    }
+
+    // Offer price policy
+    #[payable]
+    pub fn set_min_offer_price(&mut self, min_offer_price: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "set_min_offer_price",
+            json!({ "min_offer_price": min_offer_price }),
+        );
+        self.min_offer_price = min_offer_price.0;
+    }
+
+    #[payable]
+    pub fn set_collection_min_offer_price(
+        &mut self,
+        nft_contract_id: AccountId,
+        min_offer_price: Option<U128>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner_or_collection_admin(&nft_contract_id);
+        if let Some(min_offer_price) = min_offer_price {
+            self.collection_min_offer_prices
+                .insert(&nft_contract_id, &min_offer_price.0);
+        } else {
+            self.collection_min_offer_prices.remove(&nft_contract_id);
+        }
+    }
+
+    pub fn get_min_offer_price(&self, nft_contract_id: Option<AccountId>) -> U128 {
+        nft_contract_id
+            .and_then(|nft_contract_id| self.collection_min_offer_prices.get(&nft_contract_id))
+            .unwrap_or(self.min_offer_price)
+            .into()
+    }
+
+    fn assert_min_offer_price(&self, nft_contract_id: &AccountId, price: u128) {
+        let min_offer_price = self.get_min_offer_price(Some(nft_contract_id.clone())).0;
+        assert!(
+            price >= min_offer_price,
+            "Marble: Offer price is less than minimum offer price {}",
+            min_offer_price
+        );
+    }
+
+    // Listing price floors, per collection and payment token
+    #[payable]
+    pub fn set_collection_min_listing_price(
+        &mut self,
+        nft_contract_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        min_listing_price: Option<U128>,
+    ) {
+        assert_one_yocto();
+        self.assert_owner_or_collection_admin(&nft_contract_id);
+        let contract_and_ft_token_id = format!(
+            "{}{}{}",
+            nft_contract_id,
+            DELIMETER,
+            ft_token_id.unwrap_or(near_account())
+        );
+        if let Some(min_listing_price) = min_listing_price {
+            self.collection_min_listing_prices
+                .insert(&contract_and_ft_token_id, &min_listing_price.0);
+        } else {
+            self.collection_min_listing_prices
+                .remove(&contract_and_ft_token_id);
+        }
+    }
+
+    pub fn get_collection_min_listing_price(
+        &self,
+        nft_contract_id: AccountId,
+        ft_token_id: Option<AccountId>,
+    ) -> U128 {
+        let contract_and_ft_token_id = format!(
+            "{}{}{}",
+            nft_contract_id,
+            DELIMETER,
+            ft_token_id.unwrap_or(near_account())
+        );
+        self.collection_min_listing_prices
+            .get(&contract_and_ft_token_id)
+            .unwrap_or(0)
+            .into()
+    }
+
+    fn assert_min_listing_price(
+        &self,
+        nft_contract_id: &AccountId,
+        ft_token_id: &AccountId,
+        price: u128,
+    ) {
+        let min_listing_price = self
+            .get_collection_min_listing_price(nft_contract_id.clone(), Some(ft_token_id.clone()))
+            .0;
+        assert!(
+            price >= min_listing_price,
+            "Marble: Price is less than minimum listing price {}",
+            min_listing_price
+        );
+    }
+
     // Buy & Payment
 
     #[payable]
@@ -417,7 +1759,13 @@ impl Contract {
         token_id: TokenId,
         ft_token_id: Option<AccountId>,
         price: Option<U128>,
+        max_price: U128,
+        referral_id: Option<AccountId>,
+        receiver_id: Option<AccountId>,
     ) {
+        self.assert_not_paused(PauseFeature::Buys);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
         let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
         let market_data: Option<MarketData> =
             if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
@@ -451,47 +1799,70 @@ impl Contract {
             buyer_id, market_data.owner_id,
             "Marble: Cannot buy your own sale"
         );
-
-        // only NEAR supported for now
-        assert_eq!(
-            market_data.ft_token_id.to_string(),
-            NEAR,
-            "Marble: NEAR support only"
+        self.assert_not_blacklisted_seller(&market_data.owner_id);
+        if let Some(allowed_buyer_id) = self.allowed_buyer_ids.get(&contract_and_token_id) {
+            assert_eq!(
+                buyer_id, allowed_buyer_id,
+                "Marble: Sale is reserved for another buyer"
+            );
+        }
+        assert!(
+            self.layaways.get(&contract_and_token_id).is_none(),
+            "Marble: Listing is locked by a layaway"
+        );
+        assert_ne!(
+            referral_id.as_ref(),
+            Some(&buyer_id),
+            "Marble: Cannot refer yourself"
         );
+        let presale_holder_nft_contract_id =
+            self.internal_presale_holder_nft_contract_id(&contract_and_token_id, &buyer_id);
+
+        // USD listings are converted at execution time, max_price bounds the conversion
+        if let Some(usd_price) = self.usd_prices.get(&contract_and_token_id) {
+            assert!(!is_listing_expired(&market_data), "Marble: Sale has ended");
+            let receiver_id = receiver_id.unwrap_or(buyer_id.clone());
+            self.internal_buy_usd(
+                nft_contract_id,
+                token_id,
+                buyer_id,
+                receiver_id,
+                usd_price,
+                max_price,
+                referral_id,
+            );
+            return;
+        }
 
         if ft_token_id.is_some() {
-            assert_eq!(
-                ft_token_id.unwrap().to_string(),
-                market_data.ft_token_id.to_string()
-            )
+            assert_eq!(ft_token_id.unwrap().to_string(), NEAR)
         }
+        // a listing in another currency can still accept NEAR at its own price
+        let listed_price = self
+            .internal_listing_price(&contract_and_token_id, &market_data, &near_account())
+            .expect("Marble: NEAR support only");
         if price.is_some() {
-            assert_eq!(price.unwrap().0, market_data.price);
+            assert_eq!(price.unwrap().0, listed_price);
         }
 
-        let mut price = market_data.price;
-
         if market_data.is_auction.is_some() && market_data.end_price.is_some() {
-            let current_time = env::block_timestamp();
-            let end_price = market_data.end_price.unwrap();
-            let ended_at = market_data.ended_at.unwrap();
-            let started_at = market_data.started_at.unwrap();
-
             assert!(
-                current_time >= started_at,
+                env::block_timestamp() >= market_data.started_at.unwrap(),
                 "Marble: Auction has not started yet"
             );
-
-            if current_time > ended_at {
-                price = end_price;
-            } else {
-                let time_since_start = current_time - started_at;
-                let duration = ended_at - started_at;
-                price = price - ((price - end_price) / duration as u128) * time_since_start as u128;
-            }
         } else if let Some(auction) = market_data.is_auction {
             assert_eq!(auction, false, "Marble: the NFT is on auction");
         }
+        let price = current_listing_price(&market_data, listed_price);
+        assert!(!is_listing_expired(&market_data), "Marble: Sale has ended");
+
+        // the price may have moved since the buyer's quote
+        assert!(
+            price <= max_price.0,
+            "Marble: Price {} is higher than max_price {}",
+            price,
+            max_price.0
+        );
 
         assert!(
             env::attached_deposit() >= price,
@@ -499,2706 +1870,13149 @@ impl Contract {
             price
         );
 
-        self.internal_process_purchase(nft_contract_id.into(), token_id, buyer_id, price);
-    }
-
-    fn internal_buy(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        sender: AccountId,
-        price: U128,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let market_data: Option<MarketData> =
-            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
-                Some(MarketData {
-                    owner_id: market_data.owner_id,
-                    approval_id: market_data.approval_id,
-                    nft_contract_id: market_data.nft_contract_id,
-                    token_id: market_data.token_id,
-                    ft_token_id: market_data.ft_token_id,
-                    price: market_data.price,
-                    bids: None,
-                    started_at: None,
-                    ended_at: None,
-                    end_price: None,
-                    accept_nft_contract_id: None,
-                    accept_token_id: None,
-                    is_auction: None,
-                    reserve_price: None,
-                })
-            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
-                Some(market_data)
-            } else {
-                env::panic_str(&"Marble: Market data does not exist");
-            };
+        // a dutch auction may have dropped since signing, the overage is never escrowed
+        let excess_deposit = env::attached_deposit() - price;
+        if excess_deposit > 0 {
+            Promise::new(buyer_id.clone()).transfer(excess_deposit);
+        }
 
-        let market_data: MarketData = market_data.expect("Marble: Market data does not exist");
+        // gifts and custodial purchases deliver to another account, the payer is refunded on failure
+        let receiver_id = receiver_id.unwrap_or(buyer_id.clone());
+        if let Some(holder_nft_contract_id) = presale_holder_nft_contract_id {
+            self.internal_buy_presale(
+                holder_nft_contract_id,
+                nft_contract_id,
+                token_id,
+                near_account(),
+                buyer_id,
+                receiver_id,
+                price,
+                referral_id,
+            );
+            return;
+        }
+        self.internal_process_purchase(
+            nft_contract_id.into(),
+            token_id,
+            near_account(),
+            buyer_id,
+            receiver_id,
+            price,
+            referral_id,
+            self.config.gas_for_ft_payout,
+        );
+    }
 
-        let buyer_id = sender;
+    // Floor sweep, listings sold in the meantime are skipped and their share refunded
+    #[payable]
+    pub fn buy_batch(&mut self, purchases: Vec<BuyArgs>, referral_id: Option<AccountId>) {
+        self.assert_not_paused(PauseFeature::Buys);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        assert!(!purchases.is_empty(), "Marble: Purchases are empty");
+
+        // NEAR payouts resolve with the same budget as accepted offers
+        let gas_per_purchase = self.config.gas_for_nft_token.0
+            + self.config.gas_for_nft_transfer.0
+            + self.config.gas_for_royalties.0
+            + self.config.base_gas.0;
+        let gas_available = env::prepaid_gas().0 - env::used_gas().0;
+        assert!(
+            gas_available > gas_per_purchase * purchases.len() as u64 + self.config.base_gas.0,
+            "Marble: Not enough gas to buy {} tokens",
+            purchases.len()
+        );
 
+        let buyer_id = env::predecessor_account_id();
         assert_ne!(
-            buyer_id, market_data.owner_id,
-            "Marble: Cannot buy your own sale"
+            referral_id.as_ref(),
+            Some(&buyer_id),
+            "Marble: Cannot refer yourself"
         );
 
-        // // only NEAR supported for now
-        // assert_eq!(
-        //     market_data.ft_token_id.to_string(),
-        //     NEAR,
-        //     "Marble: NEAR support only"
-        // );
-
-        assert_eq!(ft_token_id.to_string(), market_data.ft_token_id.to_string());
-        assert_eq!(price, market_data.price.into());
-
-        let mut price = market_data.price;
-
-        if market_data.is_auction.is_some() && market_data.end_price.is_some() {
-            let current_time = env::block_timestamp();
-            let end_price = market_data.end_price.unwrap();
-            let ended_at = market_data.ended_at.unwrap();
-            let started_at = market_data.started_at.unwrap();
-
-            assert!(
-                current_time >= started_at,
-                "Marble: Auction has not started yet"
-            );
-
-            if current_time > ended_at {
-                price = end_price;
+        let mut contract_and_token_ids = HashSet::new();
+        let mut available_purchases = vec![];
+        let mut total_price = 0;
+        for BuyArgs {
+            nft_contract_id,
+            token_id,
+        } in purchases
+        {
+            let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+            let price = self
+                .internal_get_market_data(&contract_and_token_id)
+                .filter(|market_data| self.is_batch_purchasable(market_data, &buyer_id))
+                .filter(|_| contract_and_token_ids.insert(contract_and_token_id.clone()))
+                .map(|market_data| sale_price(&market_data));
+
+            if let Some(price) = price {
+                total_price += price;
+                available_purchases.push((nft_contract_id, token_id, price));
             } else {
-                let time_since_start = current_time - started_at;
-                let duration = ended_at - started_at;
-                price = price - ((price - end_price) / duration as u128) * time_since_start as u128;
+                env::log_str(
+                    &json!({
+                        "type": "buy_batch_skip",
+                        "params": {
+                            "buyer_id": buyer_id,
+                            "nft_contract_id": nft_contract_id,
+                            "token_id": token_id,
+                        }
+                    })
+                    .to_string(),
+                );
             }
-        } else if let Some(auction) = market_data.is_auction {
-            assert_eq!(auction, false, "Marble: the NFT is on auction");
         }
 
-        self.internal_process_purchase(nft_contract_id.into(), token_id, buyer_id, price);
+        assert!(
+            env::attached_deposit() >= total_price,
+            "Marble: Attached deposit is less than total price {}",
+            total_price
+        );
+        let excess_deposit = env::attached_deposit() - total_price;
+        if excess_deposit > 0 {
+            Promise::new(buyer_id.clone()).transfer(excess_deposit);
+        }
+
+        for (nft_contract_id, token_id, price) in available_purchases {
+            self.internal_process_purchase(
+                nft_contract_id,
+                token_id,
+                near_account(),
+                buyer_id.clone(),
+                buyer_id.clone(),
+                price,
+                referral_id.clone(),
+                self.config.gas_for_royalties,
+            );
+        }
     }
 
-    fn internal_process_purchase(
+    fn internal_buy_usd(
         &mut self,
         nft_contract_id: AccountId,
         token_id: TokenId,
         buyer_id: AccountId,
-        price: u128,
+        receiver_id: AccountId,
+        usd_price: Balance,
+        max_price: U128,
+        referral_id: Option<AccountId>,
     ) -> Promise {
-        let market_data = self
-            .internal_delete_market_data(&nft_contract_id, &token_id)
-            .expect("Marble: Sale does not exist");
-
-        ext_contract::nft_transfer_payout(
-            buyer_id.clone(),
-            token_id,
-            Some(market_data.approval_id),
-            Some(price.into()),
-            Some(10u32), // max length payout
-            nft_contract_id,
-            1,
-            GAS_FOR_NFT_TRANSFER,
+        let price_oracle = self
+            .price_oracle
+            .clone()
+            .expect("Marble: Price oracle is not set");
+
+        ext_price_oracle::get_price_data(
+            Some(vec![price_oracle.near_asset_id]),
+            price_oracle.oracle_id,
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
         )
-        .then(ext_self::resolve_purchase(
+        .then(ext_self::callback_buy_usd(
+            nft_contract_id,
+            token_id,
             buyer_id,
-            market_data,
-            price.into(),
+            receiver_id,
+            U128(usd_price),
+            max_price,
+            U128(env::attached_deposit()),
+            referral_id,
             env::current_account_id(),
             NO_DEPOSIT,
-            GAS_FOR_FT_PAYOUT,
+            self.config.gas_for_callback_buy_usd(),
         ))
     }
 
     #[private]
-    pub fn resolve_purchase(
+    pub fn callback_buy_usd(
         &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
         buyer_id: AccountId,
-        market_data: MarketData,
-        price: U128,
-    ) -> U128 {
-        env::log_str("Resolve Purchase");
-        let payout_option = promise_result_as_success().and_then(|value| {
-            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
-            if parsed_payout.is_err() {
-                near_sdk::serde_json::from_slice::<Payout>(&value)
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = price.0;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout.payout)
-                        } else {
-                            None
-                        }
-                    })
+        receiver_id: AccountId,
+        usd_price: U128,
+        max_price: U128,
+        deposit: U128,
+        referral_id: Option<AccountId>,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let price_data = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<PriceData>(&value).ok());
+
+        let price = match (price_data, self.price_oracle.as_ref()) {
+            (Some(price_data), Some(price_oracle)) => {
+                oracle_near_price(&price_data, price_oracle, usd_price.0)
+            }
+            _ => Err("Marble: Price oracle is unavailable".to_string()),
+        }
+        .and_then(|price| {
+            if self.usd_prices.get(&contract_and_token_id) != Some(usd_price.0) {
+                Err("Marble: Sale has changed since the purchase".to_string())
+            } else if price > max_price.0 {
+                Err(format!(
+                    "Marble: Price {} is higher than max_price {}",
+                    price, max_price.0
+                ))
+            } else if price > deposit.0 {
+                Err(format!(
+                    "Marble: Attached deposit is less than price {}",
+                    price
+                ))
             } else {
-                parsed_payout.ok().and_then(|payout| {
-                    let mut remainder = price.0;
-                    for &value in payout.values() {
-                        remainder = remainder.checked_sub(value.0)?;
-                    }
-                    if remainder <= 100 {
-                        Some(payout)
-                    } else {
-                        None
-                    }
-                })
+                Ok(price)
             }
         });
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            // leave function and return all FTs in ft_resolve_transfer
-            if !is_promise_success() {
-                if market_data.ft_token_id == near_account() {
-                    Promise::new(buyer_id.clone()).transfer(u128::from(price.0));
-                } else {
-                    ext_fungible_token::ft_transfer(
-                        buyer_id.clone(),
-                        (price.0).into(),
-                        None,
-                        market_data.ft_token_id.clone(),
-                        1,
-                        GAS_FOR_FT_TRANSFER,
-                    )
-                    .then(ext_self::callback_post_withdraw_deposit(
-                        market_data.ft_token_id.clone(),
-                        buyer_id.clone(),
-                        price.0.into(),
-                        env::current_account_id(),
-                        0,
-                        GAS_FOR_FT_TRANSFER,
-                    ));
-                }
-                env::log_str(
-                    &json!({
-                        "type": "resolve_purchase_fail",
-                        "params": {
-                            "owner_id": market_data.owner_id,
-                            "nft_contract_id": market_data.nft_contract_id,
-                            "token_id": market_data.token_id,
-                            "ft_token_id": market_data.ft_token_id,
-                            "price": price,
-                            "buyer_id": buyer_id,
-                        }
-                    })
-                    .to_string(),
-                );
-            } else {
-                let treasury_fee = price.0
-                    * self.calculate_market_data_transaction_fee(
-                        &market_data.nft_contract_id,
-                        &market_data.token_id,
-                    )
-                    / 10_000u128;
-                let contract_and_token_id = format!(
-                    "{}{}{}",
-                    &market_data.nft_contract_id, DELIMETER, &market_data.token_id
-                );
-                self.market_data_transaction_fee
-                    .transaction_fee
-                    .remove(&contract_and_token_id);
 
-                if market_data.ft_token_id == near_account() {
-                    Promise::new(market_data.owner_id.clone()).transfer(price.0 - treasury_fee);
-                    if treasury_fee > 0 {
-                        Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
-                    }
-                } else {
-                    ext_fungible_token::ft_transfer(
-                        market_data.owner_id.clone(),
-                        (price.0 - treasury_fee).into(),
-                        None,
-                        market_data.ft_token_id.clone(),
-                        1,
-                        GAS_FOR_FT_TRANSFER,
-                    )
-                    .then(ext_self::callback_post_withdraw_deposit(
-                        market_data.ft_token_id.clone(),
-                        market_data.owner_id.clone(),
-                        (price.0 - treasury_fee).into(),
-                        env::current_account_id(),
-                        0,
-                        GAS_FOR_FT_TRANSFER,
-                    ));
-                    if treasury_fee > 0 {
-                        ext_fungible_token::ft_transfer(
-                            self.treasury_id.clone(),
-                            (treasury_fee).into(),
-                            None,
-                            market_data.ft_token_id.clone(),
-                            1,
-                            GAS_FOR_FT_TRANSFER,
-                        )
-                        .then(ext_self::callback_post_withdraw_deposit(
-                            market_data.ft_token_id.clone(),
-                            self.treasury_id.clone(),
-                            (treasury_fee).into(),
-                            env::current_account_id(),
-                            0,
-                            GAS_FOR_FT_TRANSFER,
-                        ));
-                    }
-                }
+        let price = match price {
+            Ok(price) => price,
+            Err(error) => {
+                Promise::new(buyer_id).transfer(deposit.0);
+                env::log_str(&error);
+                return;
+            }
+        };
 
-                env::log_str(
-                    &json!({
-                        "type": "resolve_purchase",
-                        "params": {
-                            "owner_id": &market_data.owner_id,
-                            "nft_contract_id": &market_data.nft_contract_id,
-                            "token_id": &market_data.token_id,
-                            "ft_token_id": market_data.ft_token_id,
-                            "price": price,
-                            "buyer_id": buyer_id,
-                        }
-                    })
-                    .to_string(),
-                );
-            }
-            return price;
-        };
+        if deposit.0 > price {
+            Promise::new(buyer_id.clone()).transfer(deposit.0 - price);
+        }
 
-        // 5% fee for treasury
-        let treasury_fee = price.0
-            * self.calculate_market_data_transaction_fee(
-                &market_data.nft_contract_id,
-                &market_data.token_id,
-            )
-            / 10_000u128;
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            near_account(),
+            buyer_id,
+            receiver_id,
+            price,
+            referral_id,
+            self.config.gas_for_royalties,
+        );
+    }
+
+    fn internal_get_market_data(
+        &self,
+        contract_and_token_id: &ContractAndTokenId,
+    ) -> Option<MarketData> {
+        if let Some(market_data) = self.old_market.get(contract_and_token_id) {
+            Some(MarketData {
+                owner_id: market_data.owner_id,
+                approval_id: market_data.approval_id,
+                nft_contract_id: market_data.nft_contract_id,
+                token_id: market_data.token_id,
+                ft_token_id: market_data.ft_token_id,
+                price: market_data.price,
+                bids: None,
+                started_at: None,
+                ended_at: None,
+                end_price: None,
+                accept_nft_contract_id: None,
+                accept_token_id: None,
+                is_auction: None,
+                reserve_price: None,
+            })
+        } else {
+            self.market.get(contract_and_token_id)
+        }
+    }
+
+    fn is_batch_purchasable(&self, market_data: &MarketData, buyer_id: &AccountId) -> bool {
         let contract_and_token_id = format!(
             "{}{}{}",
-            &market_data.nft_contract_id, DELIMETER, &market_data.token_id
+            market_data.nft_contract_id, DELIMETER, market_data.token_id
         );
-        self.market_data_transaction_fee
-            .transaction_fee
-            .remove(&contract_and_token_id);
+        let is_dutch_auction = market_data.is_auction.is_some() && market_data.end_price.is_some();
+        let is_started = market_data
+            .started_at
+            .map_or(true, |started_at| env::block_timestamp() >= started_at);
+
+        market_data.ft_token_id == near_account()
+            && !self.mint_sales.contains(&contract_and_token_id)
+            && self.layaways.get(&contract_and_token_id).is_none()
+            && self.usd_prices.get(&contract_and_token_id).is_none()
+            && self.is_presale_buyer(&contract_and_token_id, buyer_id)
+            && &market_data.owner_id != buyer_id
+            && !self.blacklisted_seller_ids.contains(&market_data.owner_id)
+            && !self
+                .frozen_nft_contract_ids
+                .contains(&market_data.nft_contract_id)
+            && (is_dutch_auction && is_started || market_data.is_auction != Some(true))
+            && !is_listing_expired(market_data)
+            && self
+                .allowed_buyer_ids
+                .get(&contract_and_token_id)
+                .map_or(true, |allowed_buyer_id| &allowed_buyer_id == buyer_id)
+    }
 
-        // Payout (transfer to royalties and seller)
-        for (receiver_id, amount) in payout {
-            if receiver_id == market_data.owner_id {
-                if market_data.ft_token_id == near_account() {
-                    Promise::new(receiver_id).transfer(amount.0 - treasury_fee);
-                } else {
-                    ext_fungible_token::ft_transfer(
-                        receiver_id.clone(),
-                        (amount.0 - treasury_fee).into(),
-                        None,
-                        market_data.ft_token_id.clone(),
-                        1,
-                        GAS_FOR_FT_TRANSFER,
-                    )
-                    .then(ext_self::callback_post_withdraw_deposit(
-                        market_data.ft_token_id.clone(),
-                        receiver_id.clone(),
-                        (amount.0 - treasury_fee).into(),
-                        env::current_account_id(),
-                        0,
-                        GAS_FOR_FT_TRANSFER,
-                    ));
-                }
-                if treasury_fee != 0 {
-                    if market_data.ft_token_id == near_account() {
-                        Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
-                    } else {
-                        ext_fungible_token::ft_transfer(
-                            self.treasury_id.clone(),
-                            (treasury_fee).into(),
-                            None,
-                            market_data.ft_token_id.clone(),
-                            1,
-                            GAS_FOR_FT_TRANSFER,
-                        )
-                        .then(ext_self::callback_post_withdraw_deposit(
-                            market_data.ft_token_id.clone(),
-                            self.treasury_id.clone(),
-                            (treasury_fee).into(),
-                            env::current_account_id(),
-                            0,
-                            GAS_FOR_FT_TRANSFER,
-                        ));
-                    }
-                }
-            } else {
-                if market_data.ft_token_id == near_account() {
-                    Promise::new(receiver_id).transfer(amount.0);
-                } else {
-                    ext_fungible_token::ft_transfer(
-                        receiver_id.clone(),
-                        (amount.0).into(),
-                        None,
-                        market_data.ft_token_id.clone(),
-                        1,
-                        GAS_FOR_FT_TRANSFER,
-                    )
-                    .then(ext_self::callback_post_withdraw_deposit(
-                        market_data.ft_token_id.clone(),
-                        receiver_id.clone(),
-                        (amount.0).into(),
-                        env::current_account_id(),
-                        0,
-                        GAS_FOR_FT_TRANSFER,
-                    ));
-                }
-            }
+    // Bundle listings, every token is approved separately and sold together for one price
+    #[payable]
+    pub fn add_bundle_listing(&mut self, tokens: Vec<TradeToken>, price: U128) -> U64 {
+        self.assert_not_paused(PauseFeature::Listings);
+        self.assert_not_blacklisted_seller(&env::predecessor_account_id());
+        let owner_id = env::predecessor_account_id();
+        assert!(
+            env::attached_deposit() >= self.config.storage_add_market_data.0,
+            "Marble: Attach at least {} yoctoNEAR for storage",
+            self.config.storage_add_market_data.0
+        );
+        assert!(
+            tokens.len() > 1 && tokens.len() <= MAX_BUNDLE_LISTING_SIZE,
+            "Marble: Bundle listing needs 2 to {} tokens",
+            MAX_BUNDLE_LISTING_SIZE
+        );
+        assert!(
+            price.0 > 0 && price.0 < self.config.max_price.0,
+            "Marble: price higher than {}",
+            self.config.max_price.0
+        );
+
+        let mut token_ids = HashSet::new();
+        for token in &tokens {
+            assert!(
+                self.approved_nft_contract_ids
+                    .contains(&token.nft_contract_id),
+                "Marble: nft_contract_id is not approved"
+            );
+            assert!(
+                token_ids.insert((token.nft_contract_id.clone(), token.token_id.clone())),
+                "Marble: Duplicate bundle tokens"
+            );
         }
+        // approvals come in through nft_on_approve
+        let tokens: Vec<TradeToken> = tokens
+            .into_iter()
+            .map(|token| TradeToken {
+                approval_id: None,
+                ..token
+            })
+            .collect();
+
+        let bundle_listing_id = self.next_bundle_listing_id;
+        self.next_bundle_listing_id += 1;
+        self.bundle_listings.insert(
+            &bundle_listing_id,
+            &BundleListing {
+                owner_id: owner_id.clone(),
+                tokens: tokens.clone(),
+                price: price,
+                storage_deposit: env::attached_deposit(),
+                created_at: env::block_timestamp(),
+            },
+        );
 
         env::log_str(
             &json!({
-                "type": "resolve_purchase",
+                "type": "add_bundle_listing",
                 "params": {
-                    "owner_id": &market_data.owner_id,
-                    "nft_contract_id": &market_data.nft_contract_id,
-                    "token_id": &market_data.token_id,
-                    "ft_token_id": market_data.ft_token_id,
+                    "bundle_listing_id": U64(bundle_listing_id),
+                    "owner_id": owner_id,
+                    "tokens": tokens,
                     "price": price,
-                    "buyer_id": buyer_id,
                 }
             })
             .to_string(),
         );
 
-        let seller_contract_account_id_token_id = make_triple(
-            &market_data.nft_contract_id,
-            &market_data.owner_id,
-            &market_data.token_id,
-        );
-        self.trades.remove(&seller_contract_account_id_token_id);
-
-        return price;
+        U64(bundle_listing_id)
     }
 
-    // Offer
+    #[payable]
+    pub fn delete_bundle_listing(&mut self, bundle_listing_id: U64) {
+        assert_one_yocto();
+        let bundle_listing = self
+            .bundle_listings
+            .get(&bundle_listing_id.0)
+            .expect("Marble: Bundle listing does not exist");
+        assert_eq!(
+            env::predecessor_account_id(),
+            bundle_listing.owner_id,
+            "Marble: Bundle listing owner only"
+        );
 
-    fn internal_add_offer(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: Option<TokenId>,
-        token_series_id: Option<TokenId>,
-        ft_token_id: AccountId,
-        price: U128,
-        buyer_id: AccountId,
-    ) {
-        let token = if token_id.is_some() {
-            token_id.as_ref().unwrap().to_string()
-        } else {
-            token_series_id.as_ref().unwrap().to_string()
-        };
+        self.bundle_listings.remove(&bundle_listing_id.0);
+        Promise::new(bundle_listing.owner_id.clone()).transfer(bundle_listing.storage_deposit);
 
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
-        self.offers.insert(
-            &contract_account_id_token_id,
-            &OfferData {
-                buyer_id: buyer_id.clone().into(),
-                nft_contract_id: nft_contract_id.into(),
-                token_id: token_id,
-                token_series_id: token_series_id,
-                ft_token_id: ft_token_id.into(),
-                price: price.into(),
-            },
+        env::log_str(
+            &json!({
+                "type": "delete_bundle_listing",
+                "params": {
+                    "bundle_listing_id": bundle_listing_id,
+                    "owner_id": bundle_listing.owner_id,
+                }
+            })
+            .to_string(),
         );
+    }
 
-        let mut token_ids = self.by_owner_id.get(&buyer_id).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&buyer_id),
-                }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
-        token_ids.insert(&contract_account_id_token_id);
-        self.by_owner_id.insert(&buyer_id, &token_ids);
+    pub fn get_bundle_listing(&self, bundle_listing_id: U64) -> Option<BundleListing> {
+        self.bundle_listings.get(&bundle_listing_id.0)
     }
 
-    #[payable]
-    pub fn add_offer(
+    fn internal_approve_bundle_token(
         &mut self,
+        bundle_listing_id: u64,
         nft_contract_id: AccountId,
-        token_id: Option<TokenId>,
-        token_series_id: Option<String>,
-        ft_token_id: AccountId,
-        price: U128,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
     ) {
-        let token = if token_id.is_some() {
-            token_id.as_ref().unwrap().to_string()
-        } else {
-            assert!(
-                self.marble_nft_contracts.contains(&nft_contract_id),
-                "Marble: offer series for Marble NFT only"
-            );
-            token_series_id.as_ref().unwrap().to_string()
-        };
-
-        assert_eq!(
-            env::attached_deposit(),
-            price.0,
-            "Marble: Attached deposit != price"
-        );
-
+        let mut bundle_listing = self
+            .bundle_listings
+            .get(&bundle_listing_id)
+            .expect("Marble: Bundle listing does not exist");
         assert_eq!(
-            ft_token_id.to_string(),
-            "near",
-            "Marble: Only NEAR is supported"
+            owner_id, bundle_listing.owner_id,
+            "Marble: Bundle listing owner only"
         );
+        let token = bundle_listing
+            .tokens
+            .iter_mut()
+            .find(|token| token.nft_contract_id == nft_contract_id && token.token_id == token_id)
+            .expect("Marble: Token is not in the bundle listing");
+        token.approval_id = Some(approval_id);
+        self.bundle_listings
+            .insert(&bundle_listing_id, &bundle_listing);
+    }
 
+    #[payable]
+    pub fn buy_bundle_listing(&mut self, bundle_listing_id: U64) -> Promise {
+        self.assert_not_paused(PauseFeature::Buys);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        let bundle_listing = self
+            .bundle_listings
+            .get(&bundle_listing_id.0)
+            .expect("Marble: Bundle listing does not exist");
         let buyer_id = env::predecessor_account_id();
-        let offer_data = self.internal_delete_offer(
-            nft_contract_id.clone().into(),
-            buyer_id.clone(),
-            token.clone(),
+        assert_ne!(
+            buyer_id, bundle_listing.owner_id,
+            "Marble: Cannot buy your own sale"
         );
-
-        if offer_data.is_some() {
-            Promise::new(buyer_id.clone()).transfer(offer_data.unwrap().price);
+        self.assert_not_blacklisted_seller(&bundle_listing.owner_id);
+        for token in bundle_listing.tokens.iter() {
+            self.assert_collection_not_frozen(&token.nft_contract_id);
         }
-
-        let storage_amount = self.storage_minimum_balance().0;
-        let owner_paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
-        let signer_storage_required =
-            (self.get_supply_by_owner_id(buyer_id.clone()).0 + 1) as u128 * storage_amount;
-
         assert!(
-            owner_paid_storage >= signer_storage_required,
-            "Insufficient storage paid: {}, for {} offer at {} rate of per offer",
-            owner_paid_storage,
-            signer_storage_required / storage_amount,
-            storage_amount,
+            bundle_listing
+                .tokens
+                .iter()
+                .all(|token| token.approval_id.is_some()),
+            "Marble: Bundle listing is not approved yet"
         );
-
-        self.internal_add_offer(
-            nft_contract_id.clone().into(),
-            token_id.clone(),
-            token_series_id.clone(),
-            ft_token_id.clone(),
-            price,
-            buyer_id.clone(),
+        let price = bundle_listing.price.0;
+        assert!(
+            env::attached_deposit() >= price,
+            "Marble: Attached deposit is less than price {}",
+            price
         );
+        let excess_deposit = env::attached_deposit() - price;
+        if excess_deposit > 0 {
+            Promise::new(buyer_id.clone()).transfer(excess_deposit);
+        }
 
-        env::log_str(
-            &json!({
-                "type": "add_offer",
-                "params": {
-                    "buyer_id": buyer_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "token_series_id": token_series_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                }
-            })
-            .to_string(),
+        self.bundle_listings.remove(&bundle_listing_id.0);
+        Promise::new(bundle_listing.owner_id.clone()).transfer(bundle_listing.storage_deposit);
+
+        // 1. verify every token is still owned and approved, otherwise refund the buyer
+        // 2. transfer every token with nft_transfer_payout for its share of the price
+        // 3. pay the aggregated royalties, refunding the share of any token that failed
+        let transaction_fee = self.calculate_current_transaction_fee() as u16;
+        let gas = Gas(
+            self.config.gas_for_nft_transfer.0 * bundle_listing.tokens.len() as u64
+                + self.config.gas_for_royalties.0
+                + self.config.base_gas.0,
         );
+        bundle_listing
+            .tokens
+            .iter()
+            .map(|token| {
+                ext_contract::nft_token(
+                    token.token_id.clone(),
+                    token.nft_contract_id.clone(),
+                    NO_DEPOSIT,
+                    self.config.gas_for_nft_token,
+                )
+            })
+            .reduce(|promise, next| promise.and(next))
+            .unwrap()
+            .then(ext_self::callback_verify_bundle_purchase(
+                bundle_listing_id,
+                buyer_id,
+                bundle_listing,
+                transaction_fee,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas,
+            ))
     }
 
-    fn internal_delete_offer(
+    #[private]
+    pub fn callback_verify_bundle_purchase(
         &mut self,
-        nft_contract_id: AccountId,
+        bundle_listing_id: U64,
         buyer_id: AccountId,
-        token_id: TokenId,
-    ) -> Option<OfferData> {
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-        let offer_data = self.offers.remove(&contract_account_id_token_id);
+        bundle_listing: BundleListing,
+        transaction_fee: u16,
+    ) {
+        let stale_error = bundle_listing
+            .tokens
+            .iter()
+            .enumerate()
+            .find_map(|(index, token)| {
+                stale_trade_token_error(index, &bundle_listing.owner_id, token)
+            });
+        if let Some(stale_error) = stale_error {
+            Promise::new(buyer_id).transfer(bundle_listing.price.0);
+            env::log_str(&stale_error);
+            return;
+        }
 
-        match offer_data {
-            Some(offer) => {
-                let by_owner_id = self.by_owner_id.get(&offer.buyer_id);
-                if let Some(mut by_owner_id) = by_owner_id {
-                    by_owner_id.remove(&contract_account_id_token_id);
-                    if by_owner_id.is_empty() {
-                        self.by_owner_id.remove(&offer.buyer_id);
-                    } else {
-                        self.by_owner_id.insert(&offer.buyer_id, &by_owner_id);
-                    }
-                }
-                return Some(offer);
-            }
-            None => return None,
-        };
+        let shares = bundle_listing_shares(&bundle_listing);
+        bundle_listing
+            .tokens
+            .iter()
+            .zip(shares.iter())
+            .map(|(token, share)| {
+                ext_contract::nft_transfer_payout(
+                    buyer_id.clone(),
+                    token.token_id.clone(),
+                    token.approval_id,
+                    Some(U128(*share)),
+                    Some(self.max_len_payout),
+                    token.nft_contract_id.clone(),
+                    1,
+                    self.config.gas_for_nft_transfer,
+                )
+            })
+            .reduce(|promise, next| promise.and(next))
+            .unwrap()
+            .then(ext_self::callback_resolve_bundle_purchase(
+                bundle_listing_id,
+                buyer_id,
+                bundle_listing,
+                transaction_fee,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.config.gas_for_royalties,
+            ));
     }
 
-    #[payable]
-    pub fn delete_offer(
+    #[private]
+    pub fn callback_resolve_bundle_purchase(
         &mut self,
-        nft_contract_id: AccountId,
-        token_id: Option<TokenId>,
-        token_series_id: Option<String>,
+        bundle_listing_id: U64,
+        buyer_id: AccountId,
+        bundle_listing: BundleListing,
+        transaction_fee: u16,
     ) {
-        assert_one_yocto();
-        let token = if token_id.is_some() {
-            token_id.as_ref().unwrap().to_string()
-        } else {
-            token_series_id.as_ref().unwrap().to_string()
-        };
-
-        let buyer_id = env::predecessor_account_id();
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+        let shares = bundle_listing_shares(&bundle_listing);
+        let owner_id = bundle_listing.owner_id.clone();
+
+        let mut payouts: HashMap<AccountId, Balance> = HashMap::new();
+        let mut treasury_fee = 0;
+        let mut refund = 0;
+        let mut sold_tokens = vec![];
+        for (index, (token, share)) in bundle_listing.tokens.iter().zip(shares).enumerate() {
+            let value = match promise_result_at(index as u64) {
+                Some(value) => value,
+                None => {
+                    refund += share;
+                    continue;
+                }
+            };
+            sold_tokens.push(token.clone());
 
-        let offer_data = self
-            .offers
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Offer does not exist");
+            // an unreadable payout pays the whole share to the owner
+            let payout = parse_payout(&value, share).unwrap_or_else(|| {
+                let mut payout = HashMap::new();
+                payout.insert(owner_id.clone(), U128(share));
+                payout
+            });
+            for (receiver_id, amount) in payout {
+                *payouts.entry(receiver_id).or_insert(0) += amount.0;
+            }
 
-        if token_id.is_some() {
-            assert_eq!(offer_data.token_id.unwrap(), token)
-        } else {
-            assert_eq!(offer_data.token_series_id.unwrap(), token)
+            // the treasury fee comes out of the owner's cut
+            let owner_amount = payouts.entry(owner_id.clone()).or_insert(0);
+            let fee = std::cmp::min(share * transaction_fee as u128 / 10_000u128, *owner_amount);
+            *owner_amount -= fee;
+            treasury_fee += fee;
         }
 
-        assert_eq!(
-            offer_data.buyer_id, buyer_id,
-            "Marble: Caller not offer's buyer"
-        );
-
-        self.internal_delete_offer(
-            nft_contract_id.clone().into(),
-            buyer_id.clone(),
-            token.clone(),
-        )
-        .expect("Marble: Offer not found");
-
-        Promise::new(offer_data.buyer_id).transfer(offer_data.price);
+        for (receiver_id, amount) in payouts {
+            if amount > 0 {
+                Promise::new(receiver_id).transfer(amount);
+            }
+        }
+        self.internal_transfer_treasury_fee(&near_account(), treasury_fee, None);
+        if refund > 0 {
+            Promise::new(buyer_id.clone()).transfer(refund);
+        }
 
+        let sale_id = self.internal_next_sale_id();
         env::log_str(
             &json!({
-                "type": "delete_offer",
+                "type": "resolve_bundle_purchase",
                 "params": {
-                    "nft_contract_id": nft_contract_id,
+                    "bundle_listing_id": bundle_listing_id,
+                    "sale_id": sale_id,
+                    "owner_id": owner_id,
                     "buyer_id": buyer_id,
-                    "token_id": token_id,
-                    "token_series_id": token_series_id,
+                    "price": bundle_listing.price,
+                    "refund": U128(refund),
+                    "sold_tokens": sold_tokens,
                 }
             })
             .to_string(),
         );
     }
 
-    pub fn get_offer(
-        &self,
+    fn internal_buy(
+        &mut self,
         nft_contract_id: AccountId,
-        buyer_id: AccountId,
-        token_id: Option<TokenId>,
-        token_series_id: Option<String>,
-    ) -> OfferDataJson {
-        let token = if token_id.is_some() {
-            token_id.as_ref().unwrap()
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        sender: AccountId,
+        amount: U128,
+        referral_id: Option<AccountId>,
+        receiver_id: Option<AccountId>,
+        gas_for_resolve: Gas,
+    ) -> Balance {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
+                Some(MarketData {
+                    owner_id: market_data.owner_id,
+                    approval_id: market_data.approval_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    ft_token_id: market_data.ft_token_id,
+                    price: market_data.price,
+                    bids: None,
+                    started_at: None,
+                    ended_at: None,
+                    end_price: None,
+                    accept_nft_contract_id: None,
+                    accept_token_id: None,
+                    is_auction: None,
+                    reserve_price: None,
+                })
+            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                Some(market_data)
+            } else {
+                env::panic_str(&"Marble: Market data does not exist");
+            };
+
+        let market_data: MarketData = market_data.expect("Marble: Market data does not exist");
+
+        let buyer_id = sender;
+
+        assert_ne!(
+            buyer_id, market_data.owner_id,
+            "Marble: Cannot buy your own sale"
+        );
+        self.assert_not_blacklisted_seller(&market_data.owner_id);
+        if let Some(allowed_buyer_id) = self.allowed_buyer_ids.get(&contract_and_token_id) {
+            assert_eq!(
+                buyer_id, allowed_buyer_id,
+                "Marble: Sale is reserved for another buyer"
+            );
+        }
+        assert!(
+            self.layaways.get(&contract_and_token_id).is_none(),
+            "Marble: Listing is locked by a layaway"
+        );
+
+        // // only NEAR supported for now
+        // assert_eq!(
+        //     market_data.ft_token_id.to_string(),
+        //     NEAR,
+        //     "Marble: NEAR support only"
+        // );
+
+        assert_ne!(
+            referral_id.as_ref(),
+            Some(&buyer_id),
+            "Marble: Cannot refer yourself"
+        );
+        let presale_holder_nft_contract_id =
+            self.internal_presale_holder_nft_contract_id(&contract_and_token_id, &buyer_id);
+
+        assert!(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            "Marble: ft_token_id not approved"
+        );
+        // wNEAR pays for NEAR priced listings unless the seller priced it separately
+        let is_wrapped_near_payment = self.wrap_near_id.as_ref() == Some(&ft_token_id)
+            && self
+                .internal_listing_price(&contract_and_token_id, &market_data, &ft_token_id)
+                .is_none();
+        let settlement_ft_token_id = if is_wrapped_near_payment {
+            near_account()
         } else {
-            token_series_id.as_ref().unwrap()
+            ft_token_id.clone()
         };
+        let listed_price = self
+            .internal_listing_price(
+                &contract_and_token_id,
+                &market_data,
+                &settlement_ft_token_id,
+            )
+            .expect("Marble: ft_token_id is not accepted by the listing");
 
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
-
-        let offer_data = self
-            .offers
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Offer does not exist");
+        if market_data.is_auction.is_some() && market_data.end_price.is_some() {
+            assert!(
+                env::block_timestamp() >= market_data.started_at.unwrap(),
+                "Marble: Auction has not started yet"
+            );
+        } else if let Some(auction) = market_data.is_auction {
+            assert_eq!(auction, false, "Marble: the NFT is on auction");
+        }
+        let price = current_listing_price(&market_data, listed_price);
+        assert!(!is_listing_expired(&market_data), "Marble: Sale has ended");
+        // a dutch auction may have dropped below what was sent, the rest goes back unused
+        assert!(
+            amount.0 >= price,
+            "Marble: Transferred amount is less than price {}",
+            price
+        );
+        let unused_amount = amount.0 - price;
+
+        let receiver_id = receiver_id.unwrap_or(buyer_id.clone());
+        if is_wrapped_near_payment {
+            self.internal_unwrap_near_purchase(
+                ft_token_id,
+                nft_contract_id,
+                token_id,
+                market_data.owner_id,
+                buyer_id,
+                receiver_id,
+                price,
+                referral_id,
+                presale_holder_nft_contract_id,
+            );
+            return unused_amount;
+        }
+        if let Some(holder_nft_contract_id) = presale_holder_nft_contract_id {
+            self.internal_buy_presale(
+                holder_nft_contract_id,
+                nft_contract_id,
+                token_id,
+                ft_token_id,
+                buyer_id,
+                receiver_id,
+                price,
+                referral_id,
+            );
+            return unused_amount;
+        }
+        self.internal_process_purchase(
+            nft_contract_id.into(),
+            token_id,
+            ft_token_id,
+            buyer_id,
+            receiver_id,
+            price,
+            referral_id,
+            gas_for_resolve,
+        );
+        unused_amount
+    }
 
-        if token_id.is_some() {
-            assert_eq!(offer_data.token_id.as_ref().unwrap(), token);
+    // the listed currency, or one of the alternative prices set by the seller
+    fn internal_listing_price(
+        &self,
+        contract_and_token_id: &ContractAndTokenId,
+        market_data: &MarketData,
+        ft_token_id: &AccountId,
+    ) -> Option<Balance> {
+        if ft_token_id == &market_data.ft_token_id {
+            Some(market_data.price)
         } else {
-            assert_eq!(offer_data.token_series_id.as_ref().unwrap(), token);
+            self.alternative_prices
+                .get(contract_and_token_id)
+                .and_then(|alternative_prices| alternative_prices.get(ft_token_id).map(|x| x.0))
+                .or_else(|| {
+                    self.internal_stable_price(contract_and_token_id, market_data, ft_token_id)
+                })
         }
+    }
 
-        OfferDataJson {
-            buyer_id: offer_data.buyer_id,
-            nft_contract_id: offer_data.nft_contract_id,
-            token_id: offer_data.token_id,
-            token_series_id: offer_data.token_series_id,
-            ft_token_id: offer_data.ft_token_id,
-            price: U128(offer_data.price),
+    // what internal_buy would charge the buyer now, None where it would refuse them
+    pub(crate) fn internal_purchasable_price(
+        &self,
+        contract_and_token_id: &ContractAndTokenId,
+        market_data: &MarketData,
+        buyer_id: &AccountId,
+    ) -> Option<Balance> {
+        let is_reserved = self
+            .allowed_buyer_ids
+            .get(contract_and_token_id)
+            .map_or(false, |allowed_buyer_id| &allowed_buyer_id != buyer_id);
+        // a holder presale verifies the buyer itself and refunds when they hold nothing
+        let is_presale_closed = self
+            .presales
+            .get(contract_and_token_id)
+            .map_or(false, |presale| {
+                presale.holder_nft_contract_id.is_none()
+                    && !self.is_presale_buyer(contract_and_token_id, buyer_id)
+            });
+        let is_auction_pending = market_data.is_auction.is_some()
+            && market_data.end_price.is_some()
+            && market_data
+                .started_at
+                .map_or(false, |started_at| env::block_timestamp() < started_at);
+        if buyer_id == &market_data.owner_id
+            || self.blacklisted_seller_ids.contains(&market_data.owner_id)
+            || is_reserved
+            || is_presale_closed
+            || self.layaways.get(contract_and_token_id).is_some()
+            || is_listing_expired(market_data)
+            || (market_data.is_auction == Some(true) && market_data.end_price.is_none())
+            || is_auction_pending
+        {
+            return None;
         }
+        self.internal_listing_price(contract_and_token_id, market_data, &market_data.ft_token_id)
+            .map(|listed_price| current_listing_price(market_data, listed_price))
     }
 
-    fn internal_accept_offer(
-        &mut self,
-        nft_contract_id: AccountId,
-        buyer_id: AccountId,
-        token_id: TokenId,
-        seller_id: AccountId,
-        approval_id: u64,
-        price: u128,
-    ) -> Promise {
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+    // the latest listing of the token, kept once it ends so its last events still carry it
+    pub fn get_listing_id(&self, nft_contract_id: AccountId, token_id: TokenId) -> Option<U64> {
+        self.internal_listing_id(&nft_contract_id, &token_id)
+    }
 
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+    pub fn get_next_sale_id(&self) -> U64 {
+        U64(self.next_sale_id)
+    }
 
-        let offer_data = self
-            .offers
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Offer does not exist");
+    pub(crate) fn internal_listing_id(
+        &self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> Option<U64> {
+        self.listing_ids
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .map(U64)
+    }
 
-        assert_eq!(offer_data.token_id.as_ref().unwrap(), &token_id);
-        assert_eq!(offer_data.price, price);
+    fn internal_next_sale_id(&mut self) -> U64 {
+        let sale_id = self.next_sale_id;
+        self.next_sale_id += 1;
+        U64(sale_id)
+    }
 
-        let offer_data = self
-            .internal_delete_offer(
-                nft_contract_id.clone().into(),
-                buyer_id.clone(),
-                token_id.clone(),
-            )
-            .expect("Marble: Offer does not exist");
+    pub fn is_settling(&self, nft_contract_id: AccountId, token_id: TokenId) -> bool {
+        self.settling_listings
+            .contains(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+    }
 
-        ext_contract::nft_transfer_payout(
-            offer_data.buyer_id.clone(),
-            token_id.clone(),
-            Some(approval_id),
-            Some(U128::from(offer_data.price)),
-            Some(10u32), // max length payout
-            nft_contract_id,
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_self::resolve_offer(
-            seller_id,
-            offer_data,
-            token_id,
-            env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_FOR_ROYALTIES,
-        ))
+    // releases a purchase whose resolve callback never ran
+    #[payable]
+    pub fn clear_settling(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_clear_settling(&nft_contract_id, &token_id);
     }
 
-    fn internal_accept_offer_series(
+    fn internal_clear_settling(&mut self, nft_contract_id: &AccountId, token_id: &TokenId) {
+        self.settling_listings
+            .remove(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id));
+    }
+
+    fn assert_not_settling(&self, contract_and_token_id: &ContractAndTokenId) {
+        assert!(
+            !self.settling_listings.contains(contract_and_token_id),
+            "Marble: Listing is settling a purchase"
+        );
+    }
+
+    fn internal_process_purchase(
         &mut self,
         nft_contract_id: AccountId,
-        buyer_id: AccountId,
         token_id: TokenId,
-        seller_id: AccountId,
-        approval_id: u64,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
         price: u128,
+        referral_id: Option<AccountId>,
+        gas_for_resolve: Gas,
     ) -> Promise {
-        // Token delimiter : is specific for Marble NFT
-
-        let mut token_id_iter = token_id.split(":");
-        let token_series_id: String = token_id_iter.next().unwrap().parse().unwrap();
-
-        let contract_account_id_token_id =
-            make_triple(&nft_contract_id, &buyer_id, &token_series_id);
-
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
-
-        let offer_data = self
-            .offers
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Offer does not exist");
-
-        assert_eq!(
-            offer_data.token_series_id.as_ref().unwrap(),
-            &token_series_id
-        );
-        assert_eq!(offer_data.price, price);
-
-        self.internal_delete_offer(
-            nft_contract_id.clone().into(),
-            buyer_id.clone(),
-            token_series_id.clone(),
-        )
-        .expect("Marble: Offer does not exist");
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        if receiver_id != buyer_id {
+            env::log_str(
+                &json!({
+                    "type": "gift_purchase",
+                    "params": {
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                        "buyer_id": buyer_id,
+                        "receiver_id": receiver_id,
+                    }
+                })
+                .to_string(),
+            );
+        }
+        if self.mint_sales.contains(&contract_and_token_id) {
+            let market_data = self
+                .internal_get_market_data(&contract_and_token_id)
+                .expect("Marble: Sale does not exist");
+            return self.internal_process_mint_sale(
+                buyer_id,
+                receiver_id,
+                market_data,
+                price,
+                referral_id,
+            );
+        }
+        let mut market_data =
+            if let Some(editions) = self.listing_editions.get(&contract_and_token_id) {
+                self.internal_take_listing_edition(&nft_contract_id, &token_id, editions)
+            } else {
+                // the token cannot be listed again until resolve_purchase finishes
+                self.settling_listings.insert(&contract_and_token_id);
+                self.internal_delete_market_data(&nft_contract_id, &token_id)
+                    .expect("Marble: Sale does not exist")
+            };
+        // settled, refunded and paid out in the currency the buyer paid with
+        market_data.ft_token_id = ft_token_id;
 
-        ext_contract::nft_transfer_payout(
-            offer_data.buyer_id.clone(),
-            token_id.clone(),
-            Some(approval_id),
-            Some(U128::from(offer_data.price)),
-            Some(10u32), // max length payout
+        // the seller may have moved the token elsewhere, check before transferring
+        ext_contract::nft_token(
+            market_data.token_id.clone(),
             nft_contract_id,
-            1,
-            GAS_FOR_NFT_TRANSFER,
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
         )
-        .then(ext_self::resolve_offer(
-            seller_id,
-            offer_data,
-            token_id,
+        .then(ext_self::callback_verify_purchase(
+            buyer_id,
+            receiver_id,
+            market_data,
+            price.into(),
+            referral_id,
+            gas_for_resolve,
             env::current_account_id(),
             NO_DEPOSIT,
-            GAS_FOR_ROYALTIES,
+            Gas(self.config.gas_for_nft_transfer.0 + gas_for_resolve.0 + self.config.base_gas.0),
         ))
     }
 
     #[private]
-    pub fn resolve_offer(
+    pub fn callback_verify_purchase(
         &mut self,
-        seller_id: AccountId,
-        offer_data: OfferData,
-        token_id: TokenId,
-    ) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            // None means a bad payout from bad NFT contract
-            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
-            if parsed_payout.is_err() {
-                near_sdk::serde_json::from_slice::<Payout>(&value)
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = offer_data.price;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout.payout)
-                        } else {
-                            None
-                        }
-                    })
-            } else {
-                parsed_payout.ok().and_then(|payout| {
-                    let mut remainder = offer_data.price;
-                    for &value in payout.values() {
-                        remainder = remainder.checked_sub(value.0)?;
-                    }
-                    if remainder <= 100 {
-                        Some(payout)
-                    } else {
-                        None
-                    }
-                })
-            }
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        referral_id: Option<AccountId>,
+        gas_for_resolve: Gas,
+    ) {
+        let json_token = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<JsonToken>(&value).ok());
+        let is_listed = json_token.map_or(false, |json_token| {
+            json_token.owner_id == market_data.owner_id
+                && is_trade_token_approved(&json_token, Some(market_data.approval_id))
         });
 
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            if !is_promise_success() {
-                if offer_data.ft_token_id == near_account() {
-                    Promise::new(offer_data.buyer_id.clone())
-                        .transfer(u128::from(offer_data.price));
-                    env::log_str(
-                        &json!({
-                            "type": "resolve_purchase_fail",
-                            "params": {
-                                "owner_id": seller_id,
-                                "nft_contract_id": offer_data.nft_contract_id,
-                                "token_id": token_id,
-                                "token_series_id": offer_data.token_series_id,
-                                "ft_token_id": offer_data.ft_token_id,
-                                "price": offer_data.price.to_string(),
-                                "buyer_id": offer_data.buyer_id,
-                                "is_offer": true,
-                            }
-                        })
-                        .to_string(),
-                    );
-                }
-            } else if offer_data.ft_token_id == near_account() {
-                let treasury_fee = offer_data.price as u128
-                    * self.calculate_current_transaction_fee()
-                    / 10_000u128;
-                Promise::new(seller_id.clone()).transfer(offer_data.price - treasury_fee);
-                if treasury_fee > 0 {
-                    Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
-                }
-
-                env::log_str(
-                    &json!({
-                        "type": "resolve_purchase",
-                        "params": {
-                            "owner_id": seller_id,
-                            "nft_contract_id": &offer_data.nft_contract_id,
-                            "token_id": &token_id,
-                            "token_series_id": offer_data.token_series_id,
-                            "ft_token_id": offer_data.ft_token_id,
-                            "price": offer_data.price.to_string(),
-                            "buyer_id": offer_data.buyer_id,
-                            "is_offer": true,
-                        }
-                    })
-                    .to_string(),
-                );
-            }
-            return offer_data.price.into();
-        };
-
-        // Payout (transfer to royalties and seller)
-        if offer_data.ft_token_id == near_account() {
-            // 5% fee for treasury
-            let treasury_fee =
-                offer_data.price as u128 * self.calculate_current_transaction_fee() / 10_000u128;
-
-            for (receiver_id, amount) in payout {
-                if receiver_id == seller_id {
-                    Promise::new(receiver_id).transfer(amount.0 - treasury_fee);
-                    if treasury_fee != 0 {
-                        Promise::new(self.treasury_id.clone()).transfer(treasury_fee);
-                    }
-                } else {
-                    Promise::new(receiver_id).transfer(amount.0);
-                }
-            }
-
+        // the stale listing is already removed, only the buyer is left to refund
+        if !is_listed {
+            self.internal_clear_settling(&market_data.nft_contract_id, &market_data.token_id);
+            self.internal_transfer(&market_data.ft_token_id, buyer_id.clone(), price.0);
             env::log_str(
                 &json!({
-                    "type": "resolve_purchase",
+                    "type": "resolve_purchase_fail",
                     "params": {
-                        "owner_id": seller_id,
-                        "nft_contract_id": &offer_data.nft_contract_id,
-                        "token_id": &token_id,
-                        "token_series_id": offer_data.token_series_id,
-                        "ft_token_id": offer_data.ft_token_id,
-                        "price": offer_data.price.to_string(),
-                        "buyer_id": offer_data.buyer_id,
-                        "is_offer": true,
+                        "owner_id": market_data.owner_id,
+                        "nft_contract_id": market_data.nft_contract_id,
+                        "token_id": market_data.token_id,
+                        "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                        "ft_token_id": market_data.ft_token_id,
+                        "price": price,
+                        "buyer_id": buyer_id,
+                        "reason": "stale listing",
                     }
                 })
                 .to_string(),
             );
+            return;
+        }
 
-            let seller_contract_account_id_token_id =
-                make_triple(&offer_data.nft_contract_id, &seller_id, &token_id);
-            self.trades.remove(&seller_contract_account_id_token_id);
+        ext_contract::nft_transfer_payout(
+            receiver_id,
+            market_data.token_id.clone(),
+            Some(market_data.approval_id),
+            Some(price),
+            Some(self.max_len_payout),
+            market_data.nft_contract_id.clone(),
+            1,
+            self.config.gas_for_nft_transfer,
+        )
+        .then(ext_self::resolve_purchase(
+            buyer_id,
+            market_data,
+            price,
+            referral_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            gas_for_resolve,
+        ));
+    }
 
-            return offer_data.price.into();
-        } else {
-            U128(0)
-        }
+    // 1. mint the next edition of the series straight to the receiver
+    // 2. ask the minted token for its payout, the receiver's owner share goes to the creator
+    // 3. pay creator, royalties and treasury, the listing stays until the creator removes it
+    fn internal_process_mint_sale(
+        &mut self,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        market_data: MarketData,
+        price: u128,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        ext_contract::nft_mint(
+            market_data.token_id.clone(),
+            receiver_id.clone(),
+            market_data.nft_contract_id.clone(),
+            STORAGE_MINT_NFT,
+            self.config.gas_for_nft_mint(),
+        )
+        .then(ext_self::callback_mint_sale(
+            buyer_id,
+            receiver_id,
+            market_data,
+            price.into(),
+            referral_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_mint_sale(),
+        ))
     }
 
-    // Trade
-    fn add_trade(
+    #[payable]
+    pub fn set_alternative_prices(
         &mut self,
         nft_contract_id: AccountId,
-        token_id: Option<TokenId>,
-        token_series_id: Option<TokenSeriesId>,
-        buyer_nft_contract_id: AccountId,
-        buyer_id: AccountId,
-        buyer_token_id: Option<TokenId>,
-        buyer_approval_id: u64,
+        token_id: TokenId,
+        alternative_prices: HashMap<AccountId, U128>,
     ) {
-        self.internal_add_trade(
-            nft_contract_id.clone().into(),
-            token_id.clone(),
-            token_series_id.clone(),
-            buyer_nft_contract_id.clone().into(),
-            buyer_token_id.clone(),
-            buyer_id.clone(),
-            buyer_approval_id.clone(),
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Market data does not exist");
+        assert_eq!(
+            market_data.owner_id,
+            env::predecessor_account_id(),
+            "Marble: Seller only"
+        );
+        self.internal_set_alternative_prices(&market_data, alternative_prices);
+    }
+
+    // e.g. 10 NEAR listed with 35 USDC accepted, an empty map removes them
+    pub(crate) fn internal_set_alternative_prices(
+        &mut self,
+        market_data: &MarketData,
+        alternative_prices: HashMap<AccountId, U128>,
+    ) {
+        let contract_and_token_id = format!(
+            "{}{}{}",
+            market_data.nft_contract_id, DELIMETER, market_data.token_id
+        );
+        if alternative_prices.is_empty() {
+            self.alternative_prices.remove(&contract_and_token_id);
+            return;
+        }
+
+        assert!(
+            alternative_prices.len() <= MAX_ALTERNATIVE_PRICES,
+            "Marble: Up to {} alternative prices",
+            MAX_ALTERNATIVE_PRICES
+        );
+        assert!(
+            market_data.is_auction != Some(true) && market_data.end_price.is_none(),
+            "Marble: Alternative prices are for fixed price sales only"
+        );
+        assert!(
+            !self.mint_sales.contains(&contract_and_token_id)
+                && self.listing_editions.get(&contract_and_token_id).is_none()
+                && self.usd_prices.get(&contract_and_token_id).is_none(),
+            "Marble: Alternative prices are for single token sales only"
         );
+        for (ft_token_id, price) in alternative_prices.iter() {
+            assert!(
+                self.approved_ft_token_ids.contains(ft_token_id),
+                "Marble: ft_token_id not approved"
+            );
+            assert_ne!(
+                ft_token_id, &market_data.ft_token_id,
+                "Marble: ft_token_id is already the listing currency"
+            );
+            let max_price = self.internal_max_price(ft_token_id);
+            assert!(
+                price.0 > 0 && price.0 < max_price,
+                "Marble: price higher than {}",
+                max_price
+            );
+            self.assert_min_listing_price(&market_data.nft_contract_id, ft_token_id, price.0);
+        }
+        self.alternative_prices
+            .insert(&contract_and_token_id, &alternative_prices);
 
         env::log_str(
             &json!({
-                "type": "add_trade",
+                "type": "set_alternative_prices",
                 "params": {
-                    "buyer_id": buyer_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "token_series_id": token_series_id,
-                    "buyer_nft_contract_id": buyer_nft_contract_id,
-                    "buyer_token_id": buyer_token_id,
-                    "buyer_approval_id": buyer_approval_id
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                    "alternative_prices": alternative_prices,
                 }
             })
             .to_string(),
         );
     }
 
-    fn internal_add_trade(
+    pub(crate) fn internal_add_usd_price(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        usd_price: U128,
+    ) {
+        assert!(
+            self.price_oracle.is_some(),
+            "Marble: Price oracle is not set"
+        );
+        assert!(usd_price.0 > 0, "Marble: usd_price must be positive");
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.usd_prices.insert(&contract_and_token_id, &usd_price.0);
+    }
+
+    #[private]
+    pub fn callback_mint_sale(
         &mut self,
-        nft_contract_id: AccountId,
-        token_id: Option<TokenId>,
-        token_series_id: Option<TokenSeriesId>,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: Option<TokenId>,
         buyer_id: AccountId,
-        buyer_approval_id: u64,
+        receiver_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        referral_id: Option<AccountId>,
     ) {
-        let token = if token_id.is_some() {
-            token_id.as_ref().unwrap().to_string()
-        } else {
-            assert!(
-                self.marble_nft_contracts.contains(&nft_contract_id),
-                "Marble: trade series for Marble NFT only"
-            );
-            token_series_id.as_ref().unwrap().to_string()
+        let token_id = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<TokenId>(&value).ok());
+        let token_id = match token_id {
+            Some(token_id) => token_id,
+            None => {
+                self.internal_transfer(&market_data.ft_token_id, buyer_id.clone(), price.0);
+                env::log_str(
+                    &json!({
+                        "type": "resolve_mint_sale_fail",
+                        "params": {
+                            "owner_id": market_data.owner_id,
+                            "nft_contract_id": market_data.nft_contract_id,
+                            "token_series_id": market_data.token_id,
+                            "ft_token_id": market_data.ft_token_id,
+                            "price": price,
+                            "buyer_id": buyer_id,
+                        }
+                    })
+                    .to_string(),
+                );
+                return;
+            }
         };
 
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
-        let buyer_contract_account_id_token_id = make_triple(
-            &buyer_nft_contract_id,
-            &buyer_id,
-            &buyer_token_id
-                .as_ref()
-                .expect("Marble: Buyer token id is not specified"),
-        );
-
-        let trade_data = TradeData {
-            buyer_amount: None,
-            seller_amount: None,
-            is_active: None,
-            ft_token_id: None,
-            nft_contract_id: nft_contract_id.into(),
-            token_id: token_id,
-            token_series_id: token_series_id,
-        };
-        let mut buyer_trade_list = self
-            .trades
-            .get(&buyer_contract_account_id_token_id)
-            .unwrap_or_else(|| {
-                TradeList {
-                    approval_id: 0, //init
-                    trade_data: HashMap::new(),
-                }
-            });
-        buyer_trade_list.approval_id = buyer_approval_id;
-        buyer_trade_list
-            .trade_data
-            .insert(contract_account_id_token_id.clone(), trade_data);
-
-        self.trades
-            .insert(&buyer_contract_account_id_token_id, &buyer_trade_list);
-
-        let mut token_ids = self.by_owner_id.get(&buyer_id).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&buyer_id),
-                }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
-
-        token_ids.insert(&make_key_owner_by_id_trade(contract_account_id_token_id));
-        self.by_owner_id.insert(&buyer_id, &token_ids);
+        ext_contract::nft_payout(
+            token_id.clone(),
+            U128(price.0 - STORAGE_MINT_NFT),
+            Some(self.max_len_payout),
+            market_data.nft_contract_id.clone(),
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::resolve_mint_sale(
+            buyer_id,
+            receiver_id,
+            market_data,
+            token_id,
+            price,
+            referral_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_royalties,
+        ));
     }
 
-    #[payable]
-    pub fn delete_trade(
+    #[private]
+    pub fn resolve_mint_sale(
         &mut self,
-        nft_contract_id: AccountId,
-        token_id: Option<TokenId>,
-        token_series_id: Option<TokenSeriesId>,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        market_data: MarketData,
+        token_id: TokenId,
+        price: U128,
+        referral_id: Option<AccountId>,
     ) {
-        assert_one_yocto();
-        let token = if token_id.is_some() {
-            token_id.as_ref().unwrap().to_string()
-        } else {
-            token_series_id.as_ref().unwrap().to_string()
+        let proceeds = price.0 - STORAGE_MINT_NFT;
+        let creator_id = market_data.owner_id.clone();
+
+        // the token is already minted, an unreadable payout pays the creator in full
+        let mut payouts: HashMap<AccountId, Balance> = HashMap::new();
+        match promise_result_as_success().and_then(|value| parse_payout(&value, proceeds)) {
+            Some(payout) => {
+                for (payout_id, amount) in payout {
+                    let payout_id = if payout_id == receiver_id {
+                        creator_id.clone()
+                    } else {
+                        payout_id
+                    };
+                    *payouts.entry(payout_id).or_insert(0) += amount.0;
+                }
+            }
+            None => {
+                payouts.insert(creator_id.clone(), proceeds);
+            }
         };
 
-        let buyer_id = env::predecessor_account_id();
-        let buyer_contract_account_id_token_id =
-            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
-
-        let trade_list = self
-            .trades
-            .get(&buyer_contract_account_id_token_id)
-            .expect("Marble: Trade list does not exist");
-
-        let trade_data = trade_list
-            .trade_data
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Trade data does not exist");
+        let creator_amount = payouts.entry(creator_id.clone()).or_insert(0);
+        let treasury_fee = std::cmp::min(
+            price.0
+                * self.calculate_market_data_transaction_fee(
+                    &market_data.nft_contract_id,
+                    &market_data.token_id,
+                    &market_data.owner_id,
+                    price.0,
+                )
+                / 10_000u128,
+            *creator_amount,
+        );
+        *creator_amount -= treasury_fee;
 
-        if token_id.is_some() {
-            assert_eq!(trade_data.clone().token_id.unwrap(), token)
-        } else {
-            assert_eq!(trade_data.clone().token_series_id.unwrap(), token)
+        for (receiver_id, amount) in payouts {
+            if amount > 0 {
+                self.internal_transfer_payout(&market_data.ft_token_id, receiver_id, amount);
+            }
         }
+        self.internal_record_collection_fee(
+            &market_data.nft_contract_id,
+            &market_data.ft_token_id,
+            treasury_fee,
+        );
+        self.internal_transfer_treasury_fee(&market_data.ft_token_id, treasury_fee, referral_id);
 
-        self.internal_delete_trade(
-            nft_contract_id.clone().into(),
-            buyer_id.clone(),
-            token.clone(),
-            buyer_nft_contract_id.clone(),
-            buyer_token_id.clone(),
-        )
-        .expect("Marble: Trade not found");
-
+        let sale_id = self.internal_next_sale_id();
         env::log_str(
             &json!({
-                "type": "delete_trade",
+                "type": "resolve_mint_sale",
                 "params": {
-                    "nft_contract_id": nft_contract_id,
-                    "buyer_id": buyer_id,
+                    "owner_id": creator_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_series_id": market_data.token_id,
+                    "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                    "sale_id": sale_id,
                     "token_id": token_id,
-                    "token_series_id": token_series_id,
-                    "buyer_nft_contract_id": buyer_nft_contract_id,
-                    "buyer_token_id": buyer_token_id
+                    "ft_token_id": market_data.ft_token_id,
+                    "price": price,
+                    "buyer_id": buyer_id,
+                    "receiver_id": receiver_id,
                 }
             })
             .to_string(),
         );
     }
 
-    fn internal_delete_trade(
+    fn internal_add_mint_sale(
         &mut self,
+        owner_id: AccountId,
         nft_contract_id: AccountId,
-        buyer_id: AccountId,
-        token_id: TokenId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-    ) -> Option<TradeData> {
-        let buyer_contract_account_id_token_id =
-            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        token_series_id: TokenSeriesId,
+        price: U128,
+        started_at: Option<U64>,
+        ended_at: Option<U64>,
+    ) {
+        assert!(
+            price.0 > STORAGE_MINT_NFT,
+            "Marble: Price does not cover the mint storage of {}",
+            STORAGE_MINT_NFT
+        );
+        self.internal_delete_market_data(&nft_contract_id, &token_series_id);
 
-        let mut trade_list = self
-            .trades
-            .get(&buyer_contract_account_id_token_id)
-            .expect("Marble: Trade list does not exist");
+        // the series has no token yet, the listing approval is never used
+        self.internal_add_market_data(
+            owner_id,
+            0,
+            nft_contract_id.clone(),
+            token_series_id.clone(),
+            near_account(),
+            price,
+            started_at,
+            ended_at,
+            None,
+            None,
+            None,
+        );
+        let contract_and_series_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_series_id);
+        self.mint_sales.insert(&contract_and_series_id);
+    }
 
-        let trade_data = trade_list.trade_data.remove(&contract_account_id_token_id);
+    // sells the first edition, the series listing is only removed with its last edition
+    fn internal_take_listing_edition(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_series_id: &TokenSeriesId,
+        mut editions: Vec<ListingEdition>,
+    ) -> MarketData {
+        let contract_and_series_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_series_id);
+        let edition = editions.remove(0);
+        let transaction_fee = self
+            .market_data_transaction_fee
+            .transaction_fee
+            .get(&contract_and_series_id)
+            .unwrap_or_else(|| self.calculate_current_transaction_fee());
+
+        let mut market_data = if editions.is_empty() {
+            self.market_data_transaction_fee
+                .transaction_fee
+                .remove(&contract_and_series_id);
+            self.internal_delete_market_data(nft_contract_id, token_series_id)
+        } else {
+            self.listing_editions
+                .insert(&contract_and_series_id, &editions);
+            self.internal_get_market_data(&contract_and_series_id)
+        }
+        .expect("Marble: Sale does not exist");
 
-        self.trades
-            .insert(&buyer_contract_account_id_token_id, &trade_list);
+        // resolve_purchase settles the edition at the fee locked on the series
+        let contract_and_edition_id =
+            format!("{}{}{}", nft_contract_id, DELIMETER, edition.token_id);
+        self.market_data_transaction_fee
+            .transaction_fee
+            .insert(&contract_and_edition_id, &transaction_fee);
 
-        match trade_data {
-            Some(trade) => {
-                let mut by_owner_id = self
-                    .by_owner_id
-                    .get(&buyer_id)
-                    .expect("Marble: no market data by account_id");
-                by_owner_id.remove(&make_key_owner_by_id_trade(contract_account_id_token_id));
-                if by_owner_id.is_empty() {
-                    self.by_owner_id.remove(&buyer_id);
-                } else {
-                    self.by_owner_id.insert(&buyer_id, &by_owner_id);
-                }
-                return Some(trade);
+        market_data.token_id = edition.token_id;
+        market_data.approval_id = edition.approval_id;
+        market_data
+    }
+
+    fn internal_add_listing_edition(
+        &mut self,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        started_at: Option<U64>,
+        ended_at: Option<U64>,
+        end_price: Option<U128>,
+    ) {
+        // Token delimiter : is specific for Marble NFT
+        let token_series_id: TokenSeriesId = token_id.split(":").next().unwrap().to_string();
+        let contract_and_series_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_series_id);
+
+        let mut editions = match self.market.get(&contract_and_series_id) {
+            // later editions join the listing at its current terms
+            Some(market_data) => {
+                assert_eq!(
+                    market_data.owner_id, owner_id,
+                    "Marble: Series is listed by another owner"
+                );
+                assert_eq!(
+                    market_data.ft_token_id, ft_token_id,
+                    "Marble: ft_token_id differs"
+                );
+                self.listing_editions
+                    .get(&contract_and_series_id)
+                    .expect("Marble: Series listing has no editions")
             }
             None => {
-                self.trades
-                    .remove(&buyer_contract_account_id_token_id)
-                    .expect("Marble: Error delete trade list");
-                return None;
+                self.internal_add_market_data(
+                    owner_id.clone(),
+                    approval_id,
+                    nft_contract_id.clone(),
+                    token_series_id.clone(),
+                    ft_token_id,
+                    price,
+                    started_at,
+                    ended_at,
+                    end_price,
+                    None,
+                    None,
+                );
+                vec![]
             }
         };
-    }
-
-    pub fn get_trade(
-        &self,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: Option<TokenId>,
-        seller_token_series_id: Option<String>,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-    ) -> TradeData {
-        let token = if seller_token_id.is_some() {
-            seller_token_id.as_ref().unwrap()
-        } else {
-            seller_token_series_id.as_ref().unwrap()
-        };
-
-        let contract_account_id_token_id = make_triple(&seller_nft_contract_id, &buyer_id, &token);
-        let buyer_contract_account_id_token_id =
-            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
 
-        let trade_list = self
-            .trades
-            .get(&buyer_contract_account_id_token_id)
-            .expect("Marble: Trade list does not exist");
-
-        let trade_data = trade_list
-            .trade_data
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Trade data does not exist");
-
-        if seller_token_id.is_some() {
-            assert_eq!(trade_data.token_id.as_ref().unwrap(), token);
+        if let Some(edition) = editions
+            .iter_mut()
+            .find(|edition| edition.token_id == token_id)
+        {
+            edition.approval_id = approval_id;
         } else {
-            assert_eq!(trade_data.token_series_id.as_ref().unwrap(), token);
+            assert!(
+                editions.len() < MAX_LISTING_EDITIONS,
+                "Marble: Series listing is limited to {} editions",
+                MAX_LISTING_EDITIONS
+            );
+            editions.push(ListingEdition {
+                token_id: token_id.clone(),
+                approval_id,
+            });
         }
+        self.listing_editions
+            .insert(&contract_and_series_id, &editions);
 
-        return trade_data.clone();
+        env::log_str(
+            &json!({
+                "type": "add_listing_edition",
+                "params": {
+                    "owner_id": owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_series_id": token_series_id,
+                    "token_id": token_id,
+                    "approval_id": approval_id,
+                    "quantity": U64(editions.len() as u64),
+                }
+            })
+            .to_string(),
+        );
     }
 
-    fn internal_accept_trade(
+    #[private]
+    pub fn resolve_purchase(
         &mut self,
-        nft_contract_id: AccountId,
         buyer_id: AccountId,
-        token_id: TokenId,
-        seller_id: AccountId,
-        approval_id: u64,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-    ) -> Promise {
-        let buyer_contract_account_id_token_id =
-            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
-        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
-
-        let trade_list = self
-            .trades
+        market_data: MarketData,
+        price: U128,
+        referral_id: Option<AccountId>,
+    ) -> U128 {
+        env::log_str("Resolve Purchase");
+        self.internal_clear_settling(&market_data.nft_contract_id, &market_data.token_id);
+        let payout_option = promise_result_as_success().and_then(|value| {
+            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
+            if parsed_payout.is_err() {
+                near_sdk::serde_json::from_slice::<Payout>(&value)
+                    .ok()
+                    .and_then(|payout| {
+                        let mut remainder = price.0;
+                        for &value in payout.payout.values() {
+                            remainder = remainder.checked_sub(value.0)?;
+                        }
+                        if remainder <= 100 {
+                            Some(payout.payout)
+                        } else {
+                            None
+                        }
+                    })
+            } else {
+                parsed_payout.ok().and_then(|payout| {
+                    let mut remainder = price.0;
+                    for &value in payout.values() {
+                        remainder = remainder.checked_sub(value.0)?;
+                    }
+                    if remainder <= 100 {
+                        Some(payout)
+                    } else {
+                        None
+                    }
+                })
+            }
+        });
+        let payout_option = self.internal_filter_payout(
+            payout_option,
+            &market_data.nft_contract_id,
+            &market_data.token_id,
+        );
+        let payout = if let Some(payout_option) = payout_option {
+            payout_option
+        } else {
+            // leave function and return all FTs in ft_resolve_transfer
+            if !is_promise_success() {
+                if market_data.ft_token_id == near_account() {
+                    Promise::new(buyer_id.clone()).transfer(u128::from(price.0));
+                } else {
+                    self.internal_ft_transfer(
+                        &market_data.ft_token_id,
+                        buyer_id.clone(),
+                        (price.0).into(),
+                    )
+                    .then(ext_self::callback_post_withdraw_deposit(
+                        market_data.ft_token_id.clone(),
+                        buyer_id.clone(),
+                        price.0.into(),
+                        env::current_account_id(),
+                        0,
+                        self.config.gas_for_ft_transfer,
+                    ));
+                }
+                env::log_str(
+                    &json!({
+                        "type": "resolve_purchase_fail",
+                        "params": {
+                            "owner_id": market_data.owner_id,
+                            "nft_contract_id": market_data.nft_contract_id,
+                            "token_id": market_data.token_id,
+                            "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                            "ft_token_id": market_data.ft_token_id,
+                            "price": price,
+                            "buyer_id": buyer_id,
+                        }
+                    })
+                    .to_string(),
+                );
+            } else {
+                let treasury_fee = price.0
+                    * self.calculate_market_data_transaction_fee(
+                        &market_data.nft_contract_id,
+                        &market_data.token_id,
+                        &market_data.owner_id,
+                        price.0,
+                    )
+                    / 10_000u128;
+                let treasury_fee = self.internal_discount_treasury_fee(
+                    treasury_fee,
+                    &buyer_id,
+                    &market_data.owner_id,
+                );
+                let contract_and_token_id = format!(
+                    "{}{}{}",
+                    &market_data.nft_contract_id, DELIMETER, &market_data.token_id
+                );
+                self.market_data_transaction_fee
+                    .transaction_fee
+                    .remove(&contract_and_token_id);
+                self.listing_tombstones.remove(&contract_and_token_id);
+
+                self.internal_transfer_seller_proceeds(
+                    &market_data.ft_token_id,
+                    market_data.owner_id.clone(),
+                    price.0 - treasury_fee,
+                );
+                self.internal_record_collection_fee(
+                    &market_data.nft_contract_id,
+                    &market_data.ft_token_id,
+                    treasury_fee,
+                );
+                self.internal_transfer_treasury_fee(
+                    &market_data.ft_token_id,
+                    treasury_fee,
+                    referral_id,
+                );
+
+                let sale_id = self.internal_next_sale_id();
+                env::log_str(
+                    &json!({
+                        "type": "resolve_purchase",
+                        "params": {
+                            "owner_id": &market_data.owner_id,
+                            "nft_contract_id": &market_data.nft_contract_id,
+                            "token_id": &market_data.token_id,
+                            "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                            "sale_id": sale_id,
+                            "ft_token_id": market_data.ft_token_id,
+                            "price": price,
+                            "buyer_id": buyer_id,
+                        }
+                    })
+                    .to_string(),
+                );
+
+                self.internal_refund_offers_on_purchase(
+                    &market_data.nft_contract_id,
+                    &buyer_id,
+                    &market_data.token_id,
+                );
+            }
+            return price;
+        };
+
+        // 5% fee for treasury, less the staker discount
+        let treasury_fee = price.0
+            * self.calculate_market_data_transaction_fee(
+                &market_data.nft_contract_id,
+                &market_data.token_id,
+                &market_data.owner_id,
+                price.0,
+            )
+            / 10_000u128;
+        let treasury_fee =
+            self.internal_discount_treasury_fee(treasury_fee, &buyer_id, &market_data.owner_id);
+        let contract_and_token_id = format!(
+            "{}{}{}",
+            &market_data.nft_contract_id, DELIMETER, &market_data.token_id
+        );
+        self.market_data_transaction_fee
+            .transaction_fee
+            .remove(&contract_and_token_id);
+        self.listing_tombstones.remove(&contract_and_token_id);
+
+        // Payout (transfer to royalties and seller)
+        let fee_shares = payout_fee_shares(&payout, &market_data.owner_id, treasury_fee);
+        let treasury_fee: Balance = fee_shares.values().sum();
+        for (receiver_id, amount) in payout {
+            let amount = amount.0 - fee_shares.get(&receiver_id).copied().unwrap_or(0);
+            if amount == 0 {
+                continue;
+            }
+            if receiver_id == market_data.owner_id {
+                self.internal_transfer_seller_proceeds(
+                    &market_data.ft_token_id,
+                    receiver_id,
+                    amount,
+                );
+            } else {
+                self.internal_transfer_payout(&market_data.ft_token_id, receiver_id, amount);
+            }
+        }
+        self.internal_record_collection_fee(
+            &market_data.nft_contract_id,
+            &market_data.ft_token_id,
+            treasury_fee,
+        );
+        self.internal_transfer_treasury_fee(
+            &market_data.ft_token_id,
+            treasury_fee,
+            referral_id.clone(),
+        );
+
+        let sale_id = self.internal_next_sale_id();
+        env::log_str(
+            &json!({
+                "type": "resolve_purchase",
+                "params": {
+                    "owner_id": &market_data.owner_id,
+                    "nft_contract_id": &market_data.nft_contract_id,
+                    "token_id": &market_data.token_id,
+                    "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                    "sale_id": sale_id,
+                    "ft_token_id": market_data.ft_token_id,
+                    "price": price,
+                    "buyer_id": buyer_id,
+                }
+            })
+            .to_string(),
+        );
+
+        let seller_contract_account_id_token_id = make_triple(
+            &market_data.nft_contract_id,
+            &market_data.owner_id,
+            &market_data.token_id,
+        );
+        self.trades.remove(&seller_contract_account_id_token_id);
+        self.internal_record_change(ChangeType::Trade, &seller_contract_account_id_token_id);
+
+        self.internal_refund_offers_on_purchase(
+            &market_data.nft_contract_id,
+            &buyer_id,
+            &market_data.token_id,
+        );
+
+        return price;
+    }
+
+    // splits the referrer's share off the treasury fee
+    fn internal_transfer_treasury_fee(
+        &mut self,
+        ft_token_id: &AccountId,
+        treasury_fee: u128,
+        referral_id: Option<AccountId>,
+    ) {
+        if treasury_fee == 0 {
+            return;
+        }
+        let ft_fee_stats = self.ft_fee_stats.get(ft_token_id).unwrap_or(0);
+        self.ft_fee_stats
+            .insert(ft_token_id, &(ft_fee_stats + treasury_fee));
+
+        let mut referral_fee = 0;
+        if let Some(referral_id) = referral_id {
+            // registered front-ends accrue their share and claim it in bulk
+            if let Some(affiliate_fee) = self.internal_affiliate_fee(&referral_id, treasury_fee) {
+                referral_fee = affiliate_fee;
+                if affiliate_fee > 0 {
+                    self.internal_accrue_affiliate_earnings(
+                        &referral_id,
+                        ft_token_id,
+                        affiliate_fee,
+                    );
+
+                    env::log_str(
+                        &json!({
+                            "type": "affiliate_fee",
+                            "params": {
+                                "affiliate_id": referral_id,
+                                "ft_token_id": ft_token_id,
+                                "amount": U128(affiliate_fee),
+                            }
+                        })
+                        .to_string(),
+                    );
+                }
+            } else {
+                referral_fee = treasury_fee * self.referral_fee as u128 / 10_000u128;
+                if referral_fee > 0 {
+                    self.internal_transfer_payout(ft_token_id, referral_id.clone(), referral_fee);
+
+                    env::log_str(
+                        &json!({
+                            "type": "referral_fee",
+                            "params": {
+                                "referral_id": referral_id,
+                                "ft_token_id": ft_token_id,
+                                "amount": U128(referral_fee),
+                            }
+                        })
+                        .to_string(),
+                    );
+                }
+            }
+        }
+
+        let treasury_fee = treasury_fee.saturating_sub(referral_fee);
+        let burn_fee = treasury_fee * self.burn_bps as u128 / 10_000u128;
+        if burn_fee > 0 {
+            self.internal_burn_fee(ft_token_id, burn_fee);
+
+            env::log_str(
+                &json!({
+                    "type": "burn_fee",
+                    "params": {
+                        "ft_token_id": ft_token_id,
+                        "amount": U128(burn_fee),
+                        "treasury_fee": U128(treasury_fee - burn_fee),
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        // accrued rather than sent, claim_treasury_fees pays out in bulk
+        if treasury_fee > burn_fee {
+            self.internal_accrue_treasury_fee(ft_token_id, treasury_fee - burn_fee);
+        }
+    }
+
+    fn internal_burn_fee(&self, ft_token_id: &AccountId, amount: u128) {
+        if *ft_token_id == near_account() {
+            let burn_account_id = self
+                .burn_account_id
+                .clone()
+                .expect("Marble: burn_account_id is not set");
+            Promise::new(burn_account_id).transfer(amount);
+        } else {
+            ext_fungible_token::ft_burn(
+                amount.into(),
+                None,
+                ft_token_id.clone(),
+                1,
+                self.config.gas_for_ft_transfer,
+            )
+            .then(ext_self::callback_burn_fee(
+                ft_token_id.clone(),
+                amount.into(),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.config.base_gas,
+            ));
+        }
+    }
+
+    // the token has no ft_burn, its share stays with the treasury
+    #[private]
+    pub fn callback_burn_fee(&mut self, ft_token_id: AccountId, amount: U128) {
+        if is_promise_success() {
+            return;
+        }
+        self.internal_accrue_treasury_fee(&ft_token_id, amount.0);
+
+        env::log_str(
+            &json!({
+                "type": "burn_fee_fail",
+                "params": {
+                    "ft_token_id": ft_token_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_accrue_treasury_fee(&mut self, ft_token_id: &AccountId, amount: u128) {
+        let treasury_fees = self.treasury_fees.get(ft_token_id).unwrap_or(0);
+        self.treasury_fees
+            .insert(ft_token_id, &(treasury_fees + amount));
+    }
+
+    fn internal_record_collection_fee(
+        &mut self,
+        nft_contract_id: &AccountId,
+        ft_token_id: &AccountId,
+        amount: u128,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let contract_and_ft_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, ft_token_id);
+        let collection_fee_stats = self
+            .collection_fee_stats
+            .get(&contract_and_ft_token_id)
+            .unwrap_or(0);
+        self.collection_fee_stats
+            .insert(&contract_and_ft_token_id, &(collection_fee_stats + amount));
+    }
+
+    // collections are paged, every token is listed
+    pub fn get_fee_stats(&self, from_index: Option<U64>, limit: Option<u64>) -> FeeStats {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.collection_fee_stats.len());
+
+        let mut nft_contract_ids: HashMap<AccountId, HashMap<AccountId, U128>> = HashMap::new();
+        for (contract_and_ft_token_id, amount) in self
+            .collection_fee_stats
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+        {
+            let mut split = contract_and_ft_token_id.split(DELIMETER);
+            let nft_contract_id: AccountId = split.next().unwrap().parse().unwrap();
+            let ft_token_id: AccountId = split.next().unwrap().parse().unwrap();
+            nft_contract_ids
+                .entry(nft_contract_id)
+                .or_default()
+                .insert(ft_token_id, U128(amount));
+        }
+
+        FeeStats {
+            ft_token_ids: self
+                .ft_fee_stats
+                .iter()
+                .map(|(ft_token_id, amount)| (ft_token_id, U128(amount)))
+                .collect(),
+            nft_contract_ids,
+        }
+    }
+
+    pub fn get_treasury_fees(&self, ft_token_id: Option<AccountId>) -> U128 {
+        self.treasury_fees
+            .get(&ft_token_id.unwrap_or(near_account()))
+            .unwrap_or(0)
+            .into()
+    }
+
+    // anyone can sweep, the fees only ever go to the treasury recipients
+    pub fn claim_treasury_fees(&mut self, ft_token_id: Option<AccountId>) -> U128 {
+        let ft_token_id = ft_token_id.unwrap_or(near_account());
+        let amount = self.treasury_fees.remove(&ft_token_id).unwrap_or(0);
+        assert!(amount > 0, "Marble: Nothing to claim");
+
+        self.internal_transfer_treasury_shares(&ft_token_id, amount);
+
+        env::log_str(
+            &json!({
+                "type": "claim_treasury_fees",
+                "params": {
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                }
+            })
+            .to_string(),
+        );
+        amount.into()
+    }
+
+    // the last recipient takes the rounding dust
+    fn internal_transfer_treasury_shares(&self, ft_token_id: &AccountId, amount: u128) {
+        let treasury_recipients = self.get_treasury_recipients();
+        let mut remainder = amount;
+        for (index, treasury_recipient) in treasury_recipients.iter().enumerate() {
+            let share = if index + 1 == treasury_recipients.len() {
+                remainder
+            } else {
+                amount * treasury_recipient.share_bps as u128 / 10_000u128
+            };
+            remainder -= share;
+            if share == 0 {
+                continue;
+            }
+            if *ft_token_id == near_account() {
+                Promise::new(treasury_recipient.account_id.clone()).transfer(share);
+            } else {
+                self.internal_ft_transfer(
+                    ft_token_id,
+                    treasury_recipient.account_id.clone(),
+                    share.into(),
+                )
+                .then(ext_self::callback_claim_treasury_fees(
+                    ft_token_id.clone(),
+                    share.into(),
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    self.config.base_gas,
+                ));
+            }
+        }
+    }
+
+    // a failed ft_transfer goes back to the accrued fees
+    #[private]
+    pub fn callback_claim_treasury_fees(&mut self, ft_token_id: AccountId, amount: U128) {
+        if !is_promise_success() {
+            self.internal_accrue_treasury_fee(&ft_token_id, amount.0);
+        }
+    }
+
+    fn internal_transfer(&self, ft_token_id: &AccountId, receiver_id: AccountId, amount: u128) {
+        if *ft_token_id == near_account() {
+            Promise::new(receiver_id).transfer(amount);
+        } else {
+            self.internal_ft_transfer(ft_token_id, receiver_id.clone(), amount.into())
+                .then(ext_self::callback_post_withdraw_deposit(
+                    ft_token_id.clone(),
+                    receiver_id,
+                    amount.into(),
+                    env::current_account_id(),
+                    0,
+                    self.config.gas_for_ft_transfer,
+                ));
+        }
+    }
+
+    // Offer
+
+    fn internal_refund_offers_on_purchase(
+        &mut self,
+        nft_contract_id: &AccountId,
+        buyer_id: &AccountId,
+        token_id: &TokenId,
+    ) {
+        let mut tokens = vec![token_id.clone()];
+        if self.marble_nft_contracts.contains(nft_contract_id) {
+            // Token delimiter : is specific for Marble NFT
+            let token_series_id: String = token_id.split(":").next().unwrap().to_string();
+            let contract_account_id_token_series_id =
+                make_triple(nft_contract_id, buyer_id, &token_series_id);
+            // quantity offers are kept, the buyer still wants more editions
+            if let Some(offer_data) = self.offers.get(&contract_account_id_token_series_id) {
+                if offer_data.quantity.is_none() {
+                    tokens.push(token_series_id);
+                }
+            }
+        }
+
+        for token in tokens {
+            if let Some(offer_data) =
+                self.internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token)
+            {
+                self.internal_transfer(
+                    &offer_data.ft_token_id,
+                    offer_data.buyer_id.clone(),
+                    offer_escrow(&offer_data),
+                );
+
+                env::log_str(
+                    &json!({
+                        "type": "delete_offer",
+                        "params": {
+                            "nft_contract_id": nft_contract_id,
+                            "buyer_id": buyer_id,
+                            "token_id": offer_data.token_id,
+                            "token_series_id": offer_data.token_series_id,
+                            "reason": "purchased",
+                        }
+                    })
+                    .to_string(),
+                );
+            }
+        }
+    }
+
+    fn internal_add_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenId>,
+        ft_token_id: AccountId,
+        price: U128,
+        buyer_id: AccountId,
+        seller_id: Option<AccountId>,
+        quantity: Option<u64>,
+        referral_id: Option<AccountId>,
+    ) {
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            token_series_id.as_ref().unwrap().to_string()
+        };
+
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+        self.assert_below_active_limit(&buyer_id, &contract_account_id_token_id);
+        let offer_count = self
+            .offers_by_token
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token))
+            .filter(|buyer_ids| !buyer_ids.contains(&buyer_id))
+            .map_or(0, |buyer_ids| buyer_ids.len());
+        assert!(
+            offer_count < MAX_OFFERS_PER_TOKEN,
+            "Marble: Up to {} offers per token",
+            MAX_OFFERS_PER_TOKEN
+        );
+        self.offers.insert(
+            &contract_account_id_token_id,
+            &OfferData {
+                buyer_id: buyer_id.clone().into(),
+                nft_contract_id: nft_contract_id.clone().into(),
+                token_id: token_id,
+                token_series_id: token_series_id,
+                ft_token_id: ft_token_id.into(),
+                price: price.into(),
+                counter: None,
+                seller_id: seller_id,
+                quantity: quantity,
+                referral_id: referral_id,
+            },
+        );
+        self.internal_record_change(ChangeType::Offer, &contract_account_id_token_id);
+        self.internal_add_offer_index(&nft_contract_id, &token, &buyer_id);
+        let transaction_fee = self.calculate_current_transaction_fee() as u16;
+        self.offer_transaction_fees
+            .insert(&contract_account_id_token_id, &transaction_fee);
+
+        let mut token_ids = self.by_owner_id.get(&buyer_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByOwnerIdInner {
+                    account_id_hash: hash_account_id(&buyer_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+        token_ids.insert(&contract_account_id_token_id);
+        self.by_owner_id.insert(&buyer_id, &token_ids);
+    }
+
+    #[payable]
+    pub fn add_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<String>,
+        ft_token_id: AccountId,
+        price: U128,
+        seller_id: Option<AccountId>,
+        quantity: Option<u64>,
+        referral_id: Option<AccountId>,
+    ) {
+        self.assert_not_paused(PauseFeature::Offers);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        assert_eq!(
+            env::attached_deposit(),
+            price.0 * quantity.unwrap_or(1) as u128,
+            "Marble: Attached deposit != price * quantity"
+        );
+
+        // FT offers are escrowed through ft_transfer_call
+        assert_eq!(
+            ft_token_id.to_string(),
+            "near",
+            "Marble: Only NEAR is supported"
+        );
+
+        self.internal_place_offer(
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            ft_token_id,
+            price,
+            env::predecessor_account_id(),
+            seller_id,
+            quantity,
+            referral_id,
+        );
+    }
+
+    // the escrow for price * quantity is already held in ft_token_id
+    pub(crate) fn internal_place_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<String>,
+        ft_token_id: AccountId,
+        price: U128,
+        buyer_id: AccountId,
+        seller_id: Option<AccountId>,
+        quantity: Option<u64>,
+        referral_id: Option<AccountId>,
+    ) {
+        let token = if token_id.is_some() {
+            assert!(
+                quantity.is_none(),
+                "Marble: quantity offer for token series only"
+            );
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            assert!(
+                self.marble_nft_contracts.contains(&nft_contract_id),
+                "Marble: offer series for Marble NFT only"
+            );
+            token_series_id
+                .as_ref()
+                .expect("Marble: token_id or token_series_id is required")
+                .to_string()
+        };
+
+        let quantity = quantity.filter(|quantity| *quantity != 1);
+        if let Some(quantity) = quantity {
+            assert!(quantity > 1, "Marble: quantity must be positive");
+        }
+
+        // the minimum offer price is denominated in NEAR
+        if ft_token_id == near_account() {
+            self.assert_min_offer_price(&nft_contract_id, price.0);
+        }
+
+        assert_ne!(
+            referral_id.as_ref(),
+            Some(&buyer_id),
+            "Marble: Cannot refer yourself"
+        );
+        let offer_data = self.internal_delete_offer(
+            nft_contract_id.clone().into(),
+            buyer_id.clone(),
+            token.clone(),
+        );
+
+        if let Some(offer_data) = offer_data {
+            self.internal_transfer(
+                &offer_data.ft_token_id,
+                buyer_id.clone(),
+                offer_escrow(&offer_data),
+            );
+        }
+
+        let storage_amount = self.storage_minimum_balance().0;
+        let owner_paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
+        let signer_storage_required =
+            (self.get_supply_by_owner_id(buyer_id.clone()).0 + 1) as u128 * storage_amount;
+
+        assert!(
+            owner_paid_storage >= signer_storage_required,
+            "Insufficient storage paid: {}, for {} offer at {} rate of per offer",
+            owner_paid_storage,
+            signer_storage_required / storage_amount,
+            storage_amount,
+        );
+
+        self.internal_add_offer(
+            nft_contract_id.clone().into(),
+            token_id.clone(),
+            token_series_id.clone(),
+            ft_token_id.clone(),
+            price,
+            buyer_id.clone(),
+            seller_id.clone(),
+            quantity,
+            referral_id.clone(),
+        );
+
+        env::log_str(
+            &json!({
+                "type": "add_offer",
+                "params": {
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "token_series_id": token_series_id,
+                    "ft_token_id": ft_token_id,
+                    "price": price,
+                    "seller_id": seller_id,
+                    "quantity": quantity,
+                    "referral_id": referral_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn add_offers_batch(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_ids: Vec<TokenId>,
+        price_each: U128,
+    ) {
+        self.assert_not_paused(PauseFeature::Offers);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        assert!(!token_ids.is_empty(), "Marble: Token ids are empty");
+        assert!(
+            token_ids.len() <= MAX_ADD_OFFERS_BATCH,
+            "Marble: Cannot add more than {} offers at once",
+            MAX_ADD_OFFERS_BATCH
+        );
+        assert_eq!(
+            token_ids.iter().collect::<HashSet<_>>().len(),
+            token_ids.len(),
+            "Marble: Duplicate token ids"
+        );
+        assert_eq!(
+            env::attached_deposit(),
+            price_each.0 * token_ids.len() as u128,
+            "Marble: Attached deposit != price_each * token_ids length"
+        );
+        let max_price = self.internal_max_price(&near_account());
+        assert!(
+            price_each.0 < max_price,
+            "Marble: price higher than {}",
+            max_price
+        );
+        self.assert_min_offer_price(&nft_contract_id, price_each.0);
+
+        let buyer_id = env::predecessor_account_id();
+
+        // replaced NEAR offers are refunded in one transfer
+        let mut refund: Balance = 0;
+        for token_id in &token_ids {
+            if let Some(offer_data) = self.internal_delete_offer(
+                nft_contract_id.clone(),
+                buyer_id.clone(),
+                token_id.clone(),
+            ) {
+                if offer_data.ft_token_id == near_account() {
+                    refund += offer_escrow(&offer_data);
+                } else {
+                    self.internal_transfer(
+                        &offer_data.ft_token_id,
+                        buyer_id.clone(),
+                        offer_escrow(&offer_data),
+                    );
+                }
+            }
+        }
+        if refund > 0 {
+            Promise::new(buyer_id.clone()).transfer(refund);
+        }
+
+        let storage_amount = self.storage_minimum_balance().0;
+        let owner_paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
+        let signer_storage_required = (self.get_supply_by_owner_id(buyer_id.clone()).0
+            + token_ids.len() as u64) as u128
+            * storage_amount;
+
+        assert!(
+            owner_paid_storage >= signer_storage_required,
+            "Insufficient storage paid: {}, for {} offer at {} rate of per offer",
+            owner_paid_storage,
+            signer_storage_required / storage_amount,
+            storage_amount,
+        );
+
+        for token_id in token_ids {
+            self.internal_add_offer(
+                nft_contract_id.clone(),
+                Some(token_id.clone()),
+                None,
+                near_account(),
+                price_each,
+                buyer_id.clone(),
+                None,
+                None,
+                None,
+            );
+
+            env::log_str(
+                &json!({
+                    "type": "add_offer",
+                    "params": {
+                        "buyer_id": buyer_id,
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "token_series_id": None::<TokenSeriesId>,
+                        "ft_token_id": near_account(),
+                        "price": price_each,
+                        "seller_id": None::<AccountId>,
+                        "quantity": None::<u64>,
+                    }
+                })
+                .to_string(),
+            );
+        }
+    }
+
+    #[payable]
+    pub fn update_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<String>,
+        price: U128,
+    ) {
+        self.assert_not_paused(PauseFeature::Offers);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            token_series_id.as_ref().unwrap().to_string()
+        };
+
+        let buyer_id = env::predecessor_account_id();
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+        self.assert_offer_price_update(&nft_contract_id, &offer_data, price.0);
+
+        let quantity = offer_data.quantity.unwrap_or(1) as u128;
+        let refund = if price.0 > offer_data.price {
+            // an FT offer is raised by transferring the difference with the update_offer method
+            assert_eq!(
+                offer_data.ft_token_id,
+                near_account(),
+                "Marble: Raise an FT offer through ft_transfer_call"
+            );
+            assert_eq!(
+                env::attached_deposit(),
+                (price.0 - offer_data.price) * quantity,
+                "Marble: Attached deposit != (price - offer price) * quantity"
+            );
+            0
+        } else {
+            assert_one_yocto();
+            let refund = (offer_data.price - price.0) * quantity;
+            self.internal_transfer(&offer_data.ft_token_id, buyer_id.clone(), refund);
+            refund
+        };
+
+        self.internal_set_offer_price(
+            &contract_account_id_token_id,
+            offer_data,
+            price.0,
+            token_id,
+            token_series_id,
+            refund,
+        );
+    }
+
+    // ft_transfer_call with the update_offer method, the price is raised by the amount over the
+    // quantity and what does not divide evenly between the editions is handed back
+    pub(crate) fn internal_raise_ft_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        amount: u128,
+    ) -> u128 {
+        let token = token_id
+            .clone()
+            .or_else(|| token_series_id.clone())
+            .expect("Marble: token_id or token_series_id is required");
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+        assert_eq!(
+            offer_data.ft_token_id, ft_token_id,
+            "Marble: Offer is in another currency"
+        );
+        let quantity = offer_data.quantity.unwrap_or(1) as u128;
+        let price = offer_data.price + amount / quantity;
+        self.assert_offer_price_update(&nft_contract_id, &offer_data, price);
+
+        self.internal_set_offer_price(
+            &contract_account_id_token_id,
+            offer_data,
+            price,
+            token_id,
+            token_series_id,
+            0,
+        );
+        amount % quantity
+    }
+
+    fn assert_offer_price_update(
+        &self,
+        nft_contract_id: &AccountId,
+        offer_data: &OfferData,
+        price: u128,
+    ) {
+        let max_price = self.internal_max_price(&offer_data.ft_token_id);
+        assert!(price < max_price, "Marble: price higher than {}", max_price);
+        assert_ne!(offer_data.price, price, "Marble: Offer price is unchanged");
+        self.assert_min_offer_price(nft_contract_id, price);
+    }
+
+    fn internal_set_offer_price(
+        &mut self,
+        contract_account_id_token_id: &ContractAccountIdTokenId,
+        mut offer_data: OfferData,
+        price: u128,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        refund: u128,
+    ) {
+        let old_price = offer_data.price;
+        offer_data.price = price;
+        self.offers
+            .insert(contract_account_id_token_id, &offer_data);
+        self.internal_record_change(ChangeType::Offer, contract_account_id_token_id);
+
+        env::log_str(
+            &json!({
+                "type": "update_offer",
+                "params": {
+                    "buyer_id": offer_data.buyer_id,
+                    "nft_contract_id": offer_data.nft_contract_id,
+                    "token_id": token_id,
+                    "token_series_id": token_series_id,
+                    "ft_token_id": offer_data.ft_token_id,
+                    "old_price": U128(old_price),
+                    "price": U128(price),
+                    "refund": U128(refund),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_delete_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<OfferData> {
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        let offer_data = self.offers.remove(&contract_account_id_token_id);
+        self.internal_record_change(ChangeType::Offer, &contract_account_id_token_id);
+        self.offer_transaction_fees
+            .remove(&contract_account_id_token_id);
+
+        match offer_data {
+            Some(offer) => {
+                self.internal_remove_offer_index(&nft_contract_id, &token_id, &offer.buyer_id);
+                let by_owner_id = self.by_owner_id.get(&offer.buyer_id);
+                if let Some(mut by_owner_id) = by_owner_id {
+                    by_owner_id.remove(&contract_account_id_token_id);
+                    if by_owner_id.is_empty() {
+                        self.by_owner_id.remove(&offer.buyer_id);
+                    } else {
+                        self.by_owner_id.insert(&offer.buyer_id, &by_owner_id);
+                    }
+                }
+                return Some(offer);
+            }
+            None => return None,
+        };
+    }
+
+    fn internal_add_offer_index(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token: &str,
+        buyer_id: &AccountId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token);
+        let mut buyer_ids = self
+            .offers_by_token
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::OffersByTokenInner {
+                        contract_and_token_id_hash: hash_contract_account_id_token_id(
+                            &contract_and_token_id,
+                        ),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        buyer_ids.insert(buyer_id);
+        self.offers_by_token
+            .insert(&contract_and_token_id, &buyer_ids);
+    }
+
+    fn internal_remove_offer_index(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token: &str,
+        buyer_id: &AccountId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token);
+        if let Some(mut buyer_ids) = self.offers_by_token.get(&contract_and_token_id) {
+            buyer_ids.remove(buyer_id);
+            if buyer_ids.is_empty() {
+                self.offers_by_token.remove(&contract_and_token_id);
+            } else {
+                self.offers_by_token
+                    .insert(&contract_and_token_id, &buyer_ids);
+            }
+        }
+    }
+
+    fn internal_get_highest_offer(
+        &self,
+        nft_contract_id: &AccountId,
+        token: &str,
+        seller_id: &AccountId,
+        min_price: u128,
+    ) -> Option<OfferData> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token);
+        let buyer_ids = self.offers_by_token.get(&contract_and_token_id)?;
+
+        // indexed before the limit, a token may hold more offers than are scanned
+        let mut highest_offer: Option<OfferData> = None;
+        for buyer_id in buyer_ids.iter().take(MAX_OFFERS_PER_TOKEN as usize) {
+            let contract_account_id_token_id = make_triple(nft_contract_id, &buyer_id, token);
+            if let Some(offer_data) = self.offers.get(&contract_account_id_token_id) {
+                if offer_data.ft_token_id != near_account()
+                    || offer_data.price < min_price
+                    || !is_offer_seller(&offer_data, seller_id)
+                {
+                    continue;
+                }
+                if highest_offer
+                    .as_ref()
+                    .map_or(true, |highest| offer_data.price > highest.price)
+                {
+                    highest_offer = Some(offer_data);
+                }
+            }
+        }
+        highest_offer
+    }
+
+    fn internal_sell_to_highest_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        min_price: u128,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        let token_offer =
+            self.internal_get_highest_offer(&nft_contract_id, &token_id, &seller_id, min_price);
+
+        let series_offer = if self.marble_nft_contracts.contains(&nft_contract_id) {
+            // Token delimiter : is specific for Marble NFT
+            let token_series_id: String = token_id.split(":").next().unwrap().to_string();
+            self.internal_get_highest_offer(
+                &nft_contract_id,
+                &token_series_id,
+                &seller_id,
+                min_price,
+            )
+        } else {
+            None
+        };
+
+        let offer_data = match (token_offer, series_offer) {
+            (Some(token_offer), Some(series_offer)) => {
+                if series_offer.price > token_offer.price {
+                    series_offer
+                } else {
+                    token_offer
+                }
+            }
+            (Some(token_offer), None) => token_offer,
+            (None, Some(series_offer)) => series_offer,
+            (None, None) => env::panic_str("Marble: No offer at or above min price"),
+        };
+
+        env::log_str(
+            &json!({
+                "type": "sell_to_highest_offer",
+                "params": {
+                    "seller_id": seller_id,
+                    "buyer_id": offer_data.buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "min_price": min_price.to_string(),
+                    "price": offer_data.price.to_string(),
+                }
+            })
+            .to_string(),
+        );
+
+        if offer_data.token_id.is_some() {
+            self.internal_accept_offer(
+                nft_contract_id,
+                offer_data.buyer_id,
+                token_id,
+                seller_id,
+                approval_id,
+                offer_data.price,
+                referral_id,
+            )
+        } else {
+            self.internal_accept_offer_series(
+                nft_contract_id,
+                offer_data.buyer_id,
+                token_id,
+                seller_id,
+                approval_id,
+                offer_data.price,
+                referral_id,
+            )
+        }
+    }
+
+    #[payable]
+    pub fn delete_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<String>,
+    ) {
+        assert_one_yocto();
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            token_series_id.as_ref().unwrap().to_string()
+        };
+
+        let buyer_id = env::predecessor_account_id();
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+
+        if token_id.is_some() {
+            assert_eq!(offer_data.token_id.as_ref().unwrap(), &token)
+        } else {
+            assert_eq!(offer_data.token_series_id.as_ref().unwrap(), &token)
+        }
+
+        assert_eq!(
+            offer_data.buyer_id, buyer_id,
+            "Marble: Caller not offer's buyer"
+        );
+
+        self.internal_delete_offer(
+            nft_contract_id.clone().into(),
+            buyer_id.clone(),
+            token.clone(),
+        )
+        .expect("Marble: Offer not found");
+
+        self.internal_transfer(
+            &offer_data.ft_token_id,
+            offer_data.buyer_id.clone(),
+            offer_escrow(&offer_data),
+        );
+
+        env::log_str(
+            &json!({
+                "type": "delete_offer",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "buyer_id": buyer_id,
+                    "token_id": token_id,
+                    "token_series_id": token_series_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // token_id is the seller's token, a series offer is declined by the holder it targets
+    #[payable]
+    pub fn decline_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        token_series_id: Option<TokenSeriesId>,
+    ) -> Promise {
+        assert_one_yocto();
+        if let Some(token_series_id) = token_series_id.as_ref() {
+            assert!(
+                self.marble_nft_contracts.contains(&nft_contract_id),
+                "Marble: Series offers are only on Marble NFT contracts"
+            );
+            // Token delimiter : is specific for Marble NFT
+            assert_eq!(
+                token_id.split(":").next(),
+                Some(token_series_id.as_str()),
+                "Marble: token_id is not in token_series_id"
+            );
+        }
+        let token = token_series_id.as_ref().unwrap_or(&token_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, token);
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+        // an open series offer is not any single holder's to decline
+        if token_series_id.is_some() {
+            assert_eq!(
+                offer_data.seller_id,
+                Some(env::predecessor_account_id()),
+                "Marble: Only the targeted seller can decline a series offer"
+            );
+        }
+
+        // only the current token owner can decline
+        ext_contract::nft_token(
+            token_id.clone(),
+            nft_contract_id.clone(),
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::callback_decline_offer(
+            env::predecessor_account_id(),
+            nft_contract_id,
+            buyer_id,
+            token_id,
+            token_series_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_decline_offer(),
+        ))
+    }
+
+    #[private]
+    pub fn callback_decline_offer(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        token_series_id: Option<TokenSeriesId>,
+    ) {
+        let owner_id = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<JsonToken>(&value).ok())
+            .map(|token| token.owner_id);
+        assert_eq!(
+            owner_id,
+            Some(seller_id.clone()),
+            "Marble: Token owner only"
+        );
+
+        let token = token_series_id.clone().unwrap_or_else(|| token_id.clone());
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+        // a series offer targeted at another holder is theirs to decline
+        assert!(
+            is_offer_seller(&offer_data, &seller_id),
+            "Marble: Offer is targeted to another seller"
+        );
+        let offer_data = self
+            .internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token)
+            .expect("Marble: Offer does not exist");
+
+        self.internal_transfer(
+            &offer_data.ft_token_id,
+            offer_data.buyer_id.clone(),
+            offer_escrow(&offer_data),
+        );
+
+        env::log_str(
+            &json!({
+                "type": "decline_offer",
+                "params": {
+                    "seller_id": seller_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "token_series_id": token_series_id,
+                    "ft_token_id": offer_data.ft_token_id,
+                    "price": U128(offer_data.price),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_offer(
+        &self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<String>,
+    ) -> OfferDataJson {
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap()
+        } else {
+            token_series_id.as_ref().unwrap()
+        };
+
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+
+        if token_id.is_some() {
+            assert_eq!(offer_data.token_id.as_ref().unwrap(), token);
+        } else {
+            assert_eq!(offer_data.token_series_id.as_ref().unwrap(), token);
+        }
+
+        OfferDataJson {
+            buyer_id: offer_data.buyer_id,
+            nft_contract_id: offer_data.nft_contract_id,
+            token_id: offer_data.token_id,
+            token_series_id: offer_data.token_series_id,
+            ft_token_id: offer_data.ft_token_id,
+            price: U128(offer_data.price),
+            counter_seller_id: offer_data.counter.as_ref().map(|c| c.seller_id.clone()),
+            counter_token_id: offer_data.counter.as_ref().map(|c| c.token_id.clone()),
+            counter_price: offer_data.counter.as_ref().map(|c| U128(c.price)),
+            seller_id: offer_data.seller_id,
+            quantity: offer_data.quantity,
+            referral_id: offer_data.referral_id,
+            transaction_fee: self
+                .offer_transaction_fees
+                .get(&contract_account_id_token_id)
+                .unwrap_or(self.transaction_fee.current_fee),
+        }
+    }
+
+    fn internal_accept_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        price: u128,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+
+        assert_eq!(offer_data.token_id.as_ref().unwrap(), &token_id);
+        assert_eq!(offer_data.price, price);
+        assert!(
+            is_offer_seller(&offer_data, &seller_id),
+            "Marble: Offer is targeted to another seller"
+        );
+
+        let transaction_fee = self.internal_offer_transaction_fee(&contract_account_id_token_id);
+        let mut offer_data = self
+            .internal_delete_offer(
+                nft_contract_id.clone().into(),
+                buyer_id.clone(),
+                token_id.clone(),
+            )
+            .expect("Marble: Offer does not exist");
+        // the offer's own referrer takes precedence over the accepting front-end
+        if offer_data.referral_id.is_none() {
+            offer_data.referral_id = referral_id;
+        }
+
+        let resolve_gas = self.config.gas_for_resolve_offer(&offer_data.ft_token_id);
+        ext_contract::nft_transfer_payout(
+            offer_data.buyer_id.clone(),
+            token_id.clone(),
+            Some(approval_id),
+            Some(U128::from(offer_data.price)),
+            Some(self.max_len_payout),
+            nft_contract_id,
+            1,
+            self.config.gas_for_nft_transfer,
+        )
+        .then(ext_self::resolve_offer(
+            seller_id,
+            offer_data,
+            token_id,
+            transaction_fee,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            resolve_gas,
+        ))
+    }
+
+    fn internal_accept_offer_series(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        price: u128,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        // Token delimiter : is specific for Marble NFT
+
+        let mut token_id_iter = token_id.split(":");
+        let token_series_id: String = token_id_iter.next().unwrap().parse().unwrap();
+
+        let contract_account_id_token_id =
+            make_triple(&nft_contract_id, &buyer_id, &token_series_id);
+
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+
+        assert_eq!(
+            offer_data.token_series_id.as_ref().unwrap(),
+            &token_series_id
+        );
+        assert_eq!(offer_data.price, price);
+        assert!(
+            is_offer_seller(&offer_data, &seller_id),
+            "Marble: Offer is targeted to another seller"
+        );
+
+        // quantity offers stay open until every edition is bought
+        let transaction_fee = self.internal_offer_transaction_fee(&contract_account_id_token_id);
+        let mut offer_data = offer_data;
+        match offer_data.quantity {
+            Some(quantity) if quantity > 1 => {
+                offer_data.quantity = Some(quantity - 1);
+                self.offers
+                    .insert(&contract_account_id_token_id, &offer_data);
+                self.internal_record_change(ChangeType::Offer, &contract_account_id_token_id);
+                offer_data.quantity = None;
+            }
+            _ => {
+                self.internal_delete_offer(
+                    nft_contract_id.clone().into(),
+                    buyer_id.clone(),
+                    token_series_id.clone(),
+                )
+                .expect("Marble: Offer does not exist");
+            }
+        }
+        if offer_data.referral_id.is_none() {
+            offer_data.referral_id = referral_id;
+        }
+
+        let resolve_gas = self.config.gas_for_resolve_offer(&offer_data.ft_token_id);
+        ext_contract::nft_transfer_payout(
+            offer_data.buyer_id.clone(),
+            token_id.clone(),
+            Some(approval_id),
+            Some(U128::from(offer_data.price)),
+            Some(self.max_len_payout),
+            nft_contract_id,
+            1,
+            self.config.gas_for_nft_transfer,
+        )
+        .then(ext_self::resolve_offer(
+            seller_id,
+            offer_data,
+            token_id,
+            transaction_fee,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            resolve_gas,
+        ))
+    }
+
+    // each offer gets an nft_token check and a payout callback, MAX_ACCEPT_OFFERS_BATCH fit in a call
+    #[payable]
+    pub fn accept_offers_batch(
+        &mut self,
+        nft_contract_id: AccountId,
+        offers: Vec<AcceptOfferArgs>,
+    ) {
+        self.assert_not_paused(PauseFeature::Offers);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_seller(&env::predecessor_account_id());
+        assert_one_yocto();
+        assert!(!offers.is_empty(), "Marble: Offers are empty");
+        assert!(
+            offers.len() <= MAX_ACCEPT_OFFERS_BATCH,
+            "Marble: Cannot accept more than {} offers at once",
+            MAX_ACCEPT_OFFERS_BATCH
+        );
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "Marble: nft_contract_id is not approved"
+        );
+
+        let gas_per_offer =
+            self.config.gas_for_nft_token.0 + self.config.gas_for_callback_accept_offer().0;
+        let gas_available = env::prepaid_gas().0 - env::used_gas().0;
+        assert!(
+            gas_available > gas_per_offer * offers.len() as u64 + self.config.base_gas.0,
+            "Marble: Not enough gas to accept {} offers",
+            offers.len()
+        );
+
+        let seller_id = env::predecessor_account_id();
+
+        // ownership is checked against the NFT contract before each transfer
+        for AcceptOfferArgs {
+            buyer_id,
+            token_id,
+            approval_id,
+        } in offers
+        {
+            ext_contract::nft_token(
+                token_id.clone(),
+                nft_contract_id.clone(),
+                NO_DEPOSIT,
+                self.config.gas_for_nft_token,
+            )
+            .then(ext_self::callback_accept_offer_batch(
+                seller_id.clone(),
+                nft_contract_id.clone(),
+                buyer_id,
+                token_id,
+                approval_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.config.gas_for_callback_accept_offer(),
+            ));
+        }
+    }
+
+    #[private]
+    pub fn callback_accept_offer_batch(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        approval_id: u64,
+    ) {
+        let owner_id = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<JsonToken>(&value).ok())
+            .map(|token| token.owner_id);
+
+        let reason = if owner_id.as_ref() != Some(&seller_id) {
+            "seller is not token owner"
+        } else {
+            let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+            if let Some(offer_data) = self.offers.get(&contract_account_id_token_id) {
+                if offer_data.ft_token_id != near_account() {
+                    return self.internal_log_accept_offer_batch_skip(
+                        seller_id,
+                        nft_contract_id,
+                        buyer_id,
+                        token_id,
+                        FT_OFFER_BATCH_SKIP_REASON,
+                    );
+                }
+                self.internal_accept_offer(
+                    nft_contract_id,
+                    buyer_id,
+                    token_id,
+                    seller_id,
+                    approval_id,
+                    offer_data.price,
+                    None,
+                );
+                return;
+            }
+
+            if self.marble_nft_contracts.contains(&nft_contract_id) {
+                // Token delimiter : is specific for Marble NFT
+                let token_series_id: String = token_id.split(":").next().unwrap().to_string();
+                let contract_account_id_token_series_id =
+                    make_triple(&nft_contract_id, &buyer_id, &token_series_id);
+                if let Some(offer_data) = self.offers.get(&contract_account_id_token_series_id) {
+                    if offer_data.ft_token_id != near_account() {
+                        return self.internal_log_accept_offer_batch_skip(
+                            seller_id,
+                            nft_contract_id,
+                            buyer_id,
+                            token_id,
+                            FT_OFFER_BATCH_SKIP_REASON,
+                        );
+                    }
+                    self.internal_accept_offer_series(
+                        nft_contract_id,
+                        buyer_id,
+                        token_id,
+                        seller_id,
+                        approval_id,
+                        offer_data.price,
+                        None,
+                    );
+                    return;
+                }
+            }
+            "offer does not exist"
+        };
+        self.internal_log_accept_offer_batch_skip(
+            seller_id,
+            nft_contract_id,
+            buyer_id,
+            token_id,
+            reason,
+        );
+    }
+
+    fn internal_log_accept_offer_batch_skip(
+        &self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        reason: &str,
+    ) {
+        env::log_str(
+            &json!({
+                "type": "accept_offer_batch_skip",
+                "params": {
+                    "seller_id": seller_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "reason": reason,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_counter_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token: String,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        price: u128,
+    ) {
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let mut offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+
+        let max_price = self.internal_max_price(&offer_data.ft_token_id);
+        assert!(price < max_price, "Marble: price higher than {}", max_price);
+        assert_ne!(
+            offer_data.price, price,
+            "Marble: Counter price is equal to offer price"
+        );
+        assert!(
+            is_offer_seller(&offer_data, &seller_id),
+            "Marble: Offer is targeted to another seller"
+        );
+        assert!(
+            offer_data.quantity.is_none(),
+            "Marble: Cannot counter a quantity offer"
+        );
+        // the buyer tops up a higher counter with an attached NEAR deposit
+        assert!(
+            offer_data.ft_token_id == near_account() || price < offer_data.price,
+            "Marble: FT offers can only be countered lower"
+        );
+
+        offer_data.counter = Some(CounterOfferData {
+            seller_id: seller_id.clone(),
+            token_id: token_id.clone(),
+            approval_id,
+            price,
+        });
+        self.offers
+            .insert(&contract_account_id_token_id, &offer_data);
+        self.internal_record_change(ChangeType::Offer, &contract_account_id_token_id);
+
+        env::log_str(
+            &json!({
+                "type": "counter_offer",
+                "params": {
+                    "seller_id": seller_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "token_series_id": offer_data.token_series_id,
+                    "ft_token_id": offer_data.ft_token_id,
+                    "price": offer_data.price.to_string(),
+                    "counter_price": price.to_string(),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn accept_counter_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<String>,
+    ) -> Promise {
+        self.assert_not_paused(PauseFeature::Offers);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            token_series_id.as_ref().unwrap().to_string()
+        };
+
+        let buyer_id = env::predecessor_account_id();
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let offer_data = self
+            .offers
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Offer does not exist");
+
+        let counter = offer_data
+            .counter
+            .clone()
+            .expect("Marble: Offer has no counter");
+
+        if counter.price > offer_data.price {
+            assert_eq!(
+                offer_data.ft_token_id,
+                near_account(),
+                "Marble: Raise an FT offer through ft_transfer_call"
+            );
+            assert_eq!(
+                env::attached_deposit(),
+                counter.price - offer_data.price,
+                "Marble: Attached deposit != counter price - offer price"
+            );
+        } else {
+            assert_one_yocto();
+            if offer_data.ft_token_id == near_account() {
+                Promise::new(buyer_id.clone()).transfer(offer_data.price - counter.price + 1);
+            } else {
+                Promise::new(buyer_id.clone()).transfer(1);
+                self.internal_transfer(
+                    &offer_data.ft_token_id,
+                    buyer_id.clone(),
+                    offer_data.price - counter.price,
+                );
+            }
+        }
+
+        self.internal_delete_market_data(&nft_contract_id, &counter.token_id);
+
+        let transaction_fee = self.internal_offer_transaction_fee(&contract_account_id_token_id);
+        let mut offer_data = self
+            .internal_delete_offer(nft_contract_id.clone(), buyer_id.clone(), token)
+            .expect("Marble: Offer does not exist");
+        offer_data.price = counter.price;
+
+        env::log_str(
+            &json!({
+                "type": "accept_counter_offer",
+                "params": {
+                    "seller_id": counter.seller_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": counter.token_id,
+                    "token_series_id": offer_data.token_series_id,
+                    "ft_token_id": offer_data.ft_token_id,
+                    "price": counter.price.to_string(),
+                }
+            })
+            .to_string(),
+        );
+
+        let resolve_gas = self.config.gas_for_resolve_offer(&offer_data.ft_token_id);
+        ext_contract::nft_transfer_payout(
+            buyer_id,
+            counter.token_id.clone(),
+            Some(counter.approval_id),
+            Some(U128::from(counter.price)),
+            Some(self.max_len_payout),
+            nft_contract_id,
+            1,
+            self.config.gas_for_nft_transfer,
+        )
+        .then(ext_self::resolve_offer(
+            counter.seller_id,
+            offer_data,
+            counter.token_id,
+            transaction_fee,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            resolve_gas,
+        ))
+    }
+
+    #[private]
+    pub fn resolve_offer(
+        &mut self,
+        seller_id: AccountId,
+        offer_data: OfferData,
+        token_id: TokenId,
+        transaction_fee: u16,
+    ) -> U128 {
+        let payout_option = promise_result_as_success().and_then(|value| {
+            // None means a bad payout from bad NFT contract
+            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
+            if parsed_payout.is_err() {
+                near_sdk::serde_json::from_slice::<Payout>(&value)
+                    .ok()
+                    .and_then(|payout| {
+                        let mut remainder = offer_data.price;
+                        for &value in payout.payout.values() {
+                            remainder = remainder.checked_sub(value.0)?;
+                        }
+                        if remainder <= 100 {
+                            Some(payout.payout)
+                        } else {
+                            None
+                        }
+                    })
+            } else {
+                parsed_payout.ok().and_then(|payout| {
+                    let mut remainder = offer_data.price;
+                    for &value in payout.values() {
+                        remainder = remainder.checked_sub(value.0)?;
+                    }
+                    if remainder <= 100 {
+                        Some(payout)
+                    } else {
+                        None
+                    }
+                })
+            }
+        });
+        let payout_option =
+            self.internal_filter_payout(payout_option, &offer_data.nft_contract_id, &token_id);
+
+        let payout = if let Some(payout_option) = payout_option {
+            payout_option
+        } else {
+            if !is_promise_success() {
+                self.internal_transfer(
+                    &offer_data.ft_token_id,
+                    offer_data.buyer_id.clone(),
+                    offer_data.price,
+                );
+                env::log_str(
+                    &json!({
+                        "type": "resolve_purchase_fail",
+                        "params": {
+                            "owner_id": seller_id,
+                            "nft_contract_id": offer_data.nft_contract_id,
+                            "token_id": token_id,
+                            "token_series_id": offer_data.token_series_id,
+                            "ft_token_id": offer_data.ft_token_id,
+                            "price": offer_data.price.to_string(),
+                            "buyer_id": offer_data.buyer_id,
+                            "is_offer": true,
+                        }
+                    })
+                    .to_string(),
+                );
+            } else {
+                let treasury_fee = offer_data.price as u128
+                    * self.calculate_offer_transaction_fee(
+                        &offer_data.nft_contract_id,
+                        &seller_id,
+                        offer_data.price,
+                        transaction_fee,
+                    )
+                    / 10_000u128;
+                let treasury_fee = self.internal_discount_treasury_fee(
+                    treasury_fee,
+                    &offer_data.buyer_id,
+                    &seller_id,
+                );
+                self.internal_transfer_payout(
+                    &offer_data.ft_token_id,
+                    seller_id.clone(),
+                    offer_data.price - treasury_fee,
+                );
+                self.internal_record_collection_fee(
+                    &offer_data.nft_contract_id,
+                    &offer_data.ft_token_id,
+                    treasury_fee,
+                );
+                self.internal_transfer_treasury_fee(
+                    &offer_data.ft_token_id,
+                    treasury_fee,
+                    offer_data.referral_id.clone(),
+                );
+
+                let sale_id = self.internal_next_sale_id();
+                env::log_str(
+                    &json!({
+                        "type": "resolve_purchase",
+                        "params": {
+                            "owner_id": seller_id,
+                            "nft_contract_id": &offer_data.nft_contract_id,
+                            "token_id": &token_id,
+                            "sale_id": sale_id,
+                            "token_series_id": offer_data.token_series_id,
+                            "ft_token_id": offer_data.ft_token_id,
+                            "price": offer_data.price.to_string(),
+                            "buyer_id": offer_data.buyer_id,
+                            "is_offer": true,
+                        }
+                    })
+                    .to_string(),
+                );
+            }
+            return offer_data.price.into();
+        };
+
+        // Payout (transfer to royalties and seller) in the escrowed token
+        // 5% fee for treasury, less the staker discount
+        let treasury_fee = offer_data.price as u128
+            * self.calculate_offer_transaction_fee(
+                &offer_data.nft_contract_id,
+                &seller_id,
+                offer_data.price,
+                transaction_fee,
+            )
+            / 10_000u128;
+        let treasury_fee =
+            self.internal_discount_treasury_fee(treasury_fee, &offer_data.buyer_id, &seller_id);
+
+        let fee_shares = payout_fee_shares(&payout, &seller_id, treasury_fee);
+        let treasury_fee: Balance = fee_shares.values().sum();
+        for (receiver_id, amount) in payout {
+            let amount = amount.0 - fee_shares.get(&receiver_id).copied().unwrap_or(0);
+            if amount > 0 {
+                self.internal_transfer_payout(&offer_data.ft_token_id, receiver_id, amount);
+            }
+        }
+        self.internal_record_collection_fee(
+            &offer_data.nft_contract_id,
+            &offer_data.ft_token_id,
+            treasury_fee,
+        );
+        self.internal_transfer_treasury_fee(
+            &offer_data.ft_token_id,
+            treasury_fee,
+            offer_data.referral_id.clone(),
+        );
+
+        let sale_id = self.internal_next_sale_id();
+        env::log_str(
+            &json!({
+                "type": "resolve_purchase",
+                "params": {
+                    "owner_id": seller_id,
+                    "nft_contract_id": &offer_data.nft_contract_id,
+                    "token_id": &token_id,
+                    "sale_id": sale_id,
+                    "token_series_id": offer_data.token_series_id,
+                    "ft_token_id": offer_data.ft_token_id,
+                    "price": offer_data.price.to_string(),
+                    "buyer_id": offer_data.buyer_id,
+                    "is_offer": true,
+                }
+            })
+            .to_string(),
+        );
+
+        let seller_contract_account_id_token_id =
+            make_triple(&offer_data.nft_contract_id, &seller_id, &token_id);
+        self.trades.remove(&seller_contract_account_id_token_id);
+        self.internal_record_change(ChangeType::Trade, &seller_contract_account_id_token_id);
+
+        offer_data.price.into()
+    }
+
+    // Trade
+    fn add_trade(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        buyer_token_id: Option<TokenId>,
+        buyer_approval_id: u64,
+        buyer_tokens: Option<Vec<TradeToken>>,
+        seller_tokens: Option<Vec<TradeToken>>,
+        expires_at: Option<u64>,
+    ) {
+        self.internal_add_trade(
+            nft_contract_id.clone().into(),
+            token_id.clone(),
+            token_series_id.clone(),
+            buyer_nft_contract_id.clone().into(),
+            buyer_token_id.clone(),
+            buyer_id.clone(),
+            buyer_approval_id.clone(),
+            buyer_tokens.clone(),
+            seller_tokens.clone(),
+            expires_at,
+        );
+
+        env::log_str(
+            &json!({
+                "type": "add_trade",
+                "params": {
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "token_series_id": token_series_id,
+                    "buyer_nft_contract_id": buyer_nft_contract_id,
+                    "buyer_token_id": buyer_token_id,
+                    "buyer_approval_id": buyer_approval_id,
+                    "buyer_tokens": buyer_tokens,
+                    "seller_tokens": seller_tokens,
+                    "expires_at": expires_at.map(U64)
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_add_trade(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: Option<TokenId>,
+        buyer_id: AccountId,
+        buyer_approval_id: u64,
+        buyer_tokens: Option<Vec<TradeToken>>,
+        seller_tokens: Option<Vec<TradeToken>>,
+        expires_at: Option<u64>,
+    ) {
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at > env::block_timestamp(),
+                "Marble: expires_at is in the past"
+            );
+        }
+
+        assert!(
+            token_id.is_some() != token_series_id.is_some(),
+            "Marble: Specify either token_id or token_series_id"
+        );
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            assert!(
+                self.marble_nft_contracts.contains(&nft_contract_id),
+                "Marble: trade series for Marble NFT only"
+            );
+            token_series_id.as_ref().unwrap().to_string()
+        };
+
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+        let buyer_contract_account_id_token_id = make_triple(
+            &buyer_nft_contract_id,
+            &buyer_id,
+            &buyer_token_id
+                .as_ref()
+                .expect("Marble: Buyer token id is not specified"),
+        );
+
+        let buyer_tokens = buyer_tokens.unwrap_or_default();
+        let seller_tokens = seller_tokens.unwrap_or_default();
+        assert!(
+            buyer_tokens.len() < MAX_TRADE_BUNDLE_SIZE
+                && seller_tokens.len() < MAX_TRADE_BUNDLE_SIZE,
+            "Marble: Trade bundle is limited to {} tokens per side",
+            MAX_TRADE_BUNDLE_SIZE
+        );
+
+        let mut bundle_token_ids = HashSet::new();
+        bundle_token_ids.insert((nft_contract_id.clone(), token.clone()));
+        bundle_token_ids.insert((
+            buyer_nft_contract_id.clone(),
+            buyer_token_id.clone().unwrap(),
+        ));
+        for token in buyer_tokens.iter().chain(seller_tokens.iter()) {
+            assert!(
+                self.approved_nft_contract_ids
+                    .contains(&token.nft_contract_id),
+                "Marble: nft_contract_id is not approved"
+            );
+            assert!(
+                bundle_token_ids.insert((token.nft_contract_id.clone(), token.token_id.clone())),
+                "Marble: Duplicate bundle tokens"
+            );
+        }
+        for token in &buyer_tokens {
+            assert!(
+                token.approval_id.is_some(),
+                "Marble: Bundle token approval_id is not specified"
+            );
+        }
+        // seller approvals are supplied when the trade is accepted
+        let seller_tokens: Vec<TradeToken> = seller_tokens
+            .into_iter()
+            .map(|token| TradeToken {
+                approval_id: None,
+                ..token
+            })
+            .collect();
+
+        let bound_token_id = token_id.clone();
+        let bound_nft_contract_id = nft_contract_id.clone();
+        let trade_data = TradeData {
+            buyer_amount: None,
+            seller_amount: None,
+            is_active: None,
+            ft_token_id: None,
+            nft_contract_id: nft_contract_id.into(),
+            token_id: token_id,
+            token_series_id: token_series_id,
+            buyer_tokens: Some(buyer_tokens).filter(|tokens| !tokens.is_empty()),
+            seller_tokens: Some(seller_tokens).filter(|tokens| !tokens.is_empty()),
+            seller_id: None,
+            expires_at: expires_at,
+            owner_id: None,
+        };
+        let mut buyer_trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .unwrap_or_else(|| {
+                TradeList {
+                    approval_id: 0, //init
+                    trade_data: HashMap::new(),
+                    history: Vec::new(),
+                }
+            });
+        buyer_trade_list.approval_id = buyer_approval_id;
+        buyer_trade_list
+            .trade_data
+            .insert(contract_account_id_token_id.clone(), trade_data);
+
+        self.trades
+            .insert(&buyer_contract_account_id_token_id, &buyer_trade_list);
+        self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
+
+        let mut token_ids = self.by_owner_id.get(&buyer_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByOwnerIdInner {
+                    account_id_hash: hash_account_id(&buyer_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+
+        token_ids.insert(&make_key_owner_by_id_trade(
+            contract_account_id_token_id.clone(),
+        ));
+        self.by_owner_id.insert(&buyer_id, &token_ids);
+
+        // bind the proposal to the current owner of the wanted token
+        if let Some(token_id) = bound_token_id {
+            ext_contract::nft_token(
+                token_id,
+                bound_nft_contract_id,
+                NO_DEPOSIT,
+                self.config.gas_for_nft_token,
+            )
+            .then(ext_self::callback_bind_trade_owner(
+                buyer_contract_account_id_token_id,
+                contract_account_id_token_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.config.base_gas,
+            ));
+        }
+    }
+
+    #[private]
+    pub fn callback_bind_trade_owner(
+        &mut self,
+        buyer_contract_account_id_token_id: ContractAccountIdTokenId,
+        contract_account_id_token_id: ContractAccountIdTokenId,
+    ) {
+        let owner_id = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<JsonToken>(&value).ok())
+            .map(|token| token.owner_id);
+        let mut trade_list = match self.trades.get(&buyer_contract_account_id_token_id) {
+            Some(trade_list) => trade_list,
+            None => return,
+        };
+        if let Some(trade_data) = trade_list.trade_data.get_mut(&contract_account_id_token_id) {
+            if trade_data.owner_id.is_none() {
+                trade_data.owner_id = owner_id;
+                self.trades
+                    .insert(&buyer_contract_account_id_token_id, &trade_list);
+                self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
+            }
+        }
+    }
+
+    #[payable]
+    pub fn add_trade_deposit(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) {
+        self.assert_not_paused(PauseFeature::Trades);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        let buyer_id = env::predecessor_account_id();
+        self.internal_add_trade_deposit(
+            buyer_id.clone(),
+            near_account(),
+            env::attached_deposit(),
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            buyer_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+        );
+    }
+
+    #[payable]
+    pub fn add_trade_seller_deposit(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) {
+        self.assert_not_paused(PauseFeature::Trades);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_seller(&env::predecessor_account_id());
+        self.internal_add_trade_deposit(
+            env::predecessor_account_id(),
+            near_account(),
+            env::attached_deposit(),
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            buyer_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+        );
+    }
+
+    // the buyer's deposit goes to buyer_amount, anyone else escrows seller_amount
+    // and becomes the only seller able to accept the trade
+    fn internal_add_trade_deposit(
+        &mut self,
+        depositor_id: AccountId,
+        ft_token_id: AccountId,
+        amount: Balance,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) {
+        assert!(amount > 0, "Marble: Attached deposit is required");
+        assert!(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            "Marble: ft_token_id not approved"
+        );
+
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            token_series_id.as_ref().unwrap().to_string()
+        };
+
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let mut trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        let trade_data = trade_list
+            .trade_data
+            .get_mut(&contract_account_id_token_id)
+            .expect("Marble: Trade data does not exist");
+
+        if let Some(trade_ft_token_id) = trade_data.ft_token_id.as_ref() {
+            assert_eq!(
+                trade_ft_token_id,
+                &ft_token_id.to_string(),
+                "Marble: Trade is escrowed in another ft_token_id"
+            );
+        }
+        trade_data.ft_token_id = Some(ft_token_id.to_string());
+
+        let total_amount = if depositor_id == buyer_id {
+            trade_data.buyer_amount.unwrap_or(0) + amount
+        } else {
+            if let Some(seller_id) = trade_data.seller_id.as_ref() {
+                assert_eq!(
+                    seller_id, &depositor_id,
+                    "Marble: Trade is funded by another seller"
+                );
+            }
+            assert_trade_owner(trade_data, &depositor_id);
+            trade_data.seller_amount.unwrap_or(0) + amount
+        };
+        let max_price = self.internal_max_price(&ft_token_id);
+        assert!(
+            total_amount < max_price,
+            "Marble: price higher than {}",
+            max_price
+        );
+        if depositor_id == buyer_id {
+            trade_data.buyer_amount = Some(total_amount);
+        } else {
+            trade_data.seller_amount = Some(total_amount);
+            trade_data.seller_id = Some(depositor_id.clone());
+        }
+        let buyer_amount = trade_data.buyer_amount.unwrap_or(0);
+        let seller_amount = trade_data.seller_amount.unwrap_or(0);
+
+        self.trades
+            .insert(&buyer_contract_account_id_token_id, &trade_list);
+        self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
+
+        env::log_str(
+            &json!({
+                "type": "add_trade_deposit",
+                "params": {
+                    "depositor_id": depositor_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "token_series_id": token_series_id,
+                    "buyer_nft_contract_id": buyer_nft_contract_id,
+                    "buyer_token_id": buyer_token_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                    "buyer_amount": U128(buyer_amount),
+                    "seller_amount": U128(seller_amount),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn delete_trade(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) {
+        assert_one_yocto();
+        self.internal_cancel_trade(
+            env::predecessor_account_id(),
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+        );
+    }
+
+    // retracts many proposals at once, e.g. after sweeping a collection
+    #[payable]
+    pub fn delete_trades(&mut self, trades: Vec<DeleteTradeArgs>) {
+        assert_one_yocto();
+        assert!(!trades.is_empty(), "Marble: Trades are empty");
+
+        let buyer_id = env::predecessor_account_id();
+        for DeleteTradeArgs {
+            nft_contract_id,
+            token_id,
+            token_series_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+        } in trades
+        {
+            self.internal_cancel_trade(
+                buyer_id.clone(),
+                nft_contract_id,
+                token_id,
+                token_series_id,
+                buyer_nft_contract_id,
+                buyer_token_id,
+            );
+        }
+    }
+
+    fn internal_cancel_trade(
+        &mut self,
+        buyer_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: Option<TokenId>,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) {
+        let token = if token_id.is_some() {
+            token_id.as_ref().unwrap().to_string()
+        } else {
+            token_series_id.as_ref().unwrap().to_string()
+        };
+
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token);
+
+        let trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        let trade_data = trade_list
+            .trade_data
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Trade data does not exist");
+
+        if token_id.is_some() {
+            assert_eq!(trade_data.clone().token_id.unwrap(), token)
+        } else {
+            assert_eq!(trade_data.clone().token_series_id.unwrap(), token)
+        }
+
+        let trade_data = self
+            .internal_delete_trade(
+                nft_contract_id.clone().into(),
+                buyer_id.clone(),
+                token.clone(),
+                buyer_nft_contract_id.clone(),
+                buyer_token_id.clone(),
+            )
+            .expect("Marble: Trade not found");
+
+        self.internal_refund_trade_cash(&buyer_id, &trade_data);
+
+        env::log_str(
+            &json!({
+                "type": "delete_trade",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "buyer_id": buyer_id,
+                    "token_id": token_id,
+                    "token_series_id": token_series_id,
+                    "buyer_nft_contract_id": buyer_nft_contract_id,
+                    "buyer_token_id": buyer_token_id
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn reject_trade(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        token_series_id: Option<TokenSeriesId>,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) -> Promise {
+        assert_one_yocto();
+        // a series trade is rejected by the owner of any token in the series
+        if let Some(token_series_id) = token_series_id.as_ref() {
+            assert_eq!(
+                token_id.split(":").next().unwrap(),
+                token_series_id,
+                "Marble: Token is not in the trade series"
+            );
+        }
+        let token = token_series_id.clone().unwrap_or(token_id.clone());
+        let trade_list = self
+            .trades
+            .get(&make_triple(
+                &buyer_nft_contract_id,
+                &buyer_id,
+                &buyer_token_id,
+            ))
+            .expect("Marble: Trade list does not exist");
+        assert!(
+            trade_list
+                .trade_data
+                .contains_key(&make_triple(&nft_contract_id, &buyer_id, &token)),
+            "Marble: Trade data does not exist"
+        );
+
+        // only the current token owner can reject
+        ext_contract::nft_token(
+            token_id,
+            nft_contract_id.clone(),
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::callback_reject_trade(
+            env::predecessor_account_id(),
+            nft_contract_id,
+            token,
+            buyer_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_reject_trade(),
+        ))
+    }
+
+    #[private]
+    pub fn callback_reject_trade(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        token: String,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) {
+        let owner_id = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<JsonToken>(&value).ok())
+            .map(|token| token.owner_id);
+        assert_eq!(
+            owner_id,
+            Some(seller_id.clone()),
+            "Marble: Token owner only"
+        );
+
+        let trade_data = self
+            .internal_delete_trade(
+                nft_contract_id.clone(),
+                buyer_id.clone(),
+                token,
+                buyer_nft_contract_id.clone(),
+                buyer_token_id.clone(),
+            )
+            .expect("Marble: Trade not found");
+
+        self.internal_refund_trade_cash(&buyer_id, &trade_data);
+
+        env::log_str(
+            &json!({
+                "type": "reject_trade",
+                "params": {
+                    "seller_id": seller_id,
+                    "nft_contract_id": nft_contract_id,
+                    "buyer_id": buyer_id,
+                    "token_id": trade_data.token_id,
+                    "token_series_id": trade_data.token_series_id,
+                    "buyer_nft_contract_id": buyer_nft_contract_id,
+                    "buyer_token_id": buyer_token_id
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_delete_trade(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) -> Option<TradeData> {
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+
+        let mut trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        let trade_data = trade_list.trade_data.remove(&contract_account_id_token_id);
+
+        self.trades
+            .insert(&buyer_contract_account_id_token_id, &trade_list);
+        self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
+
+        match trade_data {
+            Some(trade) => {
+                let mut by_owner_id = self
+                    .by_owner_id
+                    .get(&buyer_id)
+                    .expect("Marble: no market data by account_id");
+                by_owner_id.remove(&make_key_owner_by_id_trade(contract_account_id_token_id));
+                if by_owner_id.is_empty() {
+                    self.by_owner_id.remove(&buyer_id);
+                } else {
+                    self.by_owner_id.insert(&buyer_id, &by_owner_id);
+                }
+                return Some(trade);
+            }
+            None => {
+                self.trades
+                    .remove(&buyer_contract_account_id_token_id)
+                    .expect("Marble: Error delete trade list");
+                self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
+                return None;
+            }
+        };
+    }
+
+    // anyone can clear expired proposals, escrowed cash goes back to its owners.
+    // Trade lists are scanned MAX_CLEANUP_TRADES at a time, page with from_index
+    pub fn cleanup_expired_trades(&mut self, from_index: Option<U64>, limit: Option<u64>) -> u64 {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(MAX_CLEANUP_TRADES);
+        assert!(limit > 0, "Marble: limit must be higher than 0");
+        assert!(
+            limit <= MAX_CLEANUP_TRADES,
+            "Marble: Cannot scan more than {} trades at once",
+            MAX_CLEANUP_TRADES
+        );
+
+        let expired_trades: Vec<(ContractAccountIdTokenId, ContractAccountIdTokenId)> = self
+            .trades
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .flat_map(|(buyer_contract_account_id_token_id, trade_list)| {
+                trade_list
+                    .trade_data
+                    .into_iter()
+                    .filter(|(_, trade_data)| is_trade_expired(trade_data))
+                    .map(move |(contract_account_id_token_id, _)| {
+                        (
+                            buyer_contract_account_id_token_id.clone(),
+                            contract_account_id_token_id,
+                        )
+                    })
+            })
+            .collect();
+
+        for (buyer_contract_account_id_token_id, contract_account_id_token_id) in &expired_trades {
+            let (buyer_nft_contract_id, buyer_id, buyer_token_id) =
+                split_triple(buyer_contract_account_id_token_id);
+            let (nft_contract_id, _, token) = split_triple(contract_account_id_token_id);
+
+            let trade_data = self
+                .internal_delete_trade(
+                    nft_contract_id.clone(),
+                    buyer_id.clone(),
+                    token,
+                    buyer_nft_contract_id.clone(),
+                    buyer_token_id.clone(),
+                )
+                .expect("Marble: Trade not found");
+            self.internal_refund_trade_cash(&buyer_id, &trade_data);
+
+            if self
+                .trades
+                .get(buyer_contract_account_id_token_id)
+                .map_or(false, |trade_list| trade_list.trade_data.is_empty())
+            {
+                self.trades.remove(buyer_contract_account_id_token_id);
+                self.internal_record_change(ChangeType::Trade, buyer_contract_account_id_token_id);
+            }
+
+            env::log_str(
+                &json!({
+                    "type": "delete_trade",
+                    "params": {
+                        "nft_contract_id": nft_contract_id,
+                        "buyer_id": buyer_id,
+                        "token_id": trade_data.token_id,
+                        "token_series_id": trade_data.token_series_id,
+                        "buyer_nft_contract_id": buyer_nft_contract_id,
+                        "buyer_token_id": buyer_token_id,
+                        "reason": "expired",
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        expired_trades.len() as u64
+    }
+
+    // drops a proposer's trade list, refunding the cash attached to every
+    // proposal in it except the one being accepted
+    fn internal_remove_trade_list(
+        &mut self,
+        proposer_id: &AccountId,
+        buyer_contract_account_id_token_id: &ContractAccountIdTokenId,
+        accepted_contract_account_id_token_id: Option<&ContractAccountIdTokenId>,
+    ) {
+        if let Some(trade_list) = self.trades.remove(buyer_contract_account_id_token_id) {
+            self.internal_record_change(ChangeType::Trade, buyer_contract_account_id_token_id);
+            for (contract_account_id_token_id, trade_data) in trade_list.trade_data {
+                if Some(&contract_account_id_token_id) == accepted_contract_account_id_token_id {
+                    continue;
+                }
+                self.internal_refund_trade_cash(proposer_id, &trade_data);
+            }
+        }
+    }
+
+    fn internal_refund_trade_cash(&self, buyer_id: &AccountId, trade_data: &TradeData) {
+        let cash = trade_cash(trade_data);
+        if cash.buyer_amount.0 > 0 {
+            self.internal_transfer(&cash.ft_token_id, buyer_id.clone(), cash.buyer_amount.0);
+        }
+        if cash.seller_amount.0 > 0 {
+            self.internal_transfer(
+                &cash.ft_token_id,
+                trade_data.seller_id.clone().unwrap(),
+                cash.seller_amount.0,
+            );
+        }
+    }
+
+    pub fn get_trade(
+        &self,
+        seller_nft_contract_id: AccountId,
+        seller_token_id: Option<TokenId>,
+        seller_token_series_id: Option<String>,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) -> TradeData {
+        let token = if seller_token_id.is_some() {
+            seller_token_id.as_ref().unwrap()
+        } else {
+            seller_token_series_id.as_ref().unwrap()
+        };
+
+        let contract_account_id_token_id = make_triple(&seller_nft_contract_id, &buyer_id, &token);
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+
+        let trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        let trade_data = trade_list
+            .trade_data
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Trade data does not exist");
+
+        if seller_token_id.is_some() {
+            assert_eq!(trade_data.token_id.as_ref().unwrap(), token);
+        } else {
+            assert_eq!(trade_data.token_series_id.as_ref().unwrap(), token);
+        }
+
+        return trade_data.clone();
+    }
+
+    fn internal_accept_trade(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+        seller_tokens: Option<Vec<TradeToken>>,
+    ) -> Promise {
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+        let contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+
+        let trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        // fall back to a wildcard trade on the token's series
+        if !trade_list
+            .trade_data
+            .contains_key(&contract_account_id_token_id)
+            && self.marble_nft_contracts.contains(&nft_contract_id)
+        {
+            return self.internal_accept_trade_series(
+                nft_contract_id,
+                buyer_id,
+                token_id,
+                seller_id,
+                approval_id,
+                buyer_nft_contract_id,
+                buyer_token_id,
+                seller_tokens,
+            );
+        }
+
+        let trade_data = trade_list
+            .trade_data
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Trade data does not exist");
+
+        if trade_data.seller_amount.is_some() {
+            assert_eq!(
+                trade_data.seller_id.as_ref(),
+                Some(&seller_id),
+                "Marble: Trade is funded by another seller"
+            );
+        }
+        assert_trade_owner(trade_data, &seller_id);
+        assert!(!is_trade_expired(trade_data), "Marble: Trade has expired");
+        let cash = trade_cash(trade_data);
+        let (buyer_tokens, seller_tokens) = trade_bundle(
+            trade_data,
+            TradeToken {
+                nft_contract_id: buyer_nft_contract_id.clone(),
+                token_id: buyer_token_id.clone(),
+                approval_id: Some(trade_list.approval_id),
+            },
+            TradeToken {
+                nft_contract_id: nft_contract_id.clone(),
+                token_id: token_id.clone(),
+                approval_id: Some(approval_id),
+            },
+            seller_tokens,
+        );
+
+        for token in buyer_tokens.iter().chain(seller_tokens.iter()) {
+            self.internal_delete_market_data(&token.nft_contract_id, &token.token_id);
+        }
+
+        let seller_contract_account_id_token_id =
+            make_triple(&nft_contract_id, &seller_id, &token_id);
+
+        self.internal_remove_trade_list(&seller_id, &seller_contract_account_id_token_id, None);
+        self.internal_remove_trade_list(
+            &buyer_id,
+            &buyer_contract_account_id_token_id,
+            Some(&contract_account_id_token_id),
+        );
+
+        self.trade_swap_nft(buyer_id, buyer_tokens, cash, seller_id, seller_tokens)
+    }
+
+    fn internal_accept_trade_series(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+        seller_tokens: Option<Vec<TradeToken>>,
+    ) -> Promise {
+        // Token delimiter : is specific for Marble NFT
+        let mut token_id_iter = token_id.split(":");
+        let token_series_id: String = token_id_iter.next().unwrap().parse().unwrap();
+
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+        let contract_account_id_token_id =
+            make_triple(&nft_contract_id, &buyer_id, &token_series_id);
+
+        let trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        // a trade on this exact edition matches as well
+        if !trade_list
+            .trade_data
+            .contains_key(&contract_account_id_token_id)
+            && trade_list.trade_data.contains_key(&make_triple(
+                &nft_contract_id,
+                &buyer_id,
+                &token_id,
+            ))
+        {
+            return self.internal_accept_trade(
+                nft_contract_id,
+                buyer_id,
+                token_id,
+                seller_id,
+                approval_id,
+                buyer_nft_contract_id,
+                buyer_token_id,
+                seller_tokens,
+            );
+        }
+
+        let trade_data = trade_list
+            .trade_data
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Trade data does not exist");
+
+        assert_eq!(
+            trade_data.token_series_id.as_ref().unwrap(),
+            &token_series_id
+        );
+        if trade_data.seller_amount.is_some() {
+            assert_eq!(
+                trade_data.seller_id.as_ref(),
+                Some(&seller_id),
+                "Marble: Trade is funded by another seller"
+            );
+        }
+        assert_trade_owner(trade_data, &seller_id);
+        assert!(!is_trade_expired(trade_data), "Marble: Trade has expired");
+        let cash = trade_cash(trade_data);
+        let (buyer_tokens, seller_tokens) = trade_bundle(
+            trade_data,
+            TradeToken {
+                nft_contract_id: buyer_nft_contract_id.clone(),
+                token_id: buyer_token_id.clone(),
+                approval_id: Some(trade_list.approval_id),
+            },
+            TradeToken {
+                nft_contract_id: nft_contract_id.clone(),
+                token_id: token_id.clone(),
+                approval_id: Some(approval_id),
+            },
+            seller_tokens,
+        );
+
+        for token in buyer_tokens.iter().chain(seller_tokens.iter()) {
+            self.internal_delete_market_data(&token.nft_contract_id, &token.token_id);
+        }
+
+        let seller_contract_account_id_token_id =
+            make_triple(&nft_contract_id, &seller_id, &token_id);
+        self.internal_remove_trade_list(&seller_id, &seller_contract_account_id_token_id, None);
+        self.internal_remove_trade_list(
+            &buyer_id,
+            &buyer_contract_account_id_token_id,
+            Some(&contract_account_id_token_id),
+        );
+
+        self.trade_swap_nft(buyer_id, buyer_tokens, cash, seller_id, seller_tokens)
+    }
+
+    fn internal_counter_trade(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        seller_id: AccountId,
+        approval_id: u64,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+        counter_nft_contract_id: Option<AccountId>,
+        counter_token_id: Option<TokenId>,
+        buyer_amount: Option<u128>,
+    ) {
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+
+        let mut trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        // a trade on a specific token takes precedence over a series trade
+        let mut contract_account_id_token_id = make_triple(&nft_contract_id, &buyer_id, &token_id);
+        if !trade_list
+            .trade_data
+            .contains_key(&contract_account_id_token_id)
+            && self.marble_nft_contracts.contains(&nft_contract_id)
+        {
+            // Token delimiter : is specific for Marble NFT
+            let token_series_id = token_id.split(":").next().unwrap();
+            contract_account_id_token_id =
+                make_triple(&nft_contract_id, &buyer_id, token_series_id);
+        }
+
+        let trade_data = trade_list
+            .trade_data
+            .get(&contract_account_id_token_id)
+            .expect("Marble: Trade data does not exist");
+
+        assert!(!is_trade_expired(trade_data), "Marble: Trade has expired");
+        assert!(
+            trade_data.seller_tokens.is_none(),
+            "Marble: Cannot counter a bundle trade"
+        );
+        if trade_data.seller_amount.is_some() {
+            assert_eq!(
+                trade_data.seller_id.as_ref(),
+                Some(&seller_id),
+                "Marble: Trade is funded by another seller"
+            );
+        }
+        assert_trade_owner(trade_data, &seller_id);
+
+        let counter_nft_contract_id =
+            counter_nft_contract_id.unwrap_or(buyer_nft_contract_id.clone());
+        let counter_token_id = counter_token_id.unwrap_or(buyer_token_id.clone());
+        let current_buyer_amount = trade_data.buyer_amount.unwrap_or(0);
+        let buyer_amount = buyer_amount.unwrap_or(current_buyer_amount);
+        let max_price = self.internal_max_price(
+            &trade_data
+                .ft_token_id
+                .as_ref()
+                .map_or(near_account(), |ft_token_id| {
+                    AccountId::new_unchecked(ft_token_id.clone())
+                }),
+        );
+        assert!(
+            buyer_amount < max_price,
+            "Marble: price higher than {}",
+            max_price
+        );
+        assert!(
+            buyer_amount >= current_buyer_amount,
+            "Marble: Counter amount is below the trade deposit"
+        );
+        assert!(
+            counter_nft_contract_id != buyer_nft_contract_id
+                || counter_token_id != buyer_token_id
+                || buyer_amount > current_buyer_amount,
+            "Marble: Counter does not change the trade"
+        );
+
+        trade_list.history.push(TradeCounterData {
+            contract_account_id_token_id: contract_account_id_token_id.clone(),
+            seller_id: seller_id.clone(),
+            token_id: token_id.clone(),
+            approval_id,
+            buyer_nft_contract_id: counter_nft_contract_id.clone(),
+            buyer_token_id: counter_token_id.clone(),
+            buyer_amount,
+            created_at: env::block_timestamp(),
+        });
+        if trade_list.history.len() > MAX_TRADE_HISTORY {
+            trade_list.history.remove(0);
+        }
+        self.trades
+            .insert(&buyer_contract_account_id_token_id, &trade_list);
+        self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
+
+        env::log_str(
+            &json!({
+                "type": "counter_trade",
+                "params": {
+                    "seller_id": seller_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "buyer_nft_contract_id": buyer_nft_contract_id,
+                    "buyer_token_id": buyer_token_id,
+                    "counter_nft_contract_id": counter_nft_contract_id,
+                    "counter_token_id": counter_token_id,
+                    "buyer_amount": U128(buyer_amount),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // the buyer approves the token the counter asks for, cash must already be escrowed
+    fn internal_accept_counter_trade(
+        &mut self,
+        counter_nft_contract_id: AccountId,
+        counter_token_id: TokenId,
+        buyer_id: AccountId,
+        counter_approval_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) -> Promise {
+        let buyer_contract_account_id_token_id =
+            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
+
+        let trade_list = self
+            .trades
+            .get(&buyer_contract_account_id_token_id)
+            .expect("Marble: Trade list does not exist");
+
+        let counter = trade_list
+            .history
+            .iter()
+            .rev()
+            .find(|counter| {
+                counter.buyer_nft_contract_id == counter_nft_contract_id
+                    && counter.buyer_token_id == counter_token_id
+                    && counter.token_id == token_id
+            })
+            .expect("Marble: Counter does not exist")
+            .clone();
+
+        let trade_data = trade_list
+            .trade_data
+            .get(&counter.contract_account_id_token_id)
+            .expect("Marble: Trade data does not exist");
+
+        assert_eq!(trade_data.nft_contract_id, nft_contract_id);
+        assert!(!is_trade_expired(trade_data), "Marble: Trade has expired");
+        assert!(
+            trade_data.buyer_amount.unwrap_or(0) >= counter.buyer_amount,
+            "Marble: Trade deposit is below the counter amount"
+        );
+
+        let cash = trade_cash(trade_data);
+        let (buyer_tokens, seller_tokens) = trade_bundle(
+            trade_data,
+            TradeToken {
+                nft_contract_id: counter_nft_contract_id.clone(),
+                token_id: counter_token_id.clone(),
+                approval_id: Some(counter_approval_id),
+            },
+            TradeToken {
+                nft_contract_id: nft_contract_id.clone(),
+                token_id: token_id.clone(),
+                approval_id: Some(counter.approval_id),
+            },
+            None,
+        );
+
+        for token in buyer_tokens.iter().chain(seller_tokens.iter()) {
+            self.internal_delete_market_data(&token.nft_contract_id, &token.token_id);
+        }
+
+        let seller_contract_account_id_token_id =
+            make_triple(&nft_contract_id, &counter.seller_id, &token_id);
+        self.internal_remove_trade_list(
+            &counter.seller_id,
+            &seller_contract_account_id_token_id,
+            None,
+        );
+        self.internal_remove_trade_list(
+            &buyer_id,
+            &buyer_contract_account_id_token_id,
+            Some(&counter.contract_account_id_token_id),
+        );
+        let counter_contract_account_id_token_id =
+            make_triple(&counter_nft_contract_id, &buyer_id, &counter_token_id);
+        if counter_contract_account_id_token_id != buyer_contract_account_id_token_id {
+            self.internal_remove_trade_list(&buyer_id, &counter_contract_account_id_token_id, None);
+        }
+
+        self.trade_swap_nft(
+            buyer_id,
+            buyer_tokens,
+            cash,
+            counter.seller_id,
+            seller_tokens,
+        )
+    }
+
+    pub fn get_trade_counters(
+        &self,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) -> Vec<TradeCounterData> {
+        self.trades
+            .get(&make_triple(
+                &buyer_nft_contract_id,
+                &buyer_id,
+                &buyer_token_id,
+            ))
+            .map_or(vec![], |trade_list| trade_list.history)
+    }
+
+    pub fn get_trade_history(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<TradeRecord> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.trade_history.len());
+
+        self.trade_history
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    fn trade_swap_nft(
+        &mut self,
+        buyer_id: AccountId,
+        buyer_tokens: Vec<TradeToken>,
+        cash: TradeCash,
+        seller_id: AccountId,
+        seller_tokens: Vec<TradeToken>,
+    ) -> Promise {
+        // 1. verify the owner and marketplace approval of every NFT before anything moves
+        // 2. escrow the buyer NFTs on the marketplace, they can still be returned
+        // 3. once all are escrowed, transfer the seller NFTs directly to the buyer
+        // 4. if all NFTs arrived then forward the escrowed ones and release the cash
+        // 5. if any failed then return the escrowed NFTs and refund the cash, a failed
+        //    return is left as a stuck NFT claim for its owner
+
+        let cash =
+            self.internal_lock_trade_fee(&seller_id, &seller_tokens[0].nft_contract_id, cash);
+        let gas = Gas(self
+            .config
+            .gas_for_callback_verify_trade(buyer_tokens.len(), seller_tokens.len())
+            .0
+            + self.config.base_gas.0);
+        buyer_tokens
+            .iter()
+            .chain(seller_tokens.iter())
+            .map(|token| {
+                ext_contract::nft_token(
+                    token.token_id.clone(),
+                    token.nft_contract_id.clone(),
+                    NO_DEPOSIT,
+                    self.config.gas_for_nft_token,
+                )
+            })
+            .reduce(|promise, next| promise.and(next))
+            .unwrap()
+            .then(ext_self::callback_verify_trade(
+                buyer_id,
+                buyer_tokens,
+                cash,
+                seller_id,
+                seller_tokens,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas,
+            ))
+    }
+
+    // failures below log instead of panic, a panic would drop the refund receipts
+
+    #[private]
+    pub fn callback_verify_trade(
+        &mut self,
+        buyer_id: AccountId,
+        buyer_tokens: Vec<TradeToken>,
+        cash: TradeCash,
+        seller_id: AccountId,
+        seller_tokens: Vec<TradeToken>,
+    ) {
+        // results are in the order of buyer tokens then seller tokens
+        let stale_error = buyer_tokens
+            .iter()
+            .map(|token| (&buyer_id, token))
+            .chain(seller_tokens.iter().map(|token| (&seller_id, token)))
+            .enumerate()
+            .find_map(|(index, (owner_id, token))| stale_trade_token_error(index, owner_id, token));
+        if let Some(stale_error) = stale_error {
+            self.internal_refund_escrowed_cash(&buyer_id, cash, &seller_id);
+            env::log_str(&stale_error);
+            return;
+        }
+
+        let gas = self
+            .config
+            .gas_for_callback_escrow_trade(buyer_tokens.len(), seller_tokens.len());
+        nft_transfer_trade_tokens(&self.config, &env::current_account_id(), &buyer_tokens).then(
+            ext_self::callback_escrow_trade(
+                buyer_id,
+                buyer_tokens,
+                cash,
+                seller_id,
+                seller_tokens,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas,
+            ),
+        );
+    }
+
+    #[private]
+    pub fn callback_escrow_trade(
+        &mut self,
+        buyer_id: AccountId,
+        buyer_tokens: Vec<TradeToken>,
+        cash: TradeCash,
+        seller_id: AccountId,
+        seller_tokens: Vec<TradeToken>,
+    ) {
+        // results are in the order of buyer tokens
+        let escrowed_tokens = transferred_trade_tokens(&buyer_tokens);
+        if escrowed_tokens.len() < buyer_tokens.len() {
+            self.internal_fail_trade(
+                &buyer_id,
+                &buyer_tokens,
+                cash,
+                &seller_id,
+                &seller_tokens,
+                escrowed_tokens,
+                vec![],
+            );
+            return;
+        }
+
+        let gas = self
+            .config
+            .gas_for_callback_resolve_trade(buyer_tokens.len());
+        nft_transfer_trade_tokens(&self.config, &buyer_id, &seller_tokens).then(
+            ext_self::callback_resolve_trade(
+                buyer_id,
+                buyer_tokens,
+                cash,
+                seller_id,
+                seller_tokens,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas,
+            ),
+        );
+    }
+
+    #[private]
+    pub fn callback_resolve_trade(
+        &mut self,
+        buyer_id: AccountId,
+        buyer_tokens: Vec<TradeToken>,
+        cash: TradeCash,
+        seller_id: AccountId,
+        seller_tokens: Vec<TradeToken>,
+    ) {
+        // results are in the order of seller tokens, every buyer token is escrowed by now
+        let transferred_tokens = transferred_trade_tokens(&seller_tokens);
+        if transferred_tokens.len() < seller_tokens.len() {
+            self.internal_fail_trade(
+                &buyer_id,
+                &buyer_tokens,
+                cash,
+                &seller_id,
+                &seller_tokens,
+                buyer_tokens.clone(),
+                transferred_tokens,
+            );
+            return;
+        }
+
+        self.internal_release_trade_escrow(&seller_id, &buyer_tokens);
+        let buyer_fee = cash.buyer_amount.0 * cash.fee_bps as u128 / 10_000u128;
+        let seller_fee = cash.seller_amount.0 * cash.fee_bps as u128 / 10_000u128;
+        if cash.buyer_amount.0 > buyer_fee {
+            self.internal_transfer(
+                &cash.ft_token_id,
+                seller_id.clone(),
+                cash.buyer_amount.0 - buyer_fee,
+            );
+        }
+        if cash.seller_amount.0 > seller_fee {
+            self.internal_transfer(
+                &cash.ft_token_id,
+                buyer_id.clone(),
+                cash.seller_amount.0 - seller_fee,
+            );
+        }
+        self.internal_transfer_treasury_fee(&cash.ft_token_id, buyer_fee + seller_fee, None);
+        let trade_fee_ft_token_id = cash
+            .trade_fee_ft_token_id
+            .clone()
+            .unwrap_or_else(near_account);
+        self.internal_transfer_treasury_fee(&trade_fee_ft_token_id, cash.trade_fee.0, None);
+
+        let trade_index = self.trade_history.len();
+        self.trade_history.push(&TradeRecord {
+            buyer_id: buyer_id.clone(),
+            seller_id: seller_id.clone(),
+            buyer_tokens: buyer_tokens.clone(),
+            seller_tokens: seller_tokens.clone(),
+            ft_token_id: cash.ft_token_id.clone(),
+            buyer_amount: cash.buyer_amount,
+            seller_amount: cash.seller_amount,
+            traded_at: env::block_timestamp(),
+        });
+
+        // one event per moved token, like resolve_purchase for sales
+        let transfers = buyer_tokens
+            .iter()
+            .map(|token| (&buyer_id, &seller_id, token))
+            .chain(
+                seller_tokens
+                    .iter()
+                    .map(|token| (&seller_id, &buyer_id, token)),
+            );
+        for (owner_id, receiver_id, token) in transfers {
+            env::log_str(
+                &json!({
+                    "type": "resolve_trade",
+                    "params": {
+                        "owner_id": owner_id,
+                        "nft_contract_id": token.nft_contract_id,
+                        "token_id": token.token_id,
+                        "receiver_id": receiver_id,
+                        "trade_index": U64(trade_index),
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        env::log_str(
+            &json!({
+                "type": "accept_trade",
+                "params": {
+                    "sender_id": seller_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": seller_tokens[0].nft_contract_id,
+                    "token_id": seller_tokens[0].token_id,
+                    "buyer_nft_contract_id": buyer_tokens[0].nft_contract_id,
+                    "buyer_token_id": buyer_tokens[0].token_id,
+                    "ft_token_id": cash.ft_token_id,
+                    "buyer_amount": cash.buyer_amount,
+                    "seller_amount": cash.seller_amount,
+                    "trade_fee": cash.trade_fee,
+                    "trade_fee_ft_token_id": trade_fee_ft_token_id,
+                    "cash_fee": U128(buyer_fee + seller_fee),
+                    "trade_index": U64(trade_index),
+                    "buyer_tokens": buyer_tokens,
+                    "seller_tokens": seller_tokens,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_lock_trade_fee(
+        &mut self,
+        seller_id: &AccountId,
+        nft_contract_id: &AccountId,
+        cash: TradeCash,
+    ) -> TradeCash {
+        if self.trade_fee.marble_exempt && self.marble_nft_contracts.contains(nft_contract_id) {
+            return cash;
+        }
+
+        let trade_fee = self.trade_fee.flat_fee.0;
+        if trade_fee > 0 {
+            // a fee prepaid in an FT replaces the NEAR one
+            if let Some(payment) = self.trade_fee_payments.remove(seller_id) {
+                return TradeCash {
+                    trade_fee: payment.amount,
+                    fee_bps: self.trade_fee.fee_bps,
+                    trade_fee_ft_token_id: Some(payment.ft_token_id),
+                    ..cash
+                };
+            }
+            let storage_deposit = self.storage_deposits.get(seller_id).unwrap_or(0);
+            let storage_required = self.get_supply_by_owner_id(seller_id.clone()).0 as u128
+                * self.config.storage_add_market_data.0;
+            assert!(
+                storage_deposit >= storage_required + trade_fee,
+                "Marble: Insufficient storage deposit for the trade fee"
+            );
+            self.storage_deposits
+                .insert(seller_id, &(storage_deposit - trade_fee));
+        }
+
+        TradeCash {
+            trade_fee: U128(trade_fee),
+            fee_bps: self.trade_fee.fee_bps,
+            ..cash
+        }
+    }
+
+    // escrowed NFTs go back to the buyer, seller NFTs that reached the buyer are only reported
+    fn internal_fail_trade(
+        &mut self,
+        buyer_id: &AccountId,
+        buyer_tokens: &[TradeToken],
+        cash: TradeCash,
+        seller_id: &AccountId,
+        seller_tokens: &[TradeToken],
+        escrowed_tokens: Vec<TradeToken>,
+        transferred_tokens: Vec<TradeToken>,
+    ) {
+        self.internal_release_trade_escrow(buyer_id, &escrowed_tokens);
+        self.internal_refund_escrowed_cash(buyer_id, cash, seller_id);
+        env::log_str(
+            &json!({
+                "type": "trade_failed",
+                "params": {
+                    "sender_id": seller_id,
+                    "buyer_id": buyer_id,
+                    "buyer_tokens": buyer_tokens,
+                    "seller_tokens": seller_tokens,
+                    "returned_tokens": escrowed_tokens,
+                    "transferred_tokens": transferred_tokens,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // a failed transfer out of escrow is recorded as a stuck NFT claim for the receiver
+    fn internal_release_trade_escrow(&self, receiver_id: &AccountId, tokens: &[TradeToken]) {
+        for token in tokens {
+            nft_transfer_stuck_nft(
+                &self.config,
+                token.nft_contract_id.clone(),
+                token.token_id.clone(),
+                receiver_id.clone(),
+            );
+        }
+    }
+
+    fn internal_refund_escrowed_cash(
+        &mut self,
+        buyer_id: &AccountId,
+        cash: TradeCash,
+        seller_id: &AccountId,
+    ) {
+        if let Some(ft_token_id) = cash.trade_fee_ft_token_id.clone() {
+            self.trade_fee_payments.insert(
+                seller_id,
+                &TradeFeePayment {
+                    ft_token_id,
+                    amount: cash.trade_fee,
+                },
+            );
+        } else if cash.trade_fee.0 > 0 {
+            let storage_deposit = self.storage_deposits.get(seller_id).unwrap_or(0);
+            self.storage_deposits
+                .insert(seller_id, &(storage_deposit + cash.trade_fee.0));
+        }
+        if cash.buyer_amount.0 > 0 {
+            self.internal_transfer(&cash.ft_token_id, buyer_id.clone(), cash.buyer_amount.0);
+        }
+        if cash.seller_amount.0 > 0 {
+            self.internal_transfer(&cash.ft_token_id, seller_id.clone(), cash.seller_amount.0);
+        }
+    }
+
+    // Returns NFTs left on the marketplace by custody trades
+    #[payable]
+    pub fn rescue_stuck_nft(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        receiver_id: AccountId,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+
+        env::log_str(
+            &json!({
+                "type": "rescue_stuck_nft",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "receiver_id": receiver_id,
+                }
+            })
+            .to_string(),
+        );
+
+        nft_transfer_stuck_nft(&self.config, nft_contract_id, token_id, receiver_id)
+    }
+
+    // Retries a failed return, the claim is recorded again if it fails once more
+    #[payable]
+    pub fn claim_stuck_nft(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> Promise {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let owner_id = self
+            .stuck_nft_claims
+            .remove(&contract_and_token_id)
+            .expect("Marble: Claim does not exist");
+        assert_eq!(
+            env::predecessor_account_id(),
+            owner_id,
+            "Marble: Only the rightful owner can claim"
+        );
+
+        nft_transfer_stuck_nft(&self.config, nft_contract_id, token_id, owner_id)
+    }
+
+    #[private]
+    pub fn callback_return_stuck_nft(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        receiver_id: AccountId,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if is_promise_success() {
+            self.stuck_nft_claims.remove(&contract_and_token_id);
+            return;
+        }
+
+        self.stuck_nft_claims
+            .insert(&contract_and_token_id, &receiver_id);
+        env::log_str(
+            &json!({
+                "type": "add_stuck_nft_claim",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "owner_id": receiver_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_stuck_nft_claim(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<AccountId> {
+        self.stuck_nft_claims
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+    }
+
+    // Ring trades, every participant approves their token and the last approval settles
+    #[payable]
+    pub fn add_ring_trade(
+        &mut self,
+        participants: Vec<RingTradeParticipant>,
+        expires_at: Option<U64>,
+    ) -> U64 {
+        self.assert_not_paused(PauseFeature::Trades);
+        self.assert_not_blacklisted_seller(&env::predecessor_account_id());
+        let creator_id = env::predecessor_account_id();
+        assert!(
+            env::attached_deposit() >= self.config.storage_add_market_data.0,
+            "Marble: Attach at least {} yoctoNEAR for storage",
+            self.config.storage_add_market_data.0
+        );
+        assert_eq!(
+            participants.len(),
+            RING_TRADE_SIZE,
+            "Marble: Ring trade needs {} participants",
+            RING_TRADE_SIZE
+        );
+        assert!(
+            participants
+                .iter()
+                .any(|participant| participant.account_id == creator_id),
+            "Marble: Creator is not a participant"
+        );
+        let expires_at = expires_at.map(|expires_at| expires_at.0);
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at > env::block_timestamp(),
+                "Marble: expires_at is in the past"
+            );
+        }
+
+        let mut account_ids = HashSet::new();
+        let mut token_ids = HashSet::new();
+        for participant in &participants {
+            assert!(
+                self.approved_nft_contract_ids
+                    .contains(&participant.nft_contract_id),
+                "Marble: nft_contract_id is not approved"
+            );
+            assert!(
+                account_ids.insert(participant.account_id.clone()),
+                "Marble: Duplicate ring trade participants"
+            );
+            assert!(
+                token_ids.insert((
+                    participant.nft_contract_id.clone(),
+                    participant.token_id.clone()
+                )),
+                "Marble: Duplicate ring trade tokens"
+            );
+        }
+        // approvals come in through nft_on_approve
+        let participants: Vec<RingTradeParticipant> = participants
+            .into_iter()
+            .map(|participant| RingTradeParticipant {
+                approval_id: None,
+                ..participant
+            })
+            .collect();
+
+        let ring_trade_id = self.next_ring_trade_id;
+        self.next_ring_trade_id += 1;
+        self.ring_trades.insert(
+            &ring_trade_id,
+            &RingTrade {
+                creator_id: creator_id.clone(),
+                participants: participants.clone(),
+                storage_deposit: env::attached_deposit(),
+                expires_at: expires_at,
+                created_at: env::block_timestamp(),
+            },
+        );
+
+        env::log_str(
+            &json!({
+                "type": "add_ring_trade",
+                "params": {
+                    "ring_trade_id": U64(ring_trade_id),
+                    "creator_id": creator_id,
+                    "participants": participants,
+                    "expires_at": expires_at,
+                }
+            })
+            .to_string(),
+        );
+
+        U64(ring_trade_id)
+    }
+
+    #[payable]
+    pub fn delete_ring_trade(&mut self, ring_trade_id: U64) {
+        assert_one_yocto();
+        let ring_trade = self
+            .ring_trades
+            .get(&ring_trade_id.0)
+            .expect("Marble: Ring trade does not exist");
+        let account_id = env::predecessor_account_id();
+        assert!(
+            ring_trade
+                .participants
+                .iter()
+                .any(|participant| participant.account_id == account_id),
+            "Marble: Ring trade participants only"
+        );
+
+        self.ring_trades.remove(&ring_trade_id.0);
+        Promise::new(ring_trade.creator_id).transfer(ring_trade.storage_deposit);
+
+        env::log_str(
+            &json!({
+                "type": "delete_ring_trade",
+                "params": {
+                    "ring_trade_id": ring_trade_id,
+                    "account_id": account_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_ring_trade(&self, ring_trade_id: U64) -> Option<RingTrade> {
+        self.ring_trades.get(&ring_trade_id.0)
+    }
+
+    fn internal_approve_ring_trade(
+        &mut self,
+        ring_trade_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+    ) {
+        let mut ring_trade = self
+            .ring_trades
+            .get(&ring_trade_id)
+            .expect("Marble: Ring trade does not exist");
+        assert!(
+            ring_trade
+                .expires_at
+                .map_or(true, |expires_at| env::block_timestamp() < expires_at),
+            "Marble: Ring trade has expired"
+        );
+        let participant = ring_trade
+            .participants
+            .iter_mut()
+            .find(|participant| {
+                participant.account_id == owner_id
+                    && participant.nft_contract_id == nft_contract_id
+                    && participant.token_id == token_id
+            })
+            .expect("Marble: Token is not in the ring trade");
+        participant.approval_id = Some(approval_id);
+
+        env::log_str(
+            &json!({
+                "type": "approve_ring_trade",
+                "params": {
+                    "ring_trade_id": U64(ring_trade_id),
+                    "owner_id": owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                }
+            })
+            .to_string(),
+        );
+
+        if ring_trade
+            .participants
+            .iter()
+            .any(|participant| participant.approval_id.is_none())
+        {
+            self.ring_trades.insert(&ring_trade_id, &ring_trade);
+            return;
+        }
+
+        self.ring_trades.remove(&ring_trade_id);
+        Promise::new(ring_trade.creator_id.clone()).transfer(ring_trade.storage_deposit);
+        for participant in &ring_trade.participants {
+            self.internal_delete_market_data(&participant.nft_contract_id, &participant.token_id);
+        }
+
+        let gas = Gas(
+            self.config.gas_for_nft_transfer.0 * ring_trade.participants.len() as u64
+                + self.config.base_gas.0 * 2,
+        );
+        ring_trade
+            .participants
+            .iter()
+            .map(|participant| {
+                ext_contract::nft_token(
+                    participant.token_id.clone(),
+                    participant.nft_contract_id.clone(),
+                    NO_DEPOSIT,
+                    self.config.gas_for_nft_token,
+                )
+            })
+            .reduce(|promise, next| promise.and(next))
+            .unwrap()
+            .then(ext_self::callback_verify_ring_trade(
+                U64(ring_trade_id),
+                ring_trade.participants,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas,
+            ));
+    }
+
+    #[private]
+    pub fn callback_verify_ring_trade(
+        &mut self,
+        ring_trade_id: U64,
+        participants: Vec<RingTradeParticipant>,
+    ) {
+        let stale_error = participants
+            .iter()
+            .enumerate()
+            .find_map(|(index, participant)| {
+                stale_trade_token_error(
+                    index,
+                    &participant.account_id,
+                    &ring_trade_token(participant),
+                )
+            });
+        if let Some(stale_error) = stale_error {
+            env::log_str(&stale_error);
+            return;
+        }
+
+        // every token moves straight to the next participant
+        participants
+            .iter()
+            .enumerate()
+            .map(|(index, participant)| {
+                ext_contract::nft_transfer(
+                    participants[(index + 1) % participants.len()]
+                        .account_id
+                        .clone(),
+                    participant.token_id.clone(),
+                    participant.approval_id,
+                    participant.nft_contract_id.clone(),
+                    1,
+                    self.config.gas_for_nft_transfer,
+                )
+            })
+            .reduce(|promise, next| promise.and(next))
+            .unwrap()
+            .then(ext_self::callback_resolve_ring_trade(
+                ring_trade_id,
+                participants,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.config.base_gas,
+            ));
+    }
+
+    #[private]
+    pub fn callback_resolve_ring_trade(
+        &mut self,
+        ring_trade_id: U64,
+        participants: Vec<RingTradeParticipant>,
+    ) {
+        let transferred_tokens: Vec<TradeToken> = participants
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| promise_result_at(*index as u64).is_some())
+            .map(|(_, participant)| ring_trade_token(participant))
+            .collect();
+        let is_complete = transferred_tokens.len() == participants.len();
+
+        env::log_str(
+            &json!({
+                "type": if is_complete { "resolve_ring_trade" } else { "ring_trade_failed" },
+                "params": {
+                    "ring_trade_id": ring_trade_id,
+                    "participants": participants,
+                    "transferred_tokens": transferred_tokens,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // Auction bids
+    #[payable]
+    pub fn add_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        ft_token_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+    ) {
+        self.assert_not_paused(PauseFeature::Buys);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Token id does not exist");
+
+        let bidder_id = env::predecessor_account_id();
+        let current_time = env::block_timestamp();
+
+        if market_data.started_at.is_some() {
+            assert!(
+                current_time >= market_data.started_at.unwrap(),
+                "Marble: Sale has not started yet"
+            );
+        }
+
+        if market_data.ended_at.is_some() {
+            assert!(
+                current_time <= market_data.ended_at.unwrap(),
+                "Marble: Sale has ended"
+            );
+        }
+
+        let remaining_time = market_data.ended_at.unwrap() - current_time;
+        if remaining_time <= self.config.anti_snipe_window.0 {
+            let extended_ended_at = market_data.ended_at.unwrap() + self.config.anti_snipe_window.0;
+            market_data.ended_at = Some(extended_ended_at);
+
+            env::log_str(
+                &json!({
+                    "type": "extend_auction",
+                    "params": {
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                        "ended_at": extended_ended_at,
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        assert_ne!(
+            market_data.owner_id, bidder_id,
+            "Marble: Owner cannot bid their own token"
+        );
+
+        assert!(
+            env::attached_deposit() >= amount.into(),
+            "Marble: attached deposit is less than amount"
+        );
+
+        assert_eq!(ft_token_id.to_string(), "near", "Marble: Only support NEAR");
+        assert_eq!(
+            market_data.ft_token_id.to_string(),
+            "near",
+            "Marble: Only support Registered token"
+        );
+
+        assert!(
+            market_data.end_price.is_none(),
+            "Marble: Dutch auction does not accept add_bid"
+        );
+
+        let new_bid = Bid {
+            bidder_id: bidder_id.clone(),
+            price: amount.into(),
+        };
+
+        let mut bids = market_data.bids.take().unwrap_or(Vec::new());
+
+        if !bids.is_empty() {
+            let current_bid = &bids[bids.len() - 1];
+
+            assert!(
+                amount.0 >= current_bid.price.0 + (current_bid.price.0 / 100 * 5),
+                "Marble: Can't pay less than or equal to current bid price + 5% : {:?}",
+                current_bid.price.0 + (current_bid.price.0 / 100 * 5)
+            );
+
+            assert!(
+                amount.0 >= market_data.price,
+                "Marble: Can't pay less than starting price: {:?}",
+                U128(market_data.price)
+            );
+
+            if current_bid.bidder_id != bidder_id {
+                self.internal_log_outbid(&market_data, current_bid, &new_bid);
+            }
+            // Retain all elements except account_id
+            bids.retain(|bid| {
+                if bid.bidder_id == bidder_id {
+                    self.internal_refund_bid(&market_data, bid, "rebid");
+                }
+
+                bid.bidder_id != bidder_id
+            });
+        } else {
+            assert!(
+                amount.0 >= market_data.price,
+                "Marble: Can't pay less than starting price: {:?}",
+                market_data.price
+            );
+        }
+
+        bids.push(new_bid);
+        market_data.bids = Some(bids);
+        self.market.insert(&contract_and_token_id, &market_data);
+        self.internal_record_change(ChangeType::Market, &contract_and_token_id);
+        self.internal_add_bid_index(&bidder_id, &contract_and_token_id);
+
+        // Remove first element if bids.length > 50
+        let updated_bids = market_data.bids.unwrap_or(Vec::new());
+        if updated_bids.len() as u64 >= self.config.max_bids {
+            self.internal_cancel_bid(
+                nft_contract_id.clone(),
+                token_id.clone(),
+                updated_bids[0].bidder_id.clone(),
+                "bid_cap",
+            )
+        }
+
+        env::log_str(
+            &json!({
+                "type": "add_bid",
+                "params": {
+                    "bidder_id": bidder_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                    "ft_token_id": ft_token_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // amount is the accepted bid, ft_on_transfer returns whatever was transferred above it
+    #[payable]
+    fn internal_ft_token_add_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        ft_token_id: AccountId,
+        token_id: TokenId,
+        sender_id: AccountId,
+        amount: U128,
+    ) {
+        println!("\n\n\nFT TOken Bid Added");
+        assert!(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            "Marble: ft_token_id not approved"
+        );
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Token id does not exist");
+
+        let bidder_id = sender_id;
+        let current_time = env::block_timestamp();
+        if market_data.started_at.is_some() {
+            assert!(
+                current_time >= market_data.started_at.unwrap(),
+                "Marble: Sale has not started yet"
+            );
+        }
+
+        println!(
+            "\n\n\nFT TOken Bid Added: {}, {}, {}",
+            bidder_id, ft_token_id, token_id
+        );
+
+        if market_data.ended_at.is_some() {
+            assert!(
+                current_time <= market_data.ended_at.unwrap(),
+                "Marble: Sale has ended"
+            );
+        }
+
+        let remaining_time = market_data.ended_at.unwrap() - current_time;
+        if remaining_time <= self.config.anti_snipe_window.0 {
+            let extended_ended_at = market_data.ended_at.unwrap() + self.config.anti_snipe_window.0;
+            market_data.ended_at = Some(extended_ended_at);
+
+            env::log_str(
+                &json!({
+                    "type": "extend_auction",
+                    "params": {
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                        "ended_at": extended_ended_at,
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        assert_ne!(
+            market_data.owner_id, bidder_id,
+            "Marble: Owner cannot bid their own token"
+        );
+
+        assert_eq!(
+            ft_token_id.to_string(),
+            market_data.ft_token_id.to_string(),
+            "Marble: Only support Registered token"
+        );
+
+        assert!(
+            market_data.end_price.is_none(),
+            "Marble: Dutch auction does not accept add_bid"
+        );
+
+        let new_bid = Bid {
+            bidder_id: bidder_id.clone(),
+            price: amount.into(),
+        };
+
+        let mut bids = market_data.bids.take().unwrap_or(Vec::new());
+
+        if !bids.is_empty() {
+            let current_bid = &bids[bids.len() - 1];
+
+            assert!(
+                amount.0 >= current_bid.price.0 + (current_bid.price.0 / 100 * 5),
+                "Marble: Can't pay less than or equal to current bid price + 5% : {:?}",
+                current_bid.price.0 + (current_bid.price.0 / 100 * 5)
+            );
+
+            assert!(
+                amount.0 >= market_data.price,
+                "Marble: Can't pay less than starting price: {:?}",
+                U128(market_data.price)
+            );
+            if current_bid.bidder_id != bidder_id {
+                self.internal_log_outbid(&market_data, current_bid, &new_bid);
+            }
+            // Retain all elements except account_id
+            bids.retain(|bid| {
+                if bid.bidder_id == bidder_id {
+                    self.internal_refund_bid(&market_data, bid, "rebid");
+                }
+
+                bid.bidder_id != bidder_id
+            });
+        } else {
+            assert!(
+                amount.0 >= market_data.price,
+                "Marble: Can't pay less than starting price: {:?}",
+                market_data.price
+            );
+        }
+
+        bids.push(new_bid);
+        market_data.bids = Some(bids);
+        self.market.insert(&contract_and_token_id, &market_data);
+        self.internal_record_change(ChangeType::Market, &contract_and_token_id);
+        self.internal_add_bid_index(&bidder_id, &contract_and_token_id);
+
+        // Remove first element if bids.length > 50
+        let updated_bids = market_data.bids.unwrap_or(Vec::new());
+        if updated_bids.len() as u64 >= self.config.max_bids {
+            self.internal_cancel_bid(
+                nft_contract_id.clone(),
+                token_id.clone(),
+                updated_bids[0].bidder_id.clone(),
+                "bid_cap",
+            )
+        }
+
+        env::log_str(
+            &json!({
+                "type": "add_bid",
+                "params": {
+                    "bidder_id": bidder_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                    "ft_token_id": ft_token_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[private]
+    pub fn callback_post_withdraw_deposit(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        env::log_str(
+            &json!({
+                "type": "add_bid",
+                "params": {
+                    "token_id": token_id,
+                    "sender_id": sender_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+        println!("Promise withdraw ended: {:?}", env::promise_result(0));
+        // unregistered receivers or a paused token, the amount waits in claim_balance
+        if !is_promise_success() {
+            self.internal_credit_claimable_balance(&sender_id, &token_id, amount.0);
+        }
+
+        println!("\n\nPost Withdraw: {}, {}", token_id, sender_id);
+        U128(0)
+    }
+
+    fn internal_add_bid_index(
+        &mut self,
+        bidder_id: &AccountId,
+        contract_and_token_id: &ContractAndTokenId,
+    ) {
+        let mut contract_and_token_ids =
+            self.bids_by_bidder_id.get(bidder_id).unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::BidsByBidderIdInner {
+                        account_id_hash: hash_account_id(bidder_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        contract_and_token_ids.insert(contract_and_token_id);
+        self.bids_by_bidder_id
+            .insert(bidder_id, &contract_and_token_ids);
+    }
+
+    fn internal_remove_bid_index(
+        &mut self,
+        bidder_id: &AccountId,
+        contract_and_token_id: &ContractAndTokenId,
+    ) {
+        if let Some(mut contract_and_token_ids) = self.bids_by_bidder_id.get(bidder_id) {
+            contract_and_token_ids.remove(contract_and_token_id);
+            if contract_and_token_ids.is_empty() {
+                self.bids_by_bidder_id.remove(bidder_id);
+            } else {
+                self.bids_by_bidder_id
+                    .insert(bidder_id, &contract_and_token_ids);
+            }
+        }
+    }
+
+    fn internal_cancel_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        account_id: AccountId,
+        reason: &str,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Token id does not exist");
+
+        let mut bids = market_data.bids.take().unwrap();
+
+        assert!(!bids.is_empty(), "Marble: Bids data does not exist");
+
+        for x in 0..bids.len() {
+            if bids[x].bidder_id == account_id {
+                self.internal_refund_bid(&market_data, &bids[x], reason);
+            }
+        }
+
+        bids.retain(|bid| bid.bidder_id != account_id);
+        self.internal_remove_bid_index(&account_id, &contract_and_token_id);
+
+        market_data.bids = Some(bids);
+        self.market.insert(&contract_and_token_id, &market_data);
+        self.internal_record_change(ChangeType::Market, &contract_and_token_id);
+
+        env::log_str(
+            &json!({
+              "type": "cancel_bid",
+              "params": {
+                "bidder_id": account_id, "nft_contract_id": nft_contract_id, "token_id": token_id, "listing_id": self.internal_listing_id(&nft_contract_id, &token_id)
+              }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn cancel_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        account_id: AccountId,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Token id does not exist");
+
+        let bids = market_data.bids.unwrap();
+
+        assert!(!bids.is_empty(), "Marble: Bids data does not exist");
+
+        for x in 0..bids.len() {
+            if bids[x].bidder_id == account_id {
+                assert!(
+                    [bids[x].bidder_id.clone(), self.owner_id.clone()]
+                        .contains(&env::predecessor_account_id()),
+                    "Marble: Bidder or owner only"
+                );
+            }
+        }
+
+        self.internal_cancel_bid(nft_contract_id, token_id, account_id, "cancelled");
+    }
+
+    #[payable]
+    pub fn accept_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        self.assert_not_paused(PauseFeature::Buys);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Token id does not exist");
+        self.assert_not_blacklisted_seller(&market_data.owner_id);
+        let current_time: u64 = env::block_timestamp();
+
+        let mut bids = market_data.bids.take().unwrap();
+
+        assert!(!bids.is_empty(), "Marble: Cannot accept bid with empty bid");
+
+        let selected_bid = bids.remove(bids.len() - 1);
+
+        println!(
+            "\nAccept Bid Accounts {:?}, {:?}, {:?}",
+            market_data.owner_id.clone(),
+            self.owner_id.clone(),
+            env::predecessor_account_id()
+        );
+        assert!(
+            [
+                market_data.owner_id.clone(),
+                self.owner_id.clone(),
+                selected_bid.bidder_id.clone()
+            ]
+            .contains(&env::predecessor_account_id()),
+            "Marble: Seller, owner or top bidder only"
+        );
+
+        if env::predecessor_account_id() != self.owner_id.clone() && market_data.ended_at.is_some()
+        {
+            assert!(
+                current_time >= market_data.ended_at.unwrap(),
+                "Marble: Auction has not ended yet"
+            );
+        }
+
+        if selected_bid.bidder_id == env::predecessor_account_id() {
+            assert!(
+                selected_bid.price.0 >= market_data.reserve_price.unwrap(),
+                "Marble: Your bid price isn't bigger than reserve price."
+            );
+        }
+
+        assert!(
+            market_data.end_price.is_none(),
+            "Marble: Dutch auction does not accept accept_bid"
+        );
+
+        // refund all except selected bids
+        self.internal_remove_bid_index(&selected_bid.bidder_id, &contract_and_token_id);
+        for bid in &bids {
+            self.internal_remove_bid_index(&bid.bidder_id, &contract_and_token_id);
+            self.internal_refund_bid(&market_data, bid, "bid_accepted");
+        }
+        bids.clear();
+
+        market_data.bids = Some(bids);
+        self.market.insert(&contract_and_token_id, &market_data);
+        self.internal_record_change(ChangeType::Market, &contract_and_token_id);
+
+        self.internal_process_purchase(
+            market_data.nft_contract_id,
+            token_id,
+            market_data.ft_token_id,
+            selected_bid.bidder_id.clone(),
+            selected_bid.bidder_id.clone(),
+            selected_bid.price.clone().0,
+            None,
+            self.config.gas_for_ft_payout,
+        );
+    }
+
+    // Market Data functions
+
+    #[payable]
+    pub fn update_market_data(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        mut reserve_price: Option<U128>,
+    ) {
+        self.assert_not_paused(PauseFeature::Listings);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_seller(&env::predecessor_account_id());
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Token id does not exist ");
+
+        let predecessor_id = env::predecessor_account_id();
+        assert!(
+            predecessor_id == market_data.owner_id
+                || self.is_listing_operator(market_data.owner_id.clone(), predecessor_id),
+            "Marble: Seller only"
+        );
+        assert!(
+            self.layaways.get(&contract_and_token_id).is_none(),
+            "Marble: Listing is locked by a layaway"
+        );
+
+        // bids were escrowed in the previous token, they are refunded rather than converted
+        let old_ft_token_id = market_data.ft_token_id.clone();
+        if ft_token_id != old_ft_token_id {
+            assert!(
+                self.approved_ft_token_ids.contains(&ft_token_id),
+                "Marble: ft_token_id not approved"
+            );
+            assert!(
+                !self.mint_sales.contains(&contract_and_token_id)
+                    && self.usd_prices.get(&contract_and_token_id).is_none(),
+                "Marble: NEAR support only"
+            );
+            assert!(
+                market_data.end_price.is_none(),
+                "Marble: Cannot switch the payment token of a dutch auction"
+            );
+
+            self.internal_refund_bids(&market_data, &contract_and_token_id, "listing_updated");
+            market_data.bids = market_data.bids.map(|_| Vec::new());
+            market_data.ft_token_id = ft_token_id.clone();
+
+            // the new listing currency is priced by this update, not by its alternative price
+            if let Some(mut alternative_prices) =
+                self.alternative_prices.get(&contract_and_token_id)
+            {
+                if alternative_prices.remove(&ft_token_id).is_some() {
+                    if alternative_prices.is_empty() {
+                        self.alternative_prices.remove(&contract_and_token_id);
+                    } else {
+                        self.alternative_prices
+                            .insert(&contract_and_token_id, &alternative_prices);
+                    }
+                }
+            }
+        }
+
+        let max_price = self.internal_max_price(&ft_token_id);
+        assert!(
+            price.0 < max_price,
+            "Marble: price higher than {}",
+            max_price
+        );
+
+        if reserve_price.is_some() {
+            assert!(
+                reserve_price.unwrap().0 >= price.0,
+                "Marble: Reserve price is more than starting price"
+            );
+        } else {
+            reserve_price = price.into();
+        }
+        market_data.reserve_price = match reserve_price {
+            Some(x) => Some(x.0),
+            None => None,
+        };
+
+        // USD listings keep their price in usd_prices
+        if self.usd_prices.get(&contract_and_token_id).is_some() {
+            self.usd_prices.insert(&contract_and_token_id, &price.0);
+        } else {
+            self.assert_min_listing_price(&nft_contract_id, &ft_token_id, price.0);
+            market_data.price = price.into();
+        }
+        self.market.insert(&contract_and_token_id, &market_data);
+        self.internal_record_change(ChangeType::Market, &contract_and_token_id);
+
+        env::log_str(
+            &json!({
+                "type": "update_market_data",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                    "ft_token_id": ft_token_id,
+                    "old_ft_token_id": old_ft_token_id,
+                    "price": price,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_refund_bids(
+        &mut self,
+        market_data: &MarketData,
+        contract_and_token_id: &ContractAndTokenId,
+        reason: &str,
+    ) {
+        if let Some(ref bids) = market_data.bids {
+            for bid in bids {
+                self.internal_remove_bid_index(&bid.bidder_id, contract_and_token_id);
+                self.internal_refund_bid(market_data, bid, reason);
+            }
+        };
+    }
+
+    // every bid that comes back is logged with why, for notification services
+    fn internal_refund_bid(&self, market_data: &MarketData, bid: &Bid, reason: &str) {
+        if market_data.ft_token_id == near_account() {
+            Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
+        } else {
+            self.internal_ft_transfer(&market_data.ft_token_id, bid.bidder_id.clone(), bid.price)
+                .then(ext_self::callback_post_withdraw_deposit(
+                    market_data.ft_token_id.clone(),
+                    bid.bidder_id.clone(),
+                    bid.price,
+                    env::current_account_id(),
+                    0,
+                    self.config.gas_for_ft_transfer,
+                ));
+        }
+
+        env::log_str(
+            &json!({
+                "type": "bid_refunded",
+                "params": {
+                    "bidder_id": bid.bidder_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                    "ft_token_id": market_data.ft_token_id,
+                    "amount": bid.price,
+                    "reason": reason,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // the outbid bid stays escrowed until it is cancelled or the auction ends
+    fn internal_log_outbid(&self, market_data: &MarketData, outbid: &Bid, new_bid: &Bid) {
+        env::log_str(
+            &json!({
+                "type": "outbid",
+                "params": {
+                    "bidder_id": outbid.bidder_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                    "ft_token_id": market_data.ft_token_id,
+                    "amount": outbid.price,
+                    "new_bidder_id": new_bid.bidder_id,
+                    "new_amount": new_bid.price,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_add_market_data(
+        &mut self,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        price: U128,
+        mut started_at: Option<U64>,
+        ended_at: Option<U64>,
+        end_price: Option<U128>,
+        is_auction: Option<bool>,
+        mut reserve_price: Option<U128>,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.assert_not_settling(&contract_and_token_id);
+        self.assert_below_active_limit(&owner_id, &contract_and_token_id);
+
+        let bids: Option<Bids> = match is_auction {
+            Some(u) => {
+                if u {
+                    Some(Vec::new())
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        let current_time: u64 = env::block_timestamp();
+
+        if started_at.is_some() {
+            // if start time is behind that current time, makes it current time
+            if started_at.unwrap().0 <= current_time {
+                started_at = Some(current_time.into());
+            }
+            // assert!(started_at.unwrap().0 >= current_time);
+
+            if ended_at.is_some() {
+                assert!(started_at.unwrap().0 < ended_at.unwrap().0);
+            }
+            println!(
+                "\n\n\nstarted_at Price {:?},{:?},{:?}\n\n",
+                started_at.unwrap(),
+                current_time,
+                env::block_timestamp()
+            );
+        }
+
+        if let Some(is_auction) = is_auction {
+            if is_auction == true {
+                if started_at.is_none() {
+                    started_at = Some(U64(current_time));
+                }
+                assert!(ended_at.is_some(), "Marble: Ended at is none");
+            }
+        }
+
+        if ended_at.is_some() {
+            assert!(ended_at.unwrap().0 >= current_time);
+        }
+
+        if end_price.is_some() {
+            assert!(
+                end_price.unwrap().0 < price.0,
+                "Marble: End price is more than starting price"
+            );
+        }
+
+        if reserve_price.is_some() {
+            assert!(
+                reserve_price.unwrap().0 >= price.0,
+                "Marble: Reserve price is more than starting price"
+            );
+        } else {
+            reserve_price = price.into();
+        }
+        println!("\n\n\nReserve Price {:?}", reserve_price.unwrap());
+
+        let max_price = self.internal_max_price(&ft_token_id);
+        assert!(
+            price.0 < max_price,
+            "Marble: price higher than {}",
+            max_price
+        );
+        // a dutch auction must not fall below the floor either
+        if self.usd_prices.get(&contract_and_token_id).is_none() {
+            self.assert_min_listing_price(
+                &nft_contract_id,
+                &ft_token_id,
+                end_price.map_or(price.0, |end_price| end_price.0),
+            );
+        }
+
+        let listing_id = self.next_listing_id;
+        self.next_listing_id += 1;
+        self.listing_ids.insert(&contract_and_token_id, &listing_id);
+        self.market.insert(
+            &contract_and_token_id,
+            &MarketData {
+                owner_id: owner_id.clone().into(),
+                approval_id,
+                nft_contract_id: nft_contract_id.clone().into(),
+                token_id: token_id.clone(),
+                ft_token_id: ft_token_id.clone(),
+                price: price.into(),
+                bids: bids,
+                started_at: match started_at {
+                    Some(x) => Some(x.0),
+                    None => None,
+                },
+                ended_at: match ended_at {
+                    Some(x) => Some(x.0),
+                    None => None,
+                },
+                end_price: match end_price {
+                    Some(x) => Some(x.0),
+                    None => None,
+                },
+                accept_nft_contract_id: None,
+                accept_token_id: None,
+                is_auction: is_auction,
+                reserve_price: match reserve_price {
+                    Some(x) => Some(x.0),
+                    None => None,
+                },
+            },
+        );
+        self.internal_record_change(ChangeType::Market, &contract_and_token_id);
+
+        let mut token_ids = self.by_owner_id.get(&owner_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByOwnerIdInner {
+                    account_id_hash: hash_account_id(&owner_id),
+                }
+                .try_to_vec()
+                .unwrap(),
+            )
+        });
+
+        let listing_fee = self.listing_fee;
+        if listing_fee > 0 {
+            let storage_deposit = self.storage_deposits.get(&owner_id).unwrap_or(0);
+            let storage_required =
+                (token_ids.len() + 1) as u128 * self.config.storage_add_market_data.0;
+            assert!(
+                storage_deposit >= storage_required + listing_fee,
+                "Marble: Insufficient storage deposit for the listing fee"
+            );
+            self.storage_deposits
+                .insert(&owner_id, &(storage_deposit - listing_fee));
+            self.listing_fees
+                .insert(&contract_and_token_id, &listing_fee);
+        }
+
+        token_ids.insert(&contract_and_token_id);
+
+        self.by_owner_id.insert(&owner_id, &token_ids);
+
+        // update offer trade approval_id
+        let owner_contract_account_id_token_id =
+            make_triple(&nft_contract_id, &owner_id, &token_id);
+        let trade_data = self.trades.get(&owner_contract_account_id_token_id);
+        if let Some(mut trade_list) = trade_data {
+            trade_list.approval_id = approval_id;
+            self.trades
+                .insert(&owner_contract_account_id_token_id, &trade_list);
+            self.internal_record_change(ChangeType::Trade, &owner_contract_account_id_token_id);
+        }
+
+        self.listing_tombstones.remove(&contract_and_token_id);
+
+        // set market data transaction fee
+        let current_transaction_fee = self.calculate_current_transaction_fee();
+        self.market_data_transaction_fee
+            .transaction_fee
+            .insert(&contract_and_token_id, &current_transaction_fee);
+
+        env::log_str(
+            &json!({
+                "type": "add_market_data",
+                "params": {
+                    "owner_id": owner_id,
+                    "approval_id": approval_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "listing_id": U64(listing_id),
+                    "ft_token_id": ft_token_id,
+                    "price": price,
+                    "started_at": started_at,
+                    "ended_at": ended_at,
+                    "end_price": end_price,
+                    "is_auction": is_auction,
+                    "transaction_fee": current_transaction_fee.to_string(),
+                    "listing_fee": U128(listing_fee),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // a listing cancelled without selling gets its listing fee back
+    fn internal_refund_listing_fee(
+        &mut self,
+        owner_id: &AccountId,
+        contract_and_token_id: &ContractAndTokenId,
+    ) {
+        if let Some(listing_fee) = self.listing_fees.remove(contract_and_token_id) {
+            let storage_deposit = self.storage_deposits.get(owner_id).unwrap_or(0);
+            self.storage_deposits
+                .insert(owner_id, &(storage_deposit + listing_fee));
+        }
+    }
+
+    fn internal_delete_market_data(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> Option<MarketData> {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        // only single token listings priced in their own currency can be relisted as they were
+        let is_relistable = self.listing_editions.get(&contract_and_token_id).is_none()
+            && !self.mint_sales.contains(&contract_and_token_id)
+            && self.usd_prices.get(&contract_and_token_id).is_none();
+        self.allowed_buyer_ids.remove(&contract_and_token_id);
+        self.listing_editions.remove(&contract_and_token_id);
+        self.mint_sales.remove(&contract_and_token_id);
+        self.internal_refund_layaway(&contract_and_token_id);
+        self.usd_prices.remove(&contract_and_token_id);
+        self.alternative_prices.remove(&contract_and_token_id);
+        self.stable_priced_listings.remove(&contract_and_token_id);
+        self.presales.remove(&contract_and_token_id);
+        // kept by the treasury unless the seller delisted, see internal_refund_listing_fee
+        if let Some(listing_fee) = self.listing_fees.remove(&contract_and_token_id) {
+            self.internal_accrue_treasury_fee(&near_account(), listing_fee);
+        }
+
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
+                self.old_market.remove(&contract_and_token_id);
+                Some(MarketData {
+                    owner_id: market_data.owner_id,
+                    approval_id: market_data.approval_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    ft_token_id: market_data.ft_token_id,
+                    price: market_data.price,
+                    bids: None,
+                    started_at: None,
+                    ended_at: None,
+                    end_price: None,
+                    accept_nft_contract_id: None,
+                    accept_token_id: None,
+                    is_auction: None,
+                    reserve_price: None,
+                })
+            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                self.market.remove(&contract_and_token_id);
+                self.internal_record_change(ChangeType::Market, &contract_and_token_id);
+                self.internal_refund_bids(&market_data, &contract_and_token_id, "delisted");
+
+                Some(market_data)
+            } else {
+                None
+            };
+
+        market_data.map(|mut market_data| {
+            if is_relistable {
+                // refunded bids are not part of the tombstone
+                let bids = market_data.bids.take();
+                market_data.bids = bids.as_ref().map(|_| Vec::new());
+                self.listing_tombstones
+                    .insert(&contract_and_token_id, &market_data);
+                market_data.bids = bids;
+            }
+            let by_owner_id = self.by_owner_id.get(&market_data.owner_id);
+            if let Some(mut by_owner_id) = by_owner_id {
+                by_owner_id.remove(&contract_and_token_id);
+                if by_owner_id.is_empty() {
+                    self.by_owner_id.remove(&market_data.owner_id);
+                } else {
+                    self.by_owner_id.insert(&market_data.owner_id, &by_owner_id);
+                }
+            }
+            self.internal_release_sponsored_storage(
+                &market_data.nft_contract_id,
+                &market_data.owner_id,
+                &contract_and_token_id,
+            );
+            market_data
+        })
+    }
+
+    #[payable]
+    pub fn delete_market_data(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let current_time: u64 = env::block_timestamp();
+
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
+                Some(MarketData {
+                    owner_id: market_data.owner_id,
+                    approval_id: market_data.approval_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    ft_token_id: market_data.ft_token_id,
+                    price: market_data.price,
+                    bids: None,
+                    started_at: None,
+                    ended_at: None,
+                    end_price: None,
+                    accept_nft_contract_id: None,
+                    accept_token_id: None,
+                    is_auction: None,
+                    reserve_price: None,
+                })
+            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                Some(market_data)
+            } else {
+                None
+            };
+
+        let market_data: MarketData = market_data.expect("Marble: Market data does not exist");
+
+        let predecessor_id = env::predecessor_account_id();
+        assert!(
+            predecessor_id == market_data.owner_id
+                || self.is_listing_operator(market_data.owner_id.clone(), predecessor_id.clone())
+                || self.has_role(predecessor_id, Role::ListingModerator),
+            "Marble: Seller or listing moderator only"
+        );
+
+        // if market_data.is_auction.is_some() && env::predecessor_account_id() == self.owner_id {
+        //   assert!(
+        //     current_time >= market_data.ended_at.unwrap(),
+        //     "Marble: Auction has not ended yet"
+        //   );
+        // }
+
+        self.internal_refund_listing_fee(&market_data.owner_id, &contract_and_token_id);
+        self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+        env::log_str(
+            &json!({
+                "type": "delete_market_data",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // sellers leaving the market delist in pages of limit listings
+    #[payable]
+    pub fn delete_all_market_data(&mut self, limit: Option<u64>) -> u64 {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let contract_and_token_ids: Vec<ContractAndTokenId> = match self.by_owner_id.get(&owner_id)
+        {
+            Some(by_owner_id) => {
+                let limit = limit.unwrap_or(by_owner_id.len());
+                by_owner_id.iter().take(limit as usize).collect()
+            }
+            None => vec![],
+        };
+
+        for contract_and_token_id in &contract_and_token_ids {
+            let mut split = contract_and_token_id.split(DELIMETER);
+            let nft_contract_id: AccountId = split.next().unwrap().parse().unwrap();
+            let token_id: TokenId = split.next().unwrap().to_string();
+
+            self.internal_refund_listing_fee(&owner_id, contract_and_token_id);
+            self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+            env::log_str(
+                &json!({
+                    "type": "delete_market_data",
+                    "params": {
+                        "owner_id": owner_id,
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        contract_and_token_ids.len() as u64
+    }
+
+    // restores a delisted or unsettled listing with a fresh approval, timed sales keep their duration
+    pub fn relist(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        new_approval_id: u64,
+    ) -> Promise {
+        self.assert_not_paused(PauseFeature::Listings);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        self.assert_not_blacklisted_seller(&env::predecessor_account_id());
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let tombstone = self
+            .listing_tombstones
+            .get(&contract_and_token_id)
+            .expect("Marble: No previous listing to relist");
+        self.assert_not_settling(&contract_and_token_id);
+        assert_eq!(
+            env::predecessor_account_id(),
+            tombstone.owner_id,
+            "Marble: Seller only"
+        );
+        assert!(
+            self.internal_get_market_data(&contract_and_token_id)
+                .is_none(),
+            "Marble: Token is already listed"
+        );
+
+        ext_contract::nft_token(
+            token_id.clone(),
+            nft_contract_id.clone(),
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::callback_relist(
+            nft_contract_id,
+            token_id,
+            new_approval_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_relist(),
+        ))
+    }
+
+    #[private]
+    pub fn callback_relist(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        new_approval_id: u64,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let tombstone = match self.listing_tombstones.get(&contract_and_token_id) {
+            Some(tombstone) => tombstone,
+            None => {
+                env::log_str("Marble: No previous listing to relist");
+                return;
+            }
+        };
+        let token = TradeToken {
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            approval_id: Some(new_approval_id),
+        };
+        if let Some(stale_error) = stale_trade_token_error(0, &tombstone.owner_id, &token) {
+            env::log_str(&stale_error);
+            return;
+        }
+        if self
+            .internal_get_market_data(&contract_and_token_id)
+            .is_some()
+        {
+            env::log_str("Marble: Token is already listed");
+            return;
+        }
+
+        let storage_required = (self.get_supply_by_owner_id(tombstone.owner_id.clone()).0 + 1)
+            as u128
+            * self.config.storage_add_market_data.0
+            + self.listing_fee;
+        if self.storage_deposits.get(&tombstone.owner_id).unwrap_or(0) < storage_required {
+            env::log_str("Marble: Insufficient storage deposit to relist");
+            return;
+        }
+
+        let (started_at, ended_at) = match (tombstone.started_at, tombstone.ended_at) {
+            (Some(started_at), Some(ended_at)) => {
+                let now = env::block_timestamp();
+                (Some(U64(now)), Some(U64(now + (ended_at - started_at))))
+            }
+            _ => (None, None),
+        };
+        self.internal_add_market_data(
+            tombstone.owner_id,
+            new_approval_id,
+            nft_contract_id,
+            token_id,
+            tombstone.ft_token_id,
+            U128(tombstone.price),
+            started_at,
+            ended_at,
+            tombstone.end_price.map(U128),
+            tombstone.is_auction,
+            tombstone.reserve_price.map(U128),
+        );
+    }
+
+    pub fn get_listing_tombstone(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<MarketData> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.listing_tombstones.get(&contract_and_token_id)
+    }
+
+    // anyone can delist expired fixed price sales
+    pub fn cleanup_expired_listings(&mut self, from_index: Option<U64>, limit: Option<u64>) -> u64 {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.market.len());
+
+        let expired_listings: Vec<MarketData> = self
+            .market
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter(is_listing_expired)
+            .collect();
+
+        for market_data in &expired_listings {
+            self.internal_delete_market_data(&market_data.nft_contract_id, &market_data.token_id);
+
+            env::log_str(
+                &json!({
+                    "type": "delete_market_data",
+                    "params": {
+                        "owner_id": market_data.owner_id,
+                        "nft_contract_id": market_data.nft_contract_id,
+                        "token_id": market_data.token_id,
+                        "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                        "reason": "expired",
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        expired_listings.len() as u64
+    }
+
+    // Storage
+
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) {
+        let storage_account_id = account_id
+            .map(|a| a.into())
+            .unwrap_or_else(env::predecessor_account_id);
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= self.config.storage_add_market_data.0,
+            "Requires minimum deposit of {}",
+            self.config.storage_add_market_data.0
+        );
+
+        let mut balance: u128 = self.storage_deposits.get(&storage_account_id).unwrap_or(0);
+        balance += deposit;
+        self.storage_deposits.insert(&storage_account_id, &balance);
+    }
+
+    #[payable]
+    pub fn storage_withdraw(&mut self) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let mut amount = self.storage_deposits.remove(&owner_id).unwrap_or(0);
+        let market_data_owner = self.by_owner_id.get(&owner_id);
+        let len = market_data_owner.map(|s| s.len()).unwrap_or_default();
+        let diff = u128::from(len) * self.config.storage_add_market_data.0;
+        amount -= diff;
+        if amount > 0 {
+            Promise::new(owner_id.clone()).transfer(amount);
+        }
+        if diff > 0 {
+            self.storage_deposits.insert(&owner_id, &diff);
+        }
+    }
+
+    pub fn storage_minimum_balance(&self) -> U128 {
+        self.config.storage_add_market_data
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> U128 {
+        self.storage_deposits.get(&account_id).unwrap_or(0).into()
+    }
+
+    // View
+
+    pub fn get_market_data(self, nft_contract_id: AccountId, token_id: TokenId) -> MarketDataJson {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data: Option<MarketData> =
+            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
+                Some(MarketData {
+                    owner_id: market_data.owner_id,
+                    approval_id: market_data.approval_id,
+                    nft_contract_id: market_data.nft_contract_id,
+                    token_id: market_data.token_id,
+                    ft_token_id: market_data.ft_token_id,
+                    price: market_data.price,
+                    bids: None,
+                    started_at: None,
+                    ended_at: None,
+                    end_price: None,
+                    accept_nft_contract_id: None,
+                    accept_token_id: None,
+                    is_auction: None,
+                    reserve_price: None,
+                })
+            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                Some(market_data)
+            } else {
+                None
+            };
+
+        let market_data = market_data.expect("Marble: Market data does not exist");
+
+        let mut price = market_data.price;
+
+        if market_data.is_auction.is_some() && market_data.end_price.is_some() {
+            let current_time = env::block_timestamp();
+            let end_price = market_data.end_price.unwrap();
+            let started_at = market_data.started_at.unwrap();
+            let ended_at = market_data.ended_at.unwrap();
+
+            if current_time < started_at {
+                // Use current market_data.price
+            } else if current_time > ended_at {
+                price = end_price;
+            } else {
+                let time_since_start = current_time - started_at;
+                let duration = ended_at - started_at;
+                price = price - ((price - end_price) / duration as u128) * time_since_start as u128;
+            }
+        }
+        let reserve_price = market_data.reserve_price.map(|x| x.into());
+
+        let allowed_buyer_id = self.allowed_buyer_ids.get(&contract_and_token_id);
+        let quantity = self
+            .listing_editions
+            .get(&contract_and_token_id)
+            .map(|editions| U64(editions.len() as u64));
+        let usd_price = self.usd_prices.get(&contract_and_token_id).map(U128);
+        let listing_id = self.listing_ids.get(&contract_and_token_id).map(U64);
+        let alternative_prices = self.alternative_prices.get(&contract_and_token_id);
+        let is_stable_priced = self.stable_priced_listings.contains(&contract_and_token_id);
+        let presale = self.presales.get(&contract_and_token_id);
+        let verification = self.get_collection_verification(market_data.nft_contract_id.clone());
+        let current_transaction_fee = self
+            .get_market_data_transaction_fee(&market_data.nft_contract_id, &market_data.token_id);
+
+        MarketDataJson {
+            owner_id: market_data.owner_id,
+            approval_id: market_data.approval_id.into(),
+            nft_contract_id: market_data.nft_contract_id,
+            token_id: market_data.token_id,
+            listing_id: listing_id,
+            ft_token_id: market_data.ft_token_id, // "near" for NEAR token
+            price: price.into(),
+            bids: market_data.bids,
+            started_at: market_data.started_at.map(|x| x.into()),
+            ended_at: market_data.ended_at.map(|x| x.into()),
+            end_price: market_data.end_price.map(|x| x.into()),
+            is_auction: market_data.is_auction,
+            transaction_fee: current_transaction_fee.into(),
+            reserve_price: reserve_price,
+            current_time: to_sec(env::block_timestamp()),
+            allowed_buyer_id: allowed_buyer_id,
+            quantity: quantity,
+            usd_price: usd_price,
+            alternative_prices: alternative_prices,
+            is_stable_priced: is_stable_priced,
+            presale: presale,
+            verification: verification,
+        }
+    }
+
+    pub fn approved_ft_token_ids(&self) -> Vec<AccountId> {
+        self.approved_ft_token_ids.to_vec()
+    }
+
+    pub fn approved_nft_contract_ids(&self) -> Vec<AccountId> {
+        self.approved_nft_contract_ids.to_vec()
+    }
+
+    pub fn fee_exempt_account_ids(&self) -> Vec<AccountId> {
+        self.fee_exempt_account_ids.to_vec()
+    }
+
+    pub fn fee_exempt_nft_contract_ids(&self) -> Vec<AccountId> {
+        self.fee_exempt_nft_contract_ids.to_vec()
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_treasury(&self) -> AccountId {
+        self.treasury_id.clone()
+    }
+
+    pub fn get_treasury_recipients(&self) -> Vec<TreasuryRecipient> {
+        if self.treasury_recipients.is_empty() {
+            vec![TreasuryRecipient {
+                account_id: self.treasury_id.clone(),
+                share_bps: 10_000,
+            }]
+        } else {
+            self.treasury_recipients.clone()
+        }
+    }
+
+    pub fn get_locked_balance(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        let mut locked_balance: HashMap<AccountId, Balance> = HashMap::new();
+
+        // offers are indexed in by_owner_id along with listings and trades
+        if let Some(keys) = self.by_owner_id.get(&account_id) {
+            for key in keys.iter() {
+                if let Some(offer_data) = self.offers.get(&key) {
+                    *locked_balance
+                        .entry(offer_data.ft_token_id.clone())
+                        .or_insert(0) += offer_escrow(&offer_data);
+                }
+            }
+        }
+
+        if let Some(contract_and_token_ids) = self.bids_by_bidder_id.get(&account_id) {
+            for contract_and_token_id in contract_and_token_ids.iter() {
+                if let Some(market_data) = self.market.get(&contract_and_token_id) {
+                    for bid in market_data.bids.unwrap_or_default() {
+                        if bid.bidder_id == account_id {
+                            *locked_balance
+                                .entry(market_data.ft_token_id.clone())
+                                .or_insert(0) += bid.price.0;
+                        }
+                    }
+                }
+            }
+        }
+
+        locked_balance
+            .into_iter()
+            .map(|(ft_token_id, amount)| (ft_token_id, U128(amount)))
+            .collect()
+    }
+
+    pub fn get_supply_by_owner_id(&self, account_id: AccountId) -> U64 {
+        self.by_owner_id
+            .get(&account_id)
+            .map_or(0, |by_owner_id| by_owner_id.len())
+            .into()
+    }
+
+    // private fn
+
+    fn assert_owner(&self) {
+        self.assert_role(Role::Owner);
+    }
+
+    fn assert_owner_or_collection_admin(&self, nft_contract_id: &AccountId) {
+        let predecessor_id = env::predecessor_account_id();
+        assert!(
+            self.collection_admins.get(nft_contract_id) == Some(predecessor_id.clone())
+                || self.has_role(predecessor_id, Role::ListingModerator),
+            "Marble: Owner or collection admin only"
+        );
+    }
+}
+
+pub fn hash_account_id(account_id: &AccountId) -> CryptoHash {
+    let mut hash = CryptoHash::default();
+    hash.copy_from_slice(&env::sha256(account_id.as_bytes()));
+    hash
+}
+
+pub fn hash_contract_account_id_token_id(
+    contract_account_id_token_id: &ContractAccountIdTokenId,
+) -> CryptoHash {
+    let mut hash = CryptoHash::default();
+    hash.copy_from_slice(&env::sha256(contract_account_id_token_id.as_bytes()));
+    hash
+}
+
+pub fn to_sec(timestamp: Timestamp) -> TimestampSec {
+    (timestamp / 10u64.pow(9)) as u32
+}
+
+#[ext_contract(ext_self)]
+trait ExtSelf {
+    fn resolve_purchase(
+        &mut self,
+        buyer_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        referral_id: Option<AccountId>,
+    ) -> Promise;
+
+    fn resolve_offer(
+        &mut self,
+        seller_id: AccountId,
+        offer_data: OfferData,
+        token_id: TokenId,
+        transaction_fee: u16,
+    ) -> Promise;
+
+    fn callback_verify_trade(
+        &mut self,
+        buyer_id: AccountId,
+        buyer_tokens: Vec<TradeToken>,
+        cash: TradeCash,
+        seller_id: AccountId,
+        seller_tokens: Vec<TradeToken>,
+    ) -> Promise;
+
+    fn callback_return_stuck_nft(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        receiver_id: AccountId,
+    ) -> Promise;
+
+    fn callback_bind_trade_owner(
+        &mut self,
+        buyer_contract_account_id_token_id: ContractAccountIdTokenId,
+        contract_account_id_token_id: ContractAccountIdTokenId,
+    ) -> Promise;
+
+    fn callback_reject_trade(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        token: String,
+        buyer_id: AccountId,
+        buyer_nft_contract_id: AccountId,
+        buyer_token_id: TokenId,
+    ) -> Promise;
+
+    fn callback_verify_ring_trade(
+        &mut self,
+        ring_trade_id: U64,
+        participants: Vec<RingTradeParticipant>,
+    ) -> Promise;
+
+    fn callback_resolve_ring_trade(
+        &mut self,
+        ring_trade_id: U64,
+        participants: Vec<RingTradeParticipant>,
+    ) -> Promise;
+
+    fn callback_end_rental(
+        &mut self,
+        rental: Rental,
+        renter_id: AccountId,
+        escrow: U128,
+        transaction_fee: u16,
+    ) -> Promise;
+
+    fn callback_verify_purchase(
+        &mut self,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        referral_id: Option<AccountId>,
+        gas_for_resolve: Gas,
+    ) -> Promise;
+
+    fn callback_buy_usd(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        usd_price: U128,
+        max_price: U128,
+        deposit: U128,
+        referral_id: Option<AccountId>,
+    ) -> Promise;
+
+    fn callback_wrapped_deposit(
+        &mut self,
+        wrap_near_id: AccountId,
+        seller_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+
+    fn callback_wrapped_proceeds(
+        &mut self,
+        wrap_near_id: AccountId,
+        seller_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+
+    fn callback_unwrapped_proceeds(
+        &mut self,
+        wrap_near_id: AccountId,
+        seller_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+
+    fn callback_unwrap_near_purchase(
+        &mut self,
+        wrap_near_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        price: U128,
+        referral_id: Option<AccountId>,
+        holder_nft_contract_id: Option<AccountId>,
+    ) -> Promise;
+
+    fn callback_claim_affiliate_earnings(
+        &mut self,
+        affiliate_id: AccountId,
+        ft_token_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+
+    fn callback_sync_fee_tier(&mut self, account_id: AccountId) -> Promise;
+
+    fn callback_claim_treasury_fees(&mut self, ft_token_id: AccountId, amount: U128) -> Promise;
+
+    fn callback_burn_fee(&mut self, ft_token_id: AccountId, amount: U128) -> Promise;
+
+    fn callback_buy_presale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        price: U128,
+        referral_id: Option<AccountId>,
+    ) -> Promise;
+
+    fn callback_relist(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        new_approval_id: u64,
+    ) -> Promise;
+
+    fn callback_mint_sale(
+        &mut self,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        referral_id: Option<AccountId>,
+    ) -> Promise;
+
+    fn resolve_mint_sale(
+        &mut self,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        market_data: MarketData,
+        token_id: TokenId,
+        price: U128,
+        referral_id: Option<AccountId>,
+    ) -> Promise;
+
+    fn callback_verify_bundle_purchase(
+        &mut self,
+        bundle_listing_id: U64,
+        buyer_id: AccountId,
+        bundle_listing: BundleListing,
+        transaction_fee: u16,
+    ) -> Promise;
+
+    fn callback_resolve_bundle_purchase(
+        &mut self,
+        bundle_listing_id: U64,
+        buyer_id: AccountId,
+        bundle_listing: BundleListing,
+        transaction_fee: u16,
+    ) -> Promise;
+
+    fn callback_escrow_trade(
+        &mut self,
+        buyer_id: AccountId,
+        buyer_tokens: Vec<TradeToken>,
+        cash: TradeCash,
+        seller_id: AccountId,
+        seller_tokens: Vec<TradeToken>,
+    ) -> Promise;
+
+    fn callback_resolve_trade(
+        &mut self,
+        buyer_id: AccountId,
+        buyer_tokens: Vec<TradeToken>,
+        cash: TradeCash,
+        seller_id: AccountId,
+        seller_tokens: Vec<TradeToken>,
+    ) -> Promise;
+
+    fn callback_accept_offer_batch(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        approval_id: u64,
+    );
+
+    fn callback_decline_offer(
+        &mut self,
+        seller_id: AccountId,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        token_id: TokenId,
+        token_series_id: Option<TokenSeriesId>,
+    );
+
+    fn callback_post_withdraw_deposit(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) -> U128;
+
+    fn callback_post(&mut self);
+
+    fn callback_transfer_payout(
+        &mut self,
+        ft_token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+
+    fn callback_ft_metadata(&mut self, ft_token_id: AccountId) -> Promise;
+
+    fn callback_swap_deposit(&mut self, swap_purchase: SwapPurchase) -> Promise;
+
+    fn callback_swap(&mut self, swap_purchase: SwapPurchase) -> Promise;
+
+    fn callback_swap_withdraw(
+        &mut self,
+        swap_purchase: SwapPurchase,
+        ft_token_id: AccountId,
+        amount: U128,
+    ) -> Promise;
+}
+
+fn add_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
+    accounts.map(|ids| {
+        ids.iter().for_each(|id| {
+            set.insert(id);
+        })
+    });
+}
+
+fn remove_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
+    accounts.map(|ids| {
+        ids.iter().for_each(|id| {
+            set.remove(id);
+        })
+    });
+}
+
+fn make_triple(nft_contract_id: &AccountId, buyer_id: &AccountId, token: &str) -> String {
+    format!(
+        "{}{}{}{}{}",
+        nft_contract_id, DELIMETER, buyer_id, DELIMETER, token
+    )
+}
+
+fn offer_escrow(offer_data: &OfferData) -> Balance {
+    offer_data.price * offer_data.quantity.unwrap_or(1) as u128
+}
+
+fn is_offer_seller(offer_data: &OfferData, seller_id: &AccountId) -> bool {
+    offer_data
+        .seller_id
+        .as_ref()
+        .map_or(true, |offer_seller_id| offer_seller_id == seller_id)
+}
+
+// primary tokens first, followed by the bundled ones
+fn trade_bundle(
+    trade_data: &TradeData,
+    buyer_token: TradeToken,
+    seller_token: TradeToken,
+    seller_approvals: Option<Vec<TradeToken>>,
+) -> (Vec<TradeToken>, Vec<TradeToken>) {
+    let mut buyer_tokens = vec![buyer_token];
+    buyer_tokens.extend(trade_data.buyer_tokens.clone().unwrap_or_default());
+
+    let wanted_tokens = trade_data.seller_tokens.clone().unwrap_or_default();
+    let seller_approvals = seller_approvals.unwrap_or_default();
+    assert_eq!(
+        wanted_tokens.len(),
+        seller_approvals.len(),
+        "Marble: Bundle tokens do not match the trade"
+    );
+    let mut seller_tokens = vec![seller_token];
+    for wanted_token in wanted_tokens {
+        let approval_id = seller_approvals
+            .iter()
+            .find(|token| {
+                token.nft_contract_id == wanted_token.nft_contract_id
+                    && token.token_id == wanted_token.token_id
+            })
+            .and_then(|token| token.approval_id)
+            .expect("Marble: Bundle tokens do not match the trade");
+        seller_tokens.push(TradeToken {
+            approval_id: Some(approval_id),
+            ..wanted_token
+        });
+    }
+
+    (buyer_tokens, seller_tokens)
+}
+
+// a token that changed hands leaves the proposal to its former owner
+fn assert_trade_owner(trade_data: &TradeData, seller_id: &AccountId) {
+    if let Some(owner_id) = trade_data.owner_id.as_ref() {
+        assert_eq!(
+            owner_id, seller_id,
+            "Marble: Trade is bound to another owner"
+        );
+    }
+}
+
+// converts a USD price with the oracle's NEAR price, refusing stale data
+fn oracle_near_price(
+    price_data: &PriceData,
+    price_oracle: &PriceOracleConfig,
+    usd_price: Balance,
+) -> Result<Balance, String> {
+    let max_staleness = price_oracle.max_staleness_sec as u64 * 10u64.pow(9);
+    if env::block_timestamp().saturating_sub(price_data.timestamp.0) > max_staleness {
+        return Err("Marble: Oracle price is stale".to_string());
+    }
+    price_data
+        .prices
+        .iter()
+        .find(|asset| asset.asset_id == price_oracle.near_asset_id)
+        .and_then(|asset| asset.price.as_ref())
+        .and_then(|price| usd_to_yocto(usd_price, price))
+        .ok_or("Marble: Price oracle has no NEAR price".to_string())
+}
+
+// the oracle prices one yocto at multiplier / 10^decimals USD
+fn usd_to_yocto(usd_price: Balance, price: &Price) -> Option<Balance> {
+    let decimals = price.decimals.checked_sub(USD_DECIMALS)?;
+    usd_price
+        .checked_mul(10u128.checked_pow(decimals as u32)?)?
+        .checked_div(price.multiplier.0)
+}
+
+// the bundle price split evenly over its tokens, the last one takes the remainder
+fn bundle_listing_shares(bundle_listing: &BundleListing) -> Vec<Balance> {
+    let tokens_count = bundle_listing.tokens.len() as u128;
+    let share = bundle_listing.price.0 / tokens_count;
+    let mut shares = vec![share; bundle_listing.tokens.len()];
+    shares[bundle_listing.tokens.len() - 1] = bundle_listing.price.0 - share * (tokens_count - 1);
+    shares
+}
+
+// accepts both payout shapes, the payout may not exceed the price
+fn parse_payout(value: &[u8], price: Balance) -> Option<PayoutHashMap> {
+    let payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(value)
+        .ok()
+        .or_else(|| {
+            near_sdk::serde_json::from_slice::<Payout>(value)
+                .ok()
+                .map(|payout| payout.payout)
+        })?;
+    let mut remainder = price;
+    for value in payout.values() {
+        remainder = remainder.checked_sub(value.0)?;
+    }
+    if remainder <= 100 {
+        Some(payout)
+    } else {
+        None
+    }
+}
+
+// the seller's entry pays the fee first, royalty receivers cover the rest pro rata
+// so high royalties never underflow, the fee saturates at the whole payout
+fn payout_fee_shares(
+    payout: &PayoutHashMap,
+    seller_id: &AccountId,
+    treasury_fee: Balance,
+) -> HashMap<AccountId, Balance> {
+    let mut fee_shares = HashMap::new();
+    let seller_amount = payout.get(seller_id).map_or(0, |amount| amount.0);
+    let seller_fee = std::cmp::min(seller_amount, treasury_fee);
+    fee_shares.insert(seller_id.clone(), seller_fee);
+
+    let shortfall = treasury_fee - seller_fee;
+    let royalties_total: Balance = payout
+        .iter()
+        .filter(|(receiver_id, _)| *receiver_id != seller_id)
+        .map(|(_, amount)| amount.0)
+        .sum();
+    if shortfall == 0 || royalties_total == 0 {
+        return fee_shares;
+    }
+    for (receiver_id, amount) in payout.iter() {
+        if receiver_id != seller_id {
+            let share_bps = amount.0 * 10_000u128 / royalties_total;
+            let fee = std::cmp::min(amount.0, shortfall * share_bps / 10_000u128);
+            fee_shares.insert(receiver_id.clone(), fee);
+        }
+    }
+    fee_shares
+}
+
+// dutch auctions drop linearly from price to end_price between started_at and ended_at
+fn sale_price(market_data: &MarketData) -> u128 {
+    match (
+        market_data.is_auction,
+        market_data.end_price,
+        market_data.started_at,
+        market_data.ended_at,
+    ) {
+        (Some(_), Some(end_price), Some(started_at), Some(ended_at)) => {
+            let current_time = env::block_timestamp();
+            if current_time > ended_at {
+                end_price
+            } else {
+                let time_since_start = current_time - started_at;
+                let duration = ended_at - started_at;
+                market_data.price
+                    - ((market_data.price - end_price) / duration as u128)
+                        * time_since_start as u128
+            }
+        }
+        _ => market_data.price,
+    }
+}
+
+// auctions use ended_at for bidding and dutch pricing, plain sales expire with it
+fn is_listing_expired(market_data: &MarketData) -> bool {
+    market_data.is_auction != Some(true)
+        && market_data.end_price.is_none()
+        && market_data
+            .ended_at
+            .map_or(false, |ended_at| env::block_timestamp() > ended_at)
+}
+
+// the price a dutch auction has dropped to by now, the listed price for anything else
+fn current_listing_price(market_data: &MarketData, listed_price: Balance) -> Balance {
+    if market_data.is_auction.is_none() || market_data.end_price.is_none() {
+        return listed_price;
+    }
+    let current_time = env::block_timestamp();
+    let end_price = market_data.end_price.unwrap();
+    let ended_at = market_data.ended_at.unwrap();
+    let started_at = market_data.started_at.unwrap();
+    if current_time > ended_at {
+        end_price
+    } else {
+        let time_since_start = current_time - started_at;
+        let duration = ended_at - started_at;
+        listed_price - ((listed_price - end_price) / duration as u128) * time_since_start as u128
+    }
+}
+
+fn is_trade_expired(trade_data: &TradeData) -> bool {
+    trade_data
+        .expires_at
+        .map_or(false, |expires_at| env::block_timestamp() >= expires_at)
+}
+
+fn split_triple(key: &str) -> (AccountId, AccountId, String) {
+    let parts: Vec<&str> = key.splitn(3, DELIMETER).collect();
+    (
+        AccountId::new_unchecked(parts[0].to_string()),
+        AccountId::new_unchecked(parts[1].to_string()),
+        parts[2].to_string(),
+    )
+}
+
+// moves tokens straight to the receiver with the marketplace approvals
+fn nft_transfer_trade_tokens(
+    config: &Config,
+    receiver_id: &AccountId,
+    tokens: &[TradeToken],
+) -> Promise {
+    tokens
+        .iter()
+        .map(|token| {
+            ext_contract::nft_transfer(
+                receiver_id.clone(),
+                token.token_id.clone(),
+                token.approval_id,
+                token.nft_contract_id.clone(),
+                1,
+                config.gas_for_nft_transfer,
+            )
+        })
+        .reduce(|promise, next| promise.and(next))
+        .unwrap()
+}
+
+// reads the nft_transfer results, promise results are in join order
+fn transferred_trade_tokens(tokens: &[TradeToken]) -> Vec<TradeToken> {
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| promise_result_at(*index as u64).is_some())
+        .map(|(_, token)| token.clone())
+        .collect()
+}
+
+fn trade_cash(trade_data: &TradeData) -> TradeCash {
+    TradeCash {
+        ft_token_id: trade_data
+            .ft_token_id
+            .clone()
+            .map(AccountId::new_unchecked)
+            .unwrap_or_else(near_account),
+        buyer_amount: U128(trade_data.buyer_amount.unwrap_or(0)),
+        seller_amount: U128(trade_data.seller_amount.unwrap_or(0)),
+        trade_fee: U128(0),
+        fee_bps: 0,
+        trade_fee_ft_token_id: None,
+    }
+}
+
+// reads the nft_token result at index, promise results are in join order
+fn stale_trade_token_error(
+    index: usize,
+    owner_id: &AccountId,
+    token: &TradeToken,
+) -> Option<String> {
+    let json_token = promise_result_at(index as u64)
+        .and_then(|value| near_sdk::serde_json::from_slice::<JsonToken>(&value).ok());
+    let error = match json_token {
+        None => "does not exist",
+        Some(json_token) if &json_token.owner_id != owner_id => {
+            "is no longer owned by the trading party"
+        }
+        Some(json_token) if !is_trade_token_approved(&json_token, token.approval_id) => {
+            "has a stale marketplace approval"
+        }
+        _ => return None,
+    };
+    Some(format!(
+        "Marble: Trade aborted, nft {}{}{} {}",
+        token.nft_contract_id, DELIMETER, token.token_id, error
+    ))
+}
+
+fn ring_trade_token(participant: &RingTradeParticipant) -> TradeToken {
+    TradeToken {
+        nft_contract_id: participant.nft_contract_id.clone(),
+        token_id: participant.token_id.clone(),
+        approval_id: participant.approval_id,
+    }
+}
+
+// contracts that do not report approvals are only checked for ownership
+fn is_trade_token_approved(json_token: &JsonToken, approval_id: Option<u64>) -> bool {
+    match (&json_token.approved_account_ids, approval_id) {
+        (Some(approved_account_ids), Some(approval_id)) => {
+            approved_account_ids.get(&env::current_account_id()) == Some(&approval_id)
+        }
+        _ => true,
+    }
+}
+
+fn promise_result_at(index: u64) -> Option<Vec<u8>> {
+    match env::promise_result(index) {
+        PromiseResult::Successful(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn nft_transfer_stuck_nft(
+    config: &Config,
+    nft_contract_id: AccountId,
+    token_id: TokenId,
+    receiver_id: AccountId,
+) -> Promise {
+    ext_contract::nft_transfer(
+        receiver_id.clone(),
+        token_id.clone(),
+        None,
+        nft_contract_id.clone(),
+        1,
+        config.gas_for_nft_transfer,
+    )
+    .then(ext_self::callback_return_stuck_nft(
+        nft_contract_id,
+        token_id,
+        receiver_id,
+        env::current_account_id(),
+        NO_DEPOSIT,
+        config.base_gas,
+    ))
+}
+
+fn make_key_owner_by_id_trade(contract_account_id_token_id: String) -> String {
+    format!("{}{}trade", contract_account_id_token_id, DELIMETER)
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::event_schema::{EVENT_STANDARD, EVENT_VERSION};
+    use crate::signed_order::SignedOrder;
+    use crate::wrap_near::PayoutPreference;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn setup_contract() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let contract = Contract::new(
+            accounts(0),
+            accounts(1),
+            None,
+            Some(vec![accounts(2)]),
+            Some(vec![accounts(2)]),
+            500,
+        );
+        (context, contract)
+    }
+
+    #[test]
+    fn test_new() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let contract = Contract::new(
+            accounts(0),
+            accounts(1),
+            None,
+            Some(vec![accounts(2)]),
+            Some(vec![accounts(2)]),
+            500,
+        );
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.get_owner(), accounts(0));
+        assert_eq!(contract.get_treasury(), accounts(1));
+        assert_eq!(contract.approved_ft_token_ids(), vec![near_account()]);
+        assert_eq!(contract.approved_nft_contract_ids(), vec![accounts(2)]);
+        assert_eq!(contract.transaction_fee.current_fee, 500);
+    }
+
+    #[test]
+    fn test_set_treasury() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_treasury(accounts(5));
+        assert_eq!(contract.get_treasury(), accounts(1));
+        assert_eq!(contract.get_pending_treasury(), Some(accounts(5)));
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+
+        contract.accept_treasury();
+        let new_treasury: AccountId = contract.get_treasury();
+        assert_eq!(new_treasury, accounts(5));
+        assert_eq!(contract.get_pending_treasury(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Pending treasury only")]
+    fn test_invalid_accept_treasury() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_treasury(accounts(5));
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.accept_treasury();
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Owner only")]
+    fn test_invalid_set_treasury() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_treasury(accounts(5));
+    }
+
+    #[test]
+    fn test_set_treasury_recipients() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_treasury_recipients(vec![
+            TreasuryRecipient {
+                account_id: accounts(4),
+                share_bps: 7_000,
+            },
+            TreasuryRecipient {
+                account_id: accounts(5),
+                share_bps: 3_000,
+            },
+        ]);
+        assert_eq!(contract.get_pending_treasury(), Some(accounts(4)));
+        assert_eq!(contract.get_treasury_recipients().len(), 1);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.accept_treasury();
+        assert_eq!(contract.get_treasury(), accounts(4));
+        assert_eq!(contract.get_treasury_recipients().len(), 2);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.set_treasury(accounts(3));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.accept_treasury();
+        let treasury_recipients = contract.get_treasury_recipients();
+        assert_eq!(treasury_recipients.len(), 1);
+        assert_eq!(treasury_recipients[0].account_id, accounts(3));
+        assert_eq!(treasury_recipients[0].share_bps, 10_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Treasury shares must sum to 10000")]
+    fn test_invalid_set_treasury_recipients() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_treasury_recipients(vec![
+            TreasuryRecipient {
+                account_id: accounts(4),
+                share_bps: 7_000,
+            },
+            TreasuryRecipient {
+                account_id: accounts(5),
+                share_bps: 2_000,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_max_len_payout() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        assert_eq!(contract.get_max_len_payout(), 10);
+        contract.set_max_len_payout(2);
+
+        let mut payout = HashMap::new();
+        payout.insert(accounts(3), U128(80));
+        payout.insert(accounts(4), U128(20));
+        assert!(contract
+            .internal_filter_payout(Some(payout.clone()), &accounts(2), &"1:1".to_string())
+            .is_some());
+
+        payout.insert(accounts(5), U128(10));
+        assert!(contract
+            .internal_filter_payout(Some(payout), &accounts(2), &"1:1".to_string())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: max_len_payout must be between 1 and 20")]
+    fn test_invalid_set_max_len_payout() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_max_len_payout(21);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: referral_fee cannot be higher than 10000")]
+    fn test_invalid_set_referral_fee() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_referral_fee(2_000);
+        assert_eq!(contract.get_referral_fee(), 2_000);
+
+        contract.set_referral_fee(10_001);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Owner only")]
+    fn test_invalid_rescue_stuck_nft() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+
+        contract.rescue_stuck_nft(accounts(2), "1:1".to_string(), accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Only the rightful owner can claim")]
+    fn test_invalid_claim_stuck_nft() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+
+        contract.stuck_nft_claims.insert(
+            &format!("{}{}{}", accounts(2), DELIMETER, "1:1"),
+            &accounts(3),
+        );
+        assert_eq!(
+            contract.get_stuck_nft_claim(accounts(2), "1:1".to_string()),
+            Some(accounts(3))
+        );
+
+        contract.claim_stuck_nft(accounts(2), "1:1".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: fee_bps cannot be higher than 10000")]
+    fn test_invalid_set_trade_fee() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_trade_fee(TradeFee {
+            flat_fee: U128(10u128.pow(22)),
+            fee_bps: 250,
+            marble_exempt: true,
+        });
+        assert_eq!(contract.get_trade_fee().fee_bps, 250);
+
+        contract.set_trade_fee(TradeFee {
+            flat_fee: U128(0),
+            fee_bps: 10_001,
+            marble_exempt: false,
+        });
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.transfer_ownership(accounts(5));
+        assert_eq!(contract.get_owner(), accounts(0));
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+
+        contract.accept_ownership();
+        let new_owner: AccountId = contract.get_owner();
+        assert_eq!(new_owner, accounts(5));
+        assert_eq!(contract.get_pending_owner(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Owner only")]
+    fn test_invalid_transfer_ownership() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(1)
+            .build());
+
+        contract.transfer_ownership(accounts(5));
+    }
+
+    #[test]
+    fn test_add_approved_ft_token_ids() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_approved_ft_token_ids(vec![accounts(5)]);
+        let approved_fts = contract.approved_ft_token_ids();
+        assert_eq!(approved_fts, vec![near_account(), accounts(5)]);
+    }
+
+    #[test]
+    fn test_add_approved_nft_contract_ids() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
+        let approved_nfts = contract.approved_nft_contract_ids();
+        assert_eq!(approved_nfts, vec![accounts(2), accounts(5)]);
+    }
+
+    #[test]
+    fn test_remove_approved_nft_contract_ids() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
+        contract.remove_approved_nft_contract_ids(vec![accounts(5)]);
+        let approved_nfts = contract.approved_nft_contract_ids();
+        assert_eq!(approved_nfts, vec![accounts(2)]);
+    }
+
+    #[test]
+    fn test_internal_add_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            Some(U64(100)),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let market = contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(market.owner_id, accounts(3));
+        assert_eq!(market.approval_id, U64::from(1));
+        assert_eq!(market.ft_token_id, near_account());
+        assert_eq!(market.nft_contract_id, accounts(2));
+        assert_eq!(market.owner_id, accounts(3));
+        assert_eq!(market.token_id, "1:1".to_string());
+        assert_eq!(market.price, U128::from(1 * 10u128.pow(24)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: price higher than 1000000000000000000000000000000000")]
+    fn test_invalid_price_higher_than_max_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1_000_000_000 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: price higher than 1000000000000000000000000000000000")]
+    fn test_invalid_price_higher_than_max_price_update() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(0),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.update_market_data(
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1_000_000_000 * 10u128.pow(24)),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Seller only")]
+    fn test_invalid_update_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.update_market_data(
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(2 * 10u128.pow(24)),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_update_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.update_market_data(
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(2 * 10u128.pow(24)),
+            None,
+        );
+
+        let market = contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(market.price, U128::from(2 * 10u128.pow(24)));
+    }
+
+    #[test]
+    fn test_update_market_data_ft_token_id() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.approved_ft_token_ids.insert(&accounts(5));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.update_market_data(
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            U128::from(5 * 10u128.pow(6)),
+            None,
+        );
+
+        let market = contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(market.ft_token_id, accounts(5));
+        assert_eq!(market.price, U128::from(5 * 10u128.pow(6)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Market data does not exist")]
+    fn test_delete_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+
+        contract.get_market_data(accounts(2), "1:1".to_string());
+    }
+
+    #[test]
+    fn test_delete_all_market_data() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        for token_id in &["1:1", "1:2"] {
+            contract.internal_add_market_data(
+                accounts(3),
+                1,
+                accounts(2),
+                token_id.to_string(),
+                near_account(),
+                U128::from(1 * 10u128.pow(24)),
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+        }
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        assert_eq!(contract.delete_all_market_data(Some(1)), 1);
+        assert_eq!(contract.get_supply_by_owner_id(accounts(3)), U64(1));
+        assert_eq!(contract.delete_all_market_data(None), 1);
+        assert_eq!(contract.market.len(), 0);
+    }
+
+    #[test]
+    fn test_rent() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_rental(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            U128(10u128.pow(24)),
+            7,
+        );
+        assert_eq!(contract.storage_balance_of(accounts(3)), U128(0));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(3 * 10u128.pow(24))
+            .block_timestamp(0)
+            .build());
+
+        contract.rent(accounts(2), "1:1".to_string(), 3);
+        assert_eq!(
+            contract.get_rental_user(accounts(2), "1:1".to_string()),
+            Some(accounts(4))
+        );
+
+        testing_env!(context.block_timestamp(3 * ONE_DAY).build());
+
+        assert_eq!(
+            contract.get_rental_user(accounts(2), "1:1".to_string()),
+            None
+        );
+        contract.end_rental(accounts(2), "1:1".to_string());
+        let rental = contract.get_rental(accounts(2), "1:1".to_string()).unwrap();
+        assert!(rental.renter_id.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Rental is limited to 7 days")]
+    fn test_invalid_rent_duration() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_rental(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            U128(10u128.pow(24)),
+            7,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(8 * 10u128.pow(24))
+            .build());
+
+        contract.rent(accounts(2), "1:1".to_string(), 8);
+    }
+
+    #[test]
+    fn test_layaway() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(2 * 10u128.pow(24))
+            .build());
+
+        contract.add_layaway(accounts(2), "1:1".to_string());
+        let layaway = contract
+            .get_layaway(accounts(2), "1:1".to_string())
+            .unwrap();
+        assert_eq!(layaway.paid, U128(2 * 10u128.pow(24)));
+
+        testing_env!(context
+            .attached_deposit(8 * 10u128.pow(24))
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build());
+
+        contract.pay_layaway(accounts(2), "1:1".to_string());
+        assert!(contract
+            .get_layaway(accounts(2), "1:1".to_string())
+            .is_none());
+        assert_eq!(contract.market.len(), 0);
+    }
+
+    #[test]
+    fn test_cancel_expired_layaway() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(2 * 10u128.pow(24))
+            .block_timestamp(0)
+            .build());
+
+        contract.add_layaway(accounts(2), "1:1".to_string());
+
+        testing_env!(context
+            .attached_deposit(0)
+            .block_timestamp(7 * ONE_DAY + 1)
+            .build());
+
+        contract.cancel_expired_layaway(accounts(2), "1:1".to_string());
+        assert!(contract
+            .get_layaway(accounts(2), "1:1".to_string())
+            .is_none());
+        assert_eq!(contract.market.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Price is less than minimum listing price")]
+    fn test_invalid_price_below_min_listing_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_collection_min_listing_price(accounts(2), None, Some(U128(10u128.pow(22))));
+        assert_eq!(
+            contract.get_collection_min_listing_price(accounts(2), Some(near_account())),
+            U128(10u128.pow(22))
+        );
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(1),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_relist() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+        let tombstone = contract
+            .get_listing_tombstone(accounts(2), "1:1".to_string())
+            .unwrap();
+        assert_eq!(tombstone.price, 10u128.pow(24));
+        assert_eq!(tombstone.is_auction, Some(true));
+
+        testing_env!(context.attached_deposit(0).build());
+
+        contract.relist(accounts(2), "1:1".to_string(), 2);
+    }
+
+    #[test]
+    fn test_storage_deposit() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+
+        let storage_balance = contract.storage_balance_of(accounts(0)).0;
+        assert_eq!(STORAGE_ADD_MARKET_DATA, storage_balance);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.storage_withdraw();
+
+        let storage_balance = contract.storage_balance_of(accounts(0)).0;
+        assert_eq!(0, storage_balance);
+    }
+
+    #[test]
+    fn test_add_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+            None,
+            None,
+            None,
+        );
+
+        let offer_data =
+            contract.get_offer(accounts(3), accounts(0), Some("1:1".to_string()), None);
+
+        assert_eq!(offer_data.buyer_id, accounts(0));
+        assert_eq!(offer_data.price, U128(one_near));
+    }
+
+    #[test]
+    fn test_offer_transaction_fee_locked() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(10u128.pow(24)),
+            accounts(1),
+            None,
+            None,
+            None,
+        );
+        contract.set_transaction_fee(100, None);
+
+        let offer_data =
+            contract.get_offer(accounts(3), accounts(1), Some("1:1".to_string()), None);
+        assert_eq!(offer_data.transaction_fee, 500);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Offer does not exist")]
+    fn test_delete_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.delete_offer(accounts(3), Some("1:1".to_string()), None);
+
+        contract.get_offer(accounts(3), accounts(1), Some("1:1".to_string()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Offer price is less than minimum offer price 100")]
+    fn test_invalid_add_offer_below_min_offer_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_collection_admin(accounts(3), Some(accounts(4)));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_collection_min_offer_price(accounts(3), Some(U128(100)));
+        assert_eq!(contract.get_min_offer_price(Some(accounts(3))), U128(100));
+        assert_eq!(contract.get_min_offer_price(None), U128(0));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(10)
+            .build());
+
+        contract.add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(10),
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_get_highest_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+            None,
+            None,
+            None,
+        );
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(2 * one_near),
+            accounts(1),
+            None,
+            None,
+            None,
+        );
+
+        let offer_data = contract
+            .internal_get_highest_offer(&accounts(3), "1:1", &accounts(4), one_near)
+            .unwrap();
+        assert_eq!(offer_data.buyer_id, accounts(1));
+
+        contract.internal_delete_offer(accounts(3), accounts(1), "1:1".to_string());
+        let offer_data = contract
+            .internal_get_highest_offer(&accounts(3), "1:1", &accounts(4), one_near)
+            .unwrap();
+        assert_eq!(offer_data.buyer_id, accounts(0));
+
+        assert!(contract
+            .internal_get_highest_offer(&accounts(3), "1:1", &accounts(4), 2 * one_near)
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Up to 50 offers per token")]
+    fn test_invalid_add_offer_token_limit() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        for index in 0..=MAX_OFFERS_PER_TOKEN {
+            contract.internal_add_offer(
+                accounts(3),
+                Some("1:1".to_string()),
+                None,
+                near_account(),
+                U128(10u128.pow(24)),
+                AccountId::new_unchecked(format!("buyer{}.near", index)),
+                None,
+                None,
+                None,
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Offer is targeted to another seller")]
+    fn test_invalid_counter_targeted_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+            Some(accounts(4)),
+            None,
+            None,
+        );
+
+        contract.internal_counter_offer(
+            accounts(3),
+            accounts(0),
+            "1:1".to_string(),
+            "1:1".to_string(),
+            accounts(5),
+            1,
+            2 * one_near,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Offer does not exist")]
+    fn test_accept_quantity_offer_series() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(one_near),
+            accounts(0),
+            None,
+            Some(2),
+            None,
+        );
+
+        contract.internal_accept_offer_series(
+            accounts(2),
+            accounts(0),
+            "1:1".to_string(),
+            accounts(4),
+            1,
+            one_near,
+            None,
+        );
+
+        let offer_data = contract.get_offer(accounts(2), accounts(0), None, Some("1".to_string()));
+        assert_eq!(offer_data.quantity, Some(1));
+
+        contract.internal_accept_offer_series(
+            accounts(2),
+            accounts(0),
+            "1:2".to_string(),
+            accounts(4),
+            2,
+            one_near,
+            None,
+        );
+
+        contract.get_offer(accounts(2), accounts(0), None, Some("1".to_string()));
+    }
+
+    #[test]
+    fn test_counter_offer() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(0),
+            None,
+            None,
+            None,
+        );
+
+        contract.internal_counter_offer(
+            accounts(3),
+            accounts(0),
+            "1:1".to_string(),
+            "1:1".to_string(),
+            accounts(4),
+            1,
+            2 * one_near,
+        );
+
+        let offer_data =
+            contract.get_offer(accounts(3), accounts(0), Some("1:1".to_string()), None);
+
+        assert_eq!(offer_data.price, U128(one_near));
+        assert_eq!(offer_data.counter_seller_id, Some(accounts(4)));
+        assert_eq!(offer_data.counter_price, Some(U128(2 * one_near)));
+    }
+
+    #[test]
+    fn test_add_trade() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        let trade_data = contract.get_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+        );
+
+        assert_eq!(trade_data.token_id.unwrap().to_string(), "1:1");
+        assert_eq!(trade_data.nft_contract_id, accounts(3));
+    }
+
+    #[test]
+    fn test_add_trade_bundle() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_trade(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            Some("2:1".to_string()),
+            accounts(3),
+            1,
+            Some(vec![TradeToken {
+                nft_contract_id: accounts(2),
+                token_id: "2:2".to_string(),
+                approval_id: Some(2),
+            }]),
+            Some(vec![TradeToken {
+                nft_contract_id: accounts(2),
+                token_id: "1:2".to_string(),
+                approval_id: Some(5),
+            }]),
+            None,
+        );
+
+        let trade_data = contract.get_trade(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            accounts(3),
+            accounts(2),
+            "2:1".to_string(),
+        );
+
+        let buyer_tokens = trade_data.buyer_tokens.unwrap();
+        assert_eq!(buyer_tokens[0].token_id, "2:2");
+        assert_eq!(buyer_tokens[0].approval_id, Some(2));
+        let seller_tokens = trade_data.seller_tokens.unwrap();
+        assert_eq!(seller_tokens[0].token_id, "1:2");
+        assert_eq!(seller_tokens[0].approval_id, None);
+    }
+
+    #[test]
+    fn test_add_trade_deposit() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_trade_deposit(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            "1:2".to_string(),
+        );
+        contract.add_trade_deposit(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            "1:2".to_string(),
+        );
+
+        let trade_data = contract.get_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+        );
+
+        assert_eq!(trade_data.buyer_amount, Some(2 * one_near));
+        assert_eq!(trade_data.ft_token_id, Some(near_account().to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Trade is funded by another seller")]
+    fn test_invalid_add_trade_seller_deposit() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_trade_seller_deposit(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+        );
+
+        let trade_data = contract.get_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+        );
+        assert_eq!(trade_data.seller_amount, Some(one_near));
+        assert_eq!(trade_data.seller_id, Some(accounts(4)));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_trade_seller_deposit(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_cleanup_expired_trades() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(1_000)
+            .build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            Some(2_000),
+        );
+
+        assert_eq!(contract.cleanup_expired_trades(None, None), 0);
+
+        testing_env!(context.block_timestamp(2_000).build());
+
+        assert_eq!(contract.cleanup_expired_trades(None, None), 1);
+        assert!(contract
+            .trades
+            .get(&make_triple(&accounts(1), &accounts(2), "1:2"))
+            .is_none());
+        assert_eq!(contract.get_supply_by_owner_id(accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_counter_trade() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_trade(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            accounts(1),
+            Some("1:2".to_string()),
+            accounts(2),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        contract.internal_counter_trade(
+            accounts(3),
+            "1:1".to_string(),
+            accounts(4),
+            3,
+            accounts(2),
+            accounts(1),
+            "1:2".to_string(),
+            Some(accounts(1)),
+            Some("1:3".to_string()),
+            Some(one_near),
+        );
+
+        let counters = contract.get_trade_counters(accounts(2), accounts(1), "1:2".to_string());
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].seller_id, accounts(4));
+        assert_eq!(counters[0].buyer_token_id, "1:3");
+        assert_eq!(counters[0].buyer_amount, one_near);
+    }
+
+    #[test]
+    fn test_accept_trade_matches_series() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_trade(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            accounts(2),
+            Some("2:1".to_string()),
+            accounts(3),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        contract.internal_accept_trade(
+            accounts(2),
+            accounts(3),
+            "1:5".to_string(),
+            accounts(4),
+            2,
+            accounts(2),
+            "2:1".to_string(),
+            None,
+        );
+
+        assert!(contract
+            .trades
+            .get(&make_triple(&accounts(2), &accounts(3), "2:1"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_trade_token_stale_approval() {
+        let (mut context, _contract) = setup_contract();
+
+        testing_env!(context.current_account_id(accounts(0)).build());
+
+        let mut approved_account_ids = HashMap::new();
+        approved_account_ids.insert(accounts(0), 2);
+        let json_token = JsonToken {
+            owner_id: accounts(3),
+            approved_account_ids: Some(approved_account_ids),
+        };
+
+        assert!(is_trade_token_approved(&json_token, Some(2)));
+        assert!(!is_trade_token_approved(&json_token, Some(1)));
+        assert!(is_trade_token_approved(
+            &JsonToken {
+                owner_id: accounts(3),
+                approved_account_ids: None,
+            },
+            Some(1)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Token is not in the trade series")]
+    fn test_invalid_reject_trade_series() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        contract.reject_trade(
+            accounts(2),
+            "2:5".to_string(),
+            Some("1".to_string()),
+            accounts(3),
+            accounts(2),
+            "2:1".to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Trade is bound to another owner")]
+    fn test_accept_trade_bound_owner() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_trade(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            accounts(2),
+            Some("2:1".to_string()),
+            accounts(3),
+            1,
+            None,
+            None,
+            None,
+        );
+
+        let buyer_contract_account_id_token_id = make_triple(&accounts(2), &accounts(3), "2:1");
+        let mut trade_list = contract
+            .trades
             .get(&buyer_contract_account_id_token_id)
-            .expect("Marble: Trade list does not exist");
+            .unwrap();
+        trade_list
+            .trade_data
+            .get_mut(&make_triple(&accounts(2), &accounts(3), "1:1"))
+            .unwrap()
+            .owner_id = Some(accounts(4));
+        contract
+            .trades
+            .insert(&buyer_contract_account_id_token_id, &trade_list);
+
+        contract.internal_accept_trade(
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
+            accounts(1),
+            2,
+            accounts(2),
+            "2:1".to_string(),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Sale is reserved for another buyer")]
+    fn test_buy_private_sale() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.allowed_buyer_ids.insert(
+            &format!("{}{}{}", accounts(2), DELIMETER, "1:1"),
+            &accounts(4),
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_bundle_listing() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        let tokens = ["1:1", "1:2"]
+            .iter()
+            .map(|token_id| TradeToken {
+                nft_contract_id: accounts(2),
+                token_id: token_id.to_string(),
+                approval_id: None,
+            })
+            .collect();
+        let bundle_listing_id = contract.add_bundle_listing(tokens, U128(10u128.pow(24) + 1));
+
+        contract.internal_approve_bundle_token(
+            bundle_listing_id.0,
+            accounts(2),
+            "1:2".to_string(),
+            accounts(3),
+            4,
+        );
+
+        let bundle_listing = contract.get_bundle_listing(bundle_listing_id).unwrap();
+        assert_eq!(bundle_listing.tokens[0].approval_id, None);
+        assert_eq!(bundle_listing.tokens[1].approval_id, Some(4));
+        assert_eq!(
+            bundle_listing_shares(&bundle_listing),
+            vec![10u128.pow(24) / 2, 10u128.pow(24) / 2 + 1]
+        );
+    }
+
+    #[test]
+    fn test_listing_editions() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        for token_id in &["1:1", "1:2"] {
+            contract.internal_add_listing_edition(
+                accounts(3),
+                1,
+                accounts(2),
+                token_id.to_string(),
+                near_account(),
+                U128(10u128.pow(24)),
+                None,
+                None,
+                None,
+            );
+        }
+        assert_eq!(contract.market.len(), 1);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+        let contract_and_series_id = format!("{}{}{}", accounts(2), DELIMETER, "1");
+        let editions = contract
+            .listing_editions
+            .get(&contract_and_series_id)
+            .unwrap();
+        assert_eq!(editions.len(), 1);
+        assert_eq!(editions[0].token_id, "1:2".to_string());
+
+        contract.buy(
+            accounts(2),
+            "1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+        assert_eq!(contract.market.len(), 0);
+        assert!(contract
+            .listing_editions
+            .get(&contract_and_series_id)
+            .is_none());
+    }
+
+    #[test]
+    fn test_mint_sale() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.internal_add_mint_sale(
+            accounts(3),
+            accounts(2),
+            "1".to_string(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        // the series keeps selling until the creator delists it
+        contract.buy(
+            accounts(2),
+            "1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+        assert_eq!(contract.market.len(), 1);
+        let contract_and_series_id = format!("{}{}{}", accounts(2), DELIMETER, "1");
+        assert!(contract.mint_sales.contains(&contract_and_series_id));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Price does not cover the mint storage")]
+    fn test_invalid_mint_sale_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.internal_add_mint_sale(
+            accounts(3),
+            accounts(2),
+            "1".to_string(),
+            U128(STORAGE_MINT_NFT),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_buy_gift() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            Some(accounts(5)),
+        );
+        assert_eq!(contract.market.len(), 0);
+    }
+
+    #[test]
+    fn test_buy_alternative_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            U128(35 * 10u128.pow(6)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        let mut alternative_prices = HashMap::new();
+        alternative_prices.insert(near_account(), U128(10 * 10u128.pow(24)));
+        contract.set_alternative_prices(accounts(2), "1:1".to_string(), alternative_prices);
+        let contract_and_token_id = format!("{}{}{}", accounts(2), DELIMETER, "1:1");
+        assert_eq!(
+            contract
+                .alternative_prices
+                .get(&contract_and_token_id)
+                .unwrap()
+                .get(&near_account()),
+            Some(&U128(10 * 10u128.pow(24)))
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10 * 10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10 * 10u128.pow(24)),
+            None,
+            None,
+        );
+        assert_eq!(contract.market.len(), 0);
+        assert!(contract
+            .alternative_prices
+            .get(&contract_and_token_id)
+            .is_none());
+    }
+
+    #[test]
+    fn test_buy_presale() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let market_data = contract
+            .market
+            .get(&format!("{}{}{}", accounts(2), DELIMETER, "1:1"))
+            .unwrap();
+        contract.internal_add_presale(&market_data, U64(1_000), vec![accounts(4)], None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+        assert_eq!(contract.market.len(), 0);
+        assert!(contract
+            .get_presale(accounts(2), "1:1".to_string())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Sale is reserved for the presale allowlist")]
+    fn test_invalid_buy_presale() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let market_data = contract
+            .market
+            .get(&format!("{}{}{}", accounts(2), DELIMETER, "1:1"))
+            .unwrap();
+        contract.internal_add_presale(&market_data, U64(1_000), vec![accounts(4)], None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_wrapped_near_settlement() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.set_wrap_near_id(Some(accounts(5)));
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        contract.set_receive_wrapped_near(true);
+        assert!(contract.get_receive_wrapped_near(accounts(3)));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+
+        // the listing stays until the wNEAR is unwrapped
+        contract.internal_buy(
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            accounts(4),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            GAS_FOR_FT_PAYOUT,
+        );
+        assert_eq!(contract.market.len(), 1);
+    }
+
+    #[test]
+    fn test_wrapped_deposit_failure() {
+        let (mut context, mut contract) = setup_contract();
+
+        // near_deposit failed, the seller gets the NEAR rather than someone else's wNEAR
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let balance = env::account_balance();
+        contract.callback_wrapped_deposit(accounts(5), accounts(3), U128(10u128.pow(24)));
+        assert_eq!(env::account_balance(), balance - 10u128.pow(24));
+        let logs = near_sdk::test_utils::get_logs();
+        let event: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(logs.last().unwrap()).unwrap();
+        assert_eq!(event["type"], "wrapped_proceeds_fail");
+        assert_eq!(event["params"]["seller_id"], accounts(3).to_string());
+    }
+
+    #[test]
+    fn test_unwrap_near_purchase_stale_listing() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.set_wrap_near_id(Some(accounts(5)));
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(2 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        // relisted at a higher price while unwrapping
+        for (owner_id, price) in [
+            (accounts(3), 10u128.pow(24)),
+            (accounts(1), 2 * 10u128.pow(24)),
+        ] {
+            testing_env!(
+                context.predecessor_account_id(accounts(0)).build(),
+                near_sdk::VMConfig::test(),
+                near_sdk::RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![PromiseResult::Successful(vec![])],
+            );
+            contract.callback_unwrap_near_purchase(
+                accounts(5),
+                accounts(2),
+                "1:1".to_string(),
+                owner_id,
+                accounts(4),
+                accounts(4),
+                U128(price),
+                None,
+                None,
+            );
+            let logs = near_sdk::test_utils::get_logs();
+            let event: near_sdk::serde_json::Value =
+                near_sdk::serde_json::from_str(logs.last().unwrap()).unwrap();
+            assert_eq!(event["type"], "resolve_purchase_fail");
+            assert_eq!(event["params"]["ft_token_id"], "near");
+            assert_eq!(event["params"]["reason"], "sale has changed");
+        }
+        assert_eq!(contract.market.len(), 1);
+    }
+
+    #[test]
+    fn test_fee_exemption() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_fee_exempt_account_ids(vec![accounts(3)]);
+        contract.add_fee_exempt_nft_contract_ids(vec![accounts(5)]);
+
+        assert!(contract.is_fee_exempt(accounts(2), accounts(3)));
+        assert!(contract.is_fee_exempt(accounts(5), accounts(4)));
+        assert!(!contract.is_fee_exempt(accounts(2), accounts(4)));
+        assert_eq!(
+            contract.calculate_market_data_transaction_fee(
+                &accounts(2),
+                &"1:1".to_string(),
+                &accounts(3),
+                10u128.pow(24)
+            ),
+            0
+        );
+        assert_eq!(
+            contract.calculate_offer_transaction_fee(
+                &accounts(5),
+                &accounts(4),
+                10u128.pow(24),
+                500
+            ),
+            0
+        );
+
+        contract.remove_fee_exempt_account_ids(vec![accounts(3)]);
+        assert!(contract.fee_exempt_account_ids().is_empty());
+        assert_eq!(
+            contract.calculate_market_data_transaction_fee(
+                &accounts(2),
+                &"1:1".to_string(),
+                &accounts(3),
+                10u128.pow(24)
+            ),
+            500
+        );
+    }
+
+    #[test]
+    fn test_fee_brackets() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_fee_brackets(vec![
+            FeeBracket {
+                min_price: U128(0),
+                max_price: Some(U128(10 * 10u128.pow(24))),
+                fee: 200,
+            },
+            FeeBracket {
+                min_price: U128(1_000 * 10u128.pow(24)),
+                max_price: None,
+                fee: 100,
+            },
+        ]);
+
+        assert_eq!(
+            contract.get_transaction_fee_for_price(U128(10u128.pow(24))),
+            200
+        );
+        assert_eq!(
+            contract.get_transaction_fee_for_price(U128(100 * 10u128.pow(24))),
+            500
+        );
+        assert_eq!(
+            contract.calculate_offer_transaction_fee(
+                &accounts(2),
+                &accounts(3),
+                2_000 * 10u128.pow(24),
+                500
+            ),
+            100
+        );
+    }
+
+    #[test]
+    fn test_get_effective_fee() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.set_transaction_fee(100, None);
+
+        // the listing keeps the fee it was listed at
+        let effective_fee =
+            contract.get_effective_fee(accounts(2), "1:1".to_string(), U128(10u128.pow(24)));
+        assert_eq!(effective_fee.fee, 500);
+        assert_eq!(effective_fee.amount, U128(5 * 10u128.pow(22)));
+
+        contract.add_fee_exempt_account_ids(vec![accounts(3)]);
+        let effective_fee =
+            contract.get_effective_fee(accounts(2), "1:1".to_string(), U128(10u128.pow(24)));
+        assert_eq!(effective_fee.fee, 0);
+        assert_eq!(effective_fee.amount, U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Fee brackets must ascend without overlapping")]
+    fn test_invalid_fee_brackets() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_fee_brackets(vec![
+            FeeBracket {
+                min_price: U128(0),
+                max_price: Some(U128(10 * 10u128.pow(24))),
+                fee: 200,
+            },
+            FeeBracket {
+                min_price: U128(5 * 10u128.pow(24)),
+                max_price: None,
+                fee: 100,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_listing_fee() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_listing_fee(U128(10u128.pow(22)));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2 + 2 * 10u128.pow(22))
+            .build());
+
+        contract.storage_deposit(None);
+        for token_id in ["1:1", "1:2"] {
+            contract.internal_add_market_data(
+                accounts(3),
+                1,
+                accounts(2),
+                token_id.to_string(),
+                near_account(),
+                U128::from(1 * 10u128.pow(24)),
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+        }
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)),
+            U128(STORAGE_ADD_MARKET_DATA * 2)
+        );
+
+        testing_env!(context.attached_deposit(1).build());
+
+        // delisting refunds the fee, any other removal leaves it with the treasury
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)),
+            U128(STORAGE_ADD_MARKET_DATA * 2 + 10u128.pow(22))
+        );
+        contract.internal_delete_market_data(&accounts(2), &"1:2".to_string());
+        assert_eq!(contract.get_treasury_fees(None), U128(10u128.pow(22)));
+    }
+
+    #[test]
+    fn test_fee_stats() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_record_collection_fee(&accounts(2), &near_account(), 1_000);
+        contract.internal_transfer_treasury_fee(&near_account(), 1_000, None);
+        contract.internal_transfer_treasury_fee(&near_account(), 500, None);
+
+        let fee_stats = contract.get_fee_stats(None, None);
+        assert_eq!(fee_stats.ft_token_ids[&near_account()], U128(1_500));
+        assert_eq!(
+            fee_stats.nft_contract_ids[&accounts(2)][&near_account()],
+            U128(1_000)
+        );
+    }
+
+    #[test]
+    fn test_roles() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::FeeManager);
+        assert!(contract.has_role(accounts(4), Role::FeeManager));
+        assert!(!contract.has_role(accounts(4), Role::Guardian));
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.set_transaction_fee(100, None);
+        assert_eq!(contract.get_transaction_fee().current_fee, 100);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.revoke_role(accounts(4), Role::FeeManager);
+        assert!(contract.get_roles(accounts(4)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Owner only")]
+    fn test_invalid_fee_manager_set_treasury() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::FeeManager);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.set_treasury(accounts(4));
+    }
+
+    #[test]
+    fn test_pause() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::Guardian);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.pause(vec![PauseFeature::Buys, PauseFeature::Offers]);
+        assert!(contract.is_paused(PauseFeature::Buys));
+        assert!(!contract.is_paused(PauseFeature::Listings));
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.unpause(vec![PauseFeature::Offers]);
+        assert!(!contract.is_paused(PauseFeature::Offers));
+        contract.pause(vec![PauseFeature::All]);
+        assert!(contract.is_paused(PauseFeature::Trades));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Buys are paused")]
+    fn test_invalid_buy_paused() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.pause(vec![PauseFeature::Buys]);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_blacklist() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_blacklisted_buyer_ids(vec![accounts(4)]);
+        contract.add_blacklisted_seller_ids(vec![accounts(3)]);
+        assert!(contract.is_blacklisted(accounts(3)));
+        assert_eq!(
+            contract.get_blacklisted_buyer_ids(None, None),
+            vec![accounts(4)]
+        );
+
+        assert_eq!(
+            contract.delete_blacklisted_market_data(accounts(3), None),
+            1
+        );
+        assert_eq!(contract.get_supply_by_owner_id(accounts(3)), U64(0));
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)),
+            U128(STORAGE_ADD_MARKET_DATA)
+        );
+
+        contract.remove_blacklisted_buyer_ids(vec![accounts(4)]);
+        assert!(!contract.is_blacklisted(accounts(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Buyer is blacklisted")]
+    fn test_invalid_buy_blacklisted() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_blacklisted_buyer_ids(vec![accounts(3)]);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_freeze_collection() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.grant_role(accounts(4), Role::Guardian);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.freeze_collection(accounts(2));
+        assert!(contract.is_collection_frozen(accounts(2)));
+        assert_eq!(
+            contract.get_frozen_collections(None, None),
+            vec![accounts(2)]
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.unfreeze_collection(accounts(2));
+        assert!(!contract.is_collection_frozen(accounts(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Collection is frozen")]
+    fn test_invalid_buy_frozen_collection() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.freeze_collection(accounts(2));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Owner only")]
+    fn test_invalid_upgrade() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+
+        contract.upgrade(vec![0, 97, 115, 109]);
+    }
+
+    #[test]
+    fn test_timelock() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .block_timestamp(0)
+            .build());
+
+        contract.set_timelock_delay(U64(ONE_DAY));
+        contract.set_listing_fee(U128(10u128.pow(22)));
+        contract.set_treasury(accounts(5));
+        assert_eq!(contract.get_listing_fee(), U128(0));
+        assert_eq!(contract.get_queued_admin_actions(None, None).len(), 2);
+
+        contract.grant_role(accounts(4), Role::Guardian);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.cancel_admin_action(U64(1));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(ONE_DAY)
+            .build());
+
+        contract.execute_admin_action(U64(0));
+        assert_eq!(contract.get_listing_fee(), U128(10u128.pow(22)));
+        assert_eq!(contract.get_pending_treasury(), None);
+        assert!(contract.get_queued_admin_actions(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_timelock_treasury_recipients() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .block_timestamp(0)
+            .build());
+
+        contract.set_timelock_delay(U64(ONE_DAY));
+        contract.set_treasury_recipients(vec![
+            TreasuryRecipient {
+                account_id: accounts(4),
+                share_bps: 7_000,
+            },
+            TreasuryRecipient {
+                account_id: accounts(5),
+                share_bps: 3_000,
+            },
+        ]);
+        assert_eq!(contract.get_pending_treasury(), None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(ONE_DAY)
+            .build());
+
+        contract.execute_admin_action(U64(0));
+        let pending_admin_actions = contract.get_pending_admin_actions();
+        assert_eq!(pending_admin_actions.pending_treasury_id, Some(accounts(4)));
+        assert_eq!(pending_admin_actions.pending_treasury_recipients.len(), 2);
+        assert_eq!(contract.get_treasury_recipients().len(), 1);
+    }
+
+    #[test]
+    fn test_pending_admin_actions() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .block_timestamp(0)
+            .build());
+
+        assert!(matches!(
+            contract.set_timelock_delay(U64(ONE_DAY)),
+            AdminActionResult::Applied
+        ));
+        assert!(matches!(
+            contract.set_listing_fee(U128(10u128.pow(22))),
+            AdminActionResult::Queued {
+                action_id: U64(0),
+                executable_at: U64(ONE_DAY)
+            }
+        ));
+        contract.transfer_ownership(accounts(5));
+
+        let pending_admin_actions = contract.get_pending_admin_actions();
+        assert_eq!(pending_admin_actions.pending_owner_id, Some(accounts(5)));
+        assert_eq!(pending_admin_actions.pending_treasury_id, None);
+        assert_eq!(pending_admin_actions.queued_admin_actions.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Admin action is not executable yet")]
+    fn test_invalid_execute_admin_action_early() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .block_timestamp(0)
+            .build());
+
+        contract.set_timelock_delay(U64(ONE_DAY));
+        contract.set_listing_fee(U128(10u128.pow(22)));
+
+        testing_env!(context.block_timestamp(ONE_DAY - 1).build());
+
+        contract.execute_admin_action(U64(0));
+    }
+
+    #[test]
+    fn test_settling_listing() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.internal_process_purchase(
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            accounts(4),
+            accounts(4),
+            10u128.pow(24),
+            None,
+            GAS_FOR_ROYALTIES,
+        );
+        assert!(contract.is_settling(accounts(2), "1:1".to_string()));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.clear_settling(accounts(2), "1:1".to_string());
+        assert!(!contract.is_settling(accounts(2), "1:1".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Listing is settling a purchase")]
+    fn test_invalid_list_settling() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.internal_process_purchase(
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            accounts(4),
+            accounts(4),
+            10u128.pow(24),
+            None,
+            GAS_FOR_ROYALTIES,
+        );
+        contract.internal_add_market_data(
+            accounts(3),
+            2,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_signed_order() {
+        use ed25519_dalek::Signer;
+        let (mut context, mut contract) = setup_contract();
+
+        let secret_key = ed25519_dalek::SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public_key = ed25519_dalek::PublicKey::from(&secret_key);
+        let mut public_key_bytes = vec![0u8];
+        public_key_bytes.extend_from_slice(public_key.as_bytes());
+        let keypair = ed25519_dalek::Keypair {
+            secret: secret_key,
+            public: public_key,
+        };
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .block_timestamp(0)
+            .build());
+
+        contract.set_order_public_key(Some(
+            <PublicKey as std::convert::TryFrom<Vec<u8>>>::try_from(public_key_bytes).unwrap(),
+        ));
+        let order = SignedOrder {
+            owner_id: accounts(3),
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            approval_id: U64(1),
+            price: U128(10u128.pow(24)),
+            nonce: U64(0),
+            expires_at: U64(1_000),
+        };
+        let signature = keypair.sign(&contract.get_signed_order_message(order.clone()).0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.fill_signed_order(order, signature.to_bytes().to_vec().into(), None, None);
+        assert!(contract.is_settling(accounts(2), "1:1".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Order has already been filled")]
+    fn test_invalid_signed_order_filled_twice() {
+        use ed25519_dalek::Signer;
+        let (mut context, mut contract) = setup_contract();
+
+        let secret_key = ed25519_dalek::SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public_key = ed25519_dalek::PublicKey::from(&secret_key);
+        let mut public_key_bytes = vec![0u8];
+        public_key_bytes.extend_from_slice(public_key.as_bytes());
+        let keypair = ed25519_dalek::Keypair {
+            secret: secret_key,
+            public: public_key,
+        };
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .block_timestamp(0)
+            .build());
+
+        contract.set_order_public_key(Some(
+            <PublicKey as std::convert::TryFrom<Vec<u8>>>::try_from(public_key_bytes).unwrap(),
+        ));
+        let order = SignedOrder {
+            owner_id: accounts(3),
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            approval_id: U64(1),
+            price: U128(10u128.pow(24)),
+            nonce: U64(0),
+            expires_at: U64(1_000),
+        };
+        let signature = keypair.sign(&contract.get_signed_order_message(order.clone()).0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
+
+        contract.fill_signed_order(
+            order.clone(),
+            signature.to_bytes().to_vec().into(),
+            None,
+            None,
+        );
+        assert!(contract.is_signed_order_filled(accounts(3), U64(0)));
+
+        // settled and bought back, the same order cannot be filled again
+        contract.internal_clear_settling(&accounts(2), &"1:1".to_string());
+        contract.fill_signed_order(order, signature.to_bytes().to_vec().into(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Order has been cancelled")]
+    fn test_invalid_signed_order_cancelled() {
+        use ed25519_dalek::Signer;
+        let (mut context, mut contract) = setup_contract();
+
+        let secret_key = ed25519_dalek::SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public_key = ed25519_dalek::PublicKey::from(&secret_key);
+        let mut public_key_bytes = vec![0u8];
+        public_key_bytes.extend_from_slice(public_key.as_bytes());
+        let keypair = ed25519_dalek::Keypair {
+            secret: secret_key,
+            public: public_key,
+        };
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .block_timestamp(0)
+            .build());
 
-        let trade_data = trade_list
-            .trade_data
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Trade data does not exist");
+        contract.set_order_public_key(Some(
+            <PublicKey as std::convert::TryFrom<Vec<u8>>>::try_from(public_key_bytes).unwrap(),
+        ));
+        let order = SignedOrder {
+            owner_id: accounts(3),
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            approval_id: U64(1),
+            price: U128(10u128.pow(24)),
+            nonce: U64(0),
+            expires_at: U64(1_000),
+        };
+        let signature = keypair.sign(&contract.get_signed_order_message(order.clone()).0);
 
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
-        self.internal_delete_market_data(&buyer_nft_contract_id, &buyer_token_id);
+        testing_env!(context.attached_deposit(1).build());
 
-        let seller_contract_account_id_token_id =
-            make_triple(&nft_contract_id, &seller_id, &token_id);
+        contract.cancel_signed_orders(U64(1));
 
-        if let Some(mut trades) = self.trades.get(&seller_contract_account_id_token_id) {
-            trades.trade_data.clear();
-        }
-        if let Some(mut trades) = self.trades.get(&buyer_contract_account_id_token_id) {
-            trades.trade_data.clear();
-        }
-        self.trades.remove(&seller_contract_account_id_token_id);
-        self.trades.remove(&buyer_contract_account_id_token_id);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
 
-        self.trade_swap_nft(
-            buyer_id,
-            buyer_nft_contract_id,
-            buyer_token_id,
-            trade_list.approval_id,
-            seller_id,
-            nft_contract_id,
-            token_id,
-            approval_id,
-        )
+        contract.fill_signed_order(order, signature.to_bytes().to_vec().into(), None, None);
     }
 
-    fn internal_accept_trade_series(
-        &mut self,
-        nft_contract_id: AccountId,
-        buyer_id: AccountId,
-        token_id: TokenId,
-        seller_id: AccountId,
-        approval_id: u64,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-    ) -> Promise {
-        // Token delimiter : is specific for Marble NFT
-        let mut token_id_iter = token_id.split(":");
-        let token_series_id: String = token_id_iter.next().unwrap().parse().unwrap();
+    #[test]
+    fn test_listing_operator() {
+        let (mut context, mut contract) = setup_contract();
 
-        let buyer_contract_account_id_token_id =
-            make_triple(&buyer_nft_contract_id, &buyer_id, &buyer_token_id);
-        let contract_account_id_token_id =
-            make_triple(&nft_contract_id, &buyer_id, &token_series_id);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
 
-        let trade_list = self
-            .trades
-            .get(&buyer_contract_account_id_token_id)
-            .expect("Marble: Trade list does not exist");
+        contract.storage_deposit(None);
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
 
-        let trade_data = trade_list
-            .trade_data
-            .get(&contract_account_id_token_id)
-            .expect("Marble: Trade data does not exist");
+        // anything above the storage deposit goes back right away
+        testing_env!(context
+            .attached_deposit(STORAGE_ADD_MARKET_DATA + 10)
+            .build());
 
+        let balance = env::account_balance();
+        contract.add_listing_operator(accounts(4));
+        assert_eq!(env::account_balance(), balance - 10);
+        assert!(contract.is_listing_operator(accounts(3), accounts(4)));
         assert_eq!(
-            trade_data.token_series_id.as_ref().unwrap(),
-            &token_series_id
+            contract.get_listing_operators(accounts(3)),
+            vec![accounts(4)]
         );
 
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
-        self.internal_delete_market_data(&buyer_nft_contract_id, &buyer_token_id);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
 
-        let seller_contract_account_id_token_id =
-            make_triple(&nft_contract_id, &seller_id, &token_id);
-        self.trades.remove(&seller_contract_account_id_token_id);
-        self.trades.remove(&buyer_contract_account_id_token_id);
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(contract.get_supply_by_owner_id(accounts(3)), U64(0));
 
-        self.trade_swap_nft(
-            buyer_id,
-            buyer_nft_contract_id,
-            buyer_token_id,
-            trade_list.approval_id,
-            seller_id,
-            nft_contract_id,
-            token_id,
-            approval_id,
-        )
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+
+        // the storage deposit goes back to the owner
+        let balance = env::account_balance();
+        contract.remove_listing_operator(accounts(4));
+        assert!(!contract.is_listing_operator(accounts(3), accounts(4)));
+        assert_eq!(env::account_balance(), balance - STORAGE_ADD_MARKET_DATA);
     }
 
-    fn trade_swap_nft(
-        &mut self,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-        buyer_approval_id: u64,
-        seller_id: AccountId,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-        seller_approval_id: u64,
-    ) -> Promise {
-        // 1. transfer buyer & seller NFT to marketplace
-        // 2. verify that those NFTs is valid and has approval_id
-        // 3. if those NFTs is valid then swap token to buyer & seller
-        // 4. if failed then rollback the NFT to buyer or seller
+    #[test]
+    fn test_admin_log() {
+        let (mut context, mut contract) = setup_contract();
 
-        ext_contract::nft_transfer(
-            env::current_account_id(),
-            buyer_token_id.clone(),
-            Some(buyer_approval_id),
-            buyer_nft_contract_id.clone(),
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_listing_fee(U128(10u128.pow(22)));
+        contract.pause(vec![PauseFeature::Buys]);
+
+        let admin_log = contract.get_admin_log(None, None);
+        assert_eq!(admin_log.len(), 2);
+        assert_eq!(admin_log[0].method, "set_listing_fee");
+        assert_eq!(admin_log[0].account_id, accounts(0));
+        assert_eq!(admin_log[1].method, "pause");
+
+        // the oldest entries are overwritten once the log is full
+        for _ in 0..MAX_ADMIN_LOG_ENTRIES {
+            contract.set_min_offer_price(U128(1));
+        }
+        assert_eq!(contract.get_admin_log_len(), U64(MAX_ADMIN_LOG_ENTRIES + 2));
+        let admin_log = contract.get_admin_log(None, Some(1));
+        assert_eq!(admin_log[0].index, U64(2));
+        assert_eq!(admin_log[0].method, "set_min_offer_price");
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Up to 1 active listings and offers per account")]
+    fn test_invalid_active_limit() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_config(Config {
+            max_active_per_account: 1,
+            ..Config::default()
+        });
+        assert_eq!(contract.get_config().max_active_per_account, 1);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_market_data(
+            accounts(3),
             1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_self::callback_first_trade(
-            seller_nft_contract_id.clone(),
-            seller_token_id.clone(),
-            seller_approval_id,
-            env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_FOR_CALLBACK_FIRST_TRADE,
-        ))
-        .then(ext_self::callback_second_trade(
-            buyer_id,
-            buyer_nft_contract_id.clone(),
-            buyer_token_id.clone(),
-            seller_id,
-            seller_nft_contract_id.clone(),
-            seller_token_id.clone(),
-            env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_FOR_CALLBACK_SECOND_TRADE,
-        ))
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        // relisting the same token is not a new listing
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:2".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
     }
 
-    #[private]
-    pub fn callback_first_trade(
-        &mut self,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-        seller_approval_id: u64,
-    ) -> Promise {
-        if !is_promise_success() {
-            env::panic_str(&"Marble: buyer's nft failed to trade");
-        } else {
-            return ext_contract::nft_transfer(
-                env::current_account_id(),
-                seller_token_id.clone(),
-                Some(seller_approval_id),
-                seller_nft_contract_id.clone(),
-                1,
-                GAS_FOR_NFT_TRANSFER,
-            );
-        }
+    #[test]
+    fn test_verify_collection() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.verify_collection(
+            accounts(2),
+            "Marble Genesis".to_string(),
+            Some("7bd3a8b9".to_string()),
+        );
+        let verification = contract.get_collection_verification(accounts(2)).unwrap();
+        assert_eq!(verification.name, "Marble Genesis");
+        assert_eq!(verification.verified_by, accounts(0));
+        assert_eq!(
+            contract.get_verified_collections(None, None),
+            vec![accounts(2)]
+        );
+
+        contract.remove_approved_nft_contract_ids(vec![accounts(2)]);
+        assert!(contract.get_collection_verification(accounts(2)).is_none());
+
+        contract.unverify_collection(accounts(2));
+        assert!(contract.get_verified_collections(None, None).is_empty());
     }
 
-    #[private]
-    pub fn callback_second_trade(
-        &mut self,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-        seller_id: AccountId,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-    ) {
-        if !is_promise_success() {
-            ext_contract::nft_transfer(
-                buyer_id,
-                buyer_token_id,
-                None,
-                buyer_nft_contract_id,
-                1,
-                GAS_FOR_NFT_TRANSFER,
-            );
-            env::panic_str(&"Marble: seller's nft failed to trade, rollback buyer's nft");
-        } else {
-            self.internal_swap_nft(
-                buyer_id,
-                buyer_nft_contract_id,
-                buyer_token_id,
-                seller_id,
-                seller_nft_contract_id,
-                seller_token_id,
-            );
-        }
+    #[test]
+    #[should_panic(expected = "Marble: price higher than 10000000000000000000000000")]
+    fn test_invalid_price_above_config_max_price() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_config(Config {
+            max_price: U128::from(10 * 10u128.pow(24)),
+            ..Config::default()
+        });
+        assert_eq!(contract.get_config().max_price.0, 10 * 10u128.pow(24));
+        assert_eq!(contract.get_config().max_bids, MAX_BIDS);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(20 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
     }
 
-    fn internal_swap_nft(
-        &mut self,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-        seller_id: AccountId,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-    ) {
-        ext_contract::nft_transfer(
-            seller_id.clone(),
-            buyer_token_id.clone(),
-            None,
-            buyer_nft_contract_id.clone(),
+    #[test]
+    #[should_panic(expected = "Marble: max_bids must be between 1 and 100")]
+    fn test_invalid_set_config() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_config(Config {
+            max_bids: 0,
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn test_moderate_delist() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.storage_deposit(None);
+        contract.internal_add_market_data(
+            accounts(3),
             1,
-            GAS_FOR_NFT_TRANSFER,
-        )
-        .then(ext_contract::nft_transfer(
-            buyer_id.clone(),
-            seller_token_id.clone(),
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
             None,
-            seller_nft_contract_id.clone(),
-            1,
-            GAS_FOR_NFT_TRANSFER,
-        ));
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.internal_add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128::from(10u128.pow(23)),
+            accounts(4),
+            None,
+            None,
+            None,
+        );
 
-        env::log_str(
-            &json!({
-                "type": "accept_trade",
-                "params": {
-                    "sender_id": seller_id,
-                    "buyer_id": buyer_id,
-                    "nft_contract_id": seller_nft_contract_id,
-                    "token_id": seller_token_id,
-                    "buyer_nft_contract_id": buyer_nft_contract_id,
-                    "buyer_token_id": buyer_token_id,
-                }
-            })
-            .to_string(),
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.moderate_delist(accounts(2), "1:1".to_string(), "stolen".to_string());
+
+        assert_eq!(contract.get_supply_by_owner_id(accounts(3)), U64(0));
+        assert_eq!(contract.get_supply_by_owner_id(accounts(4)), U64(0));
+        assert_eq!(
+            contract.get_admin_log(None, None).last().unwrap().method,
+            "moderate_delist".to_string()
         );
     }
 
-    // Auction bids
-    #[payable]
-    pub fn add_bid(
-        &mut self,
-        nft_contract_id: AccountId,
-        ft_token_id: AccountId,
-        token_id: TokenId,
-        amount: U128,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Marble: Token id does not exist");
+    #[test]
+    fn test_sponsor_storage() {
+        let (mut context, mut contract) = setup_contract();
 
-        let bidder_id = env::predecessor_account_id();
-        let current_time = env::block_timestamp();
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
 
-        if market_data.started_at.is_some() {
-            assert!(
-                current_time >= market_data.started_at.unwrap(),
-                "Marble: Sale has not started yet"
-            );
-        }
+        contract.sponsor_storage(accounts(2));
+        assert_eq!(
+            contract.internal_sponsor_listing_storage(
+                &accounts(2),
+                &accounts(3),
+                STORAGE_ADD_MARKET_DATA
+            ),
+            Some(STORAGE_ADD_MARKET_DATA)
+        );
+        assert_eq!(
+            contract.get_storage_sponsor_pool(accounts(2)),
+            U128(STORAGE_ADD_MARKET_DATA)
+        );
+        assert_eq!(
+            contract.storage_balance_of(accounts(3)),
+            U128(STORAGE_ADD_MARKET_DATA)
+        );
 
-        if market_data.ended_at.is_some() {
-            assert!(
-                current_time <= market_data.ended_at.unwrap(),
-                "Marble: Sale has ended"
-            );
-        }
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(1 * 10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.sponsored_listings.insert(
+            &format!("{}{}{}", accounts(2), DELIMETER, "1:1"),
+            &STORAGE_ADD_MARKET_DATA,
+        );
 
-        let remaining_time = market_data.ended_at.unwrap() - current_time;
-        if remaining_time <= FIVE_MINUTES {
-            let extended_ended_at = market_data.ended_at.unwrap() + FIVE_MINUTES;
-            market_data.ended_at = Some(extended_ended_at);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
 
-            env::log_str(
-                &json!({
-                    "type": "extend_auction",
-                    "params": {
-                        "nft_contract_id": nft_contract_id,
-                        "token_id": token_id,
-                        "ended_at": extended_ended_at,
-                    }
-                })
-                .to_string(),
-            );
-        }
+        contract.delete_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(contract.storage_balance_of(accounts(3)), U128(0));
 
-        assert_ne!(
-            market_data.owner_id, bidder_id,
-            "Marble: Owner cannot bid their own token"
-        );
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(1)
+            .build());
 
-        assert!(
-            env::attached_deposit() >= amount.into(),
-            "Marble: attached deposit is less than amount"
+        assert_eq!(
+            contract.withdraw_storage_sponsorship(accounts(2), None),
+            U128(0)
         );
+    }
 
-        assert_eq!(ft_token_id.to_string(), "near", "Marble: Only support NEAR");
+    #[test]
+    fn test_trusted_relayer() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.add_trusted_relayer_ids(vec![accounts(4)]);
         assert_eq!(
-            market_data.ft_token_id.to_string(),
-            "near",
-            "Marble: Only support Registered token"
+            contract.get_trusted_relayer_ids(None, None),
+            vec![accounts(4)]
         );
 
-        assert!(
-            market_data.end_price.is_none(),
-            "Marble: Dutch auction does not accept add_bid"
-        );
+        // a delegated nft_approve, its msg names the owner as the inner signer
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .signer_account_id(accounts(4))
+            .build());
 
-        let new_bid = Bid {
-            bidder_id: bidder_id.clone(),
-            price: amount.into(),
-        };
+        contract.assert_listing_signer(&accounts(3), Some(accounts(3)));
+    }
 
-        let mut bids = market_data.bids.unwrap_or(Vec::new());
+    #[test]
+    #[should_panic(expected = "Marble: owner_id should be the signer or their listing operator")]
+    fn test_invalid_listing_signer_relayed_for_another_owner() {
+        let (mut context, mut contract) = setup_contract();
 
-        if !bids.is_empty() {
-            let current_bid = &bids[bids.len() - 1];
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.add_trusted_relayer_ids(vec![accounts(4)]);
 
-            assert!(
-                amount.0 >= current_bid.price.0 + (current_bid.price.0 / 100 * 5),
-                "Marble: Can't pay less than or equal to current bid price + 5% : {:?}",
-                current_bid.price.0 + (current_bid.price.0 / 100 * 5)
-            );
+        // a trusted relayer does not vouch for the owner by itself
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .signer_account_id(accounts(4))
+            .build());
 
-            assert!(
-                amount.0 >= market_data.price,
-                "Marble: Can't pay less than starting price: {:?}",
-                U128(market_data.price)
-            );
+        contract.assert_listing_signer(&accounts(3), Some(accounts(1)));
+    }
 
-            // Retain all elements except account_id
-            bids.retain(|bid| {
-                if bid.bidder_id == bidder_id {
-                    // refund
-                    Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
-                }
+    #[test]
+    #[should_panic(expected = "Marble: owner_id should be the signer or their listing operator")]
+    fn test_invalid_listing_signer_relayer() {
+        let (mut context, mut contract) = setup_contract();
 
-                bid.bidder_id != bidder_id
-            });
-        } else {
-            assert!(
-                amount.0 >= market_data.price,
-                "Marble: Can't pay less than starting price: {:?}",
-                market_data.price
-            );
-        }
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.add_trusted_relayer_ids(vec![accounts(4)]);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .signer_account_id(accounts(4))
+            .build());
+
+        contract.assert_listing_signer(&accounts(3), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Relayer is not trusted")]
+    fn test_invalid_listing_signer_untrusted_relayer() {
+        let (mut context, contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .signer_account_id(accounts(4))
+            .build());
+
+        contract.assert_listing_signer(&accounts(3), Some(accounts(3)));
+    }
+
+    #[test]
+    fn test_migrate_chunk() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(10u128.pow(24)),
+            accounts(4),
+            None,
+            None,
+            None,
+        );
+        contract.internal_remove_offer_index(&accounts(3), "1:1", &accounts(4));
+        contract.migration = Some(Migration {
+            from_version: StateVersion::V2,
+            to_version: StateVersion::V3,
+            steps: vec![MigrationStep::IndexBids, MigrationStep::IndexOffers],
+            cursor: U64(0),
+        });
+
+        // no market data, the bid step completes at once
+        let migration = contract.migrate_chunk(Some(1)).unwrap();
+        assert_eq!(migration.steps, vec![MigrationStep::IndexOffers]);
+        assert_eq!(contract.migrate_chunk(Some(1)).unwrap().cursor, U64(1));
+        assert!(contract.migrate_chunk(Some(1)).is_none());
+        assert!(contract
+            .offers_by_token
+            .get(&format!("{}{}{}", accounts(3), DELIMETER, "1:1"))
+            .is_some());
+        assert_eq!(contract.get_state_version(), CURRENT_STATE_VERSION);
+    }
 
-        bids.push(new_bid);
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+    #[test]
+    fn test_ft_on_transfer_returns_unused() {
+        let (mut context, mut contract) = setup_contract();
 
-        // Remove first element if bids.length > 50
-        let updated_bids = market_data.bids.unwrap_or(Vec::new());
-        if updated_bids.len() >= 100 {
-            self.internal_cancel_bid(
-                nft_contract_id.clone(),
-                token_id.clone(),
-                updated_bids[0].bidder_id.clone(),
-            )
-        }
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
 
-        env::log_str(
-            &json!({
-                "type": "add_bid",
-                "params": {
-                    "bidder_id": bidder_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "amount": amount,
-                }
+        let msgs = vec![
+            "".to_string(),
+            "not json".to_string(),
+            json!({
+                "nft_contract_id": accounts(2),
+                "token_id": "1:1",
+                "method": "unknown"
             })
             .to_string(),
-        );
+        ];
+        for msg in msgs {
+            match contract.ft_on_transfer(accounts(4), U128(10), msg) {
+                near_sdk::PromiseOrValue::Value(unused_amount) => {
+                    assert_eq!(unused_amount, U128(10))
+                }
+                _ => panic!("Marble: expected the amount back"),
+            }
+        }
     }
 
-    #[payable]
-    fn internal_ft_token_add_bid(
-        &mut self,
-        nft_contract_id: AccountId,
-        ft_token_id: AccountId,
-        token_id: TokenId,
-        sender_id: AccountId,
-        amount: U128,
-    ) {
-        println!("\n\n\nFT TOken Bid Added");
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Marble: Token id does not exist");
-
-        let bidder_id = sender_id;
-        let current_time = env::block_timestamp();
-        if market_data.started_at.is_some() {
-            assert!(
-                current_time >= market_data.started_at.unwrap(),
-                "Marble: Sale has not started yet"
-            );
-        }
+    #[test]
+    fn test_ft_on_transfer_rejects_other_token() {
+        let (mut context, mut contract) = setup_contract();
 
-        println!(
-            "\n\n\nFT TOken Bid Added: {}, {}, {}",
-            bidder_id, ft_token_id, token_id
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            U128(10),
+            None,
+            None,
+            None,
+            None,
+            None,
         );
 
-        if market_data.ended_at.is_some() {
-            assert!(
-                current_time <= market_data.ended_at.unwrap(),
-                "Marble: Sale has ended"
-            );
+        // an unapproved token claims to be the listing currency in its msg
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "ft_token_id": accounts(5),
+            "token_id": "1:1",
+            "method": "buy"
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(1), U128(10), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(10))
+            }
+            _ => panic!("Marble: expected the amount back"),
         }
+        assert_eq!(contract.market.len(), 1);
+    }
 
-        let remaining_time = market_data.ended_at.unwrap() - current_time;
-        if remaining_time <= FIVE_MINUTES {
-            let extended_ended_at = market_data.ended_at.unwrap() + FIVE_MINUTES;
-            market_data.ended_at = Some(extended_ended_at);
+    #[test]
+    fn test_ft_on_transfer_offer() {
+        let (mut context, mut contract) = setup_contract();
 
-            env::log_str(
-                &json!({
-                    "type": "extend_auction",
-                    "params": {
-                        "nft_contract_id": nft_contract_id,
-                        "token_id": token_id,
-                        "ended_at": extended_ended_at,
-                    }
-                })
-                .to_string(),
-            );
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.marble_nft_contracts.insert(&accounts(2));
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * 2)
+            .build());
+        contract.storage_deposit(None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "offer"
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(10), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(0))
+            }
+            _ => panic!("Marble: expected a value"),
         }
+        let offer_data =
+            contract.get_offer(accounts(2), accounts(4), Some("1:1".to_string()), None);
+        assert_eq!(offer_data.ft_token_id, accounts(5));
+        assert_eq!(offer_data.price, U128(10));
 
-        assert_ne!(
-            market_data.owner_id, bidder_id,
-            "Marble: Owner cannot bid their own token"
+        // the remainder of amount / quantity is handed back
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "",
+            "token_series_id": "1",
+            "method": "offer_series",
+            "quantity": 3
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(10), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(1))
+            }
+            _ => panic!("Marble: expected a value"),
+        }
+        let offer_data = contract.get_offer(accounts(2), accounts(4), None, Some("1".to_string()));
+        assert_eq!(offer_data.price, U128(3));
+        assert_eq!(offer_data.quantity, Some(3));
+        assert_eq!(
+            contract.get_locked_balance(accounts(4)).get(&accounts(5)),
+            Some(&U128(19))
         );
+    }
+
+    #[test]
+    fn test_remove_mt_currency() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_mt_currency(accounts(5), "gold".to_string());
 
+        // the refunded total saturates instead of overflowing
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        contract.mt_on_transfer(
+            accounts(4),
+            vec![accounts(4), accounts(4)],
+            vec!["gold".to_string(), "silver".to_string()],
+            vec![U128(u128::MAX), U128(u128::MAX)],
+            "".to_string(),
+        );
         assert_eq!(
-            ft_token_id.to_string(),
-            market_data.ft_token_id.to_string(),
-            "Marble: Only support Registered token"
+            get_token_deposit_refund_event()["params"]["amount"],
+            u128::MAX.to_string()
         );
 
-        assert!(
-            market_data.end_price.is_none(),
-            "Marble: Dutch auction does not accept add_bid"
-        );
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.remove_mt_currency(accounts(5));
+        assert!(contract.get_mt_currencies(None, None).is_empty());
+    }
 
-        let new_bid = Bid {
-            bidder_id: bidder_id.clone(),
-            price: amount.into(),
-        };
+    #[test]
+    fn test_mt_on_transfer() {
+        let (mut context, mut contract) = setup_contract();
 
-        let mut bids = market_data.bids.unwrap_or(Vec::new());
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_mt_currency(accounts(5), "gold".to_string());
+        assert_eq!(contract.get_mt_currencies(None, None)[0].token_id, "gold");
 
-        if !bids.is_empty() {
-            let current_bid = &bids[bids.len() - 1];
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+        contract.storage_deposit(None);
 
-            assert!(
-                amount.0 >= current_bid.price.0 + (current_bid.price.0 / 100 * 5),
-                "Marble: Can't pay less than or equal to current bid price + 5% : {:?}",
-                current_bid.price.0 + (current_bid.price.0 / 100 * 5)
-            );
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "offer"
+        })
+        .to_string();
 
-            assert!(
-                amount.0 >= market_data.price,
-                "Marble: Can't pay less than starting price: {:?}",
-                U128(market_data.price)
-            );
-            // Retain all elements except account_id
-            bids.retain(|bid| {
-                if bid.bidder_id == bidder_id {
-                    // refund
-                    ext_fungible_token::ft_transfer(
-                        bidder_id.clone(),
-                        bid.price.into(),
-                        None,
-                        ft_token_id.clone(),
-                        1,
-                        GAS_FOR_FT_TRANSFER,
-                    )
-                    .then(ext_self::callback_post_withdraw_deposit(
-                        ft_token_id.clone(),
-                        bidder_id.clone(),
-                        amount,
-                        env::current_account_id(),
-                        0,
-                        GAS_FOR_FT_TRANSFER,
-                    ));
-                }
+        // another token of the MT contract is not the currency
+        match contract.mt_on_transfer(
+            accounts(4),
+            vec![accounts(4)],
+            vec!["silver".to_string()],
+            vec![U128(10)],
+            msg.clone(),
+        ) {
+            near_sdk::PromiseOrValue::Value(unused_amounts) => {
+                assert_eq!(unused_amounts, vec![U128(10)])
+            }
+            _ => panic!("Marble: expected the amounts back"),
+        }
 
-                bid.bidder_id != bidder_id
-            });
-        } else {
-            assert!(
-                amount.0 >= market_data.price,
-                "Marble: Can't pay less than starting price: {:?}",
-                market_data.price
-            );
+        match contract.mt_on_transfer(
+            accounts(4),
+            vec![accounts(4)],
+            vec!["gold".to_string()],
+            vec![U128(10)],
+            msg,
+        ) {
+            near_sdk::PromiseOrValue::Value(unused_amounts) => {
+                assert_eq!(unused_amounts, vec![U128(0)])
+            }
+            _ => panic!("Marble: expected a value"),
         }
+        let offer_data =
+            contract.get_offer(accounts(2), accounts(4), Some("1:1".to_string()), None);
+        assert_eq!(offer_data.ft_token_id, accounts(5));
+        assert_eq!(offer_data.price, U128(10));
+    }
 
-        bids.push(new_bid);
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+    #[test]
+    fn test_buy_with_swap() {
+        let (mut context, mut contract) = setup_contract();
 
-        // Remove first element if bids.length > 50
-        let updated_bids = market_data.bids.unwrap_or(Vec::new());
-        if updated_bids.len() >= 100 {
-            self.internal_cancel_bid(
-                nft_contract_id.clone(),
-                token_id.clone(),
-                updated_bids[0].bidder_id.clone(),
-            )
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        // NEAR listings are swapped into wNEAR
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.approved_ft_token_ids.insert(&accounts(1));
+        contract.set_wrap_near_id(Some(accounts(1)));
+        contract.set_dex_id(Some(accounts(3)));
+        contract.internal_add_market_data(
+            accounts(0),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "buy_with_swap",
+            "swap_pool_id": 1
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(20), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(20))
+            }
+            _ => panic!("Marble: expected the amount back"),
+        }
+
+        // the whole amount is deposited into the DEX
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "buy_with_swap",
+            "swap_pool_id": 1,
+            "min_amount_out": U128(10)
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(20), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(0))
+            }
+            _ => panic!("Marble: expected a value"),
         }
+    }
 
-        env::log_str(
-            &json!({
-                "type": "add_bid",
-                "params": {
-                    "bidder_id": bidder_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "amount": amount,
-                }
-            })
-            .to_string(),
+    fn setup_swap_withdraw() -> (VMContextBuilder, Contract, SwapPurchase) {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.approved_ft_token_ids.insert(&accounts(1));
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.set_wrap_near_id(Some(accounts(1)));
+        contract.set_dex_id(Some(accounts(3)));
+        contract.internal_add_market_data(
+            accounts(0),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10),
+            None,
+            None,
+            None,
+            None,
+            None,
         );
+        let swap_purchase = SwapPurchase {
+            dex_id: accounts(3),
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            buyer_id: accounts(4),
+            receiver_id: None,
+            referral_id: None,
+            swap_pool_id: 1,
+            token_in: accounts(5),
+            amount_in: U128(20),
+            token_out: accounts(1),
+            min_amount_out: U128(10),
+        };
+        (context, contract, swap_purchase)
     }
 
-    #[private]
-    pub fn callback_post_withdraw_deposit(
-        &mut self,
-        token_id: AccountId,
-        sender_id: AccountId,
-        amount: U128,
-    ) -> U128 {
-        env::log_str(
-            &json!({
-                "type": "add_bid",
-                "params": {
-                    "token_id": token_id,
-                    "sender_id": sender_id,
-                    "amount": amount,
-                }
+    fn get_buy_with_swap_fail_event() -> near_sdk::serde_json::Value {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| {
+                near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(log).ok()
             })
-            .to_string(),
+            .find(|event| event["type"] == "buy_with_swap_fail")
+            .expect("Marble: expected a buy_with_swap_fail event")
+    }
+
+    #[test]
+    fn test_swap_withdraw_reserved_listing() {
+        let (mut context, mut contract, swap_purchase) = setup_swap_withdraw();
+
+        // made private while swapping, the swapped wNEAR is refunded
+        contract.allowed_buyer_ids.insert(
+            &format!("{}{}{}", accounts(2), DELIMETER, "1:1"),
+            &accounts(3),
         );
-        println!("Promise withdraw ended: {:?}", env::promise_result(0));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.callback_swap_withdraw(swap_purchase, accounts(1), U128(10));
         assert_eq!(
-            env::promise_results_count(),
-            1,
-            "{}",
-            "Error: Withdraw Deposit Failed"
+            get_buy_with_swap_fail_event()["params"]["reason"],
+            "sale has changed"
         );
+        assert!(contract
+            .internal_get_market_data(&format!("{}{}{}", accounts(2), DELIMETER, "1:1"))
+            .is_some());
+    }
 
-        println!("\n\nPost Withdraw: {}, {}", token_id, sender_id);
-        U128(0)
+    #[test]
+    fn test_swap_withdraw_presale_listing() {
+        let (mut context, mut contract, swap_purchase) = setup_swap_withdraw();
+
+        contract.presales.insert(
+            &format!("{}{}{}", accounts(2), DELIMETER, "1:1"),
+            &Presale {
+                public_at: U64(env::block_timestamp() + 1),
+                allowlist: vec![accounts(3)],
+                holder_nft_contract_id: None,
+            },
+        );
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.callback_swap_withdraw(swap_purchase, accounts(1), U128(10));
+        assert_eq!(
+            get_buy_with_swap_fail_event()["params"]["reason"],
+            "sale has changed"
+        );
     }
 
-    fn internal_cancel_bid(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        account_id: AccountId,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Marble: Token id does not exist");
+    #[test]
+    #[should_panic(expected = "Marble: min_amount_out is less than price 10")]
+    fn test_invalid_buy_with_swap_min_amount_out() {
+        let (mut context, mut contract) = setup_contract();
 
-        let mut bids = market_data.bids.unwrap();
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
 
-        assert!(!bids.is_empty(), "Marble: Bids data does not exist");
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.set_wrap_near_id(Some(accounts(1)));
+        contract.set_dex_id(Some(accounts(3)));
+        contract.internal_add_market_data(
+            accounts(0),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
 
-        let ft_token = market_data.ft_token_id.clone();
-        for x in 0..bids.len() {
-            if bids[x].bidder_id == account_id {
-                if ft_token.clone() == near_account() {
-                    // Retain all elements except account_id
-                    Promise::new(bids[x].bidder_id.clone()).transfer(bids[x].price.0);
-                } else {
-                    // Retain all elements except account_id
-                    ext_fungible_token::ft_transfer(
-                        bids[x].bidder_id.clone(),
-                        (bids[x].price.0).into(),
-                        None,
-                        ft_token.clone(),
-                        1,
-                        GAS_FOR_FT_TRANSFER,
-                    )
-                    .then(ext_self::callback_post_withdraw_deposit(
-                        ft_token.clone(),
-                        bids[x].bidder_id.clone(),
-                        bids[x].price.0.into(),
-                        env::current_account_id(),
-                        0,
-                        GAS_FOR_FT_TRANSFER,
-                    ));
-                }
-            }
-        }
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
 
-        bids.retain(|bid| bid.bidder_id != account_id);
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "buy_with_swap",
+            "swap_pool_id": 1,
+            "min_amount_out": U128(9)
+        })
+        .to_string();
+        contract.ft_on_transfer(accounts(4), U128(20), msg);
+    }
 
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+    #[test]
+    fn test_claim_balance() {
+        let (mut context, mut contract) = setup_contract();
 
-        env::log_str(
-            &json!({
-              "type": "cancel_bid",
-              "params": {
-                "bidder_id": account_id, "nft_contract_id": nft_contract_id, "token_id": token_id
-              }
-            })
-            .to_string(),
+        contract.internal_credit_claimable_balance(&accounts(3), &accounts(5), 10);
+        contract.internal_credit_claimable_balance(&accounts(3), &accounts(5), 5);
+        assert_eq!(
+            contract.get_claimable_balance(accounts(3), accounts(5)),
+            U128(15)
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        assert_eq!(contract.claim_balance(accounts(5)), U128(15));
+        assert_eq!(
+            contract.get_claimable_balance(accounts(3), accounts(5)),
+            U128(0)
         );
     }
 
-    #[payable]
-    pub fn cancel_bid(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        account_id: AccountId,
-    ) {
-        assert_one_yocto();
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Marble: Token id does not exist");
+    #[test]
+    fn test_transfer_payout_unregistered() {
+        let (mut context, mut contract) = setup_contract();
 
-        let bids = market_data.bids.unwrap();
+        // storage_balance_of returned null, the royalty waits in the ledger
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"null".to_vec())],
+        );
+        contract.callback_transfer_payout(accounts(5), accounts(3), U128(10));
+        assert_eq!(
+            contract.get_claimable_balance(accounts(3), accounts(5)),
+            U128(10)
+        );
+    }
 
-        assert!(!bids.is_empty(), "Marble: Bids data does not exist");
+    #[test]
+    fn test_ft_on_transfer_bid_amount() {
+        let (mut context, mut contract) = setup_contract();
 
-        for x in 0..bids.len() {
-            if bids[x].bidder_id == account_id {
-                assert!(
-                    [bids[x].bidder_id.clone(), self.owner_id.clone()]
-                        .contains(&env::predecessor_account_id()),
-                    "Marble: Bidder or owner only"
-                );
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            U128(10),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+
+        // a bid above the transferred amount is refused
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "auction",
+            "bid_amount": U128(30)
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(25), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(25))
             }
+            _ => panic!("Marble: expected the amount back"),
         }
 
-        self.internal_cancel_bid(nft_contract_id, token_id, account_id);
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "auction",
+            "bid_amount": U128(20)
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(25), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(5))
+            }
+            _ => panic!("Marble: expected a value"),
+        }
+        let market_data = contract
+            .market
+            .get(&format!("{}||1:1", accounts(2)))
+            .unwrap();
+        assert_eq!(market_data.bids.unwrap()[0].price, U128(20));
     }
 
-    #[payable]
-    pub fn accept_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-        assert_one_yocto();
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Marble: Token id does not exist");
-        let current_time: u64 = env::block_timestamp();
+    #[test]
+    fn test_ft_token_decimals_max_price() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
 
-        let mut bids = market_data.bids.unwrap();
+        let max_price = contract.config.max_price.0;
+        assert_eq!(contract.get_max_price(accounts(5)).0, max_price);
+        assert_eq!(contract.get_ft_token_decimals(near_account()), Some(24));
 
-        assert!(!bids.is_empty(), "Marble: Cannot accept bid with empty bid");
+        contract.ft_token_decimals.insert(&accounts(5), &6);
+        assert_eq!(contract.get_ft_token_decimals(accounts(5)), Some(6));
+        assert_eq!(
+            contract.get_max_price(accounts(5)).0,
+            max_price / 10u128.pow(18)
+        );
+        assert_eq!(contract.get_max_price(near_account()).0, max_price);
+    }
 
-        let selected_bid = bids.remove(bids.len() - 1);
+    #[test]
+    fn test_ft_on_transfer_trade_fee() {
+        let (mut context, mut contract) = setup_contract();
 
-        println!(
-            "\nAccept Bid Accounts {:?}, {:?}, {:?}",
-            market_data.owner_id.clone(),
-            self.owner_id.clone(),
-            env::predecessor_account_id()
-        );
-        assert!(
-            [
-                market_data.owner_id.clone(),
-                self.owner_id.clone(),
-                selected_bid.bidder_id.clone()
-            ]
-            .contains(&env::predecessor_account_id()),
-            "Marble: Seller, owner or top bidder only"
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.set_trade_fee(TradeFee {
+            flat_fee: U128(10u128.pow(22)),
+            fee_bps: 0,
+            marble_exempt: false,
+        });
+        contract.set_ft_trade_fee(accounts(5), Some(U128(10)));
+        assert_eq!(contract.get_ft_trade_fee(accounts(5)), Some(U128(10)));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "trade_fee"
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(15), msg.clone()) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(5))
+            }
+            _ => panic!("Marble: expected a value"),
+        }
+        // one payment at a time
+        match contract.ft_on_transfer(accounts(4), U128(10), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(10))
+            }
+            _ => panic!("Marble: expected a value"),
+        }
+
+        // the prepaid fee replaces the NEAR one and is restored if the trade fails
+        let cash = contract.internal_lock_trade_fee(
+            &accounts(4),
+            &accounts(2),
+            TradeCash {
+                ft_token_id: near_account(),
+                buyer_amount: U128(0),
+                seller_amount: U128(0),
+                trade_fee: U128(0),
+                fee_bps: 0,
+                trade_fee_ft_token_id: None,
+            },
         );
+        assert_eq!(cash.trade_fee, U128(10));
+        assert_eq!(cash.trade_fee_ft_token_id, Some(accounts(5)));
+        assert!(contract.get_trade_fee_payment(accounts(4)).is_none());
+
+        contract.internal_refund_escrowed_cash(&accounts(3), cash, &accounts(4));
+        let payment = contract.get_trade_fee_payment(accounts(4)).unwrap();
+        assert_eq!(payment.ft_token_id, accounts(5));
+        assert_eq!(payment.amount, U128(10));
+    }
 
-        if env::predecessor_account_id() != self.owner_id.clone() && market_data.ended_at.is_some()
-        {
-            assert!(
-                current_time >= market_data.ended_at.unwrap(),
-                "Marble: Auction has not ended yet"
+    #[test]
+    fn test_ft_on_transfer_auction_batch() {
+        let (mut context, mut contract) = setup_contract();
+
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        for token_id in ["1:1", "1:2"] {
+            contract.internal_add_market_data(
+                accounts(3),
+                1,
+                accounts(2),
+                token_id.to_string(),
+                accounts(5),
+                U128(10),
+                None,
+                Some(U64(1999999952971000000)),
+                None,
+                Some(true),
+                None,
             );
         }
 
-        if selected_bid.bidder_id == env::predecessor_account_id() {
-            assert!(
-                selected_bid.price.0 >= market_data.reserve_price.unwrap(),
-                "Marble: Your bid price isn't bigger than reserve price."
-            );
-        }
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
 
-        assert!(
-            market_data.end_price.is_none(),
-            "Marble: Dutch auction does not accept accept_bid"
-        );
+        // amounts that do not sum to the transfer are refused
+        let msg = json!({
+            "method": "auction_batch",
+            "bids": [
+                { "nft_contract_id": accounts(2), "token_id": "1:1", "amount": U128(10) },
+                { "nft_contract_id": accounts(2), "token_id": "1:2", "amount": U128(20) },
+            ]
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(40), msg.clone()) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(40))
+            }
+            _ => panic!("Marble: expected the amount back"),
+        }
 
-        // refund all except selected bids
-        for bid in &bids {
-            if market_data.ft_token_id == near_account() {
-                // refund
-                Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
-            } else {
-                ext_fungible_token::ft_transfer(
-                    bid.bidder_id.clone(),
-                    (bid.price.0).into(),
-                    None,
-                    market_data.ft_token_id.clone(),
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                )
-                .then(ext_self::callback_post_withdraw_deposit(
-                    market_data.ft_token_id.clone(),
-                    bid.bidder_id.clone(),
-                    bid.price.0.into(),
-                    env::current_account_id(),
-                    0,
-                    GAS_FOR_FT_TRANSFER,
-                ));
+        match contract.ft_on_transfer(accounts(4), U128(30), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(0))
             }
+            _ => panic!("Marble: expected a value"),
         }
-        bids.clear();
+        for (token_id, price) in [("1:1", U128(10)), ("1:2", U128(20))] {
+            let market_data = contract
+                .market
+                .get(&format!("{}||{}", accounts(2), token_id))
+                .unwrap();
+            assert_eq!(market_data.bids.unwrap()[0].price, price);
+        }
+    }
 
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+    #[test]
+    fn test_ft_on_transfer_refund_error() {
+        let (mut context, mut contract) = setup_contract();
 
-        self.internal_process_purchase(
-            market_data.nft_contract_id,
-            token_id,
-            selected_bid.bidder_id.clone(),
-            selected_bid.price.clone().0,
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            U128(10),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
         );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(1999999952971000001)
+            .build());
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "auction"
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(20), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(20))
+            }
+            _ => panic!("Marble: expected the amount back"),
+        }
+        let logs = near_sdk::test_utils::get_logs();
+        let event: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(logs.last().unwrap()).unwrap();
+        assert_eq!(event["type"], "token_deposit_refund");
+        assert_eq!(event["params"]["amount"], "20");
+        assert_eq!(event["params"]["error"]["error"], "auction_ended");
+
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "bid"
+        })
+        .to_string();
+        contract.ft_on_transfer(accounts(4), U128(20), msg);
+        let logs = near_sdk::test_utils::get_logs();
+        let event: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(logs.last().unwrap()).unwrap();
+        assert_eq!(event["params"]["error"]["error"], "unknown_method");
+        assert_eq!(event["params"]["error"]["method"], "bid");
     }
 
-    // Market Data functions
+    #[test]
+    fn test_payout_preference() {
+        let (mut context, mut contract) = setup_contract();
 
-    #[payable]
-    pub fn update_market_data(
-        &mut self,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        price: U128,
-        mut reserve_price: Option<U128>,
-    ) {
-        assert_one_yocto();
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
-            .market
-            .get(&contract_and_token_id)
-            .expect("Marble: Token id does not exist ");
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.set_wrap_near_id(Some(accounts(5)));
 
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
         assert_eq!(
-            market_data.owner_id,
-            env::predecessor_account_id(),
-            "Marble: Seller only"
+            contract.get_payout_preference(accounts(3)),
+            PayoutPreference::AsListed
         );
-
+        contract.set_receive_wrapped_near(true);
         assert_eq!(
-            ft_token_id, market_data.ft_token_id,
-            "Marble: ft_token_id differs"
-        ); // sanity check
-
-        assert!(
-            price.0 < MAX_PRICE,
-            "Marble: price higher than {}",
-            MAX_PRICE
+            contract.get_payout_preference(accounts(3)),
+            PayoutPreference::WrappedNear
+        );
+        contract.set_payout_preference(PayoutPreference::NativeNear);
+        assert_eq!(
+            contract.get_payout_preference(accounts(3)),
+            PayoutPreference::NativeNear
+        );
+        assert!(!contract.get_receive_wrapped_near(accounts(3)));
+
+        // wNEAR that cannot be unwrapped is left to claim
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
         );
+        contract.callback_unwrapped_proceeds(accounts(5), accounts(3), U128(10));
+        assert_eq!(
+            contract.get_claimable_balance(accounts(3), accounts(5)),
+            U128(10)
+        );
+    }
 
-        if reserve_price.is_some() {
-            assert!(
-                reserve_price.unwrap().0 >= price.0,
-                "Marble: Reserve price is more than starting price"
-            );
-        } else {
-            reserve_price = price.into();
+    #[test]
+    fn test_stable_priced_listing() {
+        let (mut context, mut contract) = setup_contract();
+
+        let usdt: AccountId = "usdt.near".parse().unwrap();
+        for (ft_token_id, decimals) in [(accounts(5), 6), (usdt.clone(), 18)] {
+            contract.approved_ft_token_ids.insert(&ft_token_id);
+            contract.ft_token_decimals.insert(&ft_token_id, &decimals);
         }
-        market_data.reserve_price = match reserve_price {
-            Some(x) => Some(x.0),
-            None => None,
-        };
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.add_stablecoin_ids(vec![accounts(5), usdt.clone()]);
 
-        market_data.price = price.into();
-        self.market.insert(&contract_and_token_id, &market_data);
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            U128(10_000_000),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let contract_and_token_id = format!("{}||1:1", accounts(2));
+        let market_data = contract.market.get(&contract_and_token_id).unwrap();
+        assert_eq!(
+            contract.internal_listing_price(&contract_and_token_id, &market_data, &usdt),
+            None
+        );
 
-        env::log_str(
-            &json!({
-                "type": "update_market_data",
-                "params": {
-                    "owner_id": market_data.owner_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                }
-            })
-            .to_string(),
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.set_stable_priced(accounts(2), "1:1".to_string(), true);
+        assert!(contract.is_stable_priced(accounts(2), "1:1".to_string()));
+        assert_eq!(
+            contract.internal_listing_price(&contract_and_token_id, &market_data, &usdt),
+            Some(10 * 10u128.pow(18))
         );
     }
 
-    fn internal_add_market_data(
-        &mut self,
-        owner_id: AccountId,
-        approval_id: u64,
-        nft_contract_id: AccountId,
-        token_id: TokenId,
-        ft_token_id: AccountId,
-        price: U128,
-        mut started_at: Option<U64>,
-        ended_at: Option<U64>,
-        end_price: Option<U128>,
-        is_auction: Option<bool>,
-        mut reserve_price: Option<U128>,
-    ) {
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-
-        let bids: Option<Bids> = match is_auction {
-            Some(u) => {
-                if u {
-                    Some(Vec::new())
-                } else {
-                    None
-                }
-            }
-            None => None,
-        };
+    #[test]
+    fn test_ft_on_transfer_paused() {
+        let (mut context, mut contract) = setup_contract();
 
-        let current_time: u64 = env::block_timestamp();
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        contract.pause(vec![PauseFeature::All]);
 
-        if started_at.is_some() {
-            // if start time is behind that current time, makes it current time
-            if started_at.unwrap().0 <= current_time {
-                started_at = Some(current_time.into());
+        // handed back before the msg is even read
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        match contract.ft_on_transfer(accounts(4), U128(20), "not a msg".to_string()) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(20))
             }
-            // assert!(started_at.unwrap().0 >= current_time);
+            _ => panic!("Marble: expected the amount back"),
+        }
+        let logs = near_sdk::test_utils::get_logs();
+        let event: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(logs.last().unwrap()).unwrap();
+        assert_eq!(event["params"]["error"]["error"], "paused");
+        assert_eq!(event["params"]["error"]["feature"], "ft_deposits");
+    }
 
-            if ended_at.is_some() {
-                assert!(started_at.unwrap().0 < ended_at.unwrap().0);
-            }
-            println!(
-                "\n\n\nstarted_at Price {:?},{:?},{:?}\n\n",
-                started_at.unwrap(),
-                current_time,
-                env::block_timestamp()
+    #[test]
+    fn test_listing_id() {
+        let (_, mut contract) = setup_contract();
+
+        for expected_listing_id in 0..2 {
+            contract.internal_add_market_data(
+                accounts(3),
+                1,
+                accounts(2),
+                "1:1".to_string(),
+                near_account(),
+                U128(10u128.pow(24)),
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+            assert_eq!(
+                contract.get_listing_id(accounts(2), "1:1".to_string()),
+                Some(U64(expected_listing_id))
+            );
+            // the ended listing keeps its id until the token is relisted
+            contract.internal_delete_market_data(&accounts(2), &"1:1".to_string());
+            assert_eq!(
+                contract.get_listing_id(accounts(2), "1:1".to_string()),
+                Some(U64(expected_listing_id))
             );
         }
+        assert_eq!(contract.get_next_sale_id(), U64(0));
+    }
+
+    #[test]
+    fn test_outbid_and_bid_refunded() {
+        let (mut context, mut contract) = setup_contract();
+
+        contract.internal_add_market_data(
+            accounts(1),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10u128.pow(24))
+            .build());
+        contract.add_bid(
+            accounts(2),
+            near_account(),
+            "1:1".to_string(),
+            U128(10u128.pow(24)),
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(2 * 10u128.pow(24))
+            .build());
+        contract.add_bid(
+            accounts(2),
+            near_account(),
+            "1:1".to_string(),
+            U128(2 * 10u128.pow(24)),
+        );
+        let events: Vec<near_sdk::serde_json::Value> = near_sdk::test_utils::get_logs()
+            .iter()
+            .map(|log| near_sdk::serde_json::from_str(log).unwrap())
+            .collect();
+        let outbid = events
+            .iter()
+            .find(|event| event["type"] == "outbid")
+            .unwrap();
+        assert_eq!(outbid["params"]["bidder_id"], accounts(3).to_string());
+        assert_eq!(outbid["params"]["new_bidder_id"], accounts(4).to_string());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract.cancel_bid(accounts(2), "1:1".to_string(), accounts(3));
+        let events: Vec<near_sdk::serde_json::Value> = near_sdk::test_utils::get_logs()
+            .iter()
+            .map(|log| near_sdk::serde_json::from_str(log).unwrap())
+            .collect();
+        let bid_refunded = events
+            .iter()
+            .find(|event| event["type"] == "bid_refunded")
+            .unwrap();
+        assert_eq!(
+            bid_refunded["params"]["amount"],
+            "1000000000000000000000000"
+        );
+        assert_eq!(bid_refunded["params"]["reason"], "cancelled");
+    }
 
-        if let Some(is_auction) = is_auction {
-            if is_auction == true {
-                if started_at.is_none() {
-                    started_at = Some(U64(current_time));
-                }
-                assert!(ended_at.is_some(), "Marble: Ended at is none");
-            }
-        }
+    #[test]
+    fn test_event_schema() {
+        let (_, contract) = setup_contract();
+
+        let event_schema = contract.get_event_schema();
+        assert_eq!(event_schema.standard, EVENT_STANDARD);
+        assert_eq!(event_schema.version, EVENT_VERSION);
+        let mut event_types = event_schema.event_types.clone();
+        event_types.sort();
+        event_types.dedup();
+        assert_eq!(event_types, event_schema.event_types);
+        assert!(event_types.contains(&"resolve_purchase".to_string()));
+    }
 
-        if ended_at.is_some() {
-            assert!(ended_at.unwrap().0 >= current_time);
-        }
+    #[test]
+    fn test_changes_since() {
+        let (mut context, mut contract) = setup_contract();
 
-        if end_price.is_some() {
-            assert!(
-                end_price.unwrap().0 < price.0,
-                "Marble: End price is more than starting price"
-            );
+        testing_env!(context.block_index(10).build());
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128(10u128.pow(24)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        testing_env!(context.block_index(20).build());
+        contract.internal_delete_market_data(&accounts(2), &"1:1".to_string());
+
+        let contract_and_token_id = format!("{}{}{}", accounts(2), DELIMETER, "1:1");
+        let changes = contract.get_changes_since(U64(0), None);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].change_type, ChangeType::Market);
+        assert_eq!(changes[0].key, contract_and_token_id);
+        assert_eq!(changes[0].block_height, U64(10));
+        assert_eq!(changes[1].block_height, U64(20));
+
+        let changes = contract.get_changes_since(U64(11), None);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].index, U64(1));
+        assert!(contract.get_changes_since(U64(21), None).is_empty());
+
+        // the oldest changes are overwritten past MAX_CHANGES
+        testing_env!(context.block_index(30).build());
+        for _ in 0..MAX_CHANGES {
+            contract.internal_record_change(ChangeType::Offer, "key");
         }
+        assert_eq!(contract.get_changes_len(), U64(MAX_CHANGES + 2));
+        let changes = contract.get_changes_since(U64(0), Some(1));
+        assert_eq!(changes[0].index, U64(2));
+        assert_eq!(changes[0].change_type, ChangeType::Offer);
+    }
 
-        if reserve_price.is_some() {
-            assert!(
-                reserve_price.unwrap().0 >= price.0,
-                "Marble: Reserve price is more than starting price"
+    fn accept_offer_args(token_ids: &[&str]) -> Vec<AcceptOfferArgs> {
+        token_ids
+            .iter()
+            .map(|token_id| AcceptOfferArgs {
+                buyer_id: accounts(3),
+                token_id: token_id.to_string(),
+                approval_id: 1,
+            })
+            .collect()
+    }
+
+    fn setup_accept_offers_batch() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_contract();
+        for token_id in ["1:1", "1:2"] {
+            contract.internal_add_offer(
+                accounts(2),
+                Some(token_id.to_string()),
+                None,
+                near_account(),
+                U128(10u128.pow(24)),
+                accounts(3),
+                None,
+                None,
+                None,
             );
-        } else {
-            reserve_price = price.into();
         }
-        println!("\n\n\nReserve Price {:?}", reserve_price.unwrap());
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build());
+        (context, contract)
+    }
 
-        assert!(
-            price.0 < MAX_PRICE,
-            "Marble: price higher than {}",
-            MAX_PRICE
+    fn callback_token_owner(context: &mut VMContextBuilder, owner_id: AccountId) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                json!({ "owner_id": owner_id }).to_string().into_bytes()
+            )],
         );
+    }
 
-        self.market.insert(
-            &contract_and_token_id,
-            &MarketData {
-                owner_id: owner_id.clone().into(),
-                approval_id,
-                nft_contract_id: nft_contract_id.clone().into(),
-                token_id: token_id.clone(),
-                ft_token_id: ft_token_id.clone(),
-                price: price.into(),
-                bids: bids,
-                started_at: match started_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                ended_at: match ended_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                end_price: match end_price {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                accept_nft_contract_id: None,
-                accept_token_id: None,
-                is_auction: is_auction,
-                reserve_price: match reserve_price {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-            },
-        );
+    #[test]
+    fn test_accept_offers_batch() {
+        let (mut context, mut contract) = setup_accept_offers_batch();
 
-        let mut token_ids = self.by_owner_id.get(&owner_id).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&owner_id),
-                }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
+        contract.accept_offers_batch(accounts(2), accept_offer_args(&["1:1", "1:2"]));
 
-        token_ids.insert(&contract_and_token_id);
+        // nft_token confirmed the seller, the offer is taken out for the payout
+        callback_token_owner(&mut context, accounts(1));
+        contract.callback_accept_offer_batch(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
+            1,
+        );
+        assert!(contract
+            .offers
+            .get(&make_triple(&accounts(2), &accounts(3), "1:1"))
+            .is_none());
+        assert!(contract
+            .offers
+            .get(&make_triple(&accounts(2), &accounts(3), "1:2"))
+            .is_some());
+    }
 
-        self.by_owner_id.insert(&owner_id, &token_ids);
+    #[test]
+    fn test_accept_offers_batch_skip() {
+        let (mut context, mut contract) = setup_accept_offers_batch();
 
-        // update offer trade approval_id
-        let owner_contract_account_id_token_id =
-            make_triple(&nft_contract_id, &owner_id, &token_id);
-        let trade_data = self.trades.get(&owner_contract_account_id_token_id);
-        if let Some(mut trade_list) = trade_data {
-            trade_list.approval_id = approval_id;
-            self.trades
-                .insert(&owner_contract_account_id_token_id, &trade_list);
-        }
+        // the token changed hands since the batch was sent
+        callback_token_owner(&mut context, accounts(4));
+        contract.callback_accept_offer_batch(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
+            1,
+        );
+        assert!(contract
+            .offers
+            .get(&make_triple(&accounts(2), &accounts(3), "1:1"))
+            .is_some());
+        assert_eq!(
+            get_accept_offer_batch_skip_reasons(),
+            vec!["seller is not token owner"]
+        );
 
-        // set market data transaction fee
-        let current_transaction_fee = self.calculate_current_transaction_fee();
-        self.market_data_transaction_fee
-            .transaction_fee
-            .insert(&contract_and_token_id, &current_transaction_fee);
+        // the offer was deleted since the batch was sent
+        contract.internal_delete_offer(accounts(2), accounts(3), "1:2".to_string());
+        callback_token_owner(&mut context, accounts(1));
+        contract.callback_accept_offer_batch(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            "1:2".to_string(),
+            1,
+        );
+        assert_eq!(
+            get_accept_offer_batch_skip_reasons(),
+            vec!["offer does not exist"]
+        );
+    }
 
-        env::log_str(
-            &json!({
-                "type": "add_market_data",
-                "params": {
-                    "owner_id": owner_id,
-                    "approval_id": approval_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "ft_token_id": ft_token_id,
-                    "price": price,
-                    "started_at": started_at,
-                    "ended_at": ended_at,
-                    "end_price": end_price,
-                    "is_auction": is_auction,
-                    "transaction_fee": current_transaction_fee.to_string(),
-                }
+    fn get_accept_offer_batch_skip_reasons() -> Vec<String> {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .map(|log| {
+                let event: near_sdk::serde_json::Value =
+                    near_sdk::serde_json::from_str(log).unwrap();
+                assert_eq!(event["type"], "accept_offer_batch_skip");
+                event["params"]["reason"].as_str().unwrap().to_string()
             })
-            .to_string(),
-        );
+            .collect()
     }
 
-    fn internal_delete_market_data(
-        &mut self,
-        nft_contract_id: &AccountId,
-        token_id: &TokenId,
-    ) -> Option<MarketData> {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+    #[test]
+    #[should_panic(expected = "Marble: Not enough gas to accept 2 offers")]
+    fn test_invalid_accept_offers_batch_gas() {
+        let (mut context, mut contract) = setup_accept_offers_batch();
 
-        let market_data: Option<MarketData> = if let Some(market_data) =
-            self.old_market.get(&contract_and_token_id)
-        {
-            self.old_market.remove(&contract_and_token_id);
-            Some(MarketData {
-                owner_id: market_data.owner_id,
-                approval_id: market_data.approval_id,
-                nft_contract_id: market_data.nft_contract_id,
-                token_id: market_data.token_id,
-                ft_token_id: market_data.ft_token_id,
-                price: market_data.price,
-                bids: None,
-                started_at: None,
-                ended_at: None,
-                end_price: None,
-                accept_nft_contract_id: None,
-                accept_token_id: None,
-                is_auction: None,
-                reserve_price: None,
-            })
-        } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
-            self.market.remove(&contract_and_token_id);
+        testing_env!(context.prepaid_gas(Gas(100_000_000_000_000)).build());
+        contract.accept_offers_batch(accounts(2), accept_offer_args(&["1:1", "1:2"]));
+    }
 
-            if let Some(ref bids) = market_data.bids {
-                for bid in bids {
-                    if market_data.ft_token_id == near_account() {
-                        Promise::new(bid.bidder_id.clone()).transfer(bid.price.0);
-                    } else {
-                        ext_fungible_token::ft_transfer(
-                            bid.bidder_id.clone(),
-                            (bid.price.0).into(),
-                            None,
-                            market_data.ft_token_id.clone(),
-                            1,
-                            GAS_FOR_FT_TRANSFER,
-                        )
-                        .then(ext_self::callback_post_withdraw_deposit(
-                            market_data.ft_token_id.clone(),
-                            bid.bidder_id.clone(),
-                            bid.price.0.into(),
-                            env::current_account_id(),
-                            0,
-                            GAS_FOR_FT_TRANSFER,
-                        ));
-                    }
-                }
-            };
+    #[test]
+    #[should_panic(expected = "Marble: Cannot accept more than 3 offers at once")]
+    fn test_invalid_accept_offers_batch_len() {
+        let (_, mut contract) = setup_accept_offers_batch();
 
-            Some(market_data)
-        } else {
-            None
-        };
+        contract.accept_offers_batch(
+            accounts(2),
+            accept_offer_args(&["1:1", "1:2", "1:3", "1:4"]),
+        );
+    }
 
-        market_data.map(|market_data| {
-            let by_owner_id = self.by_owner_id.get(&market_data.owner_id);
-            if let Some(mut by_owner_id) = by_owner_id {
-                by_owner_id.remove(&contract_and_token_id);
-                if by_owner_id.is_empty() {
-                    self.by_owner_id.remove(&market_data.owner_id);
-                } else {
-                    self.by_owner_id.insert(&market_data.owner_id, &by_owner_id);
-                }
-            }
-            market_data
-        })
+    fn get_update_offer_event() -> near_sdk::serde_json::Value {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .map(|log| near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(log).unwrap())
+            .find(|event| event["type"] == "update_offer")
+            .expect("Marble: expected an update_offer event")
     }
 
-    #[payable]
-    pub fn delete_market_data(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-        assert_one_yocto();
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let current_time: u64 = env::block_timestamp();
+    fn setup_ft_offer() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_contract();
 
-        let market_data: Option<MarketData> =
-            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
-                Some(MarketData {
-                    owner_id: market_data.owner_id,
-                    approval_id: market_data.approval_id,
-                    nft_contract_id: market_data.nft_contract_id,
-                    token_id: market_data.token_id,
-                    ft_token_id: market_data.ft_token_id,
-                    price: market_data.price,
-                    bids: None,
-                    started_at: None,
-                    ended_at: None,
-                    end_price: None,
-                    accept_nft_contract_id: None,
-                    accept_token_id: None,
-                    is_auction: None,
-                    reserve_price: None,
-                })
-            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
-                Some(market_data)
-            } else {
-                None
-            };
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+        contract.storage_deposit(None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "offer"
+        })
+        .to_string();
+        contract.ft_on_transfer(accounts(4), U128(10), msg);
+        (context, contract)
+    }
 
-        let market_data: MarketData = market_data.expect("Marble: Market data does not exist");
+    #[test]
+    fn test_update_ft_offer() {
+        let (mut context, mut contract) = setup_ft_offer();
 
-        assert!(
-            [market_data.owner_id.clone(), self.owner_id.clone()]
-                .contains(&env::predecessor_account_id()),
-            "Marble: Seller or owner only"
+        // raised by transferring the difference in the offer's token
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "update_offer"
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(4), U128(5), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(0))
+            }
+            _ => panic!("Marble: expected a value"),
+        }
+        let offer_data =
+            contract.get_offer(accounts(2), accounts(4), Some("1:1".to_string()), None);
+        assert_eq!(offer_data.price, U128(15));
+
+        // lowered, the difference goes back in the offer's token
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.update_offer(accounts(2), Some("1:1".to_string()), None, U128(12));
+        let event = get_update_offer_event();
+        assert_eq!(event["params"]["ft_token_id"], accounts(5).to_string());
+        assert_eq!(event["params"]["refund"], "3");
+        assert_eq!(
+            contract.get_locked_balance(accounts(4)).get(&accounts(5)),
+            Some(&U128(12))
         );
+        assert!(contract
+            .get_locked_balance(accounts(4))
+            .get(&near_account())
+            .is_none());
+    }
 
-        // if market_data.is_auction.is_some() && env::predecessor_account_id() == self.owner_id {
-        //   assert!(
-        //     current_time >= market_data.ended_at.unwrap(),
-        //     "Marble: Auction has not ended yet"
-        //   );
-        // }
+    #[test]
+    #[should_panic(expected = "Marble: Raise an FT offer through ft_transfer_call")]
+    fn test_invalid_update_ft_offer_with_near() {
+        let (mut context, mut contract) = setup_ft_offer();
 
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10)
+            .build());
+        contract.update_offer(accounts(2), Some("1:1".to_string()), None, U128(20));
+    }
 
-        env::log_str(
-            &json!({
-                "type": "delete_market_data",
-                "params": {
-                    "owner_id": market_data.owner_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                }
-            })
-            .to_string(),
-        );
+    #[test]
+    #[should_panic(expected = "Marble: Raise an FT offer through ft_transfer_call")]
+    fn test_invalid_accept_ft_counter_offer_with_near() {
+        let (mut context, mut contract) = setup_ft_offer();
+
+        let offer_key = make_triple(&accounts(2), &accounts(4), "1:1");
+        let mut offer_data = contract.offers.get(&offer_key).unwrap();
+        offer_data.counter = Some(CounterOfferData {
+            seller_id: accounts(3),
+            token_id: "1:1".to_string(),
+            approval_id: 1,
+            price: 20,
+        });
+        contract.offers.insert(&offer_key, &offer_data);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(10)
+            .build());
+        contract.accept_counter_offer(accounts(2), Some("1:1".to_string()), None);
     }
 
-    // Storage
+    #[test]
+    fn test_update_near_offer_refund() {
+        let (mut context, mut contract) = setup_contract();
 
-    #[payable]
-    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) {
-        let storage_account_id = account_id
-            .map(|a| a.into())
-            .unwrap_or_else(env::predecessor_account_id);
-        let deposit = env::attached_deposit();
-        assert!(
-            deposit >= STORAGE_ADD_MARKET_DATA,
-            "Requires minimum deposit of {}",
-            STORAGE_ADD_MARKET_DATA
+        contract.internal_add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(2 * 10u128.pow(24)),
+            accounts(4),
+            None,
+            None,
+            None,
         );
 
-        let mut balance: u128 = self.storage_deposits.get(&storage_account_id).unwrap_or(0);
-        balance += deposit;
-        self.storage_deposits.insert(&storage_account_id, &balance);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.update_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            U128(10u128.pow(24)),
+        );
+        let event = get_update_offer_event();
+        assert_eq!(event["params"]["ft_token_id"], "near");
+        assert_eq!(event["params"]["refund"], 10u128.pow(24).to_string());
+        assert_eq!(
+            contract
+                .get_locked_balance(accounts(4))
+                .get(&near_account()),
+            Some(&U128(10u128.pow(24)))
+        );
     }
 
-    #[payable]
-    pub fn storage_withdraw(&mut self) {
-        assert_one_yocto();
-        let owner_id = env::predecessor_account_id();
-        let mut amount = self.storage_deposits.remove(&owner_id).unwrap_or(0);
-        let market_data_owner = self.by_owner_id.get(&owner_id);
-        let len = market_data_owner.map(|s| s.len()).unwrap_or_default();
-        let diff = u128::from(len) * STORAGE_ADD_MARKET_DATA;
-        amount -= diff;
-        if amount > 0 {
-            Promise::new(owner_id.clone()).transfer(amount);
-        }
-        if diff > 0 {
-            self.storage_deposits.insert(&owner_id, &diff);
-        }
-    }
+    #[test]
+    fn test_update_offer() {
+        let (mut context, mut contract) = setup_contract();
 
-    pub fn storage_minimum_balance(&self) -> U128 {
-        U128(STORAGE_ADD_MARKET_DATA)
-    }
+        let one_near = 10u128.pow(24);
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(one_near),
+            accounts(4),
+            None,
+            Some(3),
+            None,
+        );
 
-    pub fn storage_balance_of(&self, account_id: AccountId) -> U128 {
-        self.storage_deposits.get(&account_id).unwrap_or(0).into()
+        // raised, the top-up covers every edition
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(3 * one_near)
+            .build());
+        contract.update_offer(accounts(2), None, Some("1".to_string()), U128(2 * one_near));
+        let offer_data = contract.get_offer(accounts(2), accounts(4), None, Some("1".to_string()));
+        assert_eq!(offer_data.price, U128(2 * one_near));
+        let event = get_update_offer_event();
+        assert_eq!(event["params"]["old_price"], one_near.to_string());
+        assert_eq!(event["params"]["refund"], "0");
+
+        // lowered, the difference of every edition is refunded
+        testing_env!(context.attached_deposit(1).build());
+        contract.update_offer(accounts(2), None, Some("1".to_string()), U128(one_near / 2));
+        let event = get_update_offer_event();
+        assert_eq!(
+            event["params"]["refund"],
+            (3 * (2 * one_near - one_near / 2)).to_string()
+        );
+        assert_eq!(
+            contract
+                .get_locked_balance(accounts(4))
+                .get(&near_account()),
+            Some(&U128(3 * (one_near / 2)))
+        );
     }
 
-    // View
+    #[test]
+    #[should_panic(expected = "Marble: Attached deposit != (price - offer price) * quantity")]
+    fn test_invalid_update_offer_top_up() {
+        let (mut context, mut contract) = setup_contract();
 
-    pub fn get_market_data(self, nft_contract_id: AccountId, token_id: TokenId) -> MarketDataJson {
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let market_data: Option<MarketData> =
-            if let Some(market_data) = self.old_market.get(&contract_and_token_id) {
-                Some(MarketData {
-                    owner_id: market_data.owner_id,
-                    approval_id: market_data.approval_id,
-                    nft_contract_id: market_data.nft_contract_id,
-                    token_id: market_data.token_id,
-                    ft_token_id: market_data.ft_token_id,
-                    price: market_data.price,
-                    bids: None,
-                    started_at: None,
-                    ended_at: None,
-                    end_price: None,
-                    accept_nft_contract_id: None,
-                    accept_token_id: None,
-                    is_auction: None,
-                    reserve_price: None,
-                })
-            } else if let Some(market_data) = self.market.get(&contract_and_token_id) {
-                Some(market_data)
-            } else {
-                None
-            };
+        let one_near = 10u128.pow(24);
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(one_near),
+            accounts(4),
+            None,
+            Some(3),
+            None,
+        );
 
-        let market_data = market_data.expect("Marble: Market data does not exist");
+        // the top-up of a single edition
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(one_near)
+            .build());
+        contract.update_offer(accounts(2), None, Some("1".to_string()), U128(2 * one_near));
+    }
 
-        let mut price = market_data.price;
+    #[test]
+    #[should_panic(expected = "Marble: Offer price is unchanged")]
+    fn test_invalid_update_offer_unchanged() {
+        let (mut context, mut contract) = setup_contract();
 
-        if market_data.is_auction.is_some() && market_data.end_price.is_some() {
-            let current_time = env::block_timestamp();
-            let end_price = market_data.end_price.unwrap();
-            let started_at = market_data.started_at.unwrap();
-            let ended_at = market_data.ended_at.unwrap();
+        contract.internal_add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(10u128.pow(24)),
+            accounts(4),
+            None,
+            None,
+            None,
+        );
 
-            if current_time < started_at {
-                // Use current market_data.price
-            } else if current_time > ended_at {
-                price = end_price;
-            } else {
-                let time_since_start = current_time - started_at;
-                let duration = ended_at - started_at;
-                price = price - ((price - end_price) / duration as u128) * time_since_start as u128;
-            }
-        }
-        let reserve_price = market_data.reserve_price.map(|x| x.into());
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.update_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            U128(10u128.pow(24)),
+        );
+    }
 
-        let current_transaction_fee = self
-            .get_market_data_transaction_fee(&market_data.nft_contract_id, &market_data.token_id);
+    fn setup_add_offers_batch(offers_count: u128) -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_contract();
 
-        MarketDataJson {
-            owner_id: market_data.owner_id,
-            approval_id: market_data.approval_id.into(),
-            nft_contract_id: market_data.nft_contract_id,
-            token_id: market_data.token_id,
-            ft_token_id: market_data.ft_token_id, // "near" for NEAR token
-            price: price.into(),
-            bids: market_data.bids,
-            started_at: market_data.started_at.map(|x| x.into()),
-            ended_at: market_data.ended_at.map(|x| x.into()),
-            end_price: market_data.end_price.map(|x| x.into()),
-            is_auction: market_data.is_auction,
-            transaction_fee: current_transaction_fee.into(),
-            reserve_price: reserve_price,
-            current_time: to_sec(env::block_timestamp()),
-        }
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA * offers_count)
+            .build());
+        contract.storage_deposit(None);
+        (context, contract)
     }
 
-    pub fn approved_ft_token_ids(&self) -> Vec<AccountId> {
-        self.approved_ft_token_ids.to_vec()
-    }
+    #[test]
+    fn test_add_offers_batch() {
+        let (mut context, mut contract) = setup_add_offers_batch(3);
 
-    pub fn approved_nft_contract_ids(&self) -> Vec<AccountId> {
-        self.approved_nft_contract_ids.to_vec()
-    }
+        let one_near = 10u128.pow(24);
+        testing_env!(context.attached_deposit(2 * one_near).build());
+        contract.add_offers_batch(
+            accounts(2),
+            vec!["1:1".to_string(), "1:2".to_string()],
+            U128(one_near),
+        );
+        for token_id in ["1:1", "1:2"] {
+            let offer_data =
+                contract.get_offer(accounts(2), accounts(4), Some(token_id.to_string()), None);
+            assert_eq!(offer_data.price, U128(one_near));
+        }
 
-    pub fn get_owner(&self) -> AccountId {
-        self.owner_id.clone()
+        // the standing offer on 1:2 is refunded and replaced
+        testing_env!(context.attached_deposit(4 * one_near).build());
+        contract.add_offers_batch(
+            accounts(2),
+            vec!["1:2".to_string(), "1:3".to_string()],
+            U128(2 * one_near),
+        );
+        let offer_data =
+            contract.get_offer(accounts(2), accounts(4), Some("1:2".to_string()), None);
+        assert_eq!(offer_data.price, U128(2 * one_near));
+        assert_eq!(
+            contract
+                .get_locked_balance(accounts(4))
+                .get(&near_account()),
+            Some(&U128(5 * one_near))
+        );
     }
 
-    pub fn get_treasury(&self) -> AccountId {
-        self.treasury_id.clone()
-    }
+    #[test]
+    #[should_panic(expected = "Marble: Attached deposit != price_each * token_ids length")]
+    fn test_invalid_add_offers_batch_deposit() {
+        let (mut context, mut contract) = setup_add_offers_batch(2);
 
-    pub fn get_supply_by_owner_id(&self, account_id: AccountId) -> U64 {
-        self.by_owner_id
-            .get(&account_id)
-            .map_or(0, |by_owner_id| by_owner_id.len())
-            .into()
+        // enough for one offer only
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        contract.add_offers_batch(
+            accounts(2),
+            vec!["1:1".to_string(), "1:2".to_string()],
+            U128(10u128.pow(24)),
+        );
     }
 
-    // private fn
+    #[test]
+    #[should_panic(expected = "Marble: Duplicate token ids")]
+    fn test_invalid_add_offers_batch_duplicate() {
+        let (mut context, mut contract) = setup_add_offers_batch(2);
 
-    fn assert_owner(&self) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner_id,
-            "Marble: Owner only"
-        )
+        testing_env!(context.attached_deposit(2 * 10u128.pow(24)).build());
+        contract.add_offers_batch(
+            accounts(2),
+            vec!["1:1".to_string(), "1:1".to_string()],
+            U128(10u128.pow(24)),
+        );
     }
-}
 
-pub fn hash_account_id(account_id: &AccountId) -> CryptoHash {
-    let mut hash = CryptoHash::default();
-    hash.copy_from_slice(&env::sha256(account_id.as_bytes()));
-    hash
-}
+    #[test]
+    #[should_panic(expected = "Marble: Cannot add more than 10 offers at once")]
+    fn test_invalid_add_offers_batch_size() {
+        let (mut context, mut contract) = setup_add_offers_batch(11);
 
-pub fn hash_contract_account_id_token_id(
-    contract_account_id_token_id: &ContractAccountIdTokenId,
-) -> CryptoHash {
-    let mut hash = CryptoHash::default();
-    hash.copy_from_slice(&env::sha256(contract_account_id_token_id.as_bytes()));
-    hash
-}
+        let token_ids: Vec<TokenId> = (0..=MAX_ADD_OFFERS_BATCH)
+            .map(|index| format!("1:{}", index))
+            .collect();
+        testing_env!(context
+            .attached_deposit(token_ids.len() as u128 * 10u128.pow(24))
+            .build());
+        contract.add_offers_batch(accounts(2), token_ids, U128(10u128.pow(24)));
+    }
 
-pub fn to_sec(timestamp: Timestamp) -> TimestampSec {
-    (timestamp / 10u64.pow(9)) as u32
-}
+    #[test]
+    #[should_panic(expected = "Marble: price higher than 1000000000000000000000000000000000")]
+    fn test_invalid_add_offers_batch_max_price() {
+        let (mut context, mut contract) = setup_add_offers_batch(1);
 
-#[ext_contract(ext_self)]
-trait ExtSelf {
-    fn resolve_purchase(
-        &mut self,
-        buyer_id: AccountId,
-        market_data: MarketData,
-        price: U128,
-    ) -> Promise;
+        let max_price = contract.config.max_price.0;
+        testing_env!(context.attached_deposit(max_price).build());
+        contract.add_offers_batch(accounts(2), vec!["1:1".to_string()], U128(max_price));
+    }
 
-    fn resolve_offer(
-        &mut self,
-        seller_id: AccountId,
-        offer_data: OfferData,
-        token_id: TokenId,
-    ) -> Promise;
+    fn get_decline_offer_event() -> near_sdk::serde_json::Value {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .map(|log| near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(log).unwrap())
+            .find(|event| event["type"] == "decline_offer")
+            .unwrap()
+    }
 
-    fn callback_first_trade(
-        &mut self,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-        seller_approval_id: u64,
-    ) -> Promise;
+    #[test]
+    fn test_decline_offer() {
+        let (mut context, mut contract) = setup_accept_offers_batch();
 
-    fn callback_second_trade(
-        &mut self,
-        buyer_id: AccountId,
-        buyer_nft_contract_id: AccountId,
-        buyer_token_id: TokenId,
-        seller_id: AccountId,
-        seller_nft_contract_id: AccountId,
-        seller_token_id: TokenId,
-    ) -> Promise;
+        contract.decline_offer(accounts(2), accounts(3), "1:1".to_string(), None);
 
-    fn callback_post_withdraw_deposit(
-        &mut self,
-        token_id: AccountId,
-        sender_id: AccountId,
-        amount: U128,
-    ) -> U128;
+        // nft_token confirmed the seller, the buyer is refunded
+        callback_token_owner(&mut context, accounts(1));
+        contract.callback_decline_offer(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
+            None,
+        );
+        let event = get_decline_offer_event();
+        assert_eq!(event["params"]["price"], 10u128.pow(24).to_string());
+        assert!(contract
+            .offers
+            .get(&make_triple(&accounts(2), &accounts(3), "1:1"))
+            .is_none());
+        // the other offer stays locked
+        assert_eq!(
+            contract
+                .get_locked_balance(accounts(3))
+                .get(&near_account()),
+            Some(&U128(10u128.pow(24)))
+        );
+    }
 
-    fn callback_post(&mut self);
-}
+    #[test]
+    fn test_decline_series_offer() {
+        let (mut context, mut contract) = setup_contract();
 
-fn add_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
-    accounts.map(|ids| {
-        ids.iter().for_each(|id| {
-            set.insert(id);
-        })
-    });
-}
+        let one_near = 10u128.pow(24);
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(one_near),
+            accounts(3),
+            Some(accounts(1)),
+            Some(2),
+            None,
+        );
 
-fn remove_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<AccountId>) {
-    accounts.map(|ids| {
-        ids.iter().for_each(|id| {
-            set.remove(id);
-        })
-    });
-}
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .prepaid_gas(Gas(300_000_000_000_000))
+            .build());
+        contract.decline_offer(
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
+            Some("1".to_string()),
+        );
 
-fn make_triple(nft_contract_id: &AccountId, buyer_id: &AccountId, token: &str) -> String {
-    format!(
-        "{}{}{}{}{}",
-        nft_contract_id, DELIMETER, buyer_id, DELIMETER, token
-    )
-}
+        // every edition of the escrow is refunded
+        callback_token_owner(&mut context, accounts(1));
+        contract.callback_decline_offer(
+            accounts(1),
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
+            Some("1".to_string()),
+        );
+        let event = get_decline_offer_event();
+        assert_eq!(event["params"]["token_series_id"], "1");
+        assert!(contract
+            .offers
+            .get(&make_triple(&accounts(2), &accounts(3), "1"))
+            .is_none());
+        assert!(contract.get_locked_balance(accounts(3)).is_empty());
+    }
 
-fn make_key_owner_by_id_trade(contract_account_id_token_id: String) -> String {
-    format!("{}{}trade", contract_account_id_token_id, DELIMETER)
-}
+    #[test]
+    #[should_panic(expected = "Marble: Only the targeted seller can decline a series offer")]
+    fn test_invalid_decline_open_series_offer() {
+        let (mut context, mut contract) = setup_contract();
 
-#[cfg(all(test, not(target_arch = "wasm32")))]
-mod tests {
-    use super::*;
-    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::testing_env;
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(10u128.pow(24)),
+            accounts(3),
+            None,
+            None,
+            None,
+        );
 
-    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
-        let mut builder = VMContextBuilder::new();
-        builder
-            .current_account_id(accounts(0))
-            .signer_account_id(predecessor_account_id.clone())
-            .predecessor_account_id(predecessor_account_id);
-        builder
+        // holding an edition does not make an open offer yours to decline
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.decline_offer(
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
+            Some("1".to_string()),
+        );
     }
 
-    fn setup_contract() -> (VMContextBuilder, Contract) {
-        let mut context = VMContextBuilder::new();
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
-        let contract = Contract::new(
-            accounts(0),
-            accounts(1),
+    #[test]
+    #[should_panic(expected = "Marble: token_id is not in token_series_id")]
+    fn test_invalid_decline_series_offer_token() {
+        let (mut context, mut contract) = setup_contract();
+
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(10u128.pow(24)),
+            accounts(3),
+            None,
+            None,
             None,
-            Some(vec![accounts(2)]),
-            Some(vec![accounts(2)]),
-            500,
         );
-        (context, contract)
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.decline_offer(
+            accounts(2),
+            accounts(3),
+            "2:1".to_string(),
+            Some("1".to_string()),
+        );
     }
 
     #[test]
-    fn test_new() {
-        let mut context = get_context(accounts(0));
-        testing_env!(context.build());
-        let contract = Contract::new(
-            accounts(0),
-            accounts(1),
+    #[should_panic(expected = "Marble: Token owner only")]
+    fn test_invalid_decline_offer_not_owner() {
+        let (mut context, mut contract) = setup_accept_offers_batch();
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.decline_offer(accounts(2), accounts(3), "1:1".to_string(), None);
+
+        // accounts(1) owns the token
+        callback_token_owner(&mut context, accounts(1));
+        contract.callback_decline_offer(
+            accounts(4),
+            accounts(2),
+            accounts(3),
+            "1:1".to_string(),
             None,
-            Some(vec![accounts(2)]),
-            Some(vec![accounts(2)]),
-            500,
         );
-        testing_env!(context.is_view(true).build());
-        assert_eq!(contract.get_owner(), accounts(0));
-        assert_eq!(contract.get_treasury(), accounts(1));
-        assert_eq!(contract.approved_ft_token_ids(), vec![near_account()]);
-        assert_eq!(contract.approved_nft_contract_ids(), vec![accounts(2)]);
-        assert_eq!(contract.transaction_fee.current_fee, 500);
     }
 
     #[test]
-    fn test_set_treasury() {
+    fn test_resolve_purchase_refund_offers() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
-            .build());
+        let one_near = 10u128.pow(24);
+        contract.internal_add_offer(
+            accounts(2),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(3),
+            None,
+            None,
+            None,
+        );
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(one_near),
+            accounts(3),
+            None,
+            None,
+            None,
+        );
+        let market_data = MarketData {
+            owner_id: accounts(4),
+            approval_id: 1,
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            ft_token_id: near_account(),
+            price: 2 * one_near,
+            bids: None,
+            started_at: None,
+            ended_at: None,
+            end_price: None,
+            accept_nft_contract_id: None,
+            accept_token_id: None,
+            is_auction: None,
+            reserve_price: None,
+        };
 
-        contract.set_treasury(accounts(5));
-        let new_treasury: AccountId = contract.get_treasury();
-        assert_eq!(new_treasury, accounts(5));
+        // the buyer bought the token, their standing offers on it are refunded
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&HashMap::from([(accounts(4), U128(2 * one_near))]))
+                    .unwrap()
+            )],
+        );
+        contract.resolve_purchase(accounts(3), market_data, U128(2 * one_near), None);
+
+        let refunds: Vec<near_sdk::serde_json::Value> = near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| {
+                near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(log).ok()
+            })
+            .filter(|event| event["type"] == "delete_offer")
+            .collect();
+        assert_eq!(refunds.len(), 2);
+        assert!(refunds
+            .iter()
+            .all(|event| event["params"]["reason"] == "purchased"));
+        assert!(contract
+            .offers
+            .get(&make_triple(&accounts(2), &accounts(3), "1:1"))
+            .is_none());
+        assert!(contract
+            .offers
+            .get(&make_triple(&accounts(2), &accounts(3), "1"))
+            .is_none());
+        assert!(contract.get_locked_balance(accounts(3)).is_empty());
     }
 
     #[test]
-    #[should_panic(expected = "Marble: Owner only")]
-    fn test_invalid_set_treasury() {
+    fn test_resolve_purchase_keep_quantity_offer() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context
-            .predecessor_account_id(accounts(1))
-            .attached_deposit(1)
-            .build());
+        let one_near = 10u128.pow(24);
+        contract.internal_add_offer(
+            accounts(2),
+            None,
+            Some("1".to_string()),
+            near_account(),
+            U128(one_near),
+            accounts(3),
+            None,
+            Some(2),
+            None,
+        );
+        let market_data = MarketData {
+            owner_id: accounts(4),
+            approval_id: 1,
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            ft_token_id: near_account(),
+            price: one_near,
+            bids: None,
+            started_at: None,
+            ended_at: None,
+            end_price: None,
+            accept_nft_contract_id: None,
+            accept_token_id: None,
+            is_auction: None,
+            reserve_price: None,
+        };
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&HashMap::from([(accounts(4), U128(one_near))]))
+                    .unwrap()
+            )],
+        );
+        contract.resolve_purchase(accounts(3), market_data, U128(one_near), None);
 
-        contract.set_treasury(accounts(5));
+        // the buyer still wants more editions
+        assert_eq!(
+            contract
+                .get_locked_balance(accounts(3))
+                .get(&near_account()),
+            Some(&U128(2 * one_near))
+        );
     }
 
     #[test]
-    fn test_transfer_ownership() {
-        let (mut context, mut contract) = setup_contract();
-
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
-            .build());
+    #[should_panic(expected = "Marble: Cannot scan more than 50 trades at once")]
+    fn test_invalid_cleanup_expired_trades_limit() {
+        let (_, mut contract) = setup_contract();
 
-        contract.transfer_ownership(accounts(5));
-        let new_owner: AccountId = contract.get_owner();
-        assert_eq!(new_owner, accounts(5));
+        contract.cleanup_expired_trades(None, Some(MAX_CLEANUP_TRADES + 1));
     }
 
-    #[test]
-    #[should_panic(expected = "Marble: Owner only")]
-    fn test_invalid_transfer_ownership() {
-        let (mut context, mut contract) = setup_contract();
-
-        testing_env!(context
-            .predecessor_account_id(accounts(5))
-            .attached_deposit(1)
-            .build());
+    fn trade_tokens(token_ids: &[&str]) -> Vec<TradeToken> {
+        token_ids
+            .iter()
+            .map(|token_id| TradeToken {
+                nft_contract_id: accounts(2),
+                token_id: token_id.to_string(),
+                approval_id: Some(1),
+            })
+            .collect()
+    }
 
-        contract.transfer_ownership(accounts(5));
+    fn get_trade_failed_event() -> near_sdk::serde_json::Value {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| {
+                near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(log).ok()
+            })
+            .find(|event| event["type"] == "trade_failed")
+            .expect("Marble: expected a trade_failed event")
     }
 
     #[test]
-    fn test_add_approved_ft_token_ids() {
+    fn test_escrow_trade_partial_failure() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
-            .build());
+        // the second buyer token did not reach the escrow
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed],
+        );
+        contract.callback_escrow_trade(
+            accounts(3),
+            trade_tokens(&["1:1", "1:2"]),
+            TradeCash {
+                ft_token_id: near_account(),
+                buyer_amount: U128(10u128.pow(24)),
+                seller_amount: U128(0),
+                trade_fee: U128(0),
+                fee_bps: 0,
+                trade_fee_ft_token_id: None,
+            },
+            accounts(4),
+            trade_tokens(&["2:1"]),
+        );
 
-        contract.add_approved_ft_token_ids(vec![accounts(5)]);
-        let approved_fts = contract.approved_ft_token_ids();
-        assert_eq!(approved_fts, vec![near_account(), accounts(5)]);
+        // only the escrowed token goes back, the seller token never moves
+        let event = get_trade_failed_event();
+        let returned_tokens = event["params"]["returned_tokens"].as_array().unwrap();
+        assert_eq!(returned_tokens.len(), 1);
+        assert_eq!(returned_tokens[0]["token_id"], "1:1");
+        assert!(event["params"]["transferred_tokens"]
+            .as_array()
+            .unwrap()
+            .is_empty());
     }
 
     #[test]
-    fn test_add_approved_nft_contract_ids() {
+    fn test_resolve_trade_partial_failure() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
-            .build());
+        // the buyer token is escrowed but the seller token did not move
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.callback_resolve_trade(
+            accounts(3),
+            trade_tokens(&["1:1"]),
+            TradeCash {
+                ft_token_id: near_account(),
+                buyer_amount: U128(0),
+                seller_amount: U128(10u128.pow(24)),
+                trade_fee: U128(0),
+                fee_bps: 0,
+                trade_fee_ft_token_id: None,
+            },
+            accounts(4),
+            trade_tokens(&["2:1"]),
+        );
 
-        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
-        let approved_nfts = contract.approved_nft_contract_ids();
-        assert_eq!(approved_nfts, vec![accounts(2), accounts(5)]);
+        let event = get_trade_failed_event();
+        let returned_tokens = event["params"]["returned_tokens"].as_array().unwrap();
+        assert_eq!(returned_tokens.len(), 1);
+        assert_eq!(returned_tokens[0]["token_id"], "1:1");
+        assert!(contract.trade_history.is_empty());
+
+        // a failed return leaves a claim for the buyer
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.callback_return_stuck_nft(accounts(2), "1:1".to_string(), accounts(3));
+        assert_eq!(
+            contract.get_stuck_nft_claim(accounts(2), "1:1".to_string()),
+            Some(accounts(3))
+        );
+    }
+
+    fn get_token_deposit_refund_event() -> near_sdk::serde_json::Value {
+        near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| {
+                near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(log).ok()
+            })
+            .find(|event| event["type"] == "token_deposit_refund")
+            .expect("Marble: expected a token_deposit_refund event")
     }
 
     #[test]
-    fn test_remove_approved_nft_contract_ids() {
+    fn test_ft_on_transfer_ft_token_id_mismatch() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
-            .build());
+        contract.approved_ft_token_ids.insert(&accounts(5));
+        contract.internal_add_market_data(
+            accounts(3),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            accounts(5),
+            U128(10),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
+        );
 
-        contract.add_approved_nft_contract_ids(vec![accounts(5)]);
-        contract.remove_approved_nft_contract_ids(vec![accounts(5)]);
-        let approved_nfts = contract.approved_nft_contract_ids();
-        assert_eq!(approved_nfts, vec![accounts(2)]);
+        // an approved token pays, but its msg names another currency
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "ft_token_id": accounts(4),
+            "token_id": "1:1",
+            "method": "auction"
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(1), U128(20), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(20))
+            }
+            _ => panic!("Marble: expected the amount back"),
+        }
+        let event = get_token_deposit_refund_event();
+        assert_eq!(event["params"]["error"]["error"], "ft_token_id_mismatch");
+        assert!(contract
+            .get_market_data(accounts(2), "1:1".to_string())
+            .bids
+            .unwrap_or_default()
+            .is_empty());
     }
 
     #[test]
-    fn test_internal_add_market_data() {
+    fn test_ft_on_transfer_not_approved_ft() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
-
+        // listed in a token that was never approved
         contract.internal_add_market_data(
             accounts(3),
             1,
             accounts(2),
             "1:1".to_string(),
-            near_account(),
-            U128::from(1 * 10u128.pow(24)),
-            Some(U64(100)),
-            None,
+            accounts(4),
+            U128(10),
             None,
+            Some(U64(1999999952971000000)),
             None,
+            Some(true),
             None,
         );
 
-        let market = contract.get_market_data(accounts(2), "1:1".to_string());
-        assert_eq!(market.owner_id, accounts(3));
-        assert_eq!(market.approval_id, U64::from(1));
-        assert_eq!(market.ft_token_id, near_account());
-        assert_eq!(market.nft_contract_id, accounts(2));
-        assert_eq!(market.owner_id, accounts(3));
-        assert_eq!(market.token_id, "1:1".to_string());
-        assert_eq!(market.price, U128::from(1 * 10u128.pow(24)));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let msg = json!({
+            "nft_contract_id": accounts(2),
+            "token_id": "1:1",
+            "method": "auction"
+        })
+        .to_string();
+        match contract.ft_on_transfer(accounts(1), U128(20), msg) {
+            near_sdk::PromiseOrValue::Value(unused_amount) => {
+                assert_eq!(unused_amount, U128(20))
+            }
+            _ => panic!("Marble: expected the amount back"),
+        }
+        let event = get_token_deposit_refund_event();
+        assert_eq!(event["params"]["error"]["error"], "not_approved_ft");
+        assert!(contract.get_locked_balance(accounts(1)).is_empty());
     }
 
     #[test]
-    #[should_panic(expected = "Marble: price higher than 1000000000000000000000000000000000")]
-    fn test_invalid_price_higher_than_max_price() {
+    fn test_buy_refund_excess_deposit() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
-
+        let one_near = 10u128.pow(24);
         contract.internal_add_market_data(
             accounts(3),
             1,
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1_000_000_000 * 10u128.pow(24)),
+            U128(one_near),
+            None,
+            None,
+            None,
             None,
             None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(3 * one_near)
+            .build());
+        let balance = env::account_balance();
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
             None,
+            U128(3 * one_near),
             None,
             None,
         );
+
+        // only the price stays escrowed while the purchase settles
+        assert_eq!(env::account_balance(), balance - 2 * one_near);
+        let contract_and_token_id = format!("{}{}{}", accounts(2), DELIMETER, "1:1");
+        assert!(contract.settling_listings.contains(&contract_and_token_id));
+        assert!(contract.market.get(&contract_and_token_id).is_none());
     }
 
     #[test]
-    #[should_panic(expected = "Marble: price higher than 1000000000000000000000000000000000")]
-    fn test_invalid_price_higher_than_max_price_update() {
+    fn test_buy_dutch_auction_refund_excess_deposit() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
-
+        let one_near = 10u128.pow(24);
         contract.internal_add_market_data(
-            accounts(0),
+            accounts(3),
             1,
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            U128(2 * one_near),
+            Some(U64(0)),
+            Some(U64(1_000)),
+            Some(U128(one_near)),
+            Some(true),
+            None,
+        );
+
+        // signed at the starting price, executed once it dropped to the end price
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .block_timestamp(2_000)
+            .attached_deposit(2 * one_near)
+            .build());
+        let balance = env::account_balance();
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
             None,
             None,
+            U128(2 * one_near),
             None,
             None,
+        );
+
+        assert_eq!(env::account_balance(), balance - one_near);
+    }
+
+    fn setup_verify_purchase() -> (VMContextBuilder, Contract, MarketData) {
+        let (context, mut contract) = setup_contract();
+
+        contract
+            .settling_listings
+            .insert(&format!("{}{}{}", accounts(2), DELIMETER, "1:1"));
+        let market_data = MarketData {
+            owner_id: accounts(3),
+            approval_id: 1,
+            nft_contract_id: accounts(2),
+            token_id: "1:1".to_string(),
+            ft_token_id: near_account(),
+            price: 10u128.pow(24),
+            bids: None,
+            started_at: None,
+            ended_at: None,
+            end_price: None,
+            accept_nft_contract_id: None,
+            accept_token_id: None,
+            is_auction: None,
+            reserve_price: None,
+        };
+        (context, contract, market_data)
+    }
+
+    #[test]
+    fn test_verify_purchase() {
+        let (mut context, mut contract, market_data) = setup_verify_purchase();
+
+        callback_token_owner(&mut context, accounts(3));
+        let balance = env::account_balance();
+        contract.callback_verify_purchase(
+            accounts(4),
+            accounts(4),
+            market_data,
+            U128(10u128.pow(24)),
+            None,
+            Gas(10_000_000_000_000),
+        );
+
+        // the seller still holds the token, only the yocto of nft_transfer_payout is spent
+        assert_eq!(env::account_balance(), balance - 1);
+        assert!(contract.settling_listings.contains(&format!(
+            "{}{}{}",
+            accounts(2),
+            DELIMETER,
+            "1:1"
+        )));
+    }
+
+    #[test]
+    fn test_verify_purchase_stale_listing() {
+        let (mut context, mut contract, market_data) = setup_verify_purchase();
+
+        // the seller moved the token elsewhere after listing it
+        callback_token_owner(&mut context, accounts(1));
+        let balance = env::account_balance();
+        contract.callback_verify_purchase(
+            accounts(4),
+            accounts(4),
+            market_data,
+            U128(10u128.pow(24)),
             None,
+            Gas(10_000_000_000_000),
+        );
+
+        assert_eq!(env::account_balance(), balance - 10u128.pow(24));
+        assert!(!contract.settling_listings.contains(&format!(
+            "{}{}{}",
+            accounts(2),
+            DELIMETER,
+            "1:1"
+        )));
+        let logs = near_sdk::test_utils::get_logs();
+        let event: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(logs.last().unwrap()).unwrap();
+        assert_eq!(event["type"], "resolve_purchase_fail");
+        assert_eq!(event["params"]["reason"], "stale listing");
+    }
+
+    #[test]
+    fn test_migrate_rewrite_offers_trades() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        let offer_key = make_triple(&accounts(3), &accounts(4), "1:1");
+        contract.old_offers.insert(
+            &offer_key,
+            &OfferDataV1 {
+                buyer_id: accounts(4),
+                nft_contract_id: accounts(3),
+                token_id: Some("1:1".to_string()),
+                token_series_id: None,
+                ft_token_id: near_account(),
+                price: 10u128.pow(24),
+            },
+        );
+        let buyer_key = make_triple(&accounts(3), &accounts(4), "1:2");
+        let trade_key = make_triple(&accounts(3), &accounts(1), "1:1");
+        contract.old_trades.insert(
+            &buyer_key,
+            &TradeListV1 {
+                approval_id: 1,
+                trade_data: HashMap::from([(
+                    trade_key.clone(),
+                    TradeDataV1 {
+                        buyer_amount: None,
+                        seller_amount: None,
+                        ft_token_id: None,
+                        is_active: Some(true),
+                        nft_contract_id: accounts(3),
+                        token_id: Some("1:2".to_string()),
+                        token_series_id: None,
+                    },
+                )]),
+            },
         );
+        contract.migration = Some(Migration {
+            from_version: StateVersion::V2,
+            to_version: StateVersion::V3,
+            steps: vec![MigrationStep::RewriteOffers, MigrationStep::RewriteTrades],
+            cursor: U64(0),
+        });
+
+        let migration = contract.migrate_chunk(Some(2)).unwrap();
+        assert_eq!(migration.steps, vec![MigrationStep::RewriteTrades]);
+        assert!(contract.migrate_chunk(Some(2)).is_none());
+
+        assert_eq!(contract.old_offers.len(), 0);
+        assert_eq!(contract.old_trades.len(), 0);
+        let offer_data = contract.offers.get(&offer_key).unwrap();
+        assert_eq!(offer_data.buyer_id, accounts(4));
+        assert_eq!(offer_data.price, 10u128.pow(24));
+        assert!(offer_data.counter.is_none());
+        let trade_list = contract.trades.get(&buyer_key).unwrap();
+        assert_eq!(trade_list.approval_id, 1);
+        assert!(trade_list.history.is_empty());
+        let trade_data = trade_list.trade_data.get(&trade_key).unwrap();
+        assert_eq!(trade_data.is_active, Some(true));
+        assert!(trade_data.buyer_tokens.is_none());
+    }
+
+    #[test]
+    fn test_burn_fee() {
+        let (mut context, mut contract) = setup_contract();
 
         testing_env!(context
             .predecessor_account_id(accounts(0))
             .attached_deposit(1)
             .build());
 
-        contract.update_market_data(
-            accounts(2),
-            "1:1".to_string(),
-            near_account(),
-            U128::from(1_000_000_000 * 10u128.pow(24)),
-            None,
+        contract.set_burn_bps(2_000, Some(accounts(5)));
+        contract.internal_transfer_treasury_fee(&near_account(), 1_000, None);
+        assert_eq!(contract.get_treasury_fees(None), U128(800));
+    }
+
+    #[test]
+    fn test_claim_treasury_fees() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+
+        contract.internal_transfer_treasury_fee(&near_account(), 1_000, None);
+        contract.internal_transfer_treasury_fee(&near_account(), 500, None);
+        assert_eq!(contract.get_treasury_fees(None), U128(1_500));
+
+        assert_eq!(contract.claim_treasury_fees(None), U128(1_500));
+        assert_eq!(contract.get_treasury_fees(None), U128(0));
+    }
+
+    #[test]
+    fn test_affiliate_earnings() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_affiliate_fee(5_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
+            .build());
+
+        contract.register_affiliate();
+        contract.internal_transfer_treasury_fee(&near_account(), 1_000, Some(accounts(4)));
+        contract.internal_transfer_treasury_fee(&near_account(), 1_000, Some(accounts(4)));
+        assert_eq!(
+            contract.get_affiliate_earnings(accounts(4), None),
+            U128(1_000)
+        );
+
+        testing_env!(context.attached_deposit(1).build());
+
+        assert_eq!(contract.claim_affiliate_earnings(None), U128(1_000));
+        assert_eq!(contract.get_affiliate_earnings(accounts(4), None), U128(0));
+    }
+
+    #[test]
+    fn test_fee_tier_discount() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_fee_tiers(
+            Some(accounts(5)),
+            vec![
+                FeeTier {
+                    min_staked_balance: U128(100),
+                    discount_bps: 1_000,
+                },
+                FeeTier {
+                    min_staked_balance: U128(1_000),
+                    discount_bps: 2_500,
+                },
+            ],
+        );
+        contract.account_fee_discounts.insert(&accounts(3), &1_000);
+        contract.account_fee_discounts.insert(&accounts(4), &2_500);
+
+        assert_eq!(
+            contract.internal_discount_treasury_fee(1_000, &accounts(3), &accounts(1)),
+            900
+        );
+        assert_eq!(
+            contract.internal_discount_treasury_fee(1_000, &accounts(3), &accounts(4)),
+            750
+        );
+        assert_eq!(
+            contract.internal_discount_treasury_fee(1_000, &accounts(1), &accounts(2)),
+            1_000
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Marble: Fee tiers must ascend by min_staked_balance")]
+    fn test_invalid_fee_tiers() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        contract.set_fee_tiers(
+            Some(accounts(5)),
+            vec![
+                FeeTier {
+                    min_staked_balance: U128(1_000),
+                    discount_bps: 1_000,
+                },
+                FeeTier {
+                    min_staked_balance: U128(100),
+                    discount_bps: 2_500,
+                },
+            ],
         );
     }
 
     #[test]
-    #[should_panic(expected = "Marble: Seller only")]
-    fn test_invalid_update_market_data() {
+    #[should_panic(expected = "Marble: Price 2000000000000000000000000 is higher than max_price")]
+    fn test_invalid_buy_max_price() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
 
         contract.internal_add_market_data(
             accounts(3),
@@ -3206,7 +15020,7 @@ mod tests {
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            U128(2 * 10u128.pow(24)),
             None,
             None,
             None,
@@ -3215,62 +15029,116 @@ mod tests {
         );
 
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(2 * 10u128.pow(24))
             .build());
 
-        contract.update_market_data(
+        contract.buy(
             accounts(2),
             "1:1".to_string(),
-            near_account(),
-            U128::from(2 * 10u128.pow(24)),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
             None,
         );
     }
 
     #[test]
-    fn test_update_market_data() {
+    fn test_payout_fee_shares() {
+        let mut payout = HashMap::new();
+        payout.insert(accounts(3), U128(900));
+        payout.insert(accounts(4), U128(100));
+
+        let fee_shares = payout_fee_shares(&payout, &accounts(3), 50);
+        assert_eq!(fee_shares.get(&accounts(3)), Some(&50));
+        assert_eq!(fee_shares.get(&accounts(4)), None);
+
+        // royalties leave the seller less than the fee, the royalty entries cover the rest
+        payout.insert(accounts(3), U128(10));
+        payout.insert(accounts(4), U128(500));
+        payout.insert(accounts(5), U128(500));
+        let fee_shares = payout_fee_shares(&payout, &accounts(3), 50);
+        assert_eq!(fee_shares.get(&accounts(3)), Some(&10));
+        assert_eq!(fee_shares.get(&accounts(4)), Some(&20));
+        assert_eq!(fee_shares.get(&accounts(5)), Some(&20));
+
+        // the fee never exceeds the payout
+        let fee_shares = payout_fee_shares(&payout, &accounts(3), 10_000);
+        assert_eq!(fee_shares.values().sum::<u128>(), 1_010);
+    }
+
+    #[test]
+    fn test_usd_to_yocto() {
+        // 1 NEAR at 2.8 USD
+        let price = Price {
+            multiplier: U128(28000),
+            decimals: 28,
+        };
+        assert_eq!(usd_to_yocto(2_800_000, &price), Some(10u128.pow(24)));
+        assert_eq!(
+            usd_to_yocto(
+                1,
+                &Price {
+                    multiplier: U128(0),
+                    decimals: 28,
+                }
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_buy_usd_listing() {
         let (mut context, mut contract) = setup_contract();
 
         testing_env!(context.predecessor_account_id(accounts(0)).build());
 
+        contract.price_oracle = Some(PriceOracleConfig {
+            oracle_id: accounts(1),
+            near_asset_id: "wrap.near".to_string(),
+            max_staleness_sec: 90,
+        });
         contract.internal_add_market_data(
             accounts(3),
             1,
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            U128(0),
             None,
             None,
             None,
             None,
             None,
         );
+        contract.internal_add_usd_price(&accounts(2), &"1:1".to_string(), U128(2_800_000));
 
         testing_env!(context
-            .predecessor_account_id(accounts(3))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(2 * 10u128.pow(24))
             .build());
 
-        contract.update_market_data(
+        // the listing stays until the oracle price is known
+        contract.buy(
             accounts(2),
             "1:1".to_string(),
-            near_account(),
-            U128::from(2 * 10u128.pow(24)),
+            None,
+            None,
+            U128(2 * 10u128.pow(24)),
+            None,
             None,
         );
-
+        assert_eq!(contract.market.len(), 1);
         let market = contract.get_market_data(accounts(2), "1:1".to_string());
-        assert_eq!(market.price, U128::from(2 * 10u128.pow(24)));
+        assert_eq!(market.usd_price, Some(U128(2_800_000)));
     }
 
     #[test]
-    #[should_panic(expected = "Marble: Market data does not exist")]
-    fn test_delete_market_data() {
+    fn test_buy_batch() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
 
         contract.internal_add_market_data(
             accounts(3),
@@ -3278,7 +15146,7 @@ mod tests {
             accounts(2),
             "1:1".to_string(),
             near_account(),
-            U128::from(1 * 10u128.pow(24)),
+            U128(10u128.pow(24)),
             None,
             None,
             None,
@@ -3287,130 +15155,178 @@ mod tests {
         );
 
         testing_env!(context
-            .predecessor_account_id(accounts(3))
-            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(2 * 10u128.pow(24))
+            .prepaid_gas(Gas(300_000_000_000_000))
             .build());
 
-        contract.delete_market_data(accounts(2), "1:1".to_string());
+        // 1:2 is not listed, it is skipped and its share refunded
+        contract.buy_batch(
+            vec![
+                BuyArgs {
+                    nft_contract_id: accounts(2),
+                    token_id: "1:1".to_string(),
+                },
+                BuyArgs {
+                    nft_contract_id: accounts(2),
+                    token_id: "1:2".to_string(),
+                },
+            ],
+            None,
+        );
 
-        contract.get_market_data(accounts(2), "1:1".to_string());
+        assert_eq!(contract.market.len(), 0);
     }
 
     #[test]
-    fn test_storage_deposit() {
+    fn test_cleanup_expired_listings() {
         let (mut context, mut contract) = setup_contract();
 
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(STORAGE_ADD_MARKET_DATA)
-            .build());
-
-        contract.storage_deposit(None);
-
-        let storage_balance = contract.storage_balance_of(accounts(0)).0;
-        assert_eq!(STORAGE_ADD_MARKET_DATA, storage_balance);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
 
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
-            .build());
+        for (token_id, ended_at) in &[("1:1", Some(U64(100))), ("1:2", None)] {
+            contract.internal_add_market_data(
+                accounts(3),
+                1,
+                accounts(2),
+                token_id.to_string(),
+                near_account(),
+                U128(10u128.pow(24)),
+                None,
+                *ended_at,
+                None,
+                None,
+                None,
+            );
+        }
 
-        contract.storage_withdraw();
+        testing_env!(context.block_timestamp(101).build());
 
-        let storage_balance = contract.storage_balance_of(accounts(0)).0;
-        assert_eq!(0, storage_balance);
+        assert_eq!(contract.cleanup_expired_listings(None, None), 1);
+        assert_eq!(contract.market.len(), 1);
     }
 
     #[test]
-    fn test_add_offer() {
+    fn test_delete_trades() {
         let (mut context, mut contract) = setup_contract();
 
-        let one_near = 10u128.pow(24);
-
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(one_near)
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
             .build());
 
-        contract.internal_add_offer(
-            accounts(3),
-            Some("1:1".to_string()),
-            None,
-            near_account(),
-            U128(one_near),
-            accounts(0),
-        );
+        for token_id in &["1:1", "1:2"] {
+            contract.internal_add_trade(
+                accounts(2),
+                Some(token_id.to_string()),
+                None,
+                accounts(2),
+                Some("2:1".to_string()),
+                accounts(3),
+                1,
+                None,
+                None,
+                None,
+            );
+        }
 
-        let offer_data =
-            contract.get_offer(accounts(3), accounts(0), Some("1:1".to_string()), None);
+        contract.delete_trades(
+            ["1:1", "1:2"]
+                .iter()
+                .map(|token_id| DeleteTradeArgs {
+                    nft_contract_id: accounts(2),
+                    token_id: Some(token_id.to_string()),
+                    token_series_id: None,
+                    buyer_nft_contract_id: accounts(2),
+                    buyer_token_id: "2:1".to_string(),
+                })
+                .collect(),
+        );
 
-        assert_eq!(offer_data.buyer_id, accounts(0));
-        assert_eq!(offer_data.price, U128(one_near));
+        assert!(contract
+            .trades
+            .get(&make_triple(&accounts(2), &accounts(3), "2:1"))
+            .map_or(true, |trade_list| trade_list.trade_data.is_empty()));
     }
 
     #[test]
-    #[should_panic(expected = "Marble: Offer does not exist")]
-    fn test_delete_offer() {
+    fn test_ring_trade() {
         let (mut context, mut contract) = setup_contract();
 
-        let one_near = 10u128.pow(24);
-
         testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(one_near)
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(STORAGE_ADD_MARKET_DATA)
             .build());
 
-        contract.internal_add_offer(
+        let participants = (3..6)
+            .map(|index| RingTradeParticipant {
+                account_id: accounts(index),
+                nft_contract_id: accounts(2),
+                token_id: format!("1:{}", index),
+                approval_id: Some(1),
+            })
+            .collect();
+        let ring_trade_id = contract.add_ring_trade(participants, None);
+
+        contract.internal_approve_ring_trade(
+            ring_trade_id.0,
+            accounts(2),
+            "1:3".to_string(),
             accounts(3),
-            Some("1:1".to_string()),
-            None,
-            near_account(),
-            U128(one_near),
-            accounts(0),
+            7,
         );
 
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(1)
-            .build());
-
-        contract.delete_offer(accounts(3), Some("1:1".to_string()), None);
-
-        contract.get_offer(accounts(3), accounts(1), Some("1:1".to_string()), None);
-    }
-
-    #[test]
-    fn test_add_trade() {
-        let (mut context, mut contract) = setup_contract();
-
-        let one_near = 10u128.pow(24);
-
-        testing_env!(context
-            .predecessor_account_id(accounts(0))
-            .attached_deposit(one_near)
-            .build());
+        let ring_trade = contract.get_ring_trade(ring_trade_id).unwrap();
+        assert_eq!(ring_trade.participants[0].approval_id, Some(7));
+        assert_eq!(ring_trade.participants[1].approval_id, None);
 
-        contract.internal_add_trade(
-            accounts(3),
-            Some("1:1".to_string()),
-            None,
-            accounts(1),
-            Some("1:2".to_string()),
+        contract.internal_approve_ring_trade(
+            ring_trade_id.0,
             accounts(2),
-            1,
+            "1:4".to_string(),
+            accounts(4),
+            8,
         );
-
-        let trade_data = contract.get_trade(
-            accounts(3),
-            Some("1:1".to_string()),
-            None,
+        contract.internal_approve_ring_trade(
+            ring_trade_id.0,
             accounts(2),
-            accounts(1),
-            "1:2".to_string(),
+            "1:5".to_string(),
+            accounts(5),
+            9,
         );
 
-        assert_eq!(trade_data.token_id.unwrap().to_string(), "1:1");
-        assert_eq!(trade_data.nft_contract_id, accounts(3));
+        // settled once every participant approved
+        assert!(contract.get_ring_trade(ring_trade_id).is_none());
+    }
+
+    #[test]
+    fn test_get_trade_history() {
+        let (_context, mut contract) = setup_contract();
+
+        for index in 0..3 {
+            contract.trade_history.push(&TradeRecord {
+                buyer_id: accounts(3),
+                seller_id: accounts(4),
+                buyer_tokens: vec![TradeToken {
+                    nft_contract_id: accounts(2),
+                    token_id: format!("1:{}", index),
+                    approval_id: Some(1),
+                }],
+                seller_tokens: vec![TradeToken {
+                    nft_contract_id: accounts(2),
+                    token_id: format!("2:{}", index),
+                    approval_id: Some(1),
+                }],
+                ft_token_id: near_account(),
+                buyer_amount: U128(0),
+                seller_amount: U128(0),
+                traded_at: 0,
+            });
+        }
+
+        let trade_history = contract.get_trade_history(Some(U64(1)), Some(5));
+        assert_eq!(trade_history.len(), 2);
+        assert_eq!(trade_history[0].buyer_tokens[0].token_id, "1:1");
     }
 
     #[test]
@@ -3433,6 +15349,9 @@ mod tests {
             Some("1:1".to_string()),
             accounts(2),
             1,
+            None,
+            None,
+            None,
         );
 
         testing_env!(context
@@ -3507,7 +15426,15 @@ mod tests {
             .attached_deposit(10u128.pow(24))
             .build());
 
-        contract.buy(accounts(2), "1:1".to_string(), None, None);
+        contract.buy(
+            accounts(2),
+            "1:1".to_string(),
+            None,
+            None,
+            U128(10u128.pow(24)),
+            None,
+            None,
+        );
     }
 
     #[test]
@@ -3562,6 +15489,68 @@ mod tests {
         contract.accept_bid(accounts(2), "1:1".to_string());
     }
 
+    #[test]
+    fn test_get_locked_balance() {
+        let (mut context, mut contract) = setup_contract();
+
+        let one_near = 10u128.pow(24);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+
+        contract.internal_add_market_data(
+            accounts(1),
+            1,
+            accounts(2),
+            "1:1".to_string(),
+            near_account(),
+            U128::from(one_near),
+            None,
+            Some(U64(1999999952971000000)),
+            None,
+            Some(true),
+            None,
+        );
+        contract.internal_add_offer(
+            accounts(3),
+            Some("1:1".to_string()),
+            None,
+            near_account(),
+            U128(one_near),
+            accounts(4),
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(one_near)
+            .build());
+
+        contract.add_bid(
+            accounts(2),
+            near_account(),
+            "1:1".to_string(),
+            U128::from(one_near),
+        );
+
+        let locked_balance = contract.get_locked_balance(accounts(4));
+        assert_eq!(
+            locked_balance.get(&near_account()),
+            Some(&U128(2 * one_near))
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+
+        contract.cancel_bid(accounts(2), "1:1".to_string(), accounts(4));
+
+        let locked_balance = contract.get_locked_balance(accounts(4));
+        assert_eq!(locked_balance.get(&near_account()), Some(&U128(one_near)));
+    }
+
     #[test]
     fn test_change_transaction_fee_immediately() {
         let (mut context, mut contract) = setup_contract();
@@ -3612,6 +15601,38 @@ mod tests {
         assert_eq!(contract.get_transaction_fee().start_time, None);
     }
 
+    #[test]
+    fn test_transaction_fee_changes() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+
+        let start_time: Timestamp = 1618109122863866400;
+        let start_time_sec: TimestampSec = to_sec(start_time);
+        contract.set_transaction_fee(300, Some(start_time_sec + 20));
+        contract.set_transaction_fee(100, Some(start_time_sec));
+        contract.set_transaction_fee(200, Some(start_time_sec + 10));
+        contract.cancel_transaction_fee_change(start_time_sec + 20);
+
+        let fee_changes = contract.get_transaction_fee_changes();
+        assert_eq!(fee_changes.len(), 2);
+        assert_eq!(contract.get_transaction_fee().next_fee, Some(100));
+        assert_eq!(fee_changes[1].fee, 200);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(start_time + 10 * 10u64.pow(9))
+            .build());
+
+        // changes that are both due apply in order
+        assert_eq!(contract.calculate_current_transaction_fee(), 200);
+        assert!(contract.get_transaction_fee_changes().is_empty());
+        assert_eq!(contract.get_transaction_fee().next_fee, None);
+    }
+
     #[test]
     fn test_transaction_fee_locked() {
         let (mut context, mut contract) = setup_contract();
@@ -3672,8 +15693,9 @@ mod tests {
         amount: u128,
         account: AccountId,
     ) {
+        // the FT contract calls ft_on_transfer on behalf of the sender
         testing_env!(context
-            .predecessor_account_id(account)
+            .predecessor_account_id(accounts(5))
             .attached_deposit(1)
             .build());
         let msg = json!({
@@ -3684,7 +15706,7 @@ mod tests {
             "method": "auction"
         })
         .to_string();
-        contract.ft_on_transfer(accounts(0), U128(amount), msg);
+        contract.ft_on_transfer(account, U128(amount), msg);
     }
 
     #[test]
@@ -3697,6 +15719,7 @@ mod tests {
             .build());
 
         println!("\n\nTransfer");
+        contract.approved_ft_token_ids.insert(&accounts(5));
 
         contract.internal_add_market_data(
             accounts(4),
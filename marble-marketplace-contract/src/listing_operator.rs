@@ -0,0 +1,87 @@
+use crate::*;
+
+/// listing operators, galleries and agents list on an owner's behalf and proceeds stay with the owner
+pub const MAX_LISTING_OPERATORS: usize = 10;
+
+#[near_bindgen]
+impl Contract {
+    // the operator may sign nft_on_approve for the owner's tokens and update or delist them
+    #[payable]
+    pub fn add_listing_operator(&mut self, operator_id: AccountId) {
+        let owner_id = env::predecessor_account_id();
+        assert!(
+            env::attached_deposit() >= self.config.storage_add_market_data.0,
+            "Marble: Attach at least {} yoctoNEAR for storage",
+            self.config.storage_add_market_data.0
+        );
+        assert_ne!(owner_id, operator_id, "Marble: Cannot operate for yourself");
+        let mut operator_ids = self.listing_operators.get(&owner_id).unwrap_or_default();
+        assert!(
+            !operator_ids.contains(&operator_id),
+            "Marble: Operator is already authorized"
+        );
+        assert!(
+            operator_ids.len() < MAX_LISTING_OPERATORS,
+            "Marble: Up to {} listing operators",
+            MAX_LISTING_OPERATORS
+        );
+        operator_ids.push(operator_id.clone());
+        self.listing_operators.insert(&owner_id, &operator_ids);
+        // only the storage deposit is held, remove_listing_operator refunds it
+        let excess_deposit = env::attached_deposit() - self.config.storage_add_market_data.0;
+        if excess_deposit > 0 {
+            Promise::new(owner_id.clone()).transfer(excess_deposit);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "add_listing_operator",
+                "params": {
+                    "owner_id": owner_id,
+                    "operator_id": operator_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // listings the operator already created stay on the market, the storage deposit is refunded
+    #[payable]
+    pub fn remove_listing_operator(&mut self, operator_id: AccountId) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let mut operator_ids = self.listing_operators.get(&owner_id).unwrap_or_default();
+        let index = operator_ids
+            .iter()
+            .position(|id| id == &operator_id)
+            .expect("Marble: Operator is not authorized");
+        operator_ids.remove(index);
+        if operator_ids.is_empty() {
+            self.listing_operators.remove(&owner_id);
+        } else {
+            self.listing_operators.insert(&owner_id, &operator_ids);
+        }
+        Promise::new(owner_id.clone()).transfer(self.config.storage_add_market_data.0);
+
+        env::log_str(
+            &json!({
+                "type": "remove_listing_operator",
+                "params": {
+                    "owner_id": owner_id,
+                    "operator_id": operator_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_listing_operators(&self, owner_id: AccountId) -> Vec<AccountId> {
+        self.listing_operators.get(&owner_id).unwrap_or_default()
+    }
+
+    pub fn is_listing_operator(&self, owner_id: AccountId, operator_id: AccountId) -> bool {
+        self.listing_operators
+            .get(&owner_id)
+            .map_or(false, |operator_ids| operator_ids.contains(&operator_id))
+    }
+}
@@ -0,0 +1,250 @@
+use crate::*;
+
+/// chunked migrations, migrate() converts the layout and queues the steps that rewrite large maps.
+/// A new layout adds a StateVersion, its previous struct and the steps migrate_chunk runs after it.
+pub const DEFAULT_MIGRATION_CHUNK: u64 = 100;
+pub const CURRENT_STATE_VERSION: StateVersion = StateVersion::V3;
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum StateVersion {
+    V2,
+    V3,
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MigrationStep {
+    IndexBids,     // bids_by_bidder_id from market, V2 -> V3
+    IndexOffers,   // offers_by_token from offers, V2 -> V3
+    RewriteOffers, // old_offers into offers, V2 -> V3
+    RewriteTrades, // old_trades into trades, V2 -> V3
+}
+
+// V2 layouts of the offers and trades values, read back from old_offers and old_trades
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OfferDataV1 {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: Option<TokenId>,
+    pub token_series_id: Option<TokenId>,
+    pub ft_token_id: AccountId,
+    pub price: u128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct TradeDataV1 {
+    pub buyer_amount: Option<Balance>,
+    pub seller_amount: Option<Balance>,
+    pub ft_token_id: Option<String>,
+    pub is_active: Option<bool>,
+    pub nft_contract_id: AccountId,
+    pub token_id: Option<TokenId>,
+    pub token_series_id: Option<TokenSeriesId>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct TradeListV1 {
+    pub approval_id: u64,
+    pub trade_data: HashMap<ContractAccountIdTokenId, TradeDataV1>,
+}
+
+impl From<OfferDataV1> for OfferData {
+    fn from(offer_data: OfferDataV1) -> Self {
+        OfferData {
+            buyer_id: offer_data.buyer_id,
+            nft_contract_id: offer_data.nft_contract_id,
+            token_id: offer_data.token_id,
+            token_series_id: offer_data.token_series_id,
+            ft_token_id: offer_data.ft_token_id,
+            price: offer_data.price,
+            counter: None,
+            seller_id: None,
+            quantity: None,
+            referral_id: None,
+        }
+    }
+}
+
+impl From<TradeDataV1> for TradeData {
+    fn from(trade_data: TradeDataV1) -> Self {
+        TradeData {
+            buyer_amount: trade_data.buyer_amount,
+            seller_amount: trade_data.seller_amount,
+            ft_token_id: trade_data.ft_token_id,
+            is_active: trade_data.is_active,
+            nft_contract_id: trade_data.nft_contract_id,
+            token_id: trade_data.token_id,
+            token_series_id: trade_data.token_series_id,
+            buyer_tokens: None,
+            seller_tokens: None,
+            seller_id: None,
+            expires_at: None,
+            owner_id: None,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Migration {
+    pub from_version: StateVersion,
+    pub to_version: StateVersion,
+    pub steps: Vec<MigrationStep>, // left to run, the first one is in progress
+    pub cursor: U64,               // entries of the first step already migrated
+}
+
+#[near_bindgen]
+impl Contract {
+    // removals reorder the maps, pause the features writing them until the migration completes
+    #[payable]
+    pub fn migrate_chunk(&mut self, limit: Option<u64>) -> Option<Migration> {
+        assert_one_yocto();
+        self.assert_owner();
+        let mut migration = self
+            .migration
+            .clone()
+            .expect("Marble: No migration in progress");
+        let limit = limit.unwrap_or(DEFAULT_MIGRATION_CHUNK);
+        assert!(limit > 0, "Marble: limit must be higher than 0");
+
+        let step = migration.steps[0];
+        let cursor = migration.cursor.0;
+        let migrated = match step {
+            MigrationStep::IndexBids => self.internal_migrate_index_bids(cursor, limit),
+            MigrationStep::IndexOffers => self.internal_migrate_index_offers(cursor, limit),
+            MigrationStep::RewriteOffers => self.internal_migrate_rewrite_offers(limit),
+            MigrationStep::RewriteTrades => self.internal_migrate_rewrite_trades(limit),
+        };
+        if migrated < limit {
+            migration.steps.remove(0);
+            migration.cursor = U64(0);
+        } else {
+            migration.cursor = U64(cursor + migrated);
+        }
+        self.migration = if migration.steps.is_empty() {
+            None
+        } else {
+            Some(migration.clone())
+        };
+
+        env::log_str(
+            &json!({
+                "type": "migrate_chunk",
+                "params": {
+                    "to_version": migration.to_version,
+                    "step": step,
+                    "from_index": U64(cursor),
+                    "migrated": U64(migrated),
+                    "is_complete": self.migration.is_none(),
+                }
+            })
+            .to_string(),
+        );
+        self.migration.clone()
+    }
+
+    pub fn get_migration(&self) -> Option<Migration> {
+        self.migration.clone()
+    }
+
+    pub fn get_state_version(&self) -> StateVersion {
+        self.state_version
+    }
+
+    fn internal_migrate_index_bids(&mut self, from_index: u64, limit: u64) -> u64 {
+        let markets: Vec<(ContractAndTokenId, MarketData)> = self
+            .market
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect();
+        let bid_keys: Vec<(AccountId, ContractAndTokenId)> = markets
+            .iter()
+            .flat_map(|(contract_and_token_id, market_data)| {
+                market_data
+                    .bids
+                    .iter()
+                    .flatten()
+                    .map(move |bid| (bid.bidder_id.clone(), contract_and_token_id.clone()))
+            })
+            .collect();
+        for (bidder_id, contract_and_token_id) in bid_keys {
+            self.internal_add_bid_index(&bidder_id, &contract_and_token_id);
+        }
+        markets.len() as u64
+    }
+
+    // keys are nft_contract_id||buyer_id||token
+    fn internal_migrate_index_offers(&mut self, from_index: u64, limit: u64) -> u64 {
+        let offer_keys: Vec<ContractAccountIdTokenId> = self
+            .offers
+            .keys()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect();
+        for offer_key in &offer_keys {
+            let parts: Vec<&str> = offer_key.splitn(3, DELIMETER).collect();
+            if parts.len() == 3 {
+                self.internal_add_offer_index(
+                    &AccountId::new_unchecked(parts[0].to_string()),
+                    parts[2],
+                    &AccountId::new_unchecked(parts[1].to_string()),
+                );
+            }
+        }
+        offer_keys.len() as u64
+    }
+
+    // drained from the front, an offer made again meanwhile keeps its own escrow and the old
+    // one is refunded
+    fn internal_migrate_rewrite_offers(&mut self, limit: u64) -> u64 {
+        let offers: Vec<(ContractAccountIdTokenId, OfferDataV1)> =
+            self.old_offers.iter().take(limit as usize).collect();
+        let migrated = offers.len() as u64;
+        for (offer_key, offer_data) in offers {
+            self.old_offers.remove(&offer_key);
+            if self.offers.get(&offer_key).is_some() {
+                self.internal_transfer(
+                    &offer_data.ft_token_id,
+                    offer_data.buyer_id.clone(),
+                    offer_data.price,
+                );
+            } else {
+                self.offers.insert(&offer_key, &offer_data.into());
+            }
+        }
+        migrated
+    }
+
+    // drained from the front, trades proposed again meanwhile are kept over the old ones
+    fn internal_migrate_rewrite_trades(&mut self, limit: u64) -> u64 {
+        let trades: Vec<(ContractAccountIdTokenId, TradeListV1)> =
+            self.old_trades.iter().take(limit as usize).collect();
+        let migrated = trades.len() as u64;
+        for (buyer_contract_account_id_token_id, old_trade_list) in trades {
+            self.old_trades.remove(&buyer_contract_account_id_token_id);
+            let mut trade_list = self
+                .trades
+                .get(&buyer_contract_account_id_token_id)
+                .unwrap_or(TradeList {
+                    approval_id: old_trade_list.approval_id,
+                    trade_data: HashMap::new(),
+                    history: vec![],
+                });
+            for (contract_account_id_token_id, trade_data) in old_trade_list.trade_data {
+                trade_list
+                    .trade_data
+                    .entry(contract_account_id_token_id)
+                    .or_insert_with(|| trade_data.into());
+            }
+            self.trades
+                .insert(&buyer_contract_account_id_token_id, &trade_list);
+        }
+        migrated
+    }
+}
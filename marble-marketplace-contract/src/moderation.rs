@@ -0,0 +1,150 @@
+use crate::*;
+
+/// moderation, a flagged token is cleared from the marketplace with everything pending on it
+#[near_bindgen]
+impl Contract {
+    // the listing, the offers on the token and its owner's trade proposals are all refunded
+    #[payable]
+    pub fn moderate_delist(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        reason: String,
+    ) {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_record_admin_action(
+            "moderate_delist",
+            json!({ "nft_contract_id": nft_contract_id, "token_id": token_id, "reason": reason }),
+        );
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.assert_not_settling(&contract_and_token_id);
+
+        let owner_id = self
+            .internal_get_market_data(&contract_and_token_id)
+            .map(|market_data| market_data.owner_id);
+        if let Some(owner_id) = owner_id.as_ref() {
+            self.internal_refund_listing_fee(owner_id, &contract_and_token_id);
+            self.internal_delete_market_data(&nft_contract_id, &token_id);
+
+            env::log_str(
+                &json!({
+                    "type": "delete_market_data",
+                    "params": {
+                        "owner_id": owner_id,
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                        "reason": "moderated",
+                    }
+                })
+                .to_string(),
+            );
+        }
+
+        let buyer_ids: Vec<AccountId> = self
+            .offers_by_token
+            .get(&contract_and_token_id)
+            .map_or(vec![], |buyer_ids| buyer_ids.to_vec());
+        for buyer_id in &buyer_ids {
+            if let Some(offer_data) = self.internal_delete_offer(
+                nft_contract_id.clone(),
+                buyer_id.clone(),
+                token_id.clone(),
+            ) {
+                self.internal_transfer(
+                    &offer_data.ft_token_id,
+                    offer_data.buyer_id.clone(),
+                    offer_escrow(&offer_data),
+                );
+
+                env::log_str(
+                    &json!({
+                        "type": "delete_offer",
+                        "params": {
+                            "nft_contract_id": nft_contract_id,
+                            "buyer_id": buyer_id,
+                            "token_id": offer_data.token_id,
+                            "token_series_id": offer_data.token_series_id,
+                            "reason": "moderated",
+                        }
+                    })
+                    .to_string(),
+                );
+            }
+        }
+
+        // proposals asking for the token are left to expire, they are not indexed by token
+        let trades_count = match owner_id.as_ref() {
+            Some(owner_id) => {
+                self.internal_delete_trade_list(&nft_contract_id, owner_id, &token_id)
+            }
+            None => 0,
+        };
+
+        env::log_str(
+            &json!({
+                "type": "moderate_delist",
+                "params": {
+                    "moderator_id": env::predecessor_account_id(),
+                    "owner_id": owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "reason": reason,
+                    "offers_count": buyer_ids.len(),
+                    "trades_count": trades_count,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_delete_trade_list(
+        &mut self,
+        buyer_nft_contract_id: &AccountId,
+        buyer_id: &AccountId,
+        buyer_token_id: &TokenId,
+    ) -> usize {
+        let buyer_contract_account_id_token_id =
+            make_triple(buyer_nft_contract_id, buyer_id, buyer_token_id);
+        let contract_account_id_token_ids: Vec<ContractAccountIdTokenId> =
+            match self.trades.get(&buyer_contract_account_id_token_id) {
+                Some(trade_list) => trade_list.trade_data.into_keys().collect(),
+                None => return 0,
+            };
+
+        for contract_account_id_token_id in &contract_account_id_token_ids {
+            let (nft_contract_id, _, token) = split_triple(contract_account_id_token_id);
+            let trade_data = self
+                .internal_delete_trade(
+                    nft_contract_id.clone(),
+                    buyer_id.clone(),
+                    token,
+                    buyer_nft_contract_id.clone(),
+                    buyer_token_id.clone(),
+                )
+                .expect("Marble: Trade not found");
+            self.internal_refund_trade_cash(buyer_id, &trade_data);
+
+            env::log_str(
+                &json!({
+                    "type": "delete_trade",
+                    "params": {
+                        "nft_contract_id": nft_contract_id,
+                        "buyer_id": buyer_id,
+                        "token_id": trade_data.token_id,
+                        "token_series_id": trade_data.token_series_id,
+                        "buyer_nft_contract_id": buyer_nft_contract_id,
+                        "buyer_token_id": buyer_token_id,
+                        "reason": "moderated",
+                    }
+                })
+                .to_string(),
+            );
+        }
+        self.trades.remove(&buyer_contract_account_id_token_id);
+        self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
+
+        contract_account_id_token_ids.len()
+    }
+}
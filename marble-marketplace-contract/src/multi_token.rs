@@ -0,0 +1,117 @@
+use crate::*;
+use near_sdk::PromiseOrValue;
+
+/// multi-token (NEP-245) currencies, an approved MT contract pays with one of its token ids.
+/// Listings and bids name the MT contract as their ft_token_id, payouts go out with mt_transfer.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MtCurrency {
+    pub mt_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn set_mt_currency(&mut self, mt_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "set_mt_currency",
+            json!({ "mt_contract_id": mt_contract_id, "token_id": token_id }),
+        );
+        self.mt_currencies.insert(&mt_contract_id, &token_id);
+        self.approved_ft_token_ids.insert(&mt_contract_id);
+    }
+
+    // escrowed listings and bids keep paying out, only new deposits are refused
+    #[payable]
+    pub fn remove_mt_currency(&mut self, mt_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "remove_mt_currency",
+            json!({ "mt_contract_id": mt_contract_id }),
+        );
+        self.approved_ft_token_ids.remove(&mt_contract_id);
+    }
+
+    // removed currencies still pay out escrows but are no longer listed
+    pub fn get_mt_currencies(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MtCurrency> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.mt_currencies.len());
+
+        self.mt_currencies
+            .iter()
+            .filter(|(mt_contract_id, _)| self.approved_ft_token_ids.contains(mt_contract_id))
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(mt_contract_id, token_id)| MtCurrency {
+                mt_contract_id,
+                token_id,
+            })
+            .collect()
+    }
+
+    // msg is the same TokenInfo as ft_on_transfer, a single currency token is accepted per call
+    pub fn mt_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_ids: Vec<AccountId>,
+        token_ids: Vec<TokenId>,
+        amounts: Vec<U128>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>> {
+        let _ = previous_owner_ids; // the sender is the buyer, approvals are not used
+        let currency_token_id = self.mt_currencies.get(&env::predecessor_account_id());
+        if token_ids.len() != 1
+            || amounts.len() != 1
+            || currency_token_id.as_ref() != Some(&token_ids[0])
+        {
+            log_token_deposit_refund(
+                &sender_id,
+                amounts
+                    .iter()
+                    .fold(0, |total: Balance, amount| total.saturating_add(amount.0)),
+                &TokenReceiverError::NotApprovedFt,
+            );
+            return PromiseOrValue::Value(amounts);
+        }
+
+        let unused_amount = self.internal_on_token_transfer(sender_id, amounts[0].0, msg);
+        PromiseOrValue::Value(vec![U128(unused_amount)])
+    }
+
+    // payouts, refunds and fees in an MT currency go through mt_transfer
+    pub(crate) fn internal_ft_transfer(
+        &self,
+        ft_token_id: &AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        match self.mt_currencies.get(ft_token_id) {
+            Some(token_id) => ext_multi_token::mt_transfer(
+                receiver_id,
+                token_id,
+                amount,
+                None,
+                None,
+                ft_token_id.clone(),
+                1,
+                self.config.gas_for_ft_transfer,
+            ),
+            None => ext_fungible_token::ft_transfer(
+                receiver_id,
+                amount,
+                None,
+                ft_token_id.clone(),
+                1,
+                self.config.gas_for_ft_transfer,
+            ),
+        }
+    }
+}
@@ -30,6 +30,50 @@ pub struct MarketArgs {
     pub buyer_token_id: Option<TokenId>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reserve_price: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referral_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_tokens: Option<Vec<TradeToken>>, // trade bundle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seller_tokens: Option<Vec<TradeToken>>, // trade bundle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<U64>, // trade
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter_nft_contract_id: Option<AccountId>, // counter trade
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter_token_id: Option<TokenId>, // counter trade
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ring_trade_id: Option<U64>, // ring trade
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_buyer_id: Option<AccountId>, // private sale
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_listing_id: Option<U64>, // bundle listing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_days: Option<u64>, // rental
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usd_price: Option<U128>, // sale priced in USD through the oracle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alternative_prices: Option<HashMap<AccountId, U128>>, // other currencies a sale accepts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_stable_priced: Option<bool>, // other curated stablecoins are accepted 1:1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_at: Option<U64>, // presale ends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowlist: Option<Vec<AccountId>>, // presale
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub holder_nft_contract_id: Option<AccountId>, // presale
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer_id: Option<AccountId>, // NEP-366, the inner signer of a relayed nft_approve
+}
+
+/// series approval callbacks from Marble NFT Contracts, the marketplace may mint the series
+trait NonFungibleTokenSeriesApprovalsReceiver {
+    fn nft_on_series_approve(
+        &mut self,
+        token_series_id: TokenSeriesId,
+        owner_id: AccountId,
+        msg: String,
+    );
 }
 
 trait NonFungibleTokenApprovalsReceiver {
@@ -54,13 +98,11 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
         // enforce cross contract call and owner_id is signer
 
         let nft_contract_id = env::predecessor_account_id();
-        let signer_id = env::signer_account_id();
         assert_ne!(
             env::current_account_id(),
             nft_contract_id,
             "Marble: nft_on_approve should only be called via cross-contract call"
         );
-        assert_eq!(owner_id, signer_id, "Marble: owner_id should be signer_id");
 
         assert!(
             self.approved_nft_contract_ids.contains(&nft_contract_id),
@@ -82,10 +124,49 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
             buyer_nft_contract_id,
             buyer_token_id,
             reserve_price,
+            referral_id,
+            buyer_tokens,
+            seller_tokens,
+            expires_at,
+            counter_nft_contract_id,
+            counter_token_id,
+            ring_trade_id,
+            allowed_buyer_id,
+            bundle_listing_id,
+            max_days,
+            usd_price,
+            alternative_prices,
+            is_stable_priced,
+            public_at,
+            allowlist,
+            holder_nft_contract_id,
+            signer_id,
         } = near_sdk::serde_json::from_str(&msg).expect("Not valid MarketArgs");
+        self.assert_listing_signer(&owner_id, signer_id);
+
+        self.assert_not_paused(match market_type.as_str() {
+            "sale" | "sale_editions" | "rent" | "add_bundle_token" => PauseFeature::Listings,
+            "accept_offer"
+            | "accept_offer_marble_series"
+            | "sell_to_highest_offer"
+            | "counter_offer"
+            | "counter_offer_marble_series" => PauseFeature::Offers,
+            "add_trade"
+            | "accept_trade"
+            | "accept_trade_marble_series"
+            | "counter_trade"
+            | "accept_counter_trade"
+            | "approve_ring_trade" => PauseFeature::Trades,
+            _ => PauseFeature::All,
+        });
+        self.assert_not_blacklisted_seller(&owner_id);
+        self.assert_collection_not_frozen(&nft_contract_id);
 
         if market_type == "sale" {
-            assert!(price.is_some(), "Marble: price not specified");
+            assert!(
+                price.is_some() || usd_price.is_some(),
+                "Marble: price not specified"
+            );
 
             // //replace old data approval id
             let buyer_contract_account_id_token_id =
@@ -94,12 +175,127 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 old_trade.approval_id = approval_id;
                 self.trades
                     .insert(&buyer_contract_account_id_token_id, &old_trade);
+                self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
             }
 
             let storage_amount = self.storage_minimum_balance().0;
-            let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
+            let owner_paid_storage = self.storage_deposits.get(&owner_id).unwrap_or(0);
             let signer_storage_required =
-                (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
+                (self.get_supply_by_owner_id(owner_id.clone()).0 + 1) as u128 * storage_amount;
+
+            // a partner collection's pool may cover the shortfall
+            let sponsored_storage = if owner_paid_storage < signer_storage_required {
+                let sponsored_storage = self.internal_sponsor_listing_storage(
+                    &nft_contract_id,
+                    &owner_id,
+                    signer_storage_required - owner_paid_storage,
+                );
+                if sponsored_storage.is_none() {
+                    let notif = format!(
+                        "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
+                        owner_paid_storage,
+                        signer_storage_required / storage_amount,
+                        storage_amount
+                    );
+                    env::log_str(&notif);
+                    return;
+                }
+                sponsored_storage
+            } else {
+                None
+            };
+
+            self.internal_delete_market_data(&nft_contract_id, &token_id);
+            let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+            if let Some(sponsored_storage) = sponsored_storage {
+                self.sponsored_listings
+                    .insert(&contract_and_token_id, &sponsored_storage);
+            }
+
+            let ft_token_id_res = ft_token_id.unwrap_or(near_account());
+
+            if self.approved_ft_token_ids.contains(&ft_token_id_res) != true {
+                env::panic_str(&"Marble: ft_token_id not approved");
+            }
+
+            // only this account can buy at the agreed price
+            if let Some(allowed_buyer_id) = allowed_buyer_id.as_ref() {
+                assert!(
+                    is_auction != Some(true),
+                    "Marble: Private sale cannot be an auction"
+                );
+                assert_ne!(
+                    allowed_buyer_id, &owner_id,
+                    "Marble: Cannot reserve the sale for yourself"
+                );
+            }
+            if usd_price.is_some() {
+                assert_eq!(
+                    ft_token_id_res.to_string(),
+                    NEAR,
+                    "Marble: NEAR support only"
+                );
+                assert!(
+                    is_auction != Some(true) && end_price.is_none(),
+                    "Marble: USD listing must be a fixed price sale"
+                );
+            }
+            // recorded first, USD listings skip the NEAR price floor
+            if let Some(usd_price) = usd_price {
+                self.internal_add_usd_price(&nft_contract_id, &token_id, usd_price);
+            }
+
+            self.internal_add_market_data(
+                owner_id,
+                approval_id,
+                nft_contract_id.clone(),
+                token_id.clone(),
+                ft_token_id_res,
+                // the NEAR price of a USD listing is only known when it is bought
+                price.unwrap_or(U128(0)),
+                started_at,
+                ended_at,
+                end_price,
+                is_auction,
+                reserve_price,
+            );
+            if let Some(allowed_buyer_id) = allowed_buyer_id {
+                self.allowed_buyer_ids
+                    .insert(&contract_and_token_id, &allowed_buyer_id);
+            }
+            if alternative_prices.is_some() || is_stable_priced == Some(true) || public_at.is_some()
+            {
+                let market_data = self.market.get(&contract_and_token_id).unwrap();
+                if let Some(alternative_prices) = alternative_prices {
+                    self.internal_set_alternative_prices(&market_data, alternative_prices);
+                }
+                if is_stable_priced == Some(true) {
+                    self.internal_set_stable_priced(&market_data, true);
+                }
+                if let Some(public_at) = public_at {
+                    self.internal_add_presale(
+                        &market_data,
+                        public_at,
+                        allowlist.unwrap_or_default(),
+                        holder_nft_contract_id,
+                    );
+                }
+            }
+        } else if market_type == "sale_editions" {
+            assert!(price.is_some(), "Marble: price not specified");
+            assert!(
+                self.marble_nft_contracts.contains(&nft_contract_id),
+                "Marble: listing editions for Marble NFT only"
+            );
+            assert!(
+                is_auction != Some(true),
+                "Marble: Edition listing cannot be an auction"
+            );
+
+            let storage_amount = self.storage_minimum_balance().0;
+            let owner_paid_storage = self.storage_deposits.get(&owner_id).unwrap_or(0);
+            let signer_storage_required =
+                (self.get_supply_by_owner_id(owner_id.clone()).0 + 1) as u128 * storage_amount;
 
             if owner_paid_storage < signer_storage_required {
                 let notif = format!(
@@ -112,15 +308,13 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 return;
             }
 
-            self.internal_delete_market_data(&nft_contract_id, &token_id);
-
             let ft_token_id_res = ft_token_id.unwrap_or(near_account());
+            assert!(
+                self.approved_ft_token_ids.contains(&ft_token_id_res),
+                "Marble: ft_token_id not approved"
+            );
 
-            if self.approved_ft_token_ids.contains(&ft_token_id_res) != true {
-                env::panic_str(&"Marble: ft_token_id not approved");
-            }
-
-            self.internal_add_market_data(
+            self.internal_add_listing_edition(
                 owner_id,
                 approval_id,
                 nft_contract_id,
@@ -130,8 +324,6 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 started_at,
                 ended_at,
                 end_price,
-                is_auction,
-                reserve_price,
             );
         } else if market_type == "accept_offer" {
             assert!(buyer_id.is_some(), "Marble: Account id is not specified");
@@ -147,6 +339,7 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 owner_id,
                 approval_id,
                 price.unwrap().0,
+                referral_id,
             );
         } else if market_type == "accept_offer_marble_series" {
             assert!(buyer_id.is_some(), "Marble: Account id is not specified");
@@ -166,6 +359,51 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 owner_id,
                 approval_id,
                 price.unwrap().0,
+                referral_id,
+            );
+        } else if market_type == "sell_to_highest_offer" {
+            assert!(price.is_some(), "Marble: Min price is not specified");
+
+            self.internal_sell_to_highest_offer(
+                nft_contract_id,
+                token_id,
+                owner_id,
+                approval_id,
+                price.unwrap().0,
+                referral_id,
+            );
+        } else if market_type == "counter_offer" {
+            assert!(buyer_id.is_some(), "Marble: Account id is not specified");
+            assert!(price.is_some(), "Marble: Counter price is not specified");
+
+            self.internal_counter_offer(
+                nft_contract_id,
+                buyer_id.unwrap(),
+                token_id.clone(),
+                token_id,
+                owner_id,
+                approval_id,
+                price.unwrap().0,
+            );
+        } else if market_type == "counter_offer_marble_series" {
+            assert!(buyer_id.is_some(), "Marble: Account id is not specified");
+            assert!(
+                self.marble_nft_contracts.contains(&nft_contract_id),
+                "Marble: countering offer series for Marble NFT only"
+            );
+            assert!(price.is_some(), "Marble: Counter price is not specified");
+
+            // Token delimiter : is specific for Marble NFT
+            let token_series_id: String = token_id.split(":").next().unwrap().to_string();
+
+            self.internal_counter_offer(
+                nft_contract_id,
+                buyer_id.unwrap(),
+                token_series_id,
+                token_id,
+                owner_id,
+                approval_id,
+                price.unwrap().0,
             );
         } else if market_type == "add_trade" {
             // old market data
@@ -173,6 +411,7 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
             if let Some(mut market_data) = self.market.get(&contract_and_token_id) {
                 market_data.approval_id = approval_id;
                 self.market.insert(&contract_and_token_id, &market_data);
+                self.internal_record_change(ChangeType::Market, &contract_and_token_id);
             }
             // //replace old data approval id
             let buyer_contract_account_id_token_id =
@@ -181,12 +420,13 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 old_trade.approval_id = approval_id;
                 self.trades
                     .insert(&buyer_contract_account_id_token_id, &old_trade);
+                self.internal_record_change(ChangeType::Trade, &buyer_contract_account_id_token_id);
             }
 
             let storage_amount = self.storage_minimum_balance().0;
-            let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
+            let owner_paid_storage = self.storage_deposits.get(&owner_id).unwrap_or(0);
             let signer_storage_required =
-                (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
+                (self.get_supply_by_owner_id(owner_id.clone()).0 + 1) as u128 * storage_amount;
 
             if owner_paid_storage < signer_storage_required {
                 let notif = format!(
@@ -207,6 +447,9 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 owner_id,
                 Some(token_id),
                 approval_id,
+                buyer_tokens,
+                seller_tokens,
+                expires_at.map(|expires_at| expires_at.0),
             );
         } else if market_type == "accept_trade" {
             assert!(buyer_id.is_some(), "Marble: Account id is not specified");
@@ -227,6 +470,7 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 approval_id,
                 buyer_nft_contract_id.unwrap(),
                 buyer_token_id.unwrap(),
+                seller_tokens,
             );
         } else if market_type == "accept_trade_marble_series" {
             assert!(buyer_id.is_some(), "Marble: Account id is not specified");
@@ -251,6 +495,174 @@ impl NonFungibleTokenApprovalsReceiver for Contract {
                 approval_id,
                 buyer_nft_contract_id.unwrap(),
                 buyer_token_id.unwrap(),
+                seller_tokens,
+            );
+        } else if market_type == "counter_trade" {
+            assert!(buyer_id.is_some(), "Marble: Account id is not specified");
+            assert!(
+                buyer_nft_contract_id.is_some(),
+                "Marble: Buyer NFT contract id is not specified"
+            );
+            assert!(
+                buyer_token_id.is_some(),
+                "Marble: Buyer token id is not specified"
+            );
+
+            self.internal_counter_trade(
+                nft_contract_id,
+                token_id,
+                owner_id,
+                approval_id,
+                buyer_id.unwrap(),
+                buyer_nft_contract_id.unwrap(),
+                buyer_token_id.unwrap(),
+                counter_nft_contract_id,
+                counter_token_id,
+                price.map(|price| price.0),
+            );
+        } else if market_type == "accept_counter_trade" {
+            assert!(
+                seller_nft_contract_id.is_some(),
+                "Marble: Seller NFT contract id is not specified"
+            );
+            assert!(
+                seller_token_id.is_some(),
+                "Marble: Seller token id is not specified"
+            );
+            assert!(
+                buyer_nft_contract_id.is_some(),
+                "Marble: Buyer NFT contract id is not specified"
+            );
+            assert!(
+                buyer_token_id.is_some(),
+                "Marble: Buyer token id is not specified"
+            );
+
+            self.internal_accept_counter_trade(
+                nft_contract_id,
+                token_id,
+                owner_id,
+                approval_id,
+                seller_nft_contract_id.unwrap(),
+                seller_token_id.unwrap(),
+                buyer_nft_contract_id.unwrap(),
+                buyer_token_id.unwrap(),
+            );
+        } else if market_type == "approve_ring_trade" {
+            assert!(
+                ring_trade_id.is_some(),
+                "Marble: Ring trade id is not specified"
+            );
+
+            self.internal_approve_ring_trade(
+                ring_trade_id.unwrap().0,
+                nft_contract_id,
+                token_id,
+                owner_id,
+                approval_id,
+            );
+        } else if market_type == "rent" {
+            assert!(price.is_some(), "Marble: price per day not specified");
+            assert!(max_days.is_some(), "Marble: max_days not specified");
+            // rent is paid with the attached deposit
+            assert_eq!(
+                ft_token_id.unwrap_or(near_account()).to_string(),
+                NEAR,
+                "Marble: NEAR support only"
+            );
+
+            self.internal_add_rental(
+                owner_id,
+                approval_id,
+                nft_contract_id,
+                token_id,
+                price.unwrap(),
+                max_days.unwrap(),
+            );
+        } else if market_type == "add_bundle_token" {
+            assert!(
+                bundle_listing_id.is_some(),
+                "Marble: Bundle listing id is not specified"
+            );
+
+            self.internal_approve_bundle_token(
+                bundle_listing_id.unwrap().0,
+                nft_contract_id,
+                token_id,
+                owner_id,
+                approval_id,
+            );
+        }
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenSeriesApprovalsReceiver for Contract {
+    fn nft_on_series_approve(
+        &mut self,
+        token_series_id: TokenSeriesId,
+        owner_id: AccountId,
+        msg: String,
+    ) {
+        let nft_contract_id = env::predecessor_account_id();
+        assert_ne!(
+            env::current_account_id(),
+            nft_contract_id,
+            "Marble: nft_on_series_approve should only be called via cross-contract call"
+        );
+
+        assert!(
+            self.marble_nft_contracts.contains(&nft_contract_id),
+            "Marble: mint sale for Marble NFT only"
+        );
+
+        let MarketArgs {
+            market_type,
+            price,
+            ft_token_id,
+            started_at,
+            ended_at,
+            signer_id,
+            ..
+        } = near_sdk::serde_json::from_str(&msg).expect("Not valid MarketArgs");
+        self.assert_listing_signer(&owner_id, signer_id);
+
+        self.assert_not_paused(PauseFeature::Listings);
+        self.assert_not_blacklisted_seller(&owner_id);
+        self.assert_collection_not_frozen(&nft_contract_id);
+        if market_type == "mint_sale" {
+            assert!(price.is_some(), "Marble: price not specified");
+
+            // only NEAR supported for now, the mint storage is paid in NEAR
+            assert_eq!(
+                ft_token_id.unwrap_or(near_account()).to_string(),
+                NEAR,
+                "Marble: NEAR support only"
+            );
+
+            let storage_amount = self.storage_minimum_balance().0;
+            let owner_paid_storage = self.storage_deposits.get(&owner_id).unwrap_or(0);
+            let signer_storage_required =
+                (self.get_supply_by_owner_id(owner_id.clone()).0 + 1) as u128 * storage_amount;
+
+            if owner_paid_storage < signer_storage_required {
+                let notif = format!(
+                    "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
+                    owner_paid_storage,
+                    signer_storage_required / storage_amount,
+                    storage_amount
+                );
+                env::log_str(&notif);
+                return;
+            }
+
+            self.internal_add_mint_sale(
+                owner_id,
+                nft_contract_id,
+                token_series_id,
+                price.unwrap(),
+                started_at,
+                ended_at,
             );
         }
     }
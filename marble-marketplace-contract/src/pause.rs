@@ -0,0 +1,165 @@
+use crate::*;
+
+/// incident response, guardians can pause features and only the owner resumes them
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum PauseFeature {
+    All,
+    Buys,
+    Listings,
+    Offers,
+    Trades,
+    FtDeposits,
+}
+
+impl PauseFeature {
+    fn name(&self) -> &'static str {
+        match self {
+            PauseFeature::All => "All features",
+            PauseFeature::Buys => "Buys",
+            PauseFeature::Listings => "Listings",
+            PauseFeature::Offers => "Offers",
+            PauseFeature::Trades => "Trades",
+            PauseFeature::FtDeposits => "FT deposits",
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn pause(&mut self, features: Vec<PauseFeature>) {
+        assert_one_yocto();
+        self.assert_role(Role::Guardian);
+        self.internal_record_admin_action("pause", json!({ "features": features }));
+        let account_id = env::predecessor_account_id();
+        for feature in features.iter() {
+            if !self.paused_features.contains(feature) {
+                self.paused_features.push(*feature);
+            }
+        }
+
+        env::log_str(
+            &json!({
+                "type": "pause",
+                "params": {
+                    "account_id": account_id,
+                    "features": features,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn unpause(&mut self, features: Vec<PauseFeature>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action("unpause", json!({ "features": features }));
+        self.paused_features
+            .retain(|feature| !features.contains(feature));
+
+        env::log_str(
+            &json!({
+                "type": "unpause",
+                "params": {
+                    "features": features,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_paused_features(&self) -> Vec<PauseFeature> {
+        self.paused_features.clone()
+    }
+
+    pub fn is_paused(&self, feature: PauseFeature) -> bool {
+        self.paused_features.contains(&PauseFeature::All) || self.paused_features.contains(&feature)
+    }
+
+    // halts listings, bids, offers and purchases of one collection, delisting and refunds still work
+    #[payable]
+    pub fn freeze_collection(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::Guardian);
+        self.internal_record_admin_action(
+            "freeze_collection",
+            json!({ "nft_contract_id": nft_contract_id }),
+        );
+        self.frozen_nft_contract_ids.insert(&nft_contract_id);
+
+        env::log_str(
+            &json!({
+                "type": "freeze_collection",
+                "params": {
+                    "account_id": env::predecessor_account_id(),
+                    "nft_contract_id": nft_contract_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn unfreeze_collection(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "unfreeze_collection",
+            json!({ "nft_contract_id": nft_contract_id }),
+        );
+        self.frozen_nft_contract_ids.remove(&nft_contract_id);
+
+        env::log_str(
+            &json!({
+                "type": "unfreeze_collection",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn is_collection_frozen(&self, nft_contract_id: AccountId) -> bool {
+        self.frozen_nft_contract_ids.contains(&nft_contract_id)
+    }
+
+    pub fn get_frozen_collections(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.frozen_nft_contract_ids.len());
+
+        self.frozen_nft_contract_ids
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub(crate) fn assert_collection_not_frozen(&self, nft_contract_id: &AccountId) {
+        assert!(
+            !self.frozen_nft_contract_ids.contains(nft_contract_id),
+            "Marble: Collection is frozen"
+        );
+    }
+
+    // cancellations and withdrawals are never paused, funds can always leave
+    pub(crate) fn assert_not_paused(&self, feature: PauseFeature) {
+        let feature = if self.paused_features.contains(&PauseFeature::All) {
+            PauseFeature::All
+        } else {
+            feature
+        };
+        assert!(
+            !self.paused_features.contains(&feature),
+            "Marble: {} are paused",
+            feature.name()
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use crate::*;
+
+/// presale listings, only the allowlist or holders of a collection can buy before public_at
+pub const MAX_PRESALE_ALLOWLIST: usize = 10; // bounded by the listing storage deposit
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Presale {
+    pub public_at: U64,
+    pub allowlist: Vec<AccountId>,
+    pub holder_nft_contract_id: Option<AccountId>, // holders of this collection may buy too
+}
+
+impl Config {
+    fn gas_for_callback_buy_presale(&self) -> Gas {
+        Gas(self.gas_for_nft_token.0
+            + self.gas_for_nft_transfer.0
+            + self.gas_for_royalties.0
+            + self.base_gas.0 * 2)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub(crate) fn internal_add_presale(
+        &mut self,
+        market_data: &MarketData,
+        public_at: U64,
+        allowlist: Vec<AccountId>,
+        holder_nft_contract_id: Option<AccountId>,
+    ) {
+        let contract_and_token_id = format!(
+            "{}{}{}",
+            market_data.nft_contract_id, DELIMETER, market_data.token_id
+        );
+        assert!(
+            public_at.0 > env::block_timestamp(),
+            "Marble: public_at must be in the future"
+        );
+        assert!(
+            !allowlist.is_empty() || holder_nft_contract_id.is_some(),
+            "Marble: Presale needs an allowlist or a holder collection"
+        );
+        assert!(
+            allowlist.len() <= MAX_PRESALE_ALLOWLIST,
+            "Marble: Presale allowlist is limited to {} accounts",
+            MAX_PRESALE_ALLOWLIST
+        );
+        assert!(
+            market_data.is_auction != Some(true) && market_data.end_price.is_none(),
+            "Marble: Presale is for fixed price sales only"
+        );
+        assert!(
+            self.usd_prices.get(&contract_and_token_id).is_none(),
+            "Marble: Presale cannot be priced in USD"
+        );
+
+        let presale = Presale {
+            public_at,
+            allowlist,
+            holder_nft_contract_id,
+        };
+        self.presales.insert(&contract_and_token_id, &presale);
+
+        env::log_str(
+            &json!({
+                "type": "add_presale",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "public_at": presale.public_at,
+                    "allowlist": presale.allowlist,
+                    "holder_nft_contract_id": presale.holder_nft_contract_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_presale(&self, nft_contract_id: AccountId, token_id: TokenId) -> Option<Presale> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.presales.get(&contract_and_token_id)
+    }
+
+    // the collection the buyer still has to hold, None once public or for the allowlist
+    pub(crate) fn internal_presale_holder_nft_contract_id(
+        &self,
+        contract_and_token_id: &ContractAndTokenId,
+        buyer_id: &AccountId,
+    ) -> Option<AccountId> {
+        let presale = self.presales.get(contract_and_token_id)?;
+        if env::block_timestamp() >= presale.public_at.0 || presale.allowlist.contains(buyer_id) {
+            return None;
+        }
+        Some(
+            presale
+                .holder_nft_contract_id
+                .expect("Marble: Sale is reserved for the presale allowlist"),
+        )
+    }
+
+    pub(crate) fn is_presale_buyer(
+        &self,
+        contract_and_token_id: &ContractAndTokenId,
+        buyer_id: &AccountId,
+    ) -> bool {
+        self.presales
+            .get(contract_and_token_id)
+            .map_or(true, |presale| {
+                env::block_timestamp() >= presale.public_at.0
+                    || presale.allowlist.contains(buyer_id)
+            })
+    }
+
+    // the payment is already held, it is refunded if the buyer holds nothing
+    pub(crate) fn internal_buy_presale(
+        &mut self,
+        holder_nft_contract_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        price: u128,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        ext_contract::nft_supply_for_owner(
+            buyer_id.clone(),
+            holder_nft_contract_id,
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::callback_buy_presale(
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            buyer_id,
+            receiver_id,
+            U128(price),
+            referral_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_buy_presale(),
+        ))
+    }
+
+    #[private]
+    pub fn callback_buy_presale(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        price: U128,
+        referral_id: Option<AccountId>,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let supply = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<U128>(&value).ok())
+            .map_or(0, |supply| supply.0);
+        let listed_price = self
+            .internal_get_market_data(&contract_and_token_id)
+            .filter(|_| self.layaways.get(&contract_and_token_id).is_none())
+            .and_then(|market_data| {
+                self.internal_listing_price(&contract_and_token_id, &market_data, &ft_token_id)
+            });
+
+        let reason = if supply == 0 {
+            Some("not a holder")
+        } else if listed_price != Some(price.0) {
+            Some("sale has changed")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.internal_transfer(&ft_token_id, buyer_id.clone(), price.0);
+            env::log_str(
+                &json!({
+                    "type": "buy_presale_fail",
+                    "params": {
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "ft_token_id": ft_token_id,
+                        "price": price,
+                        "buyer_id": buyer_id,
+                        "reason": reason,
+                    }
+                })
+                .to_string(),
+            );
+            return;
+        }
+
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            buyer_id,
+            receiver_id,
+            price.0,
+            referral_id,
+            self.config.gas_for_royalties,
+        );
+    }
+}
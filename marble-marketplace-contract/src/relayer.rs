@@ -0,0 +1,67 @@
+use crate::*;
+
+/// meta-transactions (NEP-366), a relayer submits the user's delegate action and pays its gas.
+/// The user stays the predecessor of marketplace calls, only the signer becomes the relayer.
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn add_trusted_relayer_ids(&mut self, relayer_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "add_trusted_relayer_ids",
+            json!({ "relayer_ids": relayer_ids }),
+        );
+        add_accounts(Some(relayer_ids), &mut self.trusted_relayer_ids);
+    }
+
+    #[payable]
+    pub fn remove_trusted_relayer_ids(&mut self, relayer_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "remove_trusted_relayer_ids",
+            json!({ "relayer_ids": relayer_ids }),
+        );
+        remove_accounts(Some(relayer_ids), &mut self.trusted_relayer_ids);
+    }
+
+    pub fn get_trusted_relayer_ids(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.trusted_relayer_ids.len());
+
+        self.trusted_relayer_ids
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    // nft_on_approve only sees the outer signer, a relayed nft_approve is signed by the relayer.
+    // Its msg names the inner signer, trusted only when the outer signer is an allowed relayer
+    pub(crate) fn assert_listing_signer(
+        &self,
+        owner_id: &AccountId,
+        delegate_signer_id: Option<AccountId>,
+    ) {
+        let signer_id = match delegate_signer_id {
+            Some(delegate_signer_id) => {
+                assert!(
+                    self.trusted_relayer_ids.contains(&env::signer_account_id()),
+                    "Marble: Relayer is not trusted"
+                );
+                delegate_signer_id
+            }
+            None => env::signer_account_id(),
+        };
+        // an operator signs for the owner, the listing and its proceeds stay the owner's
+        assert!(
+            owner_id == &signer_id || self.is_listing_operator(owner_id.clone(), signer_id),
+            "Marble: owner_id should be the signer or their listing operator"
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use crate::*;
+
+/// rental market, the token stays with its owner and the renter is recorded as its user
+pub const ONE_DAY: u64 = 86_400_000_000_000;
+pub const MAX_RENTAL_DAYS: u64 = 365;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Rental {
+    pub owner_id: AccountId,
+    pub approval_id: u64,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub price_per_day: U128, // NEAR
+    pub max_days: u64,
+    pub storage_deposit: U128, // taken from the owner's storage balance
+    pub renter_id: Option<AccountId>,
+    pub escrow: U128, // rent paid for the current term
+    pub rented_at: Option<u64>,
+    pub ends_at: Option<u64>,
+}
+
+impl Config {
+    fn gas_for_callback_end_rental(&self) -> Gas {
+        Gas(self.gas_for_ft_transfer.0 * 3 + self.base_gas.0)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub(crate) fn internal_add_rental(
+        &mut self,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        price_per_day: U128,
+        max_days: u64,
+    ) {
+        assert!(
+            price_per_day.0 > 0 && price_per_day.0 < self.config.max_price.0,
+            "Marble: price higher than {}",
+            self.config.max_price.0
+        );
+        assert!(
+            max_days > 0 && max_days <= MAX_RENTAL_DAYS,
+            "Marble: max_days must be between 1 and {}",
+            MAX_RENTAL_DAYS
+        );
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let storage_deposit = match self.rentals.get(&contract_and_token_id) {
+            // relisting keeps the storage already set aside, unless the token changed hands
+            Some(rental) => {
+                assert!(rental.renter_id.is_none(), "Marble: Token is rented");
+                if rental.owner_id == owner_id {
+                    rental.storage_deposit.0
+                } else {
+                    self.internal_release_rental_storage(&rental);
+                    self.internal_lock_rental_storage(&owner_id)
+                }
+            }
+            None => self.internal_lock_rental_storage(&owner_id),
+        };
+
+        self.rentals.insert(
+            &contract_and_token_id,
+            &Rental {
+                owner_id: owner_id.clone(),
+                approval_id,
+                nft_contract_id: nft_contract_id.clone(),
+                token_id: token_id.clone(),
+                price_per_day,
+                max_days,
+                storage_deposit: U128(storage_deposit),
+                renter_id: None,
+                escrow: U128(0),
+                rented_at: None,
+                ends_at: None,
+            },
+        );
+
+        env::log_str(
+            &json!({
+                "type": "add_rental",
+                "params": {
+                    "owner_id": owner_id,
+                    "approval_id": approval_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "price_per_day": price_per_day,
+                    "max_days": max_days,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn rent(&mut self, nft_contract_id: AccountId, token_id: TokenId, days: u64) {
+        self.assert_not_paused(PauseFeature::Buys);
+        self.assert_not_blacklisted_buyer(&env::predecessor_account_id());
+        self.assert_collection_not_frozen(&nft_contract_id);
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut rental = self
+            .rentals
+            .get(&contract_and_token_id)
+            .expect("Marble: Rental does not exist");
+        let renter_id = env::predecessor_account_id();
+
+        assert_ne!(
+            renter_id, rental.owner_id,
+            "Marble: Cannot rent your own token"
+        );
+        assert!(rental.renter_id.is_none(), "Marble: Token is rented");
+        assert!(
+            days > 0 && days <= rental.max_days,
+            "Marble: Rental is limited to {} days",
+            rental.max_days
+        );
+
+        let price = rental.price_per_day.0 * days as u128;
+        assert!(
+            env::attached_deposit() >= price,
+            "Marble: Attached deposit is less than price {}",
+            price
+        );
+        let excess_deposit = env::attached_deposit() - price;
+        if excess_deposit > 0 {
+            Promise::new(renter_id.clone()).transfer(excess_deposit);
+        }
+
+        let rented_at = env::block_timestamp();
+        let ends_at = rented_at + days * ONE_DAY;
+        rental.renter_id = Some(renter_id.clone());
+        rental.escrow = U128(price);
+        rental.rented_at = Some(rented_at);
+        rental.ends_at = Some(ends_at);
+        self.rentals.insert(&contract_and_token_id, &rental);
+
+        env::log_str(
+            &json!({
+                "type": "rent",
+                "params": {
+                    "owner_id": rental.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "renter_id": renter_id,
+                    "price": U128(price),
+                    "days": days,
+                    "ends_at": U64(ends_at),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // anyone can return an expired rental, the owner is paid if they kept the token listed
+    pub fn end_rental(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> Promise {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut rental = self
+            .rentals
+            .get(&contract_and_token_id)
+            .expect("Marble: Rental does not exist");
+        let ends_at = rental.ends_at.expect("Marble: Token is not rented");
+        assert!(
+            env::block_timestamp() >= ends_at,
+            "Marble: Rental has not ended yet"
+        );
+
+        let renter_id = rental.renter_id.take().unwrap();
+        let escrow = rental.escrow;
+        rental.escrow = U128(0);
+        rental.rented_at = None;
+        rental.ends_at = None;
+        self.rentals.insert(&contract_and_token_id, &rental);
+
+        let transaction_fee = self.calculate_current_transaction_fee() as u16;
+        ext_contract::nft_token(
+            token_id,
+            nft_contract_id,
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::callback_end_rental(
+            rental,
+            renter_id,
+            escrow,
+            transaction_fee,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_end_rental(),
+        ))
+    }
+
+    #[private]
+    pub fn callback_end_rental(
+        &mut self,
+        rental: Rental,
+        renter_id: AccountId,
+        escrow: U128,
+        transaction_fee: u16,
+    ) {
+        let json_token = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<JsonToken>(&value).ok());
+        let is_kept = json_token.map_or(false, |json_token| {
+            json_token.owner_id == rental.owner_id
+                && is_trade_token_approved(&json_token, Some(rental.approval_id))
+        });
+
+        // the owner moved the token during the term, the renter gets the rent back
+        let treasury_fee = if is_kept {
+            let treasury_fee = escrow.0 * transaction_fee as u128 / 10_000u128;
+            Promise::new(rental.owner_id.clone()).transfer(escrow.0 - treasury_fee);
+            self.internal_transfer_treasury_fee(&near_account(), treasury_fee, None);
+            treasury_fee
+        } else {
+            Promise::new(renter_id.clone()).transfer(escrow.0);
+            0
+        };
+
+        env::log_str(
+            &json!({
+                "type": "end_rental",
+                "params": {
+                    "owner_id": rental.owner_id,
+                    "nft_contract_id": rental.nft_contract_id,
+                    "token_id": rental.token_id,
+                    "renter_id": renter_id,
+                    "escrow": escrow,
+                    "treasury_fee": U128(treasury_fee),
+                    "refunded": !is_kept,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn delete_rental(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let rental = self
+            .rentals
+            .get(&contract_and_token_id)
+            .expect("Marble: Rental does not exist");
+        assert_eq!(
+            env::predecessor_account_id(),
+            rental.owner_id,
+            "Marble: Rental owner only"
+        );
+        assert!(rental.renter_id.is_none(), "Marble: Token is rented");
+
+        self.rentals.remove(&contract_and_token_id);
+        self.internal_release_rental_storage(&rental);
+
+        env::log_str(
+            &json!({
+                "type": "delete_rental",
+                "params": {
+                    "owner_id": rental.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_rental(&self, nft_contract_id: AccountId, token_id: TokenId) -> Option<Rental> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.rentals.get(&contract_and_token_id)
+    }
+
+    // the account dapps should treat as the token's user right now
+    pub fn get_rental_user(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<AccountId> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.rentals.get(&contract_and_token_id).and_then(|rental| {
+            match (rental.renter_id, rental.ends_at) {
+                (Some(renter_id), Some(ends_at)) if env::block_timestamp() < ends_at => {
+                    Some(renter_id)
+                }
+                _ => None,
+            }
+        })
+    }
+
+    fn internal_lock_rental_storage(&mut self, owner_id: &AccountId) -> Balance {
+        let storage_deposit = self.storage_deposits.get(owner_id).unwrap_or(0);
+        let storage_required = self.get_supply_by_owner_id(owner_id.clone()).0 as u128
+            * self.config.storage_add_market_data.0;
+        assert!(
+            storage_deposit >= storage_required + self.config.storage_add_market_data.0,
+            "Marble: Insufficient storage deposit for the rental"
+        );
+        self.storage_deposits.insert(
+            owner_id,
+            &(storage_deposit - self.config.storage_add_market_data.0),
+        );
+        self.config.storage_add_market_data.0
+    }
+
+    fn internal_release_rental_storage(&mut self, rental: &Rental) {
+        let storage_deposit = self.storage_deposits.get(&rental.owner_id).unwrap_or(0);
+        self.storage_deposits.insert(
+            &rental.owner_id,
+            &(storage_deposit + rental.storage_deposit.0),
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::*;
+
+/// delegated admin rights, owner_id and Owner role holders pass every role check
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Owner,
+    FeeManager,       // fees, brackets, tiers, exemptions and burns
+    ListingModerator, // approved collections, collection admins and delisting
+    Guardian,         // pausing
+}
+
+impl Role {
+    fn name(&self) -> &'static str {
+        match self {
+            Role::Owner => "Owner",
+            Role::FeeManager => "Fee manager",
+            Role::ListingModerator => "Listing moderator",
+            Role::Guardian => "Guardian",
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.assert_role(Role::Owner);
+        self.internal_record_admin_action(
+            "grant_role",
+            json!({ "account_id": account_id, "role": role }),
+        );
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.roles.insert(&account_id, &roles);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "grant_role",
+                "params": {
+                    "account_id": account_id,
+                    "role": role,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.assert_role(Role::Owner);
+        self.internal_record_admin_action(
+            "revoke_role",
+            json!({ "account_id": account_id, "role": role }),
+        );
+        let mut roles = self
+            .roles
+            .get(&account_id)
+            .expect("Marble: Account has no roles");
+        assert!(
+            roles.contains(&role),
+            "Marble: Account does not have the role"
+        );
+        roles.retain(|account_role| *account_role != role);
+        if roles.is_empty() {
+            self.roles.remove(&account_id);
+        } else {
+            self.roles.insert(&account_id, &roles);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "revoke_role",
+                "params": {
+                    "account_id": account_id,
+                    "role": role,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // granted roles only, owner_id holds every role without being listed
+    pub fn get_roles(&self, account_id: AccountId) -> Vec<Role> {
+        self.roles.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn get_role_accounts(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, Vec<Role>)> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.roles.len());
+
+        self.roles
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn has_role(&self, account_id: AccountId, role: Role) -> bool {
+        if account_id == self.owner_id {
+            return true;
+        }
+        self.roles.get(&account_id).map_or(false, |roles| {
+            roles.contains(&role) || roles.contains(&Role::Owner)
+        })
+    }
+
+    pub(crate) fn assert_role(&self, role: Role) {
+        assert!(
+            self.has_role(env::predecessor_account_id(), role),
+            "Marble: {} only",
+            role.name()
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::*;
+use ed25519_dalek::Verifier;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::CurveType;
+
+/// signed orders, sellers sign listings off-chain and buyers settle them in one transaction
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SignedOrder {
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: U64, // the marketplace's approval on the token
+    pub price: U128,      // NEAR
+    pub nonce: U64,
+    pub expires_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    // the ed25519 key orders are signed with, usually not one of the account's access keys
+    #[payable]
+    pub fn set_order_public_key(&mut self, public_key: Option<PublicKey>) {
+        let owner_id = env::predecessor_account_id();
+        match public_key {
+            Some(public_key) => {
+                assert_eq!(
+                    public_key.curve_type(),
+                    CurveType::ED25519,
+                    "Marble: Order keys must be ed25519"
+                );
+                if self.order_public_keys.get(&owner_id).is_none() {
+                    assert!(
+                        env::attached_deposit() >= self.config.storage_add_market_data.0,
+                        "Marble: Attach at least {} yoctoNEAR for storage",
+                        self.config.storage_add_market_data.0
+                    );
+                } else {
+                    assert_one_yocto();
+                }
+                self.order_public_keys.insert(&owner_id, &public_key);
+            }
+            None => {
+                assert_one_yocto();
+                self.order_public_keys.remove(&owner_id);
+            }
+        }
+    }
+
+    pub fn get_order_public_key(&self, account_id: AccountId) -> Option<PublicKey> {
+        self.order_public_keys.get(&account_id)
+    }
+
+    // every order signed with a lower nonce stops being fillable
+    #[payable]
+    pub fn cancel_signed_orders(&mut self, min_nonce: U64) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        assert!(
+            min_nonce.0 > self.order_min_nonces.get(&owner_id).unwrap_or(0),
+            "Marble: min_nonce must increase"
+        );
+        self.order_min_nonces.insert(&owner_id, &min_nonce.0);
+
+        env::log_str(
+            &json!({
+                "type": "cancel_signed_orders",
+                "params": {
+                    "owner_id": owner_id,
+                    "min_nonce": min_nonce,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_order_min_nonce(&self, account_id: AccountId) -> U64 {
+        U64(self.order_min_nonces.get(&account_id).unwrap_or(0))
+    }
+
+    pub fn is_signed_order_filled(&self, owner_id: AccountId, nonce: U64) -> bool {
+        self.filled_order_nonces
+            .contains(&order_nonce_key(&owner_id, nonce.0))
+    }
+
+    // the bytes a seller signs, bound to this marketplace so orders cannot be replayed elsewhere
+    pub fn get_signed_order_message(&self, order: SignedOrder) -> Base64VecU8 {
+        Base64VecU8(env::sha256(
+            &(env::current_account_id(), order)
+                .try_to_vec()
+                .expect("Marble: Cannot serialize order"),
+        ))
+    }
+
+    #[payable]
+    pub fn fill_signed_order(
+        &mut self,
+        order: SignedOrder,
+        signature: Base64VecU8,
+        referral_id: Option<AccountId>,
+        receiver_id: Option<AccountId>,
+    ) -> Promise {
+        self.assert_not_paused(PauseFeature::Buys);
+        let buyer_id = env::predecessor_account_id();
+        self.assert_not_blacklisted_buyer(&buyer_id);
+        self.assert_not_blacklisted_seller(&order.owner_id);
+        self.assert_collection_not_frozen(&order.nft_contract_id);
+        assert!(
+            self.approved_nft_contract_ids
+                .contains(&order.nft_contract_id),
+            "Marble: nft_contract_id is not approved"
+        );
+        assert_ne!(buyer_id, order.owner_id, "Marble: Cannot buy your own sale");
+        assert!(
+            env::block_timestamp() < order.expires_at.0,
+            "Marble: Order has expired"
+        );
+        assert!(
+            order.nonce.0 >= self.order_min_nonces.get(&order.owner_id).unwrap_or(0),
+            "Marble: Order has been cancelled"
+        );
+        // consumed as it is filled, a fill that fails is not retried with the same order
+        assert!(
+            self.filled_order_nonces
+                .insert(&order_nonce_key(&order.owner_id, order.nonce.0)),
+            "Marble: Order has already been filled"
+        );
+        assert!(
+            order.price.0 > 0 && order.price.0 < self.config.max_price.0,
+            "Marble: price higher than {}",
+            self.config.max_price.0
+        );
+        let public_key = self
+            .order_public_keys
+            .get(&order.owner_id)
+            .expect("Marble: Seller has no order public key");
+        let message = self.get_signed_order_message(order.clone());
+        assert!(
+            verify_order_signature(&public_key, &message.0, &signature.0),
+            "Marble: Invalid order signature"
+        );
+
+        let price = order.price.0;
+        assert!(
+            env::attached_deposit() >= price,
+            "Marble: Attached deposit is less than price {}",
+            price
+        );
+        let excess_deposit = env::attached_deposit() - price;
+        if excess_deposit > 0 {
+            Promise::new(buyer_id.clone()).transfer(excess_deposit);
+        }
+
+        let contract_and_token_id =
+            format!("{}{}{}", order.nft_contract_id, DELIMETER, order.token_id);
+        self.assert_not_settling(&contract_and_token_id);
+        self.settling_listings.insert(&contract_and_token_id);
+
+        env::log_str(
+            &json!({
+                "type": "fill_signed_order",
+                "params": {
+                    "order": order,
+                    "buyer_id": buyer_id,
+                }
+            })
+            .to_string(),
+        );
+
+        // settles like a listing that was just bought, the approval is checked by the NFT contract
+        let market_data = MarketData {
+            owner_id: order.owner_id,
+            approval_id: order.approval_id.0,
+            nft_contract_id: order.nft_contract_id.clone(),
+            token_id: order.token_id.clone(),
+            ft_token_id: near_account(),
+            price,
+            bids: None,
+            started_at: None,
+            ended_at: None,
+            end_price: None,
+            accept_nft_contract_id: None,
+            accept_token_id: None,
+            is_auction: None,
+            reserve_price: None,
+        };
+        ext_contract::nft_token(
+            order.token_id,
+            order.nft_contract_id,
+            NO_DEPOSIT,
+            self.config.gas_for_nft_token,
+        )
+        .then(ext_self::callback_verify_purchase(
+            buyer_id.clone(),
+            receiver_id.unwrap_or(buyer_id),
+            market_data,
+            price.into(),
+            referral_id,
+            self.config.gas_for_royalties,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            Gas(self.config.gas_for_nft_transfer.0
+                + self.config.gas_for_royalties.0
+                + self.config.base_gas.0),
+        ))
+    }
+}
+
+fn order_nonce_key(owner_id: &AccountId, nonce: u64) -> String {
+    format!("{}{}{}", owner_id, DELIMETER, nonce)
+}
+
+fn verify_order_signature(public_key: &PublicKey, message: &[u8], signature: &[u8]) -> bool {
+    // the first byte of a near_sdk PublicKey is its curve type
+    let public_key = match ed25519_dalek::PublicKey::from_bytes(&public_key.as_bytes()[1..]) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+    let signature = match ed25519_dalek::Signature::try_from(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    public_key.verify(message, &signature).is_ok()
+}
@@ -0,0 +1,145 @@
+use crate::*;
+
+/// collection-sponsored storage, a pool funded by the collection covers its holders' listing storage
+#[near_bindgen]
+impl Contract {
+    // the collection account or the marketplace owner tops up the pool
+    #[payable]
+    pub fn sponsor_storage(&mut self, nft_contract_id: AccountId) -> U128 {
+        self.assert_storage_sponsor(&nft_contract_id);
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "Marble: nft_contract_id is not approved"
+        );
+        let amount = env::attached_deposit();
+        assert!(amount > 0, "Marble: Attached deposit is 0");
+
+        let pool = self
+            .storage_sponsor_pools
+            .get(&nft_contract_id)
+            .unwrap_or(0)
+            + amount;
+        self.storage_sponsor_pools.insert(&nft_contract_id, &pool);
+
+        env::log_str(
+            &json!({
+                "type": "sponsor_storage",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "sponsor_id": env::predecessor_account_id(),
+                    "amount": U128(amount),
+                    "pool": U128(pool),
+                }
+            })
+            .to_string(),
+        );
+        U128(pool)
+    }
+
+    // storage locked by active sponsored listings returns to the pool when they end
+    #[payable]
+    pub fn withdraw_storage_sponsorship(
+        &mut self,
+        nft_contract_id: AccountId,
+        amount: Option<U128>,
+    ) -> U128 {
+        assert_one_yocto();
+        self.assert_storage_sponsor(&nft_contract_id);
+        let pool = self
+            .storage_sponsor_pools
+            .get(&nft_contract_id)
+            .unwrap_or(0);
+        let amount = amount.map_or(pool, |amount| amount.0);
+        assert!(
+            amount > 0 && amount <= pool,
+            "Marble: Storage sponsor pool is {}",
+            pool
+        );
+
+        if pool == amount {
+            self.storage_sponsor_pools.remove(&nft_contract_id);
+        } else {
+            self.storage_sponsor_pools
+                .insert(&nft_contract_id, &(pool - amount));
+        }
+        Promise::new(env::predecessor_account_id()).transfer(amount);
+
+        env::log_str(
+            &json!({
+                "type": "withdraw_storage_sponsorship",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "sponsor_id": env::predecessor_account_id(),
+                    "amount": U128(amount),
+                    "pool": U128(pool - amount),
+                }
+            })
+            .to_string(),
+        );
+        U128(pool - amount)
+    }
+
+    pub fn get_storage_sponsor_pool(&self, nft_contract_id: AccountId) -> U128 {
+        U128(
+            self.storage_sponsor_pools
+                .get(&nft_contract_id)
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn get_sponsored_storage(&self, nft_contract_id: AccountId, token_id: TokenId) -> U128 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        U128(
+            self.sponsored_listings
+                .get(&contract_and_token_id)
+                .unwrap_or(0),
+        )
+    }
+
+    fn assert_storage_sponsor(&self, nft_contract_id: &AccountId) {
+        let predecessor_id = env::predecessor_account_id();
+        assert!(
+            &predecessor_id == nft_contract_id || predecessor_id == self.owner_id,
+            "Marble: Collection or owner only"
+        );
+    }
+
+    // covers what the owner is short of for one more listing, None if the pool cannot
+    pub(crate) fn internal_sponsor_listing_storage(
+        &mut self,
+        nft_contract_id: &AccountId,
+        owner_id: &AccountId,
+        storage_shortfall: Balance,
+    ) -> Option<Balance> {
+        let pool = self.storage_sponsor_pools.get(nft_contract_id).unwrap_or(0);
+        // the owner still pays for their other listings themselves
+        if storage_shortfall > self.storage_minimum_balance().0 || storage_shortfall > pool {
+            return None;
+        }
+        self.storage_sponsor_pools
+            .insert(nft_contract_id, &(pool - storage_shortfall));
+        let storage_deposit = self.storage_deposits.get(owner_id).unwrap_or(0);
+        self.storage_deposits
+            .insert(owner_id, &(storage_deposit + storage_shortfall));
+        Some(storage_shortfall)
+    }
+
+    pub(crate) fn internal_release_sponsored_storage(
+        &mut self,
+        nft_contract_id: &AccountId,
+        owner_id: &AccountId,
+        contract_and_token_id: &ContractAndTokenId,
+    ) {
+        let sponsored_storage = match self.sponsored_listings.remove(contract_and_token_id) {
+            Some(sponsored_storage) => sponsored_storage,
+            None => return,
+        };
+        let storage_deposit = self.storage_deposits.get(owner_id).unwrap_or(0);
+        let released = std::cmp::min(storage_deposit, sponsored_storage);
+        self.storage_deposits
+            .insert(owner_id, &(storage_deposit - released));
+        let pool = self.storage_sponsor_pools.get(nft_contract_id).unwrap_or(0);
+        self.storage_sponsor_pools
+            .insert(nft_contract_id, &(pool + released));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::*;
+
+/// stable-priced listings, a sale listed in a curated stablecoin accepts the others 1:1
+/// and settles in whichever one the buyer sent. Decimals come from the ft_decimals registry.
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn add_stablecoin_ids(&mut self, stablecoin_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "add_stablecoin_ids",
+            json!({ "stablecoin_ids": stablecoin_ids }),
+        );
+        for stablecoin_id in stablecoin_ids.iter() {
+            assert!(
+                self.approved_ft_token_ids.contains(stablecoin_id),
+                "Marble: {} is not approved",
+                stablecoin_id
+            );
+            assert!(
+                self.ft_token_decimals.get(stablecoin_id).is_some(),
+                "Marble: Decimals of {} are unknown",
+                stablecoin_id
+            );
+        }
+        add_accounts(Some(stablecoin_ids), &mut self.stablecoin_ids);
+    }
+
+    // stable-priced listings in a removed stablecoin are only bought in their own currency
+    #[payable]
+    pub fn remove_stablecoin_ids(&mut self, stablecoin_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "remove_stablecoin_ids",
+            json!({ "stablecoin_ids": stablecoin_ids }),
+        );
+        remove_accounts(Some(stablecoin_ids), &mut self.stablecoin_ids);
+    }
+
+    pub fn get_stablecoin_ids(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.stablecoin_ids.len());
+
+        self.stablecoin_ids
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    #[payable]
+    pub fn set_stable_priced(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        is_stable_priced: bool,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .expect("Marble: Market data does not exist");
+        assert_eq!(
+            market_data.owner_id,
+            env::predecessor_account_id(),
+            "Marble: Seller only"
+        );
+        self.internal_set_stable_priced(&market_data, is_stable_priced);
+    }
+
+    pub fn is_stable_priced(&self, nft_contract_id: AccountId, token_id: TokenId) -> bool {
+        self.stable_priced_listings
+            .contains(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+    }
+
+    pub(crate) fn internal_set_stable_priced(
+        &mut self,
+        market_data: &MarketData,
+        is_stable_priced: bool,
+    ) {
+        let contract_and_token_id = format!(
+            "{}{}{}",
+            market_data.nft_contract_id, DELIMETER, market_data.token_id
+        );
+        if is_stable_priced {
+            assert!(
+                self.stablecoin_ids.contains(&market_data.ft_token_id),
+                "Marble: Listing is not priced in a stablecoin"
+            );
+            assert!(
+                market_data.is_auction != Some(true) && market_data.end_price.is_none(),
+                "Marble: Stable-priced listing must be a fixed price sale"
+            );
+            self.stable_priced_listings.insert(&contract_and_token_id);
+        } else {
+            self.stable_priced_listings.remove(&contract_and_token_id);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "set_stable_priced",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "listing_id": self.internal_listing_id(&market_data.nft_contract_id, &market_data.token_id),
+                    "ft_token_id": market_data.ft_token_id,
+                    "is_stable_priced": is_stable_priced,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // the listing price in another curated stablecoin, rounded up so the seller never gets less
+    pub(crate) fn internal_stable_price(
+        &self,
+        contract_and_token_id: &ContractAndTokenId,
+        market_data: &MarketData,
+        ft_token_id: &AccountId,
+    ) -> Option<Balance> {
+        if !self.stable_priced_listings.contains(contract_and_token_id)
+            || !self.stablecoin_ids.contains(&market_data.ft_token_id)
+            || !self.stablecoin_ids.contains(ft_token_id)
+        {
+            return None;
+        }
+        let listed_decimals = self.ft_token_decimals.get(&market_data.ft_token_id)?;
+        let decimals = self.ft_token_decimals.get(ft_token_id)?;
+        if decimals >= listed_decimals {
+            10u128
+                .checked_pow((decimals - listed_decimals) as u32)
+                .and_then(|scale| market_data.price.checked_mul(scale))
+        } else {
+            let scale = 10u128.pow((listed_decimals - decimals) as u32);
+            Some((market_data.price + scale - 1) / scale)
+        }
+    }
+}
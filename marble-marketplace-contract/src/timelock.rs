@@ -0,0 +1,275 @@
+use crate::*;
+use near_sdk::json_types::Base58CryptoHash;
+use near_sdk::CryptoHash;
+use std::convert::TryInto;
+
+/// timelock, sensitive admin changes wait timelock_delay so users can exit before they apply
+pub const MAX_TIMELOCK_DELAY: u64 = 30 * ONE_DAY;
+pub const MAX_QUEUED_ADMIN_ACTIONS: u64 = 20;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminAction {
+    SetTransactionFee {
+        next_fee: u16,
+        start_time: Option<TimestampSec>,
+    },
+    SetTradeFee {
+        trade_fee: TradeFee,
+    },
+    SetListingFee {
+        listing_fee: U128,
+    },
+    SetFtTradeFee {
+        ft_token_id: AccountId,
+        flat_fee: Option<U128>,
+    },
+    SetFeeBrackets {
+        fee_brackets: Vec<FeeBracket>,
+    },
+    SetTreasury {
+        treasury_id: AccountId,
+    },
+    SetTreasuryRecipients {
+        treasury_recipients: Vec<TreasuryRecipient>,
+    },
+    RemoveApprovedNftContractIds {
+        nft_contract_ids: Vec<AccountId>,
+    },
+    Upgrade {
+        code_hash: Base58CryptoHash,
+    },
+    SetTimelockDelay {
+        timelock_delay: U64,
+    },
+}
+
+// what a DAO proposal sees as the outcome of a timelocked admin call
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AdminActionResult {
+    Applied,
+    Queued { action_id: U64, executable_at: U64 },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingAdminActions {
+    pub pending_owner_id: Option<AccountId>,
+    pub pending_treasury_id: Option<AccountId>,
+    pub pending_treasury_recipients: Vec<TreasuryRecipient>,
+    pub transaction_fee_changes: Vec<TransactionFeeChange>,
+    pub queued_admin_actions: Vec<QueuedAdminAction>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QueuedAdminAction {
+    pub action_id: U64,
+    pub action: AdminAction,
+    pub queued_by: AccountId,
+    pub executable_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    // shortening the delay waits out the current one
+    #[payable]
+    pub fn set_timelock_delay(&mut self, timelock_delay: U64) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action(
+            "set_timelock_delay",
+            json!({ "timelock_delay": timelock_delay }),
+        );
+        assert!(
+            timelock_delay.0 <= MAX_TIMELOCK_DELAY,
+            "Marble: timelock_delay cannot be longer than {}",
+            MAX_TIMELOCK_DELAY
+        );
+        if let Some(result) =
+            self.internal_queue_admin_action(AdminAction::SetTimelockDelay { timelock_delay })
+        {
+            return result;
+        }
+        self.timelock_delay = timelock_delay.0;
+        AdminActionResult::Applied
+    }
+
+    pub fn get_timelock_delay(&self) -> U64 {
+        U64(self.timelock_delay)
+    }
+
+    // the code is only sent with upgrade once the queued hash is executable
+    #[payable]
+    pub fn queue_upgrade(&mut self, code_hash: Base58CryptoHash) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_record_admin_action("queue_upgrade", json!({ "code_hash": code_hash }));
+        self.internal_queue_admin_action(AdminAction::Upgrade { code_hash })
+            .expect("Marble: Timelock is not set")
+    }
+
+    // anyone can apply a queued action once its delay has passed
+    #[payable]
+    pub fn execute_admin_action(&mut self, action_id: U64) {
+        assert_one_yocto();
+        let queued_admin_action = self
+            .queued_admin_actions
+            .get(&action_id.0)
+            .expect("Marble: Admin action does not exist");
+        assert!(
+            env::block_timestamp() >= queued_admin_action.executable_at.0,
+            "Marble: Admin action is not executable yet"
+        );
+        self.queued_admin_actions.remove(&action_id.0);
+        self.internal_record_admin_action(
+            "execute_admin_action",
+            json!({ "action_id": action_id, "action": queued_admin_action.action }),
+        );
+
+        match queued_admin_action.action.clone() {
+            AdminAction::SetTransactionFee {
+                next_fee,
+                start_time,
+            } => self.internal_set_transaction_fee(next_fee, start_time),
+            AdminAction::SetTradeFee { trade_fee } => self.trade_fee = trade_fee,
+            AdminAction::SetListingFee { listing_fee } => self.listing_fee = listing_fee.0,
+            AdminAction::SetFtTradeFee {
+                ft_token_id,
+                flat_fee,
+            } => self.internal_set_ft_trade_fee(ft_token_id, flat_fee),
+            AdminAction::SetFeeBrackets { fee_brackets } => self.fee_brackets = fee_brackets,
+            AdminAction::SetTreasury { treasury_id } => {
+                self.internal_propose_treasury(treasury_id, vec![])
+            }
+            AdminAction::SetTreasuryRecipients {
+                treasury_recipients,
+            } => self.internal_propose_treasury(
+                treasury_recipients[0].account_id.clone(),
+                treasury_recipients,
+            ),
+            AdminAction::RemoveApprovedNftContractIds { nft_contract_ids } => {
+                remove_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids)
+            }
+            AdminAction::Upgrade { .. } => {
+                env::panic_str("Marble: Upgrades are executed through upgrade")
+            }
+            AdminAction::SetTimelockDelay { timelock_delay } => {
+                self.timelock_delay = timelock_delay.0
+            }
+        }
+
+        env::log_str(
+            &json!({
+                "type": "execute_admin_action",
+                "params": queued_admin_action,
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn cancel_admin_action(&mut self, action_id: U64) {
+        assert_one_yocto();
+        self.assert_role(Role::Guardian);
+        self.internal_record_admin_action("cancel_admin_action", json!({ "action_id": action_id }));
+        let queued_admin_action = self
+            .queued_admin_actions
+            .remove(&action_id.0)
+            .expect("Marble: Admin action does not exist");
+
+        env::log_str(
+            &json!({
+                "type": "cancel_admin_action",
+                "params": queued_admin_action,
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_queued_admin_actions(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<QueuedAdminAction> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.queued_admin_actions.len());
+
+        self.queued_admin_actions
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    // everything a governance UI has to show before it is applied
+    pub fn get_pending_admin_actions(&self) -> PendingAdminActions {
+        PendingAdminActions {
+            pending_owner_id: self.pending_owner_id.clone(),
+            pending_treasury_id: self.pending_treasury_id.clone(),
+            pending_treasury_recipients: self.pending_treasury_recipients.clone(),
+            transaction_fee_changes: self.get_transaction_fee_changes(),
+            queued_admin_actions: self.get_queued_admin_actions(None, None),
+        }
+    }
+
+    // None when there is no timelock and the caller applies the change right away
+    pub(crate) fn internal_queue_admin_action(
+        &mut self,
+        action: AdminAction,
+    ) -> Option<AdminActionResult> {
+        if self.timelock_delay == 0 {
+            return None;
+        }
+        assert!(
+            self.queued_admin_actions.len() < MAX_QUEUED_ADMIN_ACTIONS,
+            "Marble: Up to {} admin actions can be queued",
+            MAX_QUEUED_ADMIN_ACTIONS
+        );
+        let action_id = self.next_admin_action_id;
+        self.next_admin_action_id += 1;
+        let queued_admin_action = QueuedAdminAction {
+            action_id: U64(action_id),
+            action,
+            queued_by: env::predecessor_account_id(),
+            executable_at: U64(env::block_timestamp() + self.timelock_delay),
+        };
+        self.queued_admin_actions
+            .insert(&action_id, &queued_admin_action);
+
+        env::log_str(
+            &json!({
+                "type": "queue_admin_action",
+                "params": queued_admin_action,
+            })
+            .to_string(),
+        );
+        Some(AdminActionResult::Queued {
+            action_id: queued_admin_action.action_id,
+            executable_at: queued_admin_action.executable_at,
+        })
+    }
+
+    // the executable queued upgrade of this exact code, taken off the queue
+    pub(crate) fn internal_take_queued_upgrade(&mut self, code: &[u8]) {
+        let code_hash =
+            Base58CryptoHash::from(TryInto::<CryptoHash>::try_into(env::sha256(code)).unwrap());
+        let action_id = self
+            .queued_admin_actions
+            .values()
+            .find(|queued_admin_action| {
+                env::block_timestamp() >= queued_admin_action.executable_at.0
+                    && matches!(
+                        &queued_admin_action.action,
+                        AdminAction::Upgrade { code_hash: queued_code_hash }
+                            if *queued_code_hash == code_hash
+                    )
+            })
+            .map(|queued_admin_action| queued_admin_action.action_id)
+            .expect("Marble: Upgrade is not queued or not executable yet");
+        self.queued_admin_actions.remove(&action_id.0);
+    }
+}
@@ -1,29 +1,91 @@
 use crate::*;
+use near_sdk::json_types::U128;
 use near_sdk::PromiseOrValue;
+use near_sdk::{serde_json, Promise};
 use std::convert::TryInto;
-use near_sdk::json_types::{U128};
-use near_sdk::{Promise, serde_json};
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 
+/// why a deposit was handed back, logged in token_deposit_refund for wallets to explain
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum TokenReceiverError {
+    EmptyMsg,
+    InvalidMsg,
+    UnknownMethod { method: String },
+    MissingField { field: String },
+    FtTokenIdMismatch,
+    NotApprovedFt,
+    Paused { feature: PauseFeature },
+    BuyerBlacklisted,
+    CollectionFrozen,
+    ListingNotFound,
+    AuctionNotStarted,
+    AuctionEnded,
+    InvalidBidAmount,
+    InvalidBatch,
+    InvalidQuantity,
+    TradeFeeNotPayable,
+    TradeFeeAlreadyPaid,
+    InsufficientTradeFee { trade_fee: U128 },
+}
+
 #[derive(Deserialize)]
 #[serde(crate = "near_sdk::serde")]
-pub struct TokenInfo {
-    #[serde(skip_serializing_if = "Option::is_none")]
+pub struct BatchBid {
     pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub amount: U128,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub ft_token_id: AccountId,
+    pub nft_contract_id: Option<AccountId>, // every method but auction_batch
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub ft_token_id: Option<AccountId>, // optional, the token is always the calling contract
+    #[serde(default)]
     pub token_id: TokenId,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub method: String
+    pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referral_id: Option<AccountId>,
+    // auction, the bid when less than the transferred amount is bid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bid_amount: Option<U128>,
+    // auction_batch, the amounts sum to the transferred amount
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bids: Option<Vec<BatchBid>>,
+    // trade deposit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_series_id: Option<TokenSeriesId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_nft_contract_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_token_id: Option<TokenId>,
+    // buy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receiver_id: Option<AccountId>,
+    // offer, the price is the amount over the quantity, update_offer raises it by as much
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seller_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<u64>,
+    // buy_with_swap, the DEX pool from the paid token into the listing's currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_pool_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_amount_out: Option<U128>,
 }
 
-
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
     /// Callback on receiving tokens by this contract.
-    /// transfer reward token with specific msg indicate 
+    /// transfer reward token with specific msg indicate
     fn ft_on_transfer(
         &mut self,
         sender_id: ValidAccountId,
@@ -31,26 +93,323 @@ impl FungibleTokenReceiver for Contract {
         msg: String,
     ) -> PromiseOrValue<U128> {
         // assert!(self.data().state == RunningState::Running, "{}", ERR600_CONTRACT_PAUSED);
-        
+
         let sender: AccountId = sender_id.into();
         let amount: u128 = amount.into();
         println!("Sender {}", sender);
-        assert!(msg.is_empty()==false, "Empty Message");
+        if self
+            .mt_currencies
+            .get(&env::predecessor_account_id())
+            .is_some()
+        {
+            log_token_deposit_refund(&sender, amount, &TokenReceiverError::NotApprovedFt);
+            return PromiseOrValue::Value(U128(amount));
+        }
+        PromiseOrValue::Value(U128(self.internal_on_token_transfer(sender, amount, msg)))
+    }
+}
+
+impl Contract {
+    // shared by ft_on_transfer and mt_on_transfer, the paying token is the predecessor
+    pub(crate) fn internal_on_token_transfer(
+        &mut self,
+        sender: AccountId,
+        amount: u128,
+        msg: String,
+    ) -> u128 {
+        // whatever cannot be used is handed back by the token contract
+        match self.internal_try_on_token_transfer(sender.clone(), amount, msg) {
+            Ok(unused_amount) => unused_amount,
+            Err(error) => {
+                log_token_deposit_refund(&sender, amount, &error);
+                amount
+            }
+        }
+    }
+
+    fn internal_try_on_token_transfer(
+        &mut self,
+        sender: AccountId,
+        amount: u128,
+        msg: String,
+    ) -> Result<u128, TokenReceiverError> {
+        // paused deposits are handed back in full, never panic as the transfer would not be refunded
+        if self.is_paused(PauseFeature::FtDeposits) {
+            return Err(TokenReceiverError::Paused {
+                feature: PauseFeature::FtDeposits,
+            });
+        }
+        if msg.is_empty() {
+            return Err(TokenReceiverError::EmptyMsg);
+        }
+        let token_info: TokenInfo =
+            serde_json::from_str(&msg).map_err(|_| TokenReceiverError::InvalidMsg)?;
 
         let TokenInfo {
             nft_contract_id,
             ft_token_id,
             token_id,
-            method
-        } = near_sdk::serde_json::from_str(&msg).expect("Not valid TokneInfoArgs");
-        
+            method,
+            referral_id,
+            bid_amount,
+            bids,
+            token_series_id,
+            buyer_id,
+            buyer_nft_contract_id,
+            buyer_token_id,
+            receiver_id,
+            seller_id,
+            quantity,
+            swap_pool_id,
+            min_amount_out,
+        } = token_info;
+
         println!("Info: {:?}, {:?}", nft_contract_id, ft_token_id);
-        if method == "auction" {
-            self.internal_ft_token_add_bid(nft_contract_id, ft_token_id, token_id, sender, amount.into());
-        } else if method == "buy" {
-            self.internal_buy(nft_contract_id, token_id, ft_token_id, sender, amount.into());
+
+        // the paying token is the contract calling us, never what the msg claims
+        let ft_token_id_res = env::predecessor_account_id();
+        if ft_token_id.map_or(false, |ft_token_id| ft_token_id != ft_token_id_res) {
+            return Err(TokenReceiverError::FtTokenIdMismatch);
+        }
+        if !self.approved_ft_token_ids.contains(&ft_token_id_res) {
+            return Err(TokenReceiverError::NotApprovedFt);
+        }
+        let ft_token_id = ft_token_id_res;
+
+        let feature = match method.as_str() {
+            "auction" | "auction_batch" | "buy" | "buy_with_swap" => PauseFeature::Buys,
+            "offer" | "offer_series" | "update_offer" => PauseFeature::Offers,
+            _ => PauseFeature::Trades,
+        };
+        if self.is_paused(feature) {
+            return Err(TokenReceiverError::Paused { feature });
+        }
+        if self.blacklisted_buyer_ids.contains(&sender) {
+            return Err(TokenReceiverError::BuyerBlacklisted);
+        }
+
+        if method == "auction_batch" {
+            return self.internal_ft_token_add_bids(
+                ft_token_id,
+                sender,
+                amount,
+                bids.unwrap_or_default(),
+            );
+        }
+        let nft_contract_id = match nft_contract_id {
+            Some(nft_contract_id) => nft_contract_id,
+            None => {
+                return Err(TokenReceiverError::MissingField {
+                    field: "nft_contract_id".to_string(),
+                });
+            }
+        };
+        if self.frozen_nft_contract_ids.contains(&nft_contract_id) {
+            return Err(TokenReceiverError::CollectionFrozen);
         }
+
+        let unused_amount = if method == "auction" {
+            let bid_amount = bid_amount.map_or(amount, |bid_amount| bid_amount.0);
+            if bid_amount == 0 || bid_amount > amount {
+                return Err(TokenReceiverError::InvalidBidAmount);
+            }
+            self.internal_check_ft_bid(&nft_contract_id, &token_id)?;
+            self.internal_ft_token_add_bid(
+                nft_contract_id,
+                ft_token_id,
+                token_id,
+                sender,
+                bid_amount.into(),
+            );
+            amount - bid_amount
+        } else if method == "buy" {
+            self.internal_buy(
+                nft_contract_id,
+                token_id,
+                ft_token_id,
+                sender,
+                amount.into(),
+                referral_id,
+                receiver_id,
+                self.config.gas_for_ft_payout,
+            )
+        } else if method == "buy_with_swap" {
+            let (swap_pool_id, min_amount_out) = match (swap_pool_id, min_amount_out) {
+                (Some(swap_pool_id), Some(min_amount_out)) => (swap_pool_id, min_amount_out),
+                _ => {
+                    return Err(TokenReceiverError::MissingField {
+                        field: "swap_pool_id and min_amount_out".to_string(),
+                    });
+                }
+            };
+            self.internal_buy_with_swap(
+                nft_contract_id,
+                token_id,
+                ft_token_id,
+                sender,
+                amount,
+                swap_pool_id,
+                min_amount_out,
+                referral_id,
+                receiver_id,
+            );
+            0
+        } else if method == "add_trade_deposit" {
+            let (buyer_nft_contract_id, buyer_token_id) =
+                match (buyer_nft_contract_id, buyer_token_id) {
+                    (Some(buyer_nft_contract_id), Some(buyer_token_id)) => {
+                        (buyer_nft_contract_id, buyer_token_id)
+                    }
+                    _ => {
+                        return Err(TokenReceiverError::MissingField {
+                            field: "buyer_nft_contract_id and buyer_token_id".to_string(),
+                        });
+                    }
+                };
+            // escrow is held in the token that was actually transferred
+            let (token_id, token_series_id) = if token_series_id.is_some() {
+                (None, token_series_id)
+            } else {
+                (Some(token_id), None)
+            };
+            self.internal_add_trade_deposit(
+                sender.clone(),
+                ft_token_id,
+                amount,
+                nft_contract_id,
+                token_id,
+                token_series_id,
+                buyer_id.unwrap_or(sender),
+                buyer_nft_contract_id,
+                buyer_token_id,
+            );
+            0
+        } else if method == "trade_fee" {
+            self.internal_pay_trade_fee(sender, ft_token_id, amount)?
+        } else if method == "offer" || method == "offer_series" {
+            let (token_id, token_series_id) = if method == "offer_series" {
+                (None, token_series_id)
+            } else {
+                (Some(token_id), None)
+            };
+            let editions = quantity.unwrap_or(1) as u128;
+            if editions == 0 {
+                return Err(TokenReceiverError::InvalidQuantity);
+            }
+            // what does not divide evenly between the editions is handed back
+            self.internal_place_offer(
+                nft_contract_id,
+                token_id,
+                token_series_id,
+                ft_token_id,
+                U128(amount / editions),
+                sender,
+                seller_id,
+                quantity,
+                referral_id,
+            );
+            amount % editions
+        } else if method == "update_offer" {
+            // raises the sender's offer in the transferred token, lowering goes through update_offer
+            let (token_id, token_series_id) = if token_series_id.is_some() {
+                (None, token_series_id)
+            } else {
+                (Some(token_id), None)
+            };
+            self.internal_raise_ft_offer(
+                nft_contract_id,
+                token_id,
+                token_series_id,
+                ft_token_id,
+                sender,
+                amount,
+            )
+        } else {
+            return Err(TokenReceiverError::UnknownMethod { method });
+        };
         println!("FT Transfer Call");
-        PromiseOrValue::Value(U128(0))
+        Ok(unused_amount)
+    }
+
+    // one transfer for many auctions, a bid that cannot be placed fails the whole transfer
+    fn internal_ft_token_add_bids(
+        &mut self,
+        ft_token_id: AccountId,
+        sender: AccountId,
+        amount: u128,
+        bids: Vec<BatchBid>,
+    ) -> Result<u128, TokenReceiverError> {
+        if bids.is_empty() || bids.len() > MAX_BATCH_BIDS {
+            return Err(TokenReceiverError::InvalidBatch);
+        }
+        let total_amount = bids
+            .iter()
+            .try_fold(0u128, |total, bid| total.checked_add(bid.amount.0));
+        if total_amount != Some(amount) {
+            return Err(TokenReceiverError::InvalidBatch);
+        }
+        for bid in bids.iter() {
+            if self.frozen_nft_contract_ids.contains(&bid.nft_contract_id) {
+                return Err(TokenReceiverError::CollectionFrozen);
+            }
+            self.internal_check_ft_bid(&bid.nft_contract_id, &bid.token_id)?;
+        }
+
+        for bid in bids {
+            self.internal_ft_token_add_bid(
+                bid.nft_contract_id,
+                ft_token_id.clone(),
+                bid.token_id,
+                sender.clone(),
+                bid.amount,
+            );
+        }
+        Ok(0)
+    }
+
+    // the auction checks a wallet can explain, the others still fail the transfer in add_bid
+    fn internal_check_ft_bid(
+        &self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+    ) -> Result<(), TokenReceiverError> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self
+            .market
+            .get(&contract_and_token_id)
+            .ok_or(TokenReceiverError::ListingNotFound)?;
+        let current_time = env::block_timestamp();
+        if market_data
+            .started_at
+            .map_or(false, |started_at| current_time < started_at)
+        {
+            return Err(TokenReceiverError::AuctionNotStarted);
+        }
+        if market_data
+            .ended_at
+            .map_or(false, |ended_at| current_time > ended_at)
+        {
+            return Err(TokenReceiverError::AuctionEnded);
+        }
+        Ok(())
     }
 }
+
+pub(crate) fn log_token_deposit_refund(
+    sender_id: &AccountId,
+    amount: u128,
+    error: &TokenReceiverError,
+) {
+    env::log_str(
+        &json!({
+            "type": "token_deposit_refund",
+            "params": {
+                "sender_id": sender_id,
+                "ft_token_id": env::predecessor_account_id(),
+                "amount": U128(amount),
+                "error": error,
+            }
+        })
+        .to_string(),
+    );
+}
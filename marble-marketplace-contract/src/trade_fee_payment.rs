@@ -0,0 +1,127 @@
+use crate::*;
+
+/// trade fees paid in an approved FT, the seller pays ahead with ft_transfer_call "trade_fee"
+/// and their next accepted trade consumes it instead of the NEAR flat fee.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeFeePayment {
+    pub ft_token_id: AccountId,
+    pub amount: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    // the flat fee in the FT's own decimals, None stops accepting it
+    #[payable]
+    pub fn set_ft_trade_fee(
+        &mut self,
+        ft_token_id: AccountId,
+        flat_fee: Option<U128>,
+    ) -> AdminActionResult {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        self.internal_record_admin_action(
+            "set_ft_trade_fee",
+            json!({ "ft_token_id": ft_token_id, "flat_fee": flat_fee }),
+        );
+        assert_ne!(
+            ft_token_id,
+            near_account(),
+            "Marble: NEAR trade fee is set with set_trade_fee"
+        );
+        if let Some(result) = self.internal_queue_admin_action(AdminAction::SetFtTradeFee {
+            ft_token_id: ft_token_id.clone(),
+            flat_fee,
+        }) {
+            return result;
+        }
+        self.internal_set_ft_trade_fee(ft_token_id, flat_fee);
+        AdminActionResult::Applied
+    }
+
+    pub fn get_ft_trade_fee(&self, ft_token_id: AccountId) -> Option<U128> {
+        self.ft_trade_fees.get(&ft_token_id).map(U128)
+    }
+
+    pub fn get_trade_fee_payment(&self, account_id: AccountId) -> Option<TradeFeePayment> {
+        self.trade_fee_payments.get(&account_id)
+    }
+
+    // an unused payment is returned in the token it was paid in
+    #[payable]
+    pub fn withdraw_trade_fee_payment(&mut self) -> U128 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let payment = self
+            .trade_fee_payments
+            .remove(&account_id)
+            .expect("Marble: No trade fee payment");
+        self.internal_transfer(&payment.ft_token_id, account_id.clone(), payment.amount.0);
+
+        env::log_str(
+            &json!({
+                "type": "withdraw_trade_fee_payment",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": payment.ft_token_id,
+                    "amount": payment.amount,
+                }
+            })
+            .to_string(),
+        );
+        payment.amount
+    }
+
+    pub(crate) fn internal_set_ft_trade_fee(
+        &mut self,
+        ft_token_id: AccountId,
+        flat_fee: Option<U128>,
+    ) {
+        match flat_fee {
+            Some(flat_fee) => self.ft_trade_fees.insert(&ft_token_id, &flat_fee.0),
+            None => self.ft_trade_fees.remove(&ft_token_id),
+        };
+    }
+
+    // returns what is left over the fee
+    pub(crate) fn internal_pay_trade_fee(
+        &mut self,
+        account_id: AccountId,
+        ft_token_id: AccountId,
+        amount: Balance,
+    ) -> Result<Balance, TokenReceiverError> {
+        let flat_fee = match self.ft_trade_fees.get(&ft_token_id) {
+            Some(flat_fee) if flat_fee > 0 => flat_fee,
+            _ => return Err(TokenReceiverError::TradeFeeNotPayable),
+        };
+        if self.trade_fee_payments.get(&account_id).is_some() {
+            return Err(TokenReceiverError::TradeFeeAlreadyPaid);
+        }
+        if amount < flat_fee {
+            return Err(TokenReceiverError::InsufficientTradeFee {
+                trade_fee: U128(flat_fee),
+            });
+        }
+
+        self.trade_fee_payments.insert(
+            &account_id,
+            &TradeFeePayment {
+                ft_token_id: ft_token_id.clone(),
+                amount: U128(flat_fee),
+            },
+        );
+
+        env::log_str(
+            &json!({
+                "type": "pay_trade_fee",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(flat_fee),
+                }
+            })
+            .to_string(),
+        );
+        Ok(amount - flat_fee)
+    }
+}
@@ -0,0 +1,43 @@
+use crate::*;
+
+/// code upgrades, the new code is deployed and migrated in one batch, see queue_upgrade
+impl Config {
+    fn gas_for_migrate(&self) -> Gas {
+        Gas(self.base_gas.0 * 20)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // a DAO owner passes the wasm borsh-serialized in a function call proposal
+    #[payable]
+    pub fn upgrade(&mut self, #[serializer(borsh)] code: Vec<u8>) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(!code.is_empty(), "Marble: Code is empty");
+        if self.timelock_delay > 0 {
+            self.internal_take_queued_upgrade(&code);
+        }
+        self.internal_record_admin_action("upgrade", json!({ "code_size": code.len() }));
+
+        env::log_str(
+            &json!({
+                "type": "upgrade",
+                "params": {
+                    "account_id": env::predecessor_account_id(),
+                    "code_size": code.len(),
+                }
+            })
+            .to_string(),
+        );
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                "migrate".to_string(),
+                vec![],
+                NO_DEPOSIT,
+                self.config.gas_for_migrate(),
+            )
+    }
+}
@@ -0,0 +1,97 @@
+use crate::*;
+
+/// verified collections, front-ends show a badge for approved contracts vetted by moderators
+pub const MAX_COLLECTION_NAME_LEN: usize = 64;
+pub const MAX_ICON_HASH_LEN: usize = 64;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionVerification {
+    pub name: String,
+    pub icon_hash: Option<String>, // e.g. the sha256 of the collection icon
+    pub verified_by: AccountId,
+    pub verified_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    // verifying again updates the metadata
+    #[payable]
+    pub fn verify_collection(
+        &mut self,
+        nft_contract_id: AccountId,
+        name: String,
+        icon_hash: Option<String>,
+    ) {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_record_admin_action(
+            "verify_collection",
+            json!({ "nft_contract_id": nft_contract_id, "name": name, "icon_hash": icon_hash }),
+        );
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "Marble: nft_contract_id is not approved"
+        );
+        assert!(
+            !name.is_empty() && name.len() <= MAX_COLLECTION_NAME_LEN,
+            "Marble: name must be 1 to {} bytes",
+            MAX_COLLECTION_NAME_LEN
+        );
+        assert!(
+            icon_hash
+                .as_ref()
+                .map_or(true, |icon_hash| icon_hash.len() <= MAX_ICON_HASH_LEN),
+            "Marble: icon_hash is longer than {} bytes",
+            MAX_ICON_HASH_LEN
+        );
+        self.verified_collections.insert(
+            &nft_contract_id,
+            &CollectionVerification {
+                name,
+                icon_hash,
+                verified_by: env::predecessor_account_id(),
+                verified_at: U64(env::block_timestamp()),
+            },
+        );
+    }
+
+    #[payable]
+    pub fn unverify_collection(&mut self, nft_contract_id: AccountId) {
+        assert_one_yocto();
+        self.assert_role(Role::ListingModerator);
+        self.internal_record_admin_action(
+            "unverify_collection",
+            json!({ "nft_contract_id": nft_contract_id }),
+        );
+        self.verified_collections
+            .remove(&nft_contract_id)
+            .expect("Marble: Collection is not verified");
+    }
+
+    // a collection loses its badge while it is not approved
+    pub fn get_collection_verification(
+        &self,
+        nft_contract_id: AccountId,
+    ) -> Option<CollectionVerification> {
+        if !self.approved_nft_contract_ids.contains(&nft_contract_id) {
+            return None;
+        }
+        self.verified_collections.get(&nft_contract_id)
+    }
+
+    pub fn get_verified_collections(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let from_index = from_index.map_or(0, |from_index| from_index.0);
+        let limit = limit.unwrap_or(self.verified_collections.len());
+
+        self.verified_collections
+            .keys()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+}
@@ -0,0 +1,377 @@
+use crate::*;
+
+/// wNEAR settlements, wNEAR pays NEAR listings 1:1 and sellers may take proceeds wrapped or unwrapped
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutPreference {
+    AsListed,
+    WrappedNear, // NEAR proceeds are paid in wNEAR
+    NativeNear,  // wNEAR proceeds are paid in NEAR
+}
+
+impl Config {
+    fn gas_for_near_deposit(&self) -> Gas {
+        Gas(self.base_gas.0 * 2)
+    }
+
+    fn gas_for_near_withdraw(&self) -> Gas {
+        Gas(self.base_gas.0 * 2)
+    }
+
+    fn gas_for_callback_unwrap_purchase(&self) -> Gas {
+        Gas(self.gas_for_nft_token.0 * 2
+            + self.gas_for_nft_transfer.0
+            + self.gas_for_royalties.0
+            + self.base_gas.0 * 4)
+    }
+
+    fn gas_for_callback_wrapped_proceeds(&self) -> Gas {
+        Gas(self.gas_for_near_withdraw().0 + self.gas_for_ft_transfer.0 + self.base_gas.0)
+    }
+
+    fn gas_for_callback_wrapped_deposit(&self) -> Gas {
+        Gas(self.gas_for_ft_transfer.0
+            + self.gas_for_callback_wrapped_proceeds().0
+            + self.base_gas.0)
+    }
+
+    fn gas_for_callback_unwrapped_proceeds(&self) -> Gas {
+        Gas(self.base_gas.0 * 2)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn set_payout_preference(&mut self, payout_preference: PayoutPreference) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        if payout_preference != PayoutPreference::AsListed {
+            assert!(
+                self.wrap_near_id.is_some(),
+                "Marble: wNEAR contract is not set"
+            );
+        }
+        self.wrapped_near_sellers.remove(&account_id);
+        self.native_near_sellers.remove(&account_id);
+        match payout_preference {
+            PayoutPreference::AsListed => {}
+            PayoutPreference::WrappedNear => {
+                self.wrapped_near_sellers.insert(&account_id);
+            }
+            PayoutPreference::NativeNear => {
+                self.native_near_sellers.insert(&account_id);
+            }
+        }
+
+        env::log_str(
+            &json!({
+                "type": "set_payout_preference",
+                "params": {
+                    "account_id": account_id,
+                    "payout_preference": payout_preference,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_payout_preference(&self, account_id: AccountId) -> PayoutPreference {
+        if self.wrapped_near_sellers.contains(&account_id) {
+            PayoutPreference::WrappedNear
+        } else if self.native_near_sellers.contains(&account_id) {
+            PayoutPreference::NativeNear
+        } else {
+            PayoutPreference::AsListed
+        }
+    }
+
+    // superseded by set_payout_preference
+    #[payable]
+    pub fn set_receive_wrapped_near(&mut self, receive_wrapped_near: bool) {
+        self.set_payout_preference(if receive_wrapped_near {
+            PayoutPreference::WrappedNear
+        } else {
+            PayoutPreference::AsListed
+        });
+    }
+
+    pub fn get_receive_wrapped_near(&self, account_id: AccountId) -> bool {
+        self.wrapped_near_sellers.contains(&account_id)
+    }
+
+    // the seller's share of a sale, wrapped or unwrapped first as they asked
+    pub(crate) fn internal_transfer_seller_proceeds(
+        &self,
+        ft_token_id: &AccountId,
+        seller_id: AccountId,
+        amount: u128,
+    ) {
+        if let Some(wrap_near_id) = self.wrap_near_id.as_ref() {
+            if ft_token_id == wrap_near_id && self.native_near_sellers.contains(&seller_id) {
+                return self.internal_transfer_unwrapped_proceeds(wrap_near_id, seller_id, amount);
+            }
+        }
+        let wrap_near_id = match self.wrap_near_id.as_ref() {
+            Some(wrap_near_id)
+                if *ft_token_id == near_account()
+                    && self.wrapped_near_sellers.contains(&seller_id) =>
+            {
+                wrap_near_id.clone()
+            }
+            _ => return self.internal_transfer_payout(ft_token_id, seller_id, amount),
+        };
+
+        ext_wrap_near::near_deposit(
+            wrap_near_id.clone(),
+            amount,
+            self.config.gas_for_near_deposit(),
+        )
+        .then(ext_self::callback_wrapped_deposit(
+            wrap_near_id,
+            seller_id,
+            amount.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_wrapped_deposit(),
+        ));
+    }
+
+    // the NEAR comes back when it cannot be wrapped, the seller is paid in NEAR instead
+    #[private]
+    pub fn callback_wrapped_deposit(
+        &mut self,
+        wrap_near_id: AccountId,
+        seller_id: AccountId,
+        amount: U128,
+    ) {
+        if is_promise_success() {
+            ext_fungible_token::ft_transfer(
+                seller_id.clone(),
+                amount,
+                None,
+                wrap_near_id.clone(),
+                1,
+                self.config.gas_for_ft_transfer,
+            )
+            .then(ext_self::callback_wrapped_proceeds(
+                wrap_near_id,
+                seller_id,
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.config.gas_for_callback_wrapped_proceeds(),
+            ));
+            return;
+        }
+        Promise::new(seller_id.clone()).transfer(amount.0);
+
+        env::log_str(
+            &json!({
+                "type": "wrapped_proceeds_fail",
+                "params": {
+                    "seller_id": seller_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // the seller is not registered with the wNEAR contract, they are paid in NEAR instead
+    #[private]
+    pub fn callback_wrapped_proceeds(
+        &mut self,
+        wrap_near_id: AccountId,
+        seller_id: AccountId,
+        amount: U128,
+    ) {
+        if is_promise_success() {
+            return;
+        }
+        ext_wrap_near::near_withdraw(amount, wrap_near_id, 1, self.config.gas_for_near_withdraw())
+            .then(Promise::new(seller_id.clone()).transfer(amount.0));
+
+        env::log_str(
+            &json!({
+                "type": "wrapped_proceeds_fail",
+                "params": {
+                    "seller_id": seller_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    fn internal_transfer_unwrapped_proceeds(
+        &self,
+        wrap_near_id: &AccountId,
+        seller_id: AccountId,
+        amount: u128,
+    ) {
+        ext_wrap_near::near_withdraw(
+            U128(amount),
+            wrap_near_id.clone(),
+            1,
+            self.config.gas_for_near_withdraw(),
+        )
+        .then(ext_self::callback_unwrapped_proceeds(
+            wrap_near_id.clone(),
+            seller_id,
+            U128(amount),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_unwrapped_proceeds(),
+        ));
+    }
+
+    // the wNEAR stays with the marketplace if it cannot be unwrapped, the seller claims it
+    #[private]
+    pub fn callback_unwrapped_proceeds(
+        &mut self,
+        wrap_near_id: AccountId,
+        seller_id: AccountId,
+        amount: U128,
+    ) {
+        if is_promise_success() {
+            Promise::new(seller_id).transfer(amount.0);
+            return;
+        }
+        self.internal_credit_claimable_balance(&seller_id, &wrap_near_id, amount.0);
+
+        env::log_str(
+            &json!({
+                "type": "unwrapped_proceeds_fail",
+                "params": {
+                    "seller_id": seller_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    // wNEAR sent for a NEAR listing is unwrapped, the sale then settles in NEAR
+    pub(crate) fn internal_unwrap_near_purchase(
+        &mut self,
+        wrap_near_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        price: u128,
+        referral_id: Option<AccountId>,
+        holder_nft_contract_id: Option<AccountId>,
+    ) -> Promise {
+        ext_wrap_near::near_withdraw(
+            U128(price),
+            wrap_near_id.clone(),
+            1,
+            self.config.gas_for_near_withdraw(),
+        )
+        .then(ext_self::callback_unwrap_near_purchase(
+            wrap_near_id,
+            nft_contract_id,
+            token_id,
+            owner_id,
+            buyer_id,
+            receiver_id,
+            U128(price),
+            referral_id,
+            holder_nft_contract_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.config.gas_for_callback_unwrap_purchase(),
+        ))
+    }
+
+    // the listing is checked again once unwrapped, a dutch auction settles at its price by then
+    #[private]
+    pub fn callback_unwrap_near_purchase(
+        &mut self,
+        wrap_near_id: AccountId,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        buyer_id: AccountId,
+        receiver_id: AccountId,
+        price: U128,
+        referral_id: Option<AccountId>,
+        holder_nft_contract_id: Option<AccountId>,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let is_unwrapped = is_promise_success();
+        // relisted by another owner or at a higher price, the sale has changed
+        let current_price = self
+            .internal_get_market_data(&contract_and_token_id)
+            .filter(|market_data| market_data.owner_id == owner_id)
+            .and_then(|market_data| {
+                self.internal_purchasable_price(&contract_and_token_id, &market_data, &buyer_id)
+            })
+            .filter(|current_price| *current_price <= price.0);
+        let is_paused = self.is_paused(PauseFeature::Buys);
+
+        if !is_unwrapped || current_price.is_none() || is_paused {
+            // refunded in whatever the marketplace holds after the unwrap attempt
+            let refund_token_id = if is_unwrapped {
+                near_account()
+            } else {
+                wrap_near_id
+            };
+            self.internal_transfer(&refund_token_id, buyer_id.clone(), price.0);
+            env::log_str(
+                &json!({
+                    "type": "resolve_purchase_fail",
+                    "params": {
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "listing_id": self.internal_listing_id(&nft_contract_id, &token_id),
+                        "ft_token_id": refund_token_id,
+                        "price": price,
+                        "buyer_id": buyer_id,
+                        "reason": if !is_unwrapped {
+                            "unwrap failed"
+                        } else if is_paused {
+                            "buys are paused"
+                        } else {
+                            "sale has changed"
+                        },
+                    }
+                })
+                .to_string(),
+            );
+            return;
+        }
+        let current_price = current_price.unwrap();
+        if current_price < price.0 {
+            Promise::new(buyer_id.clone()).transfer(price.0 - current_price);
+        }
+
+        if let Some(holder_nft_contract_id) = holder_nft_contract_id {
+            self.internal_buy_presale(
+                holder_nft_contract_id,
+                nft_contract_id,
+                token_id,
+                near_account(),
+                buyer_id,
+                receiver_id,
+                current_price,
+                referral_id,
+            );
+            return;
+        }
+        self.internal_process_purchase(
+            nft_contract_id,
+            token_id,
+            near_account(),
+            buyer_id,
+            receiver_id,
+            current_price,
+            referral_id,
+            self.config.gas_for_royalties,
+        );
+    }
+}